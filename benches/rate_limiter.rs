@@ -0,0 +1,74 @@
+//! Criterion benchmarks of the hot path, run with
+//! `cargo bench --features bench --bench rate_limiter`.
+
+use criterion::{BenchmarkId, Criterion, Throughput};
+use rate_limit::{
+    CalendarWindow, FixedWindow, Gcra, LeakyBucket, MovingWindow, RateLimiter, ShardedTokenBucket,
+    Shared, SlidingWindow, SystemClock, TokenBucket,
+};
+use std::{sync::Arc, thread, time};
+
+/// High enough that the benchmarks exercise the admitting path, which does the most work.
+const LIMIT: usize = 1_000_000_000;
+
+fn single<L: RateLimiter<Clock = SystemClock>>(c: &mut Criterion, name: &str) {
+    let mut group = c.benchmark_group("allowed");
+    group.throughput(Throughput::Elements(1));
+
+    let mut limiter = L::new(time::Duration::from_secs(1), LIMIT);
+    group.bench_function(name, |b| b.iter(|| limiter.allowed()));
+    group.finish();
+}
+
+/// Checks from `threads` threads at once, each doing an equal share of the iterations.
+fn contended(c: &mut Criterion, name: &str, allowed: Arc<dyn Fn() -> bool + Send + Sync>) {
+    let mut group = c.benchmark_group(format!("contended/{name}"));
+
+    for threads in [1, 2, 4, 8] {
+        group.throughput(Throughput::Elements(1));
+        group.bench_with_input(BenchmarkId::from_parameter(threads), &threads, |b, &n| {
+            b.iter_custom(|iters| {
+                let per_thread = iters.div_ceil(n);
+                let start = time::Instant::now();
+                let handles: Vec<_> = (0..n)
+                    .map(|_| {
+                        let allowed = allowed.clone();
+                        thread::spawn(move || (0..per_thread).for_each(|_| _ = allowed()))
+                    })
+                    .collect();
+                handles.into_iter().for_each(|h| h.join().unwrap());
+                start.elapsed()
+            })
+        });
+    }
+
+    group.finish();
+}
+
+fn all(c: &mut Criterion) {
+    single::<FixedWindow>(c, "fixed window");
+    single::<CalendarWindow>(c, "calendar window");
+    single::<MovingWindow>(c, "moving window");
+    single::<SlidingWindow>(c, "sliding window");
+    single::<TokenBucket>(c, "token bucket");
+    single::<LeakyBucket>(c, "leaky bucket");
+    single::<Gcra>(c, "gcra");
+
+    let shared: Arc<Shared<TokenBucket>> =
+        Arc::new(Shared::new(time::Duration::from_secs(1), LIMIT));
+    contended(c, "shared token bucket", Arc::new(move || shared.allowed()));
+
+    let sharded: Arc<ShardedTokenBucket> = Arc::new(ShardedTokenBucket::new(
+        time::Duration::from_secs(1),
+        LIMIT,
+        8,
+    ));
+    contended(
+        c,
+        "sharded token bucket",
+        Arc::new(move || sharded.allowed()),
+    );
+}
+
+criterion::criterion_group!(benches, all);
+criterion::criterion_main!(benches);
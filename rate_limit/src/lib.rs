@@ -0,0 +1,2377 @@
+//! Rate limiters: the `RateLimiter` trait, the clocks that drive limiters, and the fixed window,
+//! moving window, sliding window, token bucket, leaky bucket and GCRA algorithms, which all build
+//! without the standard library. Without the default `std` feature this builds for `no_std`
+//! targets, such as a microcontroller pacing uploads, where time comes from a `Clock` reading the
+//! device's own timer. `cargo check-no-std` checks that it still does.
+//!
+//! The rest needs `std`: `CalendarWindow` and `WallClock`, which read the wall clock,
+//! `ManualClock`, and `Shared` and `ShardedTokenBucket`, which lock. The `serde` feature adds
+//! `Persist` for saving limiter state, and the `tokio` feature adds `Shared::acquire`.
+#![cfg_attr(not(feature = "std"), no_std)]
+
+extern crate alloc;
+
+use alloc::{vec, vec::Vec};
+use core::{
+    cmp, fmt,
+    ops::{Add, AddAssign, Sub, SubAssign},
+    time,
+};
+#[cfg(feature = "std")]
+use std::{
+    hash::{Hash, Hasher},
+    sync::{Arc, Mutex},
+    thread,
+    time::SystemTime,
+};
+
+#[cfg(feature = "serde")]
+mod persist;
+#[cfg(feature = "serde")]
+pub use persist::*;
+
+/// The outcome of an admission check, with enough detail to build a response for the caller.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Decision {
+    Allowed { remaining: usize },
+    Denied { retry_after: time::Duration },
+}
+
+impl Decision {
+    pub fn is_allowed(&self) -> bool {
+        matches!(self, Decision::Allowed { .. })
+    }
+}
+
+/// A point in time as read from a `Clock`, in nanoseconds from an epoch chosen by the clock. Since
+/// limiters only compare instants and add durations to them, any monotonic tick source can drive
+/// them, not just `std::time::Instant`.
+///
+/// Unlike `std::time::Instant`, instants can be before the epoch, and subtracting never panics.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Instant(i128);
+
+impl Instant {
+    /// The instant `since_epoch` after the clock's epoch.
+    pub fn from_ticks(since_epoch: time::Duration) -> Self {
+        Instant(since_epoch.as_nanos() as i128)
+    }
+
+    /// The time from `earlier` to this instant, or zero if `earlier` is actually later.
+    pub fn duration_since(&self, earlier: Instant) -> time::Duration {
+        let nanos = (self.0 - earlier.0).max(0);
+        time::Duration::new(
+            (nanos / 1_000_000_000) as u64,
+            (nanos % 1_000_000_000) as u32,
+        )
+    }
+}
+
+impl Add<time::Duration> for Instant {
+    type Output = Instant;
+
+    fn add(self, rhs: time::Duration) -> Instant {
+        Instant(self.0 + rhs.as_nanos() as i128)
+    }
+}
+
+impl AddAssign<time::Duration> for Instant {
+    fn add_assign(&mut self, rhs: time::Duration) {
+        *self = *self + rhs;
+    }
+}
+
+impl Sub<time::Duration> for Instant {
+    type Output = Instant;
+
+    fn sub(self, rhs: time::Duration) -> Instant {
+        Instant(self.0 - rhs.as_nanos() as i128)
+    }
+}
+
+impl SubAssign<time::Duration> for Instant {
+    fn sub_assign(&mut self, rhs: time::Duration) {
+        *self = *self - rhs;
+    }
+}
+
+/// A source of the current time. Limiters read the time through a clock rather than calling
+/// `std::time::Instant::now()` directly so that tests and simulations can control it, and so that
+/// they can run where it isn't available.
+pub trait Clock: Clone {
+    fn now(&self) -> Instant;
+}
+
+/// A clock that also knows the wall clock time, for limiters that align to calendar boundaries and
+/// for saving state that outlives the process. Only available with the `std` feature, so clocks
+/// written for `no_std` only need to implement `Clock`.
+#[cfg(feature = "std")]
+pub trait WallClock: Clock {
+    fn wall(&self) -> SystemTime;
+}
+
+/// The real monotonic clock, used by default. It's only a `Clock` with the `std` feature, and
+/// limiters need to be given some other clock without it.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SystemClock;
+
+#[cfg(feature = "std")]
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        static EPOCH: std::sync::OnceLock<std::time::Instant> = std::sync::OnceLock::new();
+        Instant::from_ticks(EPOCH.get_or_init(std::time::Instant::now).elapsed())
+    }
+}
+
+#[cfg(feature = "std")]
+impl WallClock for SystemClock {
+    fn wall(&self) -> SystemTime {
+        SystemTime::now()
+    }
+}
+
+pub trait RateLimiter {
+    type Clock: Clock;
+
+    fn with_clock(window: time::Duration, limit: usize, clock: Self::Clock) -> Self;
+    fn new(window: time::Duration, limit: usize) -> Self
+    where
+        Self: Sized,
+        Self::Clock: Default,
+    {
+        Self::with_clock(window, limit, Self::Clock::default())
+    }
+    fn allowed(&mut self) -> bool {
+        self.allowed_n(1)
+    }
+    /// Admits a request costing `cost` units of quota, consuming all of it or none of it.
+    fn allowed_n(&mut self, cost: usize) -> bool;
+    /// How long until `allowed` would next return true, or zero if it would be allowed now.
+    fn time_until_allowed(&self) -> time::Duration;
+    /// How much quota is available right now, without consuming any of it.
+    fn remaining(&self) -> usize;
+    /// When the full quota will be available again if no more requests are made.
+    fn reset_at(&self) -> Instant;
+
+    fn window(&self) -> time::Duration;
+    fn limit(&self) -> usize;
+    /// Changes the window and limit of a live limiter. Quota that has already been used (or
+    /// accrued) carries over to the new configuration instead of being reset.
+    fn update(&mut self, window: time::Duration, limit: usize);
+    /// Returns `n` units of previously consumed quota, for example when the rate limited operation
+    /// failed before it reached the protected resource. Never raises quota above the limit.
+    fn give_back(&mut self, n: usize);
+
+    fn set_window(&mut self, window: time::Duration) {
+        self.update(window, self.limit());
+    }
+
+    fn set_limit(&mut self, limit: usize) {
+        self.update(self.window(), limit);
+    }
+
+    /// Whether a request would be allowed right now, without consuming any quota.
+    fn check(&self) -> bool {
+        self.remaining() > 0
+    }
+
+    /// Like `allowed`, but reports the remaining quota or how long to wait before retrying.
+    fn decide(&mut self) -> Decision {
+        if self.allowed() {
+            Decision::Allowed {
+                remaining: self.remaining(),
+            }
+        } else {
+            Decision::Denied {
+                retry_after: self.time_until_allowed(),
+            }
+        }
+    }
+
+    /// Blocks the calling thread until a request is admitted.
+    #[cfg(feature = "std")]
+    fn wait(&mut self) {
+        while !self.allowed() {
+            std::thread::sleep(self.time_until_allowed().max(MIN_WAIT));
+        }
+    }
+}
+
+/// The shortest a caller waiting on a denied request sleeps before checking again. Limiters can
+/// report a zero wait while still denying, for example when the wait is rounded down just before a
+/// permit is available, and sleeping for that would spin.
+pub const MIN_WAIT: time::Duration = time::Duration::from_millis(1);
+
+/// Accrues `limit` tokens per `window`, holding at most `capacity` tokens. The capacity defaults to
+/// the limit, but can be set independently to allow bursts larger than the sustained rate.
+///
+/// Tokens are tracked as integer credit where one token is worth `window.as_nanos()` units and
+/// every elapsed nanosecond adds `limit` units. This keeps partial tokens exactly, so accrual
+/// doesn't depend on how often the bucket is checked, even at very low rates. Credit goes negative
+/// when tokens are reserved before they have accrued.
+///
+/// With a warm-up configured, a cold bucket starts out accruing at a fraction of the rate and
+/// holding that fraction of its capacity, ramping linearly to full over the warm-up period. The
+/// bucket goes cold again after being idle for a whole warm-up period.
+pub struct TokenBucket<C = SystemClock> {
+    credit: i128,
+    last_hit: Instant,
+    window: time::Duration,
+    limit: usize,
+    capacity: usize,
+    warm_up: Option<WarmUp>,
+    clock: C,
+}
+
+struct WarmUp {
+    period: time::Duration,
+    initial: f64,
+    started: Instant,
+}
+
+impl WarmUp {
+    /// The fraction of the full rate in effect `since` the warm-up started.
+    fn factor(&self, since: time::Duration) -> f64 {
+        let progress = (since.as_secs_f64() / self.period.as_secs_f64()).min(1.0);
+        self.initial + (1.0 - self.initial) * progress
+    }
+
+    /// The average fraction of the full rate in effect from `from` to `to`, both measured from the
+    /// start of the warm-up. The ramp is linear, so its average is the midpoint.
+    fn average_factor(&self, from: time::Duration, to: time::Duration) -> f64 {
+        if to <= from {
+            return 1.0;
+        }
+
+        let ramp_end = cmp::min(to, self.period);
+        let ramping = ramp_end.saturating_sub(from).as_secs_f64();
+        let ramp_avg = if from < ramp_end {
+            (self.factor(from) + self.factor(ramp_end)) / 2.0
+        } else {
+            1.0
+        };
+        let full = (to - cmp::max(from, ramp_end)).as_secs_f64();
+
+        (ramping * ramp_avg + full) / (to - from).as_secs_f64()
+    }
+}
+
+impl<C: Clock> TokenBucket<C> {
+    /// Sets the maximum number of tokens the bucket can hold, which is the largest burst it will
+    /// admit after being idle.
+    pub fn with_capacity(mut self, capacity: usize) -> Self {
+        self.capacity = capacity;
+        self.credit = cmp::min(self.credit, self.max_credit());
+        self
+    }
+
+    /// Starts the bucket cold, accruing at `initial` (between 0 and 1) of its rate and ramping up
+    /// to the full rate over `period`.
+    pub fn with_warm_up(mut self, period: time::Duration, initial: f64) -> Self {
+        self.warm_up = Some(WarmUp {
+            period,
+            initial: initial.clamp(0.0, 1.0),
+            started: self.clock.now(),
+        });
+        self
+    }
+
+    /// Credit units that make up a single token.
+    fn per_token(&self) -> i128 {
+        self.window.as_nanos() as i128
+    }
+
+    fn max_credit(&self) -> i128 {
+        self.capacity as i128 * self.per_token()
+    }
+
+    fn credit_at(&self, now: Instant) -> i128 {
+        // Every nanosecond since the last hit is worth `limit` units of credit, capped at the
+        // bucket's capacity.
+        let elapsed = now.duration_since(self.last_hit).as_nanos() as i128;
+        let accrued = elapsed * self.limit as i128;
+
+        let Some(warm_up) = &self.warm_up else {
+            return cmp::min(self.credit + accrued, self.max_credit());
+        };
+
+        // While warming up, both the rate and the capacity are scaled down. A bucket that has gone
+        // cold is capped as if its warm-up started over right now.
+        let from = self.last_hit.duration_since(warm_up.started);
+        let to = now.duration_since(warm_up.started);
+        let accrued = (accrued as f64 * warm_up.average_factor(from, to)) as i128;
+        let factor = if self.is_cold(now) {
+            warm_up.initial
+        } else {
+            warm_up.factor(to)
+        };
+        let cap = (self.max_credit() as f64 * factor) as i128;
+
+        cmp::min(self.credit + accrued, cap)
+    }
+
+    fn is_cold(&self, now: Instant) -> bool {
+        self.warm_up
+            .as_ref()
+            .is_some_and(|w| now.duration_since(self.last_hit) >= w.period)
+    }
+
+    /// Brings the credit up to date as of `now`, restarting the warm-up if the bucket went cold.
+    fn settle(&mut self, now: Instant) {
+        self.credit = self.credit_at(now);
+        if self.is_cold(now) {
+            if let Some(warm_up) = &mut self.warm_up {
+                warm_up.started = now;
+            }
+        }
+        self.last_hit = now;
+    }
+
+    /// How long it takes to accrue `units` of credit at the full rate, rounded up to the
+    /// nanosecond. This underestimates while warming up, which callers that wait on it handle by
+    /// checking again.
+    fn time_to_accrue(&self, units: i128) -> time::Duration {
+        let nanos = (units.max(0) as u128).div_ceil(self.limit as u128);
+        time::Duration::from_nanos(nanos as u64)
+    }
+
+    /// Takes `n` tokens now, whether or not they have accrued yet, and returns when the caller may
+    /// go ahead with the operation they are for. Later requests queue up behind the reservation.
+    /// Returns `None` without reserving anything if `n` is more than the bucket can ever hold.
+    pub fn reserve(&mut self, n: usize) -> Option<Instant> {
+        if n > self.capacity {
+            return None;
+        }
+
+        let now = self.clock.now();
+        self.settle(now);
+        self.credit -= n as i128 * self.per_token();
+
+        // The reservation is ready once the bucket has paid off whatever it went into debt for.
+        Some(now + self.time_to_accrue(-self.credit))
+    }
+
+    /// The bucket's credit as of now, where a token is worth `window.as_nanos()` units.
+    #[cfg(any(feature = "std", test))]
+    fn credit(&self) -> i128 {
+        self.credit_at(self.clock.now())
+    }
+
+    /// Replaces the bucket's credit as of now, up to what its capacity allows.
+    #[cfg(any(feature = "std", test))]
+    fn set_credit(&mut self, credit: i128) {
+        self.settle(self.clock.now());
+        self.credit = cmp::min(credit, self.max_credit());
+    }
+}
+
+impl<C: Clock> RateLimiter for TokenBucket<C> {
+    type Clock = C;
+
+    fn with_clock(window: time::Duration, limit: usize, clock: C) -> Self {
+        TokenBucket {
+            credit: 0,
+            last_hit: clock.now(),
+            window,
+            limit,
+            capacity: limit,
+            warm_up: None,
+            clock,
+        }
+    }
+
+    fn allowed_n(&mut self, cost: usize) -> bool {
+        let now = self.clock.now();
+
+        // Fractional tokens are kept in the credit, so the last hit time can always move forward.
+        self.settle(now);
+
+        let needed = cost as i128 * self.per_token();
+        if self.credit < needed {
+            return false;
+        }
+
+        self.credit -= needed;
+
+        true
+    }
+
+    fn time_until_allowed(&self) -> time::Duration {
+        let credit = self.credit_at(self.clock.now());
+        self.time_to_accrue(self.per_token() - credit)
+    }
+
+    fn remaining(&self) -> usize {
+        (self.credit_at(self.clock.now()).max(0) / self.per_token()) as usize
+    }
+
+    fn reset_at(&self) -> Instant {
+        let now = self.clock.now();
+        now + self.time_to_accrue(self.max_credit() - self.credit_at(now))
+    }
+
+    fn window(&self) -> time::Duration {
+        self.window
+    }
+
+    fn limit(&self) -> usize {
+        self.limit
+    }
+
+    fn update(&mut self, window: time::Duration, limit: usize) {
+        // Settle the credit accrued so far at the old rate before changing it.
+        let now = self.clock.now();
+        self.settle(now);
+
+        // A capacity that was left at its default keeps tracking the limit.
+        if self.capacity == self.limit {
+            self.capacity = limit;
+        }
+
+        // Credit is denominated in units of the window, so rescale it to keep the same number of
+        // (possibly fractional) tokens.
+        self.credit = self.credit * window.as_nanos() as i128 / self.per_token();
+        self.window = window;
+        self.limit = limit;
+        self.credit = cmp::min(self.credit, self.max_credit());
+    }
+
+    fn give_back(&mut self, n: usize) {
+        self.credit = cmp::min(
+            self.credit + n as i128 * self.per_token(),
+            self.max_credit(),
+        );
+    }
+}
+
+/// A clock that only moves when told to. Clones share the same time, so one handle can be given to
+/// a limiter and another kept to advance it.
+#[cfg(feature = "std")]
+#[derive(Debug, Clone)]
+pub struct ManualClock {
+    now: Arc<Mutex<(Instant, SystemTime)>>,
+}
+
+#[cfg(feature = "std")]
+impl ManualClock {
+    pub fn new() -> Self {
+        Self::at(SystemTime::now())
+    }
+
+    /// A manual clock whose wall clock time starts at `wall`.
+    pub fn at(wall: SystemTime) -> Self {
+        ManualClock {
+            now: Arc::new(Mutex::new((Instant::default(), wall))),
+        }
+    }
+
+    pub fn advance(&self, by: time::Duration) {
+        let mut now = self.now.lock().unwrap();
+        now.0 += by;
+        now.1 += by;
+    }
+}
+
+#[cfg(feature = "std")]
+impl Default for ManualClock {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(feature = "std")]
+impl Clock for ManualClock {
+    fn now(&self) -> Instant {
+        self.now.lock().unwrap().0
+    }
+}
+
+#[cfg(feature = "std")]
+impl WallClock for ManualClock {
+    fn wall(&self) -> SystemTime {
+        self.now.lock().unwrap().1
+    }
+}
+
+/// Why `RateLimiterBuilder` refused a configuration.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConfigError {
+    MissingWindow,
+    MissingLimit,
+    ZeroWindow,
+    ZeroLimit,
+    RateTooHigh { limit: usize },
+    BurstBelowLimit { burst: usize, limit: usize },
+    BurstUnsupported,
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfigError::MissingWindow => write!(f, "no window was given"),
+            ConfigError::MissingLimit => write!(f, "no limit was given"),
+            ConfigError::ZeroWindow => write!(f, "window must be non-zero"),
+            ConfigError::ZeroLimit => write!(f, "limit must be greater than zero"),
+            ConfigError::RateTooHigh { limit } => write!(
+                f,
+                "limit of {limit} is more than one per nanosecond of the window"
+            ),
+            ConfigError::BurstBelowLimit { burst, limit } => {
+                write!(f, "burst of {burst} is less than the limit of {limit}")
+            }
+            ConfigError::BurstUnsupported => write!(f, "burst is only supported by token buckets"),
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl std::error::Error for ConfigError {}
+
+/// Builds limiters from a configuration that is checked first, rather than producing a limiter that
+/// divides by zero or never admits anything:
+///
+/// `RateLimiterBuilder::new().window(Duration::from_secs(1)).limit(10).burst(20).token_bucket()`
+pub struct RateLimiterBuilder<C = SystemClock> {
+    window: Option<time::Duration>,
+    limit: Option<usize>,
+    burst: Option<usize>,
+    clock: C,
+}
+
+impl RateLimiterBuilder {
+    pub fn new() -> Self {
+        RateLimiterBuilder {
+            window: None,
+            limit: None,
+            burst: None,
+            clock: SystemClock,
+        }
+    }
+}
+
+impl Default for RateLimiterBuilder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<C: Clock> RateLimiterBuilder<C> {
+    pub fn window(mut self, window: time::Duration) -> Self {
+        self.window = Some(window);
+        self
+    }
+
+    pub fn limit(mut self, limit: usize) -> Self {
+        self.limit = Some(limit);
+        self
+    }
+
+    /// The largest burst a token bucket admits after being idle. Must be at least the limit.
+    pub fn burst(mut self, burst: usize) -> Self {
+        self.burst = Some(burst);
+        self
+    }
+
+    pub fn clock<D: Clock>(self, clock: D) -> RateLimiterBuilder<D> {
+        RateLimiterBuilder {
+            window: self.window,
+            limit: self.limit,
+            burst: self.burst,
+            clock,
+        }
+    }
+
+    /// Checks the configuration without building anything, returning the window and limit.
+    pub fn validate(&self) -> Result<(time::Duration, usize), ConfigError> {
+        let window = self.window.ok_or(ConfigError::MissingWindow)?;
+        let limit = self.limit.ok_or(ConfigError::MissingLimit)?;
+
+        if window.is_zero() {
+            return Err(ConfigError::ZeroWindow);
+        }
+        if limit == 0 {
+            return Err(ConfigError::ZeroLimit);
+        }
+        // Limiters that space requests out work in whole nanoseconds between them.
+        if window.as_nanos() < limit as u128 {
+            return Err(ConfigError::RateTooHigh { limit });
+        }
+        if let Some(burst) = self.burst.filter(|&burst| burst < limit) {
+            return Err(ConfigError::BurstBelowLimit { burst, limit });
+        }
+
+        Ok((window, limit))
+    }
+
+    /// Builds any kind of limiter. Only `token_bucket` supports a burst.
+    pub fn build<L: RateLimiter<Clock = C>>(self) -> Result<L, ConfigError> {
+        let (window, limit) = self.validate()?;
+        if self.burst.is_some() {
+            return Err(ConfigError::BurstUnsupported);
+        }
+
+        Ok(L::with_clock(window, limit, self.clock))
+    }
+
+    pub fn token_bucket(self) -> Result<TokenBucket<C>, ConfigError> {
+        let (window, limit) = self.validate()?;
+        let bucket = TokenBucket::with_clock(window, limit, self.clock);
+
+        Ok(match self.burst {
+            Some(burst) => bucket.with_capacity(burst),
+            None => bucket,
+        })
+    }
+}
+
+pub struct FixedWindow<C = SystemClock> {
+    window_start: Instant,
+    hits: usize,
+    window: time::Duration,
+    limit: usize,
+    clock: C,
+}
+
+impl<C: Clock> RateLimiter for FixedWindow<C> {
+    type Clock = C;
+
+    fn with_clock(window: time::Duration, limit: usize, clock: C) -> Self {
+        FixedWindow {
+            window_start: clock.now(),
+            hits: 0,
+            window,
+            limit,
+            clock,
+        }
+    }
+
+    fn allowed_n(&mut self, cost: usize) -> bool {
+        let now = self.clock.now();
+
+        if now.duration_since(self.window_start) > self.window {
+            self.window_start = now;
+            self.hits = 0;
+        };
+
+        if self.hits + cost > self.limit {
+            return false;
+        };
+
+        self.hits += cost;
+        true
+    }
+
+    fn time_until_allowed(&self) -> time::Duration {
+        let elapsed = self.clock.now().duration_since(self.window_start);
+
+        if self.hits < self.limit || elapsed > self.window {
+            return time::Duration::ZERO;
+        }
+
+        // The window resets once strictly more than a full window has elapsed.
+        self.window - elapsed + time::Duration::from_micros(1)
+    }
+
+    fn remaining(&self) -> usize {
+        if self.clock.now().duration_since(self.window_start) > self.window {
+            return self.limit;
+        }
+
+        self.limit.saturating_sub(self.hits)
+    }
+
+    fn reset_at(&self) -> Instant {
+        let now = self.clock.now();
+        if self.hits == 0 || now.duration_since(self.window_start) > self.window {
+            return now;
+        }
+
+        self.window_start + self.window
+    }
+
+    fn window(&self) -> time::Duration {
+        self.window
+    }
+
+    fn limit(&self) -> usize {
+        self.limit
+    }
+
+    fn update(&mut self, window: time::Duration, limit: usize) {
+        // Hits in the current window still count, so lowering the limit below them denies
+        // requests until the window resets.
+        self.window = window;
+        self.limit = limit;
+    }
+
+    fn give_back(&mut self, n: usize) {
+        // If the window has already reset, the hits being returned were forgotten along with it.
+        self.hits = self.hits.saturating_sub(n);
+    }
+}
+
+/// A fixed window aligned to wall clock boundaries instead of starting when the limiter is created,
+/// for quotas that reset at the top of the minute, hour, or day (UTC). Windows are numbered by how
+/// many whole windows have passed since the UNIX epoch.
+///
+/// Since that numbering doesn't depend on the process, the state from `Persist::save` can be
+/// restored exactly in another process or after a restart. Hits saved in a window that has since
+/// ended are discarded on restore.
+#[cfg(feature = "std")]
+pub struct CalendarWindow<C = SystemClock> {
+    index: u64,
+    hits: usize,
+    window: time::Duration,
+    limit: usize,
+    clock: C,
+}
+
+#[cfg(feature = "std")]
+impl<C: WallClock> CalendarWindow<C> {
+    fn since_epoch(&self) -> time::Duration {
+        // A wall clock set before 1970 is treated as the epoch itself.
+        self.clock
+            .wall()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap_or_default()
+    }
+
+    fn current_index(&self) -> u64 {
+        (self.since_epoch().as_nanos() / self.window.as_nanos().max(1)) as u64
+    }
+
+    /// The hits counted in the current window, which is zero if the last hit was in an earlier one.
+    fn current_hits(&self) -> usize {
+        if self.index == self.current_index() {
+            self.hits
+        } else {
+            0
+        }
+    }
+
+    /// Time remaining until the current window ends.
+    fn until_boundary(&self) -> time::Duration {
+        let window = self.window.as_nanos().max(1);
+        let into = self.since_epoch().as_nanos() % window;
+        time::Duration::from_nanos((window - into) as u64)
+    }
+}
+
+#[cfg(feature = "std")]
+impl<C: WallClock> RateLimiter for CalendarWindow<C> {
+    type Clock = C;
+
+    fn with_clock(window: time::Duration, limit: usize, clock: C) -> Self {
+        let mut limiter = CalendarWindow {
+            index: 0,
+            hits: 0,
+            window,
+            limit,
+            clock,
+        };
+        limiter.index = limiter.current_index();
+        limiter
+    }
+
+    fn allowed_n(&mut self, cost: usize) -> bool {
+        let index = self.current_index();
+        if index != self.index {
+            self.index = index;
+            self.hits = 0;
+        }
+
+        if self.hits + cost > self.limit {
+            return false;
+        }
+
+        self.hits += cost;
+        true
+    }
+
+    fn time_until_allowed(&self) -> time::Duration {
+        if self.current_hits() < self.limit {
+            return time::Duration::ZERO;
+        }
+
+        self.until_boundary()
+    }
+
+    fn remaining(&self) -> usize {
+        self.limit.saturating_sub(self.current_hits())
+    }
+
+    fn reset_at(&self) -> Instant {
+        let now = self.clock.now();
+        if self.current_hits() == 0 {
+            return now;
+        }
+
+        now + self.until_boundary()
+    }
+
+    fn window(&self) -> time::Duration {
+        self.window
+    }
+
+    fn limit(&self) -> usize {
+        self.limit
+    }
+
+    fn update(&mut self, window: time::Duration, limit: usize) {
+        // Window numbers depend on the window length, so the hits only carry over if the window
+        // stays the same.
+        if window != self.window {
+            self.window = window;
+            self.index = self.current_index();
+            self.hits = 0;
+        }
+        self.limit = limit;
+    }
+
+    fn give_back(&mut self, n: usize) {
+        self.hits = self.hits.saturating_sub(n);
+    }
+}
+
+/// How many of the previous window's `hits` still count with `left` of the window to go, assuming
+/// they were spread evenly over it. Computed in nanoseconds, since windows can be shorter than a
+/// microsecond.
+fn decayed(hits: usize, left: time::Duration, window: time::Duration) -> usize {
+    (hits as u128 * left.as_nanos() / window.as_nanos().max(1)) as usize
+}
+
+pub struct MovingWindow<C = SystemClock> {
+    prev_start: Instant,
+    prev_count: usize,
+    this_start: Instant,
+    this_count: usize,
+    window: time::Duration,
+    limit: usize,
+    clock: C,
+}
+
+impl<C: Clock> RateLimiter for MovingWindow<C> {
+    type Clock = C;
+
+    fn with_clock(window: time::Duration, limit: usize, clock: C) -> Self {
+        let now = clock.now();
+
+        MovingWindow {
+            prev_start: now,
+            prev_count: 0,
+            this_start: now,
+            this_count: 0,
+            window,
+            limit,
+            clock,
+        }
+    }
+
+    fn allowed_n(&mut self, cost: usize) -> bool {
+        let now = self.clock.now();
+
+        // Cycle the current window values into the previous window repeatedly until we "catch up"
+        // to the present time. In cases where more than two windows duration have passed since the
+        // start of this window period this will cycle through twice and essentially reset the
+        // counter.
+        while now.duration_since(self.this_start) > self.window {
+            self.prev_start = self.this_start;
+            self.prev_count = self.this_count;
+            self.this_start = self.prev_start + self.window;
+            self.this_count = 0;
+        }
+
+        let this_period = now.duration_since(self.this_start);
+        let last_period = self.window - this_period;
+
+        let hits_from_last_period = decayed(self.prev_count, last_period, self.window);
+
+        if self.this_count + hits_from_last_period + cost > self.limit {
+            return false;
+        }
+
+        self.this_count += cost;
+
+        true
+    }
+
+    fn time_until_allowed(&self) -> time::Duration {
+        let now = self.clock.now();
+        let window = self.window.as_nanos();
+
+        let (this_start, mut this_count, mut prev_count) = self.caught_up(now);
+
+        let mut wait = 0;
+        let mut this_period = now.duration_since(this_start).as_nanos();
+
+        // If the current window is already full nothing will be admitted until it becomes the
+        // previous window, at which point its hits start decaying.
+        if this_count >= self.limit {
+            wait += window - this_period + 1;
+            this_period = 0;
+            prev_count = this_count;
+            this_count = 0;
+        }
+
+        // Interpolated hits from the previous window decay linearly, so solve for the point in
+        // this window where they have dropped far enough to leave room for one more hit.
+        if prev_count > 0 {
+            let room = (self.limit - this_count) as u128;
+            let threshold = window.saturating_sub(room * window / prev_count as u128);
+            if this_period <= threshold {
+                wait += threshold - this_period + 1;
+            }
+        }
+
+        time::Duration::from_nanos(wait as u64)
+    }
+
+    fn remaining(&self) -> usize {
+        let now = self.clock.now();
+        let (this_start, this_count, prev_count) = self.caught_up(now);
+
+        let last_period = self.window - now.duration_since(this_start);
+        let hits_from_last_period = decayed(prev_count, last_period, self.window);
+
+        self.limit
+            .saturating_sub(this_count + hits_from_last_period)
+    }
+
+    fn reset_at(&self) -> Instant {
+        let now = self.clock.now();
+        let (this_start, this_count, prev_count) = self.caught_up(now);
+
+        // Hits in the current window keep counting against the limit until the end of the window
+        // after it, and hits in the previous window until the end of this one.
+        if this_count > 0 {
+            this_start + self.window * 2
+        } else if prev_count > 0 {
+            this_start + self.window
+        } else {
+            now
+        }
+    }
+
+    fn window(&self) -> time::Duration {
+        self.window
+    }
+
+    fn limit(&self) -> usize {
+        self.limit
+    }
+
+    fn update(&mut self, window: time::Duration, limit: usize) {
+        // Catch up under the old window first so the counts are attributed to the right windows.
+        let (this_start, this_count, prev_count) = self.caught_up(self.clock.now());
+        if this_start != self.this_start {
+            self.prev_start = this_start - self.window;
+        }
+        self.this_start = this_start;
+        self.this_count = this_count;
+        self.prev_count = prev_count;
+
+        self.window = window;
+        self.limit = limit;
+    }
+
+    fn give_back(&mut self, n: usize) {
+        // Recent hits are the most likely to be the ones being returned, so take from the current
+        // window first and only then from the previous one.
+        let from_this = cmp::min(n, self.this_count);
+        self.this_count -= from_this;
+        self.prev_count = self.prev_count.saturating_sub(n - from_this);
+    }
+}
+
+impl<C: Clock> MovingWindow<C> {
+    /// Returns the (start, count) of the current window and the count of the previous window as
+    /// they would be at `now`, catching up the same way `allowed` does but without mutating.
+    fn caught_up(&self, now: Instant) -> (Instant, usize, usize) {
+        let (mut this_start, mut this_count, mut prev_count) =
+            (self.this_start, self.this_count, self.prev_count);
+        while now.duration_since(this_start) > self.window {
+            prev_count = this_count;
+            this_start += self.window;
+            this_count = 0;
+        }
+
+        (this_start, this_count, prev_count)
+    }
+}
+
+/// Approximates a sliding window by splitting it into `buckets` sub-windows, each counting the hits
+/// that landed in it. Hits are forgotten a whole sub-window at a time as the window slides forward,
+/// so more buckets trade memory for accuracy. Defaults to 10 buckets.
+pub struct SlidingWindow<C = SystemClock> {
+    buckets: Vec<usize>,
+    head: usize,
+    head_start: Instant,
+    window: time::Duration,
+    limit: usize,
+    clock: C,
+}
+
+impl<C: Clock> SlidingWindow<C> {
+    pub fn with_buckets(mut self, buckets: usize) -> Self {
+        self.buckets = vec![0; buckets.max(1)];
+        self.head = 0;
+        self
+    }
+
+    fn bucket_width(&self) -> time::Duration {
+        self.window / self.buckets.len() as u32
+    }
+
+    /// How many sub-windows the head has to move forward to reach `now`, capped at the number of
+    /// buckets since beyond that everything has expired anyway.
+    fn shifts(&self, now: Instant) -> usize {
+        let elapsed = now.duration_since(self.head_start).as_nanos();
+        let shifts = elapsed / self.bucket_width().as_nanos().max(1);
+        cmp::min(shifts, self.buckets.len() as u128) as usize
+    }
+
+    /// The count in the bucket `age` sub-windows before the head.
+    fn bucket(&self, age: usize) -> usize {
+        let len = self.buckets.len();
+        self.buckets[(self.head + len - age) % len]
+    }
+
+    /// The total hits that would still be in the window at `now`.
+    fn total_at(&self, now: Instant) -> usize {
+        let live = self.buckets.len() - self.shifts(now);
+        (0..live).map(|age| self.bucket(age)).sum()
+    }
+
+    fn advance(&mut self, now: Instant) {
+        let shifts = self.shifts(now);
+        let len = self.buckets.len();
+
+        for _ in 0..shifts {
+            self.head = (self.head + 1) % len;
+            self.buckets[self.head] = 0;
+        }
+
+        // Keep the head's start aligned to the sub-window grid, even if more than a whole window
+        // has gone by.
+        let width = self.bucket_width().as_nanos().max(1);
+        let elapsed = now.duration_since(self.head_start).as_nanos();
+        self.head_start += time::Duration::from_nanos((elapsed - elapsed % width) as u64);
+    }
+}
+
+impl<C: Clock> RateLimiter for SlidingWindow<C> {
+    type Clock = C;
+
+    fn with_clock(window: time::Duration, limit: usize, clock: C) -> Self {
+        SlidingWindow {
+            buckets: vec![0; 10],
+            head: 0,
+            head_start: clock.now(),
+            window,
+            limit,
+            clock,
+        }
+    }
+
+    fn allowed_n(&mut self, cost: usize) -> bool {
+        let now = self.clock.now();
+        self.advance(now);
+
+        let total: usize = self.buckets.iter().sum();
+        if total + cost > self.limit {
+            return false;
+        }
+
+        self.buckets[self.head] += cost;
+
+        true
+    }
+
+    fn time_until_allowed(&self) -> time::Duration {
+        let now = self.clock.now();
+        let shifts = self.shifts(now);
+        let len = self.buckets.len();
+        let mut total = self.total_at(now);
+
+        if total < self.limit {
+            return time::Duration::ZERO;
+        }
+
+        // Expire the oldest live buckets one at a time until there is room for another hit. The
+        // bucket `age` sub-windows before the head expires once the head has moved `len - age`
+        // times.
+        let head_start = self.head_start;
+        for age in (0..len - shifts).rev() {
+            total -= self.bucket(age);
+            if total < self.limit {
+                let expires = head_start + self.bucket_width() * (len - age) as u32;
+                return expires.duration_since(now);
+            }
+        }
+
+        // Only reachable with a limit of zero, which never admits anything.
+        self.window
+    }
+
+    fn remaining(&self) -> usize {
+        self.limit.saturating_sub(self.total_at(self.clock.now()))
+    }
+
+    fn reset_at(&self) -> Instant {
+        let now = self.clock.now();
+        let live = self.buckets.len() - self.shifts(now);
+
+        // Everything has expired once the youngest non-empty bucket has.
+        match (0..live).find(|&age| self.bucket(age) > 0) {
+            Some(age) => self.head_start + self.bucket_width() * (self.buckets.len() - age) as u32,
+            None => now,
+        }
+    }
+
+    fn window(&self) -> time::Duration {
+        self.window
+    }
+
+    fn limit(&self) -> usize {
+        self.limit
+    }
+
+    fn update(&mut self, window: time::Duration, limit: usize) {
+        // Settle the buckets on the old grid, then keep their counts as the sub-windows resize.
+        self.advance(self.clock.now());
+        self.window = window;
+        self.limit = limit;
+    }
+
+    fn give_back(&mut self, mut n: usize) {
+        // Take from the newest buckets first, since they most likely hold the hits being returned.
+        let len = self.buckets.len();
+        for age in 0..len {
+            let idx = (self.head + len - age) % len;
+            let taken = cmp::min(n, self.buckets[idx]);
+            self.buckets[idx] -= taken;
+            n -= taken;
+        }
+    }
+}
+
+/// A token bucket split into independently locked shards, for workloads where the mutex of a
+/// single `Shared` limiter becomes the bottleneck (typically above a few hundred thousand checks per
+/// second). Each thread draws from the shard its id hashes to, and every `rebalance_every` the
+/// credit is redistributed between the shards in proportion to their share of the limit.
+///
+/// The cost is accuracy: a request can be denied while other shards still hold tokens, until the
+/// next rebalance evens them out, so a thread can see up to `shards` times fewer tokens than the
+/// whole bucket has. Admissions never exceed the overall limit, since the shard limits add up to it.
+#[cfg(feature = "std")]
+pub struct ShardedTokenBucket<C: Clock = SystemClock> {
+    shards: Vec<Mutex<TokenBucket<C>>>,
+    limit: usize,
+    rebalance_every: time::Duration,
+    last_rebalance: Mutex<Instant>,
+    clock: C,
+}
+
+#[cfg(feature = "std")]
+impl<C: Clock> ShardedTokenBucket<C> {
+    pub fn new(window: time::Duration, limit: usize, shards: usize) -> Self
+    where
+        C: Default,
+    {
+        Self::with_clock(window, limit, shards, C::default())
+    }
+
+    /// Splits the limit over `shards` shards, or fewer if the limit is too small for every shard to
+    /// get a token per window.
+    pub fn with_clock(window: time::Duration, limit: usize, shards: usize, clock: C) -> Self {
+        let count = shards.clamp(1, limit.max(1));
+        let shards = (0..count)
+            .map(|i| {
+                // Spread the remainder over the first shards.
+                let share = limit / count + usize::from(i < limit % count);
+                Mutex::new(TokenBucket::with_clock(window, share, clock.clone()))
+            })
+            .collect();
+
+        ShardedTokenBucket {
+            shards,
+            limit,
+            rebalance_every: window / 10,
+            last_rebalance: Mutex::new(clock.now()),
+            clock,
+        }
+    }
+
+    /// How often credit is redistributed between the shards. Defaults to a tenth of the window.
+    pub fn with_rebalance_every(mut self, every: time::Duration) -> Self {
+        self.rebalance_every = every;
+        self
+    }
+
+    pub fn allowed(&self) -> bool {
+        self.allowed_n(1)
+    }
+
+    pub fn allowed_n(&self, cost: usize) -> bool {
+        self.maybe_rebalance();
+
+        let index = {
+            let mut hasher = std::collections::hash_map::DefaultHasher::new();
+            thread::current().id().hash(&mut hasher);
+            hasher.finish() as usize % self.shards.len()
+        };
+
+        let mut shard = self.shards[index].lock().unwrap_or_else(|e| e.into_inner());
+        shard.allowed_n(cost)
+    }
+
+    /// The tokens left across all shards, though a single thread may not be able to use them all
+    /// before the next rebalance.
+    pub fn remaining(&self) -> usize {
+        self.shards
+            .iter()
+            .map(|s| s.lock().unwrap_or_else(|e| e.into_inner()).remaining())
+            .sum()
+    }
+
+    fn maybe_rebalance(&self) {
+        // Only one thread needs to rebalance; the others carry on with their shards.
+        let Ok(mut last) = self.last_rebalance.try_lock() else {
+            return;
+        };
+
+        let now = self.clock.now();
+        if now.duration_since(*last) >= self.rebalance_every {
+            *last = now;
+            self.rebalance();
+        }
+    }
+
+    fn rebalance(&self) {
+        // Shards are always locked in order, and checks only ever lock one, so this can't deadlock.
+        let mut shards: Vec<_> = self
+            .shards
+            .iter()
+            .map(|s| s.lock().unwrap_or_else(|e| e.into_inner()))
+            .collect();
+
+        // All shards share the window, so their credit is in the same units.
+        let mut total = 0;
+        for shard in shards.iter() {
+            total += shard.credit();
+        }
+
+        for shard in shards.iter_mut() {
+            let share = total * shard.limit() as i128 / self.limit as i128;
+            shard.set_credit(share);
+        }
+    }
+}
+
+pub struct LeakyBucket<C = SystemClock> {
+    level: usize,
+    last_leak: Instant,
+    window: time::Duration,
+    limit: usize,
+    clock: C,
+}
+
+impl<C: Clock> LeakyBucket<C> {
+    fn leaked(&self, now: Instant) -> usize {
+        // The bucket drains at a constant rate of limit / window, so the amount that has leaked out
+        // since the last drain is the elapsed time multiplied by that rate.
+        let elapsed = now.duration_since(self.last_leak);
+
+        (elapsed.as_nanos() * self.limit as u128 / self.window.as_nanos().max(1)) as usize
+    }
+
+    /// How long it takes for `n` requests to drain out, rounded up to the nanosecond so that
+    /// `leaked` counts them as drained once it has passed.
+    fn drain_time(&self, n: usize) -> time::Duration {
+        let nanos = (self.window.as_nanos() * n as u128).div_ceil(self.limit.max(1) as u128);
+        time::Duration::from_nanos(nanos.try_into().unwrap_or(u64::MAX))
+    }
+}
+
+impl<C: Clock> RateLimiter for LeakyBucket<C> {
+    type Clock = C;
+
+    fn with_clock(window: time::Duration, limit: usize, clock: C) -> Self {
+        LeakyBucket {
+            level: 0,
+            last_leak: clock.now(),
+            window,
+            limit,
+            clock,
+        }
+    }
+
+    fn allowed_n(&mut self, cost: usize) -> bool {
+        let now = self.clock.now();
+
+        // Drain the bucket first. Like the token bucket, the last leak time only moves forward by
+        // the time the whole requests that drained out took, so partial progress towards the next
+        // one isn't thrown away. An empty bucket has nothing to drain though, so progress made
+        // while it was empty doesn't count towards the next request.
+        let leaked = self.leaked(now);
+        if leaked >= self.level {
+            self.level = 0;
+            self.last_leak = now;
+        } else if leaked > 0 {
+            self.level -= leaked;
+            self.last_leak += self.drain_time(leaked);
+        }
+
+        // A full bucket overflows, and the request is rejected rather than queued.
+        if self.level + cost > self.limit {
+            return false;
+        }
+
+        self.level += cost;
+
+        true
+    }
+
+    fn time_until_allowed(&self) -> time::Duration {
+        let now = self.clock.now();
+
+        if self.level < self.limit || self.leaked(now) > 0 {
+            return time::Duration::ZERO;
+        }
+
+        // One request's worth drains out every window / limit.
+        self.drain_time(1)
+            .saturating_sub(now.duration_since(self.last_leak))
+    }
+
+    fn remaining(&self) -> usize {
+        let level = self.level.saturating_sub(self.leaked(self.clock.now()));
+        self.limit.saturating_sub(level)
+    }
+
+    fn reset_at(&self) -> Instant {
+        // The bucket is empty once everything in it as of the last drain has leaked out.
+        cmp::max(
+            self.last_leak + self.drain_time(self.level),
+            self.clock.now(),
+        )
+    }
+
+    fn window(&self) -> time::Duration {
+        self.window
+    }
+
+    fn limit(&self) -> usize {
+        self.limit
+    }
+
+    fn update(&mut self, window: time::Duration, limit: usize) {
+        // Drain at the old rate up to now, so the new rate only applies from here on.
+        let now = self.clock.now();
+        self.level = self.level.saturating_sub(self.leaked(now));
+        self.last_leak = now;
+
+        self.window = window;
+        self.limit = limit;
+    }
+
+    fn give_back(&mut self, n: usize) {
+        self.level = self.level.saturating_sub(n);
+    }
+}
+
+pub struct Gcra<C = SystemClock> {
+    tat: Instant,
+    window: time::Duration,
+    limit: usize,
+    clock: C,
+}
+
+impl<C: Clock> Gcra<C> {
+    /// How far `n` units push the TAT ahead: their spacing at the sustained rate of limit / window.
+    /// Computed for all `n` at once in nanoseconds, so neither the division nor the multiplication
+    /// truncates more than the final nanosecond. A limit of zero admits nothing, so that case is
+    /// only guarded against dividing by zero.
+    fn intervals(&self, n: usize) -> time::Duration {
+        let nanos = self.window.as_nanos() * n as u128 / self.limit.max(1) as u128;
+        time::Duration::from_nanos(nanos.try_into().unwrap_or(u64::MAX))
+    }
+
+    /// Takes `n` units now, even if that pushes the TAT further ahead than `allowed_n` would, and
+    /// returns when the caller may go ahead. Later requests queue up behind the reservation.
+    /// Returns `None` without reserving anything if `n` is more than a full window's worth.
+    pub fn reserve(&mut self, n: usize) -> Option<Instant> {
+        if n > self.limit {
+            return None;
+        }
+
+        let now = self.clock.now();
+        self.tat = cmp::max(self.tat, now) + self.intervals(n);
+
+        // Admission only requires the TAT to be within a window of the present.
+        Some(cmp::max(self.tat - self.window, now))
+    }
+}
+
+impl<C: Clock> RateLimiter for Gcra<C> {
+    type Clock = C;
+
+    fn with_clock(window: time::Duration, limit: usize, clock: C) -> Self {
+        Gcra {
+            tat: clock.now(),
+            window,
+            limit,
+            clock,
+        }
+    }
+
+    fn allowed_n(&mut self, cost: usize) -> bool {
+        if cost > self.limit {
+            return false;
+        }
+
+        let now = self.clock.now();
+
+        // The theoretical arrival time (TAT) is when the next request would be due if requests
+        // arrived exactly at the sustained rate. A TAT in the past means the limiter has been idle,
+        // so it is pulled up to the present rather than banking unlimited credit. A request costing
+        // more than one unit advances the TAT by one interval per unit.
+        let new_tat = cmp::max(self.tat, now) + self.intervals(cost);
+
+        // Allowing this request would push the TAT more than a full window ahead of now, which
+        // means more than `limit` requests would have been admitted within the window.
+        if new_tat.duration_since(now) > self.window {
+            return false;
+        }
+
+        self.tat = new_tat;
+
+        true
+    }
+
+    fn time_until_allowed(&self) -> time::Duration {
+        if self.limit == 0 {
+            return self.window;
+        }
+
+        let now = self.clock.now();
+        let new_tat = cmp::max(self.tat, now) + self.intervals(1);
+
+        new_tat.duration_since(now).saturating_sub(self.window)
+    }
+
+    fn remaining(&self) -> usize {
+        // Each admitted unit pushes the TAT one interval further ahead of now, and the TAT may be
+        // at most a full window ahead.
+        let now = self.clock.now();
+        let ahead = self.tat.duration_since(now);
+
+        let window = self.window.as_nanos().max(1);
+        (self.window.saturating_sub(ahead).as_nanos() * self.limit as u128 / window) as usize
+    }
+
+    fn reset_at(&self) -> Instant {
+        cmp::max(self.tat, self.clock.now())
+    }
+
+    fn window(&self) -> time::Duration {
+        self.window
+    }
+
+    fn limit(&self) -> usize {
+        self.limit
+    }
+
+    fn update(&mut self, window: time::Duration, limit: usize) {
+        // How far the TAT is ahead of now reflects the units admitted recently at the old emission
+        // interval. Keep the same number of units outstanding at the new interval.
+        // Scale by the new interval over the old one, as a single division so that neither interval
+        // is rounded on its own.
+        let now = self.clock.now();
+        let outstanding = self.tat.duration_since(now).as_nanos();
+        let rescaled = outstanding
+            .saturating_mul(window.as_nanos())
+            .saturating_mul(self.limit as u128)
+            / (limit.max(1) as u128 * self.window.as_nanos().max(1));
+
+        self.window = window;
+        self.limit = limit;
+        self.tat = now + time::Duration::from_nanos(rescaled.try_into().unwrap_or(u64::MAX));
+    }
+
+    fn give_back(&mut self, n: usize) {
+        // Pull the TAT back by the intervals the returned units added, but no further back than
+        // the present: a TAT in the past already means the full burst is available.
+        let now = self.clock.now();
+        let refund = self.intervals(n);
+        self.tat = cmp::max(self.tat - refund, now);
+    }
+}
+
+/// Wraps any limiter with internal locking so that a single instance can be shared between
+/// threads (typically behind an `Arc`) and checked from `&self`.
+#[cfg(feature = "std")]
+pub struct Shared<L> {
+    inner: Mutex<L>,
+}
+
+#[cfg(feature = "std")]
+impl<L: RateLimiter> Shared<L> {
+    pub fn new(window: time::Duration, limit: usize) -> Self
+    where
+        L::Clock: Default,
+    {
+        Self::with_clock(window, limit, L::Clock::default())
+    }
+
+    pub fn with_clock(window: time::Duration, limit: usize, clock: L::Clock) -> Self {
+        Shared {
+            inner: Mutex::new(L::with_clock(window, limit, clock)),
+        }
+    }
+
+    pub fn allowed(&self) -> bool {
+        // A poisoned lock only means another thread panicked mid-check; the limiter state itself is
+        // still usable, so keep going rather than propagating the panic.
+        let mut limiter = self.inner.lock().unwrap_or_else(|e| e.into_inner());
+        limiter.allowed()
+    }
+
+    pub fn allowed_n(&self, cost: usize) -> bool {
+        let mut limiter = self.inner.lock().unwrap_or_else(|e| e.into_inner());
+        limiter.allowed_n(cost)
+    }
+
+    pub fn time_until_allowed(&self) -> time::Duration {
+        let limiter = self.inner.lock().unwrap_or_else(|e| e.into_inner());
+        limiter.time_until_allowed()
+    }
+
+    pub fn remaining(&self) -> usize {
+        let limiter = self.inner.lock().unwrap_or_else(|e| e.into_inner());
+        limiter.remaining()
+    }
+
+    pub fn reset_at(&self) -> Instant {
+        let limiter = self.inner.lock().unwrap_or_else(|e| e.into_inner());
+        limiter.reset_at()
+    }
+
+    pub fn check(&self) -> bool {
+        let limiter = self.inner.lock().unwrap_or_else(|e| e.into_inner());
+        limiter.check()
+    }
+
+    pub fn decide(&self) -> Decision {
+        let mut limiter = self.inner.lock().unwrap_or_else(|e| e.into_inner());
+        limiter.decide()
+    }
+
+    pub fn update(&self, window: time::Duration, limit: usize) {
+        let mut limiter = self.inner.lock().unwrap_or_else(|e| e.into_inner());
+        limiter.update(window, limit)
+    }
+
+    pub fn give_back(&self, n: usize) {
+        let mut limiter = self.inner.lock().unwrap_or_else(|e| e.into_inner());
+        limiter.give_back(n)
+    }
+
+    /// Blocks the calling thread until a request is admitted. The lock is not held while sleeping,
+    /// so other threads can keep using the limiter.
+    pub fn wait(&self) {
+        loop {
+            let wait = {
+                let mut limiter = self.inner.lock().unwrap_or_else(|e| e.into_inner());
+                if limiter.allowed() {
+                    return;
+                }
+                limiter.time_until_allowed()
+            };
+
+            thread::sleep(wait.max(MIN_WAIT));
+        }
+    }
+
+    /// Waits until the limiter admits a request. Rather than polling `allowed` in a loop, this
+    /// sleeps for as long as the limiter reports it will take for the next request to be admitted.
+    #[cfg(feature = "tokio")]
+    pub async fn acquire(&self) {
+        loop {
+            // Check and compute the wait under a single lock, and make sure the lock is released
+            // before sleeping so other tasks aren't blocked in the meantime.
+            let wait = {
+                let mut limiter = self.inner.lock().unwrap_or_else(|e| e.into_inner());
+                if limiter.allowed() {
+                    return;
+                }
+                limiter.time_until_allowed()
+            };
+
+            // Another task may grab the token first, in which case this loops around and waits
+            // again.
+            tokio::time::sleep(wait.max(MIN_WAIT)).await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{cell::Cell, collections::HashMap, rc::Rc, time};
+
+    /// A millisecond counter like a device's timer would give.
+    #[derive(Clone, Default)]
+    struct Millis(Rc<Cell<u64>>);
+
+    impl Clock for Millis {
+        fn now(&self) -> Instant {
+            Instant::from_ticks(time::Duration::from_millis(self.0.get()))
+        }
+    }
+
+    #[test]
+    fn test_token_bucket_with_a_tick_clock() {
+        let clock = Millis::default();
+        let mut bucket = TokenBucket::with_clock(time::Duration::from_secs(1), 4, clock.clone())
+            .with_capacity(4);
+        bucket.set_credit(4 * 1_000_000_000);
+
+        assert!(bucket.allowed_n(4));
+        assert!(!bucket.allowed());
+        assert_eq!(
+            time::Duration::from_millis(250),
+            bucket.time_until_allowed()
+        );
+
+        clock.0.set(250);
+        assert!(bucket.allowed());
+        assert_eq!(0, bucket.remaining());
+    }
+
+    #[test]
+    fn test_instants_before_the_epoch() {
+        let epoch = Instant::default();
+        let before = epoch - time::Duration::from_millis(1500);
+
+        assert!(before < epoch);
+        assert_eq!(
+            time::Duration::from_millis(1500),
+            epoch.duration_since(before)
+        );
+        assert_eq!(time::Duration::ZERO, before.duration_since(epoch));
+        assert_eq!(epoch, before + time::Duration::from_millis(1500));
+    }
+
+    #[test]
+    fn test_sharded_token_bucket() {
+        let clock = ManualClock::new();
+        let limiter: ShardedTokenBucket<ManualClock> =
+            ShardedTokenBucket::with_clock(time::Duration::from_secs(1), 100, 4, clock.clone());
+
+        clock.advance(time::Duration::from_secs(1));
+        assert_eq!(100, limiter.remaining());
+
+        // A single thread only sees its own shard until the next rebalance.
+        assert_eq!(25, (0..100).filter(|_| limiter.allowed()).count());
+
+        // By then its shard has accrued 2.5 tokens, and the 77.5 in total are split evenly.
+        clock.advance(time::Duration::from_millis(100));
+        assert_eq!(19, (0..100).filter(|_| limiter.allowed()).count());
+
+        let limiter = Arc::new(limiter);
+        clock.advance(time::Duration::from_secs(1));
+        let handles: Vec<_> = (0..8)
+            .map(|_| {
+                let limiter = limiter.clone();
+                thread::spawn(move || (0..50).filter(|_| limiter.allowed()).count())
+            })
+            .collect();
+
+        let admitted: usize = handles.into_iter().map(|h| h.join().unwrap()).sum();
+        assert!(admitted <= 100);
+    }
+
+    #[test]
+    fn test_builder_validates_configuration() {
+        let second = time::Duration::from_secs(1);
+
+        assert_eq!(
+            Some(ConfigError::MissingWindow),
+            RateLimiterBuilder::new().limit(10).token_bucket().err()
+        );
+        assert_eq!(
+            Some(ConfigError::ZeroWindow),
+            RateLimiterBuilder::new()
+                .window(time::Duration::ZERO)
+                .limit(10)
+                .build::<Gcra>()
+                .err()
+        );
+        assert_eq!(
+            Some(ConfigError::ZeroLimit),
+            RateLimiterBuilder::new()
+                .window(second)
+                .limit(0)
+                .build::<FixedWindow>()
+                .err()
+        );
+        assert_eq!(
+            Some(ConfigError::RateTooHigh { limit: 1000 }),
+            RateLimiterBuilder::new()
+                .window(time::Duration::from_nanos(10))
+                .limit(1000)
+                .build::<LeakyBucket>()
+                .err()
+        );
+        // Windows shorter than a microsecond are fine as long as each request gets a nanosecond.
+        let clock = ManualClock::new();
+        let mut moving = RateLimiterBuilder::new()
+            .window(time::Duration::from_nanos(500))
+            .limit(1)
+            .clock(clock.clone())
+            .build::<MovingWindow<_>>()
+            .unwrap();
+        assert!(moving.allowed());
+        assert!(!moving.allowed());
+        assert_eq!(0, moving.remaining());
+        clock.advance(time::Duration::from_nanos(1000));
+        assert!(moving.allowed());
+
+        assert_eq!(
+            Some(ConfigError::BurstBelowLimit {
+                burst: 5,
+                limit: 10
+            }),
+            RateLimiterBuilder::new()
+                .window(second)
+                .limit(10)
+                .burst(5)
+                .token_bucket()
+                .err()
+        );
+        assert_eq!(
+            Some(ConfigError::BurstUnsupported),
+            RateLimiterBuilder::new()
+                .window(second)
+                .limit(10)
+                .burst(20)
+                .build::<FixedWindow>()
+                .err()
+        );
+
+        let clock = ManualClock::new();
+        let mut bucket = RateLimiterBuilder::new()
+            .window(second)
+            .limit(10)
+            .burst(20)
+            .clock(clock.clone())
+            .token_bucket()
+            .unwrap();
+
+        clock.advance(time::Duration::from_secs(5));
+        assert_eq!(20, (0..30).filter(|_| bucket.allowed()).count());
+    }
+
+    #[test]
+    fn test_shared_across_threads() {
+        let limiter: Arc<Shared<FixedWindow<ManualClock>>> = Arc::new(Shared::with_clock(
+            time::Duration::from_secs(1),
+            100,
+            ManualClock::new(),
+        ));
+
+        let handles: Vec<_> = (0..8)
+            .map(|_| {
+                let limiter = limiter.clone();
+                thread::spawn(move || (0..50).filter(|_| limiter.allowed()).count())
+            })
+            .collect();
+
+        let admitted: usize = handles.into_iter().map(|h| h.join().unwrap()).sum();
+        assert_eq!(100, admitted);
+    }
+
+    #[test]
+    fn test_token_bucket_refills_over_time() {
+        let clock = ManualClock::new();
+        let mut limiter = TokenBucket::with_clock(time::Duration::from_secs(1), 10, clock.clone());
+
+        // The bucket starts empty.
+        assert!(!limiter.allowed());
+
+        clock.advance(time::Duration::from_millis(300));
+        assert!(limiter.allowed());
+        assert!(limiter.allowed());
+        assert!(limiter.allowed());
+        assert!(!limiter.allowed());
+
+        // Never accumulates more than the limit.
+        clock.advance(time::Duration::from_secs(10));
+        assert_eq!(10, (0..20).filter(|_| limiter.allowed()).count());
+    }
+
+    #[test]
+    fn test_token_bucket_keeps_fractional_tokens() {
+        let clock = ManualClock::new();
+        let mut limiter = TokenBucket::with_clock(time::Duration::from_secs(1), 10, clock.clone());
+
+        // Each check lands 1.5 tokens after the last one. Dropping the half token on every check
+        // would only admit 20 requests over 3 seconds instead of 30.
+        let mut admitted = 0;
+        for _ in 0..20 {
+            clock.advance(time::Duration::from_millis(150));
+            admitted += (0..10).filter(|_| limiter.allowed()).count();
+        }
+        assert_eq!(30, admitted);
+    }
+
+    #[test]
+    fn test_token_bucket_low_rate() {
+        let clock = ManualClock::new();
+        let mut limiter =
+            TokenBucket::with_clock(time::Duration::from_secs(3600), 1, clock.clone());
+
+        // Frequent checks at a rate of one per hour must not keep resetting accrual.
+        for _ in 0..59 {
+            clock.advance(time::Duration::from_secs(60));
+            assert!(!limiter.allowed());
+        }
+        assert_eq!(time::Duration::from_secs(60), limiter.time_until_allowed());
+
+        clock.advance(time::Duration::from_secs(60));
+        assert!(limiter.allowed());
+    }
+
+    #[test]
+    fn test_token_bucket_warm_up() {
+        let clock = ManualClock::new();
+        let mut limiter = TokenBucket::with_clock(time::Duration::from_secs(1), 100, clock.clone())
+            .with_warm_up(time::Duration::from_secs(10), 0.1);
+
+        // The rate ramps from 10% to 100% over the first 10 seconds, so on average 55% of the
+        // full rate is admitted.
+        let admitted: Vec<usize> = (0..10)
+            .map(|_| {
+                clock.advance(time::Duration::from_secs(1));
+                (0..200).filter(|_| limiter.allowed()).count()
+            })
+            .collect();
+        assert_eq!(14, admitted[0]);
+        assert!(admitted.windows(2).all(|w| w[0] < w[1]));
+        assert!((548..=550).contains(&admitted.iter().sum::<usize>()));
+
+        // Warm, it refills at the full rate.
+        clock.advance(time::Duration::from_secs(1));
+        assert_eq!(100, (0..200).filter(|_| limiter.allowed()).count());
+
+        // Idle for a whole warm-up period goes cold again, limiting the burst.
+        clock.advance(time::Duration::from_secs(10));
+        assert_eq!(10, limiter.remaining());
+        assert_eq!(10, (0..200).filter(|_| limiter.allowed()).count());
+    }
+
+    #[test]
+    fn test_token_bucket_burst_capacity() {
+        let clock = ManualClock::new();
+        let mut limiter = TokenBucket::with_clock(time::Duration::from_secs(1), 10, clock.clone())
+            .with_capacity(100);
+
+        // Idle long enough to fill the bucket, then burst well past the per-second rate.
+        clock.advance(time::Duration::from_secs(60));
+        assert_eq!(100, limiter.remaining());
+        assert_eq!(100, (0..200).filter(|_| limiter.allowed()).count());
+
+        // Refill still happens at the sustained rate.
+        clock.advance(time::Duration::from_secs(1));
+        assert_eq!(10, (0..200).filter(|_| limiter.allowed()).count());
+    }
+
+    #[test]
+    fn test_leaky_bucket_drains_at_a_constant_rate() {
+        let clock = ManualClock::new();
+        let mut limiter = LeakyBucket::with_clock(time::Duration::from_secs(1), 10, clock.clone());
+        assert!(limiter.allowed_n(10));
+        assert!(!limiter.allowed());
+
+        // One request drains out every 100ms, however often the bucket is checked.
+        let mut admitted = 0;
+        for _ in 0..10 {
+            clock.advance(time::Duration::from_millis(150));
+            while limiter.allowed() {
+                admitted += 1;
+            }
+        }
+        assert_eq!(15, admitted);
+        assert_eq!(
+            time::Duration::from_millis(100),
+            limiter.time_until_allowed()
+        );
+
+        // Time spent empty doesn't bring forward when the next request drains out.
+        let mut limiter = LeakyBucket::with_clock(time::Duration::from_secs(1), 1, clock.clone());
+        clock.advance(time::Duration::from_millis(900));
+        assert!(limiter.allowed());
+        clock.advance(time::Duration::from_millis(200));
+        assert!(!limiter.allowed());
+        clock.advance(time::Duration::from_millis(800));
+        assert!(limiter.allowed());
+    }
+
+    #[test]
+    fn test_leaky_bucket_waits_round_up() {
+        let clock = ManualClock::new();
+
+        // A third of a second isn't a whole number of nanoseconds, so waiting for a truncated one
+        // would leave nothing drained yet.
+        let mut limiter = LeakyBucket::with_clock(time::Duration::from_secs(1), 3, clock.clone());
+        assert!(limiter.allowed_n(3));
+        let wait = limiter.time_until_allowed();
+        assert_eq!(time::Duration::from_nanos(333_333_334), wait);
+        assert_eq!(
+            clock.now() + time::Duration::from_secs(1),
+            limiter.reset_at()
+        );
+        clock.advance(wait);
+        assert!(limiter.allowed());
+
+        // A limit of zero admits nothing, rather than dividing by zero.
+        let limiter = LeakyBucket::with_clock(time::Duration::from_secs(1), 0, clock.clone());
+        assert_eq!(time::Duration::from_secs(1), limiter.time_until_allowed());
+        assert_eq!(clock.now(), limiter.reset_at());
+    }
+
+    #[test]
+    fn test_sliding_window_expires_sub_buckets() {
+        let clock = ManualClock::new();
+        let mut limiter =
+            SlidingWindow::with_clock(time::Duration::from_secs(60), 10, clock.clone())
+                .with_buckets(60);
+
+        assert_eq!(4, (0..4).filter(|_| limiter.allowed()).count());
+        clock.advance(time::Duration::from_secs(30));
+        assert_eq!(6, (0..10).filter(|_| limiter.allowed()).count());
+        assert_eq!(time::Duration::from_secs(30), limiter.time_until_allowed());
+
+        // The first four hits age out a minute after they were made, while the rest remain.
+        clock.advance(time::Duration::from_secs(30));
+        assert_eq!(4, limiter.remaining());
+        assert_eq!(4, (0..10).filter(|_| limiter.allowed()).count());
+        assert_eq!(
+            clock.now() + time::Duration::from_secs(60),
+            limiter.reset_at()
+        );
+
+        // Idle for longer than the whole window clears everything.
+        clock.advance(time::Duration::from_secs(600));
+        assert_eq!(10, limiter.remaining());
+        assert_eq!(10, (0..20).filter(|_| limiter.allowed()).count());
+    }
+
+    #[test]
+    fn test_give_back_restores_quota() {
+        let clock = ManualClock::new();
+        let window = time::Duration::from_secs(1);
+
+        let mut fixed = FixedWindow::with_clock(window, 3, clock.clone());
+        assert!(fixed.allowed_n(3));
+        fixed.give_back(2);
+        assert_eq!(2, fixed.remaining());
+
+        let mut gcra = Gcra::with_clock(window, 10, clock.clone());
+        assert!(gcra.allowed_n(10));
+        gcra.give_back(4);
+        assert_eq!(4, gcra.remaining());
+        gcra.give_back(100);
+        assert_eq!(10, gcra.remaining());
+
+        let mut bucket = TokenBucket::with_clock(window, 10, clock.clone());
+        clock.advance(window);
+        assert!(bucket.allowed_n(5));
+        bucket.give_back(50);
+        assert_eq!(10, bucket.remaining());
+
+        let mut sliding = SlidingWindow::with_clock(window, 10, clock.clone());
+        assert!(sliding.allowed_n(6));
+        clock.advance(time::Duration::from_millis(500));
+        assert!(sliding.allowed_n(4));
+        sliding.give_back(5);
+        assert_eq!(5, sliding.remaining());
+    }
+
+    #[test]
+    fn test_reserve_returns_admission_time() {
+        let clock = ManualClock::new();
+        let window = time::Duration::from_secs(1);
+        let start = clock.now();
+
+        let mut bucket = TokenBucket::with_clock(window, 10, clock.clone());
+        assert_eq!(
+            Some(start + time::Duration::from_millis(200)),
+            bucket.reserve(2)
+        );
+        assert_eq!(
+            Some(start + time::Duration::from_millis(500)),
+            bucket.reserve(3)
+        );
+        assert_eq!(None, bucket.reserve(11));
+
+        // Reservations are paid for before anything else is admitted.
+        clock.advance(time::Duration::from_millis(500));
+        assert!(!bucket.allowed());
+        clock.advance(time::Duration::from_millis(100));
+        assert!(bucket.allowed());
+
+        let mut gcra = Gcra::with_clock(window, 10, clock.clone());
+        assert_eq!(Some(clock.now()), gcra.reserve(10));
+        assert_eq!(
+            Some(clock.now() + time::Duration::from_millis(300)),
+            gcra.reserve(3)
+        );
+        assert!(!gcra.allowed());
+    }
+
+    #[test]
+    fn test_update_keeps_accumulated_state() {
+        let clock = ManualClock::new();
+        let mut limiter = TokenBucket::with_clock(time::Duration::from_secs(1), 10, clock.clone());
+
+        clock.advance(time::Duration::from_millis(500));
+        assert_eq!(5, limiter.remaining());
+
+        // The tokens already accrued are kept, and the bucket fills faster from now on.
+        limiter.set_limit(100);
+        assert_eq!(5, limiter.remaining());
+        clock.advance(time::Duration::from_millis(100));
+        assert_eq!(15, limiter.remaining());
+
+        let mut limiter = FixedWindow::with_clock(time::Duration::from_secs(1), 10, clock.clone());
+        assert_eq!(8, (0..8).filter(|_| limiter.allowed()).count());
+        limiter.set_limit(5);
+        assert!(!limiter.allowed());
+
+        let mut limiter = Gcra::with_clock(time::Duration::from_secs(1), 10, clock.clone());
+        assert!(limiter.allowed_n(5));
+        limiter.set_window(time::Duration::from_secs(2));
+        assert_eq!(5, limiter.remaining());
+    }
+
+    #[test]
+    fn test_gcra_intervals_are_exact() {
+        let clock = ManualClock::new();
+
+        // A third of a second doesn't divide evenly into nanoseconds, but three of them make a
+        // whole window.
+        let mut limiter = Gcra::with_clock(time::Duration::from_secs(1), 3, clock.clone());
+        assert!(limiter.allowed_n(3));
+        assert!(!limiter.allowed());
+        assert_eq!(
+            time::Duration::from_nanos(333_333_333),
+            limiter.time_until_allowed()
+        );
+
+        // Limits beyond u32::MAX aren't truncated.
+        let mut limiter =
+            Gcra::with_clock(time::Duration::from_secs(10), (1 << 32) + 1, clock.clone());
+        assert!(limiter.allowed_n(1000));
+
+        // A limit of zero admits nothing, and can be raised later.
+        let mut limiter = Gcra::with_clock(time::Duration::from_secs(1), 0, clock.clone());
+        assert!(!limiter.allowed());
+        assert_eq!(0, limiter.remaining());
+        assert_eq!(time::Duration::from_secs(1), limiter.time_until_allowed());
+        limiter.set_limit(10);
+        assert!(limiter.allowed_n(10));
+    }
+
+    #[test]
+    fn test_moving_window_interpolates_previous_window() {
+        let clock = ManualClock::new();
+        let mut limiter = MovingWindow::with_clock(time::Duration::from_secs(1), 10, clock.clone());
+
+        assert_eq!(10, (0..20).filter(|_| limiter.allowed()).count());
+
+        // A quarter of the way into the next window, three quarters of the previous window's hits
+        // still count.
+        clock.advance(time::Duration::from_millis(1250));
+        assert_eq!(3, limiter.remaining());
+        assert_eq!(3, (0..20).filter(|_| limiter.allowed()).count());
+    }
+
+    #[test]
+    fn test_allowed_n_is_all_or_nothing() {
+        let mut limiter =
+            FixedWindow::with_clock(time::Duration::from_secs(1), 10, ManualClock::new());
+
+        assert!(limiter.allowed_n(7));
+        assert!(!limiter.allowed_n(4));
+        assert!(limiter.allowed_n(3));
+        assert!(!limiter.allowed());
+
+        let mut limiter = Gcra::with_clock(time::Duration::from_secs(1), 10, ManualClock::new());
+
+        assert!(!limiter.allowed_n(11));
+        assert!(limiter.allowed_n(10));
+        assert!(!limiter.allowed());
+    }
+
+    #[test]
+    fn test_introspection_does_not_consume() {
+        let clock = ManualClock::new();
+        let mut limiter = FixedWindow::with_clock(time::Duration::from_secs(1), 3, clock.clone());
+
+        assert_eq!(3, limiter.remaining());
+        assert!(limiter.allowed_n(2));
+        assert_eq!(1, limiter.remaining());
+        assert!(limiter.check());
+        assert!(limiter.check());
+        assert_eq!(1, limiter.remaining());
+        assert_eq!(
+            clock.now() + time::Duration::from_secs(1),
+            limiter.reset_at()
+        );
+
+        assert!(limiter.allowed());
+        assert!(!limiter.check());
+        assert_eq!(0, limiter.remaining());
+
+        let mut limiter = Gcra::with_clock(time::Duration::from_secs(1), 10, clock.clone());
+        assert_eq!(10, limiter.remaining());
+        assert!(limiter.allowed_n(4));
+        assert_eq!(6, limiter.remaining());
+        clock.advance(time::Duration::from_millis(200));
+        assert_eq!(8, limiter.remaining());
+
+        // Requests half a microsecond apart.
+        let mut limiter = Gcra::with_clock(time::Duration::from_millis(1), 2000, clock.clone());
+        assert!(limiter.allowed());
+        assert_eq!(1999, limiter.remaining());
+    }
+
+    #[test]
+    fn test_decide_reports_details() {
+        let clock = ManualClock::new();
+        let mut limiter = FixedWindow::with_clock(time::Duration::from_secs(1), 2, clock.clone());
+
+        assert_eq!(Decision::Allowed { remaining: 1 }, limiter.decide());
+        clock.advance(time::Duration::from_millis(400));
+        assert_eq!(Decision::Allowed { remaining: 0 }, limiter.decide());
+
+        assert_eq!(
+            Decision::Denied {
+                retry_after: time::Duration::from_micros(600_001)
+            },
+            limiter.decide()
+        );
+    }
+
+    #[test]
+    fn test_wait_blocks_for_tokens() {
+        let mut limiter: Gcra = Gcra::new(time::Duration::from_millis(100), 10);
+
+        let start = time::Instant::now();
+        for _ in 0..15 {
+            limiter.wait();
+        }
+
+        // The first 10 are admitted as a burst, and the remaining 5 are spaced 10ms apart.
+        assert!(start.elapsed() >= time::Duration::from_millis(50));
+    }
+
+    #[cfg(feature = "tokio")]
+    #[tokio::test]
+    async fn test_acquire_waits_for_tokens() {
+        let limiter: Arc<Shared<TokenBucket>> =
+            Arc::new(Shared::new(time::Duration::from_millis(100), 10));
+
+        let start = time::Instant::now();
+        let tasks: Vec<_> = (0..5)
+            .map(|_| {
+                let limiter = limiter.clone();
+                tokio::spawn(async move { limiter.acquire().await })
+            })
+            .collect();
+
+        for task in tasks {
+            task.await.unwrap();
+        }
+
+        // The bucket starts empty and accrues a token every 10ms.
+        assert!(start.elapsed() >= time::Duration::from_millis(50));
+    }
+
+    mod properties {
+        use super::*;
+        use proptest::prelude::*;
+
+        const WINDOW: time::Duration = time::Duration::from_secs(1);
+
+        /// Replays `steps` of (time to advance, cost) and returns the time and cost of every
+        /// admitted request. The clock starts at the epoch, so calendar windows line up with the
+        /// elapsed time.
+        fn replay<L: RateLimiter<Clock = ManualClock>>(
+            limit: usize,
+            steps: &[(u64, usize)],
+        ) -> Vec<(time::Duration, usize)> {
+            let clock = ManualClock::at(SystemTime::UNIX_EPOCH);
+            let mut limiter = L::with_clock(WINDOW, limit, clock.clone());
+            let mut elapsed = time::Duration::ZERO;
+            let mut admitted = Vec::new();
+
+            for &(advance, cost) in steps {
+                let advance = time::Duration::from_millis(advance);
+                clock.advance(advance);
+                elapsed += advance;
+                if limiter.allowed_n(cost) {
+                    admitted.push((elapsed, cost));
+                }
+            }
+
+            admitted
+        }
+
+        /// The most admitted in any interval of `windows` windows.
+        fn busiest(admitted: &[(time::Duration, usize)], windows: u32) -> usize {
+            admitted
+                .iter()
+                .map(|&(start, _)| {
+                    admitted
+                        .iter()
+                        .filter(|&&(at, _)| at >= start && at < start + WINDOW * windows)
+                        .map(|&(_, cost)| cost)
+                        .sum()
+                })
+                .max()
+                .unwrap_or_default()
+        }
+
+        /// Checks that no period admits more than `limit`, where `period` numbers the period each
+        /// time falls in.
+        fn check_periods(
+            admitted: &[(time::Duration, usize)],
+            limit: usize,
+            period: impl Fn(time::Duration) -> u128,
+        ) -> Result<(), TestCaseError> {
+            let mut periods = HashMap::new();
+            for &(at, cost) in admitted {
+                *periods.entry(period(at)).or_insert(0) += cost;
+            }
+            for (period, admitted) in periods {
+                prop_assert!(
+                    admitted <= limit,
+                    "{} admitted in period {}",
+                    admitted,
+                    period
+                );
+            }
+            Ok(())
+        }
+
+        /// Checks that from any admitted request to any later one, both included, no more than
+        /// `allowance` of the time between them is admitted.
+        fn check_between(
+            admitted: &[(time::Duration, usize)],
+            allowance: impl Fn(time::Duration) -> usize,
+        ) -> Result<(), TestCaseError> {
+            for (i, &(first, _)) in admitted.iter().enumerate() {
+                let mut total = 0;
+                for &(at, cost) in &admitted[i..] {
+                    total += cost;
+                    prop_assert!(
+                        total <= allowance(at - first),
+                        "{} admitted in {:?}",
+                        total,
+                        at - first
+                    );
+                }
+            }
+            Ok(())
+        }
+
+        /// The whole units that accrue in `elapsed` at `limit` per window.
+        fn accrued(limit: usize, elapsed: time::Duration) -> usize {
+            (limit as u128 * elapsed.as_nanos() / WINDOW.as_nanos()) as usize
+        }
+
+        fn steps() -> impl Strategy<Value = Vec<(u64, usize)>> {
+            prop::collection::vec((0..400u64, 1..=3usize), 1..200)
+        }
+
+        proptest! {
+            /// A window starts with the first request after the last one ended, so an interval
+            /// of `k` windows overlaps at most `k + 1` of them, with a full window's worth
+            /// admitted on each side of every boundary.
+            #[test]
+            fn fixed_window(limit in 1..20usize, steps in steps()) {
+                let admitted = replay::<FixedWindow<ManualClock>>(limit, &steps);
+                for windows in 1..=5 {
+                    prop_assert!(busiest(&admitted, windows) <= (windows as usize + 1) * limit);
+                }
+            }
+
+            /// Exact within each window counted from the epoch.
+            #[test]
+            fn calendar_window(limit in 1..20usize, steps in steps()) {
+                let admitted = replay::<CalendarWindow<ManualClock>>(limit, &steps);
+                check_periods(&admitted, limit, |at| at.as_nanos() / WINDOW.as_nanos())?;
+            }
+
+            /// Exact within each of its windows, which end a whole window after the previous
+            /// one, inclusive. Across a boundary the previous window's hits are assumed to be
+            /// spread evenly, so up to two windows' worth can land within one window of time.
+            #[test]
+            fn moving_window(limit in 1..20usize, steps in steps()) {
+                let admitted = replay::<MovingWindow<ManualClock>>(limit, &steps);
+                let window = WINDOW.as_nanos();
+                check_periods(&admitted, limit, |at| at.as_nanos().saturating_sub(1) / window)?;
+                prop_assert!(busiest(&admitted, 1) <= 2 * limit);
+            }
+
+            /// Exact over every run of ten sub-windows, but a window of time can also take in
+            /// the hits of the sub-window that was just forgotten.
+            #[test]
+            fn sliding_window(limit in 1..20usize, steps in steps()) {
+                let admitted = replay::<SlidingWindow<ManualClock>>(limit, &steps);
+                let width = (WINDOW / 10).as_nanos();
+                let mut buckets = std::collections::BTreeMap::new();
+                for &(at, cost) in &admitted {
+                    *buckets.entry(at.as_nanos() / width).or_insert(0) += cost;
+                }
+                for &last in buckets.keys() {
+                    let run: usize = buckets.range(last.saturating_sub(9)..=last).map(|(_, cost)| cost).sum();
+                    prop_assert!(run <= limit, "{} admitted in the run ending at {}", run, last);
+                }
+                prop_assert!(busiest(&admitted, 1) <= 2 * limit);
+            }
+
+            /// Starts empty and never holds more than `limit`, accruing `limit` per window.
+            #[test]
+            fn token_bucket(limit in 1..20usize, steps in steps()) {
+                let admitted = replay::<TokenBucket<ManualClock>>(limit, &steps);
+                check_between(&admitted, |elapsed| limit + accrued(limit, elapsed))?;
+
+                let mut total = 0;
+                for &(at, cost) in &admitted {
+                    total += cost;
+                    prop_assert!(total <= accrued(limit, at));
+                }
+            }
+
+            /// Holds at most `limit`, and drains at `limit` per window.
+            #[test]
+            fn leaky_bucket(limit in 1..20usize, steps in steps()) {
+                let admitted = replay::<LeakyBucket<ManualClock>>(limit, &steps);
+                check_between(&admitted, |elapsed| limit + accrued(limit, elapsed))?;
+            }
+
+            /// Each unit pushes the TAT one emission interval further ahead, and it can be at most
+            /// a window ahead of the present.
+            #[test]
+            fn gcra(limit in 1..20usize, steps in steps()) {
+                let admitted = replay::<Gcra<ManualClock>>(limit, &steps);
+                let interval = (WINDOW / limit as u32).as_nanos();
+                check_between(&admitted, |elapsed| ((WINDOW + elapsed).as_nanos() / interval) as usize)?;
+            }
+        }
+    }
+}
@@ -0,0 +1,204 @@
+//! Saving and restoring limiter state, behind the `serde` feature.
+
+use super::*;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+
+/// Saves and restores the state of a limiter, such as hit counts and accrued tokens, so quota isn't
+/// reset when a process restarts or is handed off to another process. The window and limit are not
+/// part of the state: restore into a limiter created with the same configuration.
+///
+/// Instants only mean something within the process that created them, so they are saved as wall
+/// clock times and converted back relative to the restoring limiter's clock.
+pub trait Persist {
+    type State: Serialize + DeserializeOwned;
+
+    fn save(&self) -> Self::State;
+    fn restore(&mut self, state: Self::State);
+}
+
+fn to_wall<C: WallClock>(clock: &C, at: Instant) -> SystemTime {
+    let (now, wall) = (clock.now(), clock.wall());
+    if at <= now {
+        wall - now.duration_since(at)
+    } else {
+        wall + at.duration_since(now)
+    }
+}
+
+fn from_wall<C: WallClock>(clock: &C, at: SystemTime) -> Instant {
+    let (now, wall) = (clock.now(), clock.wall());
+    match at.duration_since(wall) {
+        Ok(ahead) => now + ahead,
+        Err(behind) => now - behind.duration(),
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct FixedWindowState {
+    window_start: SystemTime,
+    hits: usize,
+}
+
+impl<C: WallClock> Persist for FixedWindow<C> {
+    type State = FixedWindowState;
+
+    fn save(&self) -> FixedWindowState {
+        FixedWindowState {
+            window_start: to_wall(&self.clock, self.window_start),
+            hits: self.hits,
+        }
+    }
+
+    fn restore(&mut self, state: FixedWindowState) {
+        self.window_start = from_wall(&self.clock, state.window_start);
+        self.hits = state.hits;
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct CalendarWindowState {
+    index: u64,
+    hits: usize,
+}
+
+impl<C: WallClock> Persist for CalendarWindow<C> {
+    type State = CalendarWindowState;
+
+    fn save(&self) -> CalendarWindowState {
+        CalendarWindowState {
+            index: self.index,
+            hits: self.hits,
+        }
+    }
+
+    fn restore(&mut self, state: CalendarWindowState) {
+        self.index = state.index;
+        self.hits = state.hits;
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct MovingWindowState {
+    prev_start: SystemTime,
+    prev_count: usize,
+    this_start: SystemTime,
+    this_count: usize,
+}
+
+impl<C: WallClock> Persist for MovingWindow<C> {
+    type State = MovingWindowState;
+
+    fn save(&self) -> MovingWindowState {
+        MovingWindowState {
+            prev_start: to_wall(&self.clock, self.prev_start),
+            prev_count: self.prev_count,
+            this_start: to_wall(&self.clock, self.this_start),
+            this_count: self.this_count,
+        }
+    }
+
+    fn restore(&mut self, state: MovingWindowState) {
+        self.prev_start = from_wall(&self.clock, state.prev_start);
+        self.prev_count = state.prev_count;
+        self.this_start = from_wall(&self.clock, state.this_start);
+        self.this_count = state.this_count;
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SlidingWindowState {
+    buckets: Vec<usize>,
+    head: usize,
+    head_start: SystemTime,
+}
+
+impl<C: WallClock> Persist for SlidingWindow<C> {
+    type State = SlidingWindowState;
+
+    fn save(&self) -> SlidingWindowState {
+        SlidingWindowState {
+            buckets: self.buckets.clone(),
+            head: self.head,
+            head_start: to_wall(&self.clock, self.head_start),
+        }
+    }
+
+    fn restore(&mut self, state: SlidingWindowState) {
+        // A saved head outside of the buckets would mean the state came from a limiter with a
+        // different bucket count, which isn't supported.
+        self.head = state.head % state.buckets.len().max(1);
+        self.buckets = state.buckets;
+        self.head_start = from_wall(&self.clock, state.head_start);
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct TokenBucketState {
+    credit: i128,
+    last_hit: SystemTime,
+    warm_up_started: Option<SystemTime>,
+}
+
+impl<C: WallClock> Persist for TokenBucket<C> {
+    type State = TokenBucketState;
+
+    fn save(&self) -> TokenBucketState {
+        TokenBucketState {
+            credit: self.credit,
+            last_hit: to_wall(&self.clock, self.last_hit),
+            warm_up_started: self
+                .warm_up
+                .as_ref()
+                .map(|w| to_wall(&self.clock, w.started)),
+        }
+    }
+
+    fn restore(&mut self, state: TokenBucketState) {
+        self.credit = state.credit;
+        self.last_hit = from_wall(&self.clock, state.last_hit);
+        if let (Some(warm_up), Some(started)) = (&mut self.warm_up, state.warm_up_started) {
+            warm_up.started = from_wall(&self.clock, started);
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct LeakyBucketState {
+    level: usize,
+    last_leak: SystemTime,
+}
+
+impl<C: WallClock> Persist for LeakyBucket<C> {
+    type State = LeakyBucketState;
+
+    fn save(&self) -> LeakyBucketState {
+        LeakyBucketState {
+            level: self.level,
+            last_leak: to_wall(&self.clock, self.last_leak),
+        }
+    }
+
+    fn restore(&mut self, state: LeakyBucketState) {
+        self.level = state.level;
+        self.last_leak = from_wall(&self.clock, state.last_leak);
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct GcraState {
+    tat: SystemTime,
+}
+
+impl<C: WallClock> Persist for Gcra<C> {
+    type State = GcraState;
+
+    fn save(&self) -> GcraState {
+        GcraState {
+            tat: to_wall(&self.clock, self.tat),
+        }
+    }
+
+    fn restore(&mut self, state: GcraState) {
+        self.tat = from_wall(&self.clock, state.tat);
+    }
+}
@@ -1,178 +1,7753 @@
 use async_trait::async_trait;
-use futures::StreamExt;
-use reqwest::header::HeaderMap;
+use bytes::Bytes;
+use futures::{
+    stream::{BoxStream, FuturesUnordered},
+    Future, StreamExt, TryStreamExt,
+};
+use rand::Rng;
+use rate_limit::{FixedWindow, RateLimiter};
+use reqwest::{
+    header::{HeaderMap, HeaderName, HeaderValue, CONTENT_ENCODING, CONTENT_TYPE, RETRY_AFTER},
+    Method, Response, StatusCode,
+};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
 use serde_json::json;
+use sha2::{Digest, Sha256};
+use std::{
+    collections::{BTreeMap, HashMap, VecDeque},
+    fmt,
+    fs::{self, File, OpenOptions},
+    io::{self, BufRead, BufReader, Write},
+    marker::PhantomData,
+    ops::Deref,
+    path::{Path, PathBuf},
+    pin::Pin,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering},
+        Arc, Mutex, MutexGuard,
+    },
+    task::{Context, Poll},
+    time::Duration,
+};
 use thiserror::Error;
-use tokio::sync::mpsc;
+use tokio::sync::{mpsc, oneshot, Notify};
+use tokio_util::sync::CancellationToken;
+use tracing::Instrument;
 
-#[derive(Error, Debug)]
+// Errors are cloneable so that everyone waiting on a failed batch can be told why it failed.
+#[derive(Error, Debug, Clone)]
 pub enum DispatchError {
     #[error("client failed to post")]
-    PostFailed(#[from] reqwest::Error),
+    PostFailed(#[source] Arc<reqwest::Error>),
+    #[error("failed to build request")]
+    BuildFailed(#[source] Arc<reqwest::Error>),
     #[error("failed to send on dispatcher")]
     SendFailed,
     #[error("failed to flush dispatcher")]
     FlushFailed,
+    #[error("failed to encode payload")]
+    EncodeFailed(#[source] Arc<dyn std::error::Error + Send + Sync>),
+    #[error("failed to compress payload")]
+    CompressFailed(#[source] Arc<io::Error>),
+    #[error("grpc call failed")]
+    GrpcFailed(#[source] Arc<tonic::Status>),
+    #[cfg(feature = "kafka")]
+    #[error("failed to publish to kafka")]
+    KafkaFailed(#[source] Arc<rdkafka::error::KafkaError>),
+    #[cfg(feature = "sqs")]
+    /// SQS refused the request, or it couldn't be signed. `transient` is set for failures on
+    /// SQS's side, which may go differently if tried again.
+    #[error("failed to send to sqs: {message}")]
+    SqsFailed { message: String, transient: bool },
+    #[error("dispatcher stopped before the payload was delivered")]
+    Abandoned,
+    #[error("dispatcher queue is full")]
+    QueueFull,
+    #[error("timed out waiting for room in the dispatcher queue")]
+    EnqueueTimedOut,
+    #[error("payload was dropped from a full dispatcher queue")]
+    Dropped,
+    #[error("failed to write payload to the journal")]
+    JournalFailed(#[source] Arc<io::Error>),
+    #[error("failed to write payload to a file")]
+    WriteFailed(#[source] Arc<io::Error>),
+    #[error("failed to read a file to upload")]
+    ReadFailed(#[source] Arc<io::Error>),
+    #[error("server rejected the auth token")]
+    Unauthorized,
+    /// The server replied with a status other than a success, along with the body it sent.
+    #[error("server replied with {status}")]
+    Rejected { status: StatusCode, body: Bytes },
+    #[error("request timed out")]
+    TimedOut,
+    #[error("payload wasn't delivered before its deadline")]
+    DeadlineExceeded,
+    /// The server asked for requests to slow down, and maybe for how long.
+    #[error("server is throttling requests")]
+    Throttled { retry_after: Option<Duration> },
+}
+
+impl From<reqwest::Error> for DispatchError {
+    fn from(e: reqwest::Error) -> Self {
+        DispatchError::PostFailed(Arc::new(e))
+    }
+}
+
+impl From<serde_json::Error> for DispatchError {
+    fn from(e: serde_json::Error) -> Self {
+        DispatchError::EncodeFailed(Arc::new(e))
+    }
+}
+
+impl From<io::Error> for DispatchError {
+    fn from(e: io::Error) -> Self {
+        DispatchError::JournalFailed(Arc::new(e))
+    }
+}
+
+/// Statuses that mean the same as one of the HTTP failures are converted to the same error, so
+/// that they are handled the same way.
+impl From<tonic::Status> for DispatchError {
+    fn from(status: tonic::Status) -> Self {
+        match status.code() {
+            tonic::Code::Unauthenticated => DispatchError::Unauthorized,
+            tonic::Code::ResourceExhausted => DispatchError::Throttled { retry_after: None },
+            tonic::Code::DeadlineExceeded => DispatchError::TimedOut,
+            _ => DispatchError::GrpcFailed(Arc::new(status)),
+        }
+    }
+}
+
+impl DispatchError {
+    /// Whether trying again could go any differently. Only failures to reach the server, or
+    /// replies saying it's struggling, are worth retrying. Deadlines can't be extended, requests
+    /// that were refused because of the request itself, such as with a 400 or most other 4xx
+    /// statuses, would be refused again, and failing to encode, read or write a payload fails the
+    /// same way every time. A rejected auth token is permanent too, since clients have already
+    /// tried again with a fresh one by the time it's reported.
+    fn retryable(&self) -> bool {
+        use tonic::Code;
+
+        match self {
+            DispatchError::PostFailed(_)
+            | DispatchError::TimedOut
+            | DispatchError::Throttled { .. } => true,
+            #[cfg(feature = "kafka")]
+            DispatchError::KafkaFailed(_) => true,
+            #[cfg(feature = "sqs")]
+            DispatchError::SqsFailed { transient, .. } => *transient,
+            DispatchError::Rejected { status, .. } => matches!(
+                *status,
+                StatusCode::REQUEST_TIMEOUT
+                    | StatusCode::INTERNAL_SERVER_ERROR
+                    | StatusCode::BAD_GATEWAY
+                    | StatusCode::SERVICE_UNAVAILABLE
+                    | StatusCode::GATEWAY_TIMEOUT
+            ),
+            DispatchError::GrpcFailed(status) => matches!(
+                status.code(),
+                Code::Unavailable | Code::Aborted | Code::Internal | Code::Unknown
+            ),
+            _ => false,
+        }
+    }
 }
 
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
+    tracing_subscriber::fmt::init();
+
     let mut headers = HeaderMap::new();
     headers.insert("key", "val".parse().unwrap());
 
     let url = std::env::var("TEST_URL").unwrap();
 
-    let client = ReqwestClient::new(headers, url.parse().unwrap());
+    // TEST_URL can list several endpoints separated by commas, with the first being the primary.
+    let mut client = if url.contains(',') {
+        let urls = url.split(',').map(|url| url.parse().unwrap()).collect();
+        ReqwestClient::with_endpoints(headers, urls, Balance::Failover)
+    } else {
+        ReqwestClient::new(headers, url.parse().unwrap())
+    };
+    // Setting CONFIG reads the Dispatcher's settings from that TOML file.
+    let config = match std::env::var("CONFIG") {
+        Ok(path) => toml::from_str(&fs::read_to_string(path)?)?,
+        Err(_) => DispatcherConfig {
+            concurrency: 5,
+            ..Default::default()
+        },
+    };
+    let mut builder = DispatcherBuilder::from_config(&config);
+
+    // Setting COMPRESS to gzip or zstd compresses larger request bodies.
+    match std::env::var("COMPRESS").as_deref() {
+        Ok("gzip") => client = client.with_compression(Compression::new(Encoding::Gzip)),
+        Ok("zstd") => client = client.with_compression(Compression::new(Encoding::Zstd)),
+        _ => {}
+    }
+
+    // Setting SIGNING_SECRET signs request bodies with it.
+    if let Ok(secret) = std::env::var("SIGNING_SECRET") {
+        client = client.with_signing(Signing::new(secret));
+    }
+
+    // Setting TOKEN_URL authorizes requests with a bearer token fetched from there, which is
+    // refreshed hourly.
+    if let Ok(token_url) = std::env::var("TOKEN_URL") {
+        let provider = RefreshingToken::new(move || {
+            let token_url = token_url.clone();
+            async move {
+                let token = reqwest::get(token_url).await?.text().await?;
+                Ok((token.trim().to_string(), Duration::from_secs(3600)))
+            }
+        });
+        client = client.with_token_provider(provider);
+    }
+
+    // Setting BATCH sends the payloads as NDJSON batches rather than one request each.
+    if std::env::var_os("BATCH").is_some() {
+        builder = builder.batch(BatchPolicy::default());
+        client = client.with_batch_format(BatchFormat::Ndjson);
+    }
+
+    // Setting RPS limits how many requests are sent per second.
+    if let Ok(rps) = std::env::var("RPS") {
+        let limiter: FixedWindow = FixedWindow::new(Duration::from_secs(1), rps.parse()?);
+        builder = builder.rate_limit(RateLimit::new(limiter));
+    }
+
+    // Setting JOURNAL keeps payloads in that file until they are delivered, so that they are
+    // posted again after a restart.
+    let journal = std::env::var_os("JOURNAL");
+    if let Some(path) = &journal {
+        builder = builder.journal(Arc::new(Journal::open(path)?));
+    }
 
-    let dispatch = Dispatcher::new(5, client, |count| println!("did it {}", count));
+    let dispatch = builder.build(client, |count, _| println!("did it {}", count))?;
+    if journal.is_some() {
+        println!("recovered {}", dispatch.recover().await?);
+    }
 
     for idx in 0..20 {
         dispatch.post(json!({ "hello": idx })).await.unwrap();
         println!("sent {}", idx);
     }
 
-    dispatch.flush().await.unwrap();
+    let reply = dispatch.post_and_wait(json!({ "hello": "last" })).await?;
+    println!("delivered last: {}", reply.status);
+
+    // Exits with an error if anything failed to be delivered.
+    dispatch.flush().await?;
+    dispatch.close().await.unwrap();
 
     Ok(())
 }
 
-#[async_trait]
-trait Client {
-    async fn post(&self, body: serde_json::Value) -> Result<(), DispatchError>;
+/// Anything the Dispatcher can deliver. Payloads are cloned for each retry.
+pub trait Payload: Serialize + Clone + Send + Sync + 'static {}
+
+impl<P> Payload for P where P: Serialize + Clone + Send + Sync + 'static {}
+
+/// A payload along with how to deliver it. The URL defaults to the client's, and the headers are
+/// added to the client's.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(
+    into = "RequestRepr<P>",
+    try_from = "RequestRepr<P>",
+    bound(
+        serialize = "P: Serialize + Clone",
+        deserialize = "P: DeserializeOwned"
+    )
+)]
+pub struct Request<P = serde_json::Value> {
+    pub method: Method,
+    pub url: Option<url::Url>,
+    pub headers: HeaderMap,
+    pub body: P,
+    /// Requests with the same partition key are delivered in order when using
+    /// [`DeliveryOrder::Partitioned`].
+    pub partition: Option<String>,
+    /// Sent in a header so the server can tell retries and redeliveries apart from new requests.
+    /// See [`Idempotency`].
+    pub idempotency_key: Option<String>,
+    /// Queued requests are taken off the queue highest priority first, and in the order they
+    /// were posted within a priority.
+    pub priority: Priority,
+}
+
+impl<P> Request<P> {
+    /// A POST of the body to the client's URL.
+    pub fn new(body: P) -> Self {
+        Request {
+            method: Method::POST,
+            url: None,
+            headers: HeaderMap::new(),
+            body,
+            partition: None,
+            idempotency_key: None,
+            priority: Priority::default(),
+        }
+    }
+
+    pub fn with_method(mut self, method: Method) -> Self {
+        self.method = method;
+        self
+    }
+
+    pub fn with_url(mut self, url: url::Url) -> Self {
+        self.url = Some(url);
+        self
+    }
+
+    pub fn with_header(mut self, name: HeaderName, value: HeaderValue) -> Self {
+        self.headers.insert(name, value);
+        self
+    }
+
+    pub fn with_partition(mut self, key: impl Into<String>) -> Self {
+        self.partition = Some(key.into());
+        self
+    }
+
+    pub fn with_idempotency_key(mut self, key: impl Into<String>) -> Self {
+        self.idempotency_key = Some(key.into());
+        self
+    }
+
+    pub fn with_priority(mut self, priority: Priority) -> Self {
+        self.priority = priority;
+        self
+    }
 }
 
-struct ReqwestClient {
-    builder: reqwest::RequestBuilder,
+/// How urgently a request should be delivered relative to others waiting in the queue.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Priority {
+    Low,
+    #[default]
+    Normal,
+    High,
 }
 
-impl ReqwestClient {
-    pub fn new(headers: HeaderMap, url: url::Url) -> Self {
-        let c = reqwest::Client::builder().build().unwrap();
+impl Priority {
+    const LEVELS: usize = 3;
 
-        ReqwestClient {
-            builder: c.post(url).headers(headers),
+    fn is_normal(&self) -> bool {
+        *self == Priority::Normal
+    }
+}
+
+/// How a request is written to a dead letter file. Header values that aren't valid UTF-8 are left
+/// out.
+#[derive(Serialize, Deserialize)]
+struct RequestRepr<P> {
+    method: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    url: Option<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    headers: Vec<(String, String)>,
+    body: P,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    partition: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    idempotency_key: Option<String>,
+    #[serde(default, skip_serializing_if = "Priority::is_normal")]
+    priority: Priority,
+}
+
+impl<P> From<Request<P>> for RequestRepr<P> {
+    fn from(request: Request<P>) -> Self {
+        let headers = request
+            .headers
+            .iter()
+            .filter_map(|(name, value)| Some((name.to_string(), value.to_str().ok()?.to_owned())))
+            .collect();
+
+        RequestRepr {
+            method: request.method.to_string(),
+            url: request.url.map(String::from),
+            headers,
+            body: request.body,
+            partition: request.partition,
+            idempotency_key: request.idempotency_key,
+            priority: request.priority,
         }
     }
 }
 
-#[async_trait]
-impl Client for ReqwestClient {
-    async fn post(&self, body: serde_json::Value) -> Result<(), DispatchError> {
-        self.builder.try_clone().unwrap().json(&body).send().await?;
-        Ok(())
+impl<P> TryFrom<RequestRepr<P>> for Request<P> {
+    type Error = String;
+
+    fn try_from(repr: RequestRepr<P>) -> Result<Self, String> {
+        let mut headers = HeaderMap::new();
+        for (name, value) in repr.headers {
+            let name = HeaderName::try_from(name).map_err(|e| e.to_string())?;
+            let value = HeaderValue::try_from(value).map_err(|e| e.to_string())?;
+            headers.append(name, value);
+        }
+
+        let url = match repr.url {
+            Some(url) => Some(url::Url::parse(&url).map_err(|e| e.to_string())?),
+            None => None,
+        };
+
+        Ok(Request {
+            method: repr.method.parse().map_err(|_| "invalid method")?,
+            url,
+            headers,
+            body: repr.body,
+            partition: repr.partition,
+            idempotency_key: repr.idempotency_key,
+            priority: repr.priority,
+        })
     }
 }
 
-struct Dispatcher {
-    tx: mpsc::Sender<serde_json::Value>,
-    consumer: tokio::task::JoinHandle<()>,
+/// What the server replied to a request that was delivered.
+#[derive(Debug, Clone, Default)]
+pub struct Reply {
+    pub status: StatusCode,
+    pub headers: HeaderMap,
+    pub body: Bytes,
 }
 
-impl Dispatcher {
-    pub fn new<T, F>(concurrency: usize, client: T, success: F) -> Self
+impl Reply {
+    /// Reads the body as JSON, e.g. to get an ID the server assigned.
+    pub fn json<T: DeserializeOwned>(&self) -> Result<T, serde_json::Error> {
+        serde_json::from_slice(&self.body)
+    }
+}
+
+#[async_trait]
+pub trait Client<P: Payload = serde_json::Value> {
+    async fn post(&self, request: Request<P>) -> Result<Reply, DispatchError>;
+
+    /// Posts a batch of requests in a single request, returning a reply for each request or a
+    /// single reply for the whole batch. By default the requests are posted one at a time, so
+    /// clients that can send a batch at once should override this.
+    async fn post_batch(&self, batch: Vec<Request<P>>) -> Result<Vec<Reply>, DispatchError>
     where
-        T: Client + Send + Sync + 'static,
-        F: Fn(usize) + Send + Sync + 'static,
+        Self: Sync,
     {
-        let (tx_body, rx_body): (
-            mpsc::Sender<serde_json::Value>,
-            mpsc::Receiver<serde_json::Value>,
-        ) = mpsc::channel(1);
+        let mut replies = Vec::with_capacity(batch.len());
+        for request in batch {
+            replies.push(self.post(request).await?);
+        }
+        Ok(replies)
+    }
+}
 
-        let consumer = tokio::spawn(Self::new_consumer(concurrency, rx_body, client, success));
+/// How request bodies are encoded, and the Content-Type they're sent with.
+pub trait BodyFormat<P> {
+    fn content_type(&self) -> &'static str;
 
-        Dispatcher {
-            tx: tx_body,
-            consumer,
-        }
+    fn encode(&self, body: &P) -> Result<Vec<u8>, DispatchError>;
+
+    /// Encodes the bodies of a batch as a single body.
+    fn encode_batch(&self, bodies: &[&P]) -> Result<Vec<u8>, DispatchError>;
+}
+
+fn encode_failed(e: impl std::error::Error + Send + Sync + 'static) -> DispatchError {
+    DispatchError::EncodeFailed(Arc::new(e))
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Json;
+
+impl<P: Serialize> BodyFormat<P> for Json {
+    fn content_type(&self) -> &'static str {
+        "application/json"
     }
 
-    async fn new_consumer<T, F>(
-        concurrency: usize,
-        rx: mpsc::Receiver<serde_json::Value>,
-        client: T,
-        success: F,
-    ) where
-        T: Client + Send + Sync + 'static,
-        F: Fn(usize),
-    {
-        let stream = tokio_stream::wrappers::ReceiverStream::new(rx)
-            .map(|val| client.post(val))
-            .buffer_unordered(concurrency);
+    fn encode(&self, body: &P) -> Result<Vec<u8>, DispatchError> {
+        Ok(serde_json::to_vec(body)?)
+    }
+
+    fn encode_batch(&self, bodies: &[&P]) -> Result<Vec<u8>, DispatchError> {
+        Ok(serde_json::to_vec(bodies)?)
+    }
+}
 
-        futures::pin_mut!(stream);
+/// Encodes bodies as MessagePack maps, keeping field names.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct MessagePack;
 
-        let mut count = 0;
-        while let Some(res) = stream.next().await {
-            match res {
-                Ok(_) => {
-                    success(count);
-                    count += 1;
+impl<P: Serialize> BodyFormat<P> for MessagePack {
+    fn content_type(&self) -> &'static str {
+        "application/msgpack"
+    }
+
+    fn encode(&self, body: &P) -> Result<Vec<u8>, DispatchError> {
+        rmp_serde::to_vec_named(body).map_err(encode_failed)
+    }
+
+    fn encode_batch(&self, bodies: &[&P]) -> Result<Vec<u8>, DispatchError> {
+        rmp_serde::to_vec_named(bodies).map_err(encode_failed)
+    }
+}
+
+/// Encodes bodies as `application/x-www-form-urlencoded`, so payloads need to be flat. Batches
+/// can't be form encoded.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Form;
+
+impl<P: Serialize> BodyFormat<P> for Form {
+    fn content_type(&self) -> &'static str {
+        "application/x-www-form-urlencoded"
+    }
+
+    fn encode(&self, body: &P) -> Result<Vec<u8>, DispatchError> {
+        let body = serde_urlencoded::to_string(body).map_err(encode_failed)?;
+        Ok(body.into_bytes())
+    }
+
+    fn encode_batch(&self, _: &[&P]) -> Result<Vec<u8>, DispatchError> {
+        let unsupported =
+            io::Error::new(io::ErrorKind::Unsupported, "batches can't be form encoded");
+        Err(encode_failed(unsupported))
+    }
+}
+
+/// Encodes protobuf messages, with batches being length-delimited messages one after another.
+/// Payloads still need to be `Serialize` for the Dispatcher, which prost can be told to derive.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Protobuf;
+
+impl<P: prost::Message> BodyFormat<P> for Protobuf {
+    fn content_type(&self) -> &'static str {
+        "application/x-protobuf"
+    }
+
+    fn encode(&self, body: &P) -> Result<Vec<u8>, DispatchError> {
+        Ok(body.encode_to_vec())
+    }
+
+    fn encode_batch(&self, bodies: &[&P]) -> Result<Vec<u8>, DispatchError> {
+        Ok(bodies
+            .iter()
+            .flat_map(|body| body.encode_length_delimited_to_vec())
+            .collect())
+    }
+}
+
+/// A `multipart/form-data` payload, such as for uploading files. File parts only hold the path,
+/// and are streamed from disk each time the form is sent, so large files aren't read into memory
+/// and forms can be journaled. Forms are sent with a `ReqwestClient<Multipart>`.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MultipartForm {
+    pub parts: Vec<FormPart>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FormPart {
+    pub name: String,
+    pub content: PartContent,
+    /// The part's Content-Type, if it should have one.
+    pub content_type: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum PartContent {
+    Text(String),
+    /// A file that's sent with its file name.
+    File(PathBuf),
+}
+
+impl MultipartForm {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn text(self, name: impl Into<String>, value: impl Into<String>) -> Self {
+        self.part(name, PartContent::Text(value.into()), None)
+    }
+
+    pub fn file(self, name: impl Into<String>, path: impl Into<PathBuf>) -> Self {
+        self.part(name, PartContent::File(path.into()), None)
+    }
+
+    pub fn file_with_type(
+        self,
+        name: impl Into<String>,
+        path: impl Into<PathBuf>,
+        content_type: impl Into<String>,
+    ) -> Self {
+        let content_type = Some(content_type.into());
+        self.part(name, PartContent::File(path.into()), content_type)
+    }
+
+    fn part(
+        mut self,
+        name: impl Into<String>,
+        content: PartContent,
+        content_type: Option<String>,
+    ) -> Self {
+        self.parts.push(FormPart {
+            name: name.into(),
+            content,
+            content_type,
+        });
+        self
+    }
+
+    /// Checks the content types and finds the size of each file, so that sending the form can't
+    /// fail on anything but the files changing in between.
+    fn prepare(&self) -> Result<Vec<PreparedPart<'_>>, DispatchError> {
+        self.parts
+            .iter()
+            .map(|part| {
+                let mut headers = HeaderMap::new();
+                if let Some(content_type) = &part.content_type {
+                    let value = HeaderValue::try_from(content_type).map_err(encode_failed)?;
+                    headers.insert(CONTENT_TYPE, value);
+                }
+
+                let length = match &part.content {
+                    PartContent::Text(_) => 0,
+                    PartContent::File(path) => fs::metadata(path)
+                        .map_err(|e| DispatchError::ReadFailed(Arc::new(e)))?
+                        .len(),
+                };
+
+                Ok(PreparedPart {
+                    part,
+                    headers,
+                    length,
+                })
+            })
+            .collect()
+    }
+}
+
+struct PreparedPart<'a> {
+    part: &'a FormPart,
+    headers: HeaderMap,
+    /// The size of a file part.
+    length: u64,
+}
+
+impl PreparedPart<'_> {
+    fn to_part(&self) -> reqwest::multipart::Part {
+        let part = match &self.part.content {
+            PartContent::Text(value) => reqwest::multipart::Part::text(value.clone()),
+            PartContent::File(path) => {
+                // The file isn't opened until the body is sent.
+                let open = tokio::fs::File::open(path.clone());
+                let stream = futures::stream::once(open)
+                    .map_ok(tokio_util::io::ReaderStream::new)
+                    .try_flatten();
+                let body = reqwest::Body::wrap_stream(stream);
+                let part = reqwest::multipart::Part::stream_with_length(body, self.length);
+                match path.file_name() {
+                    Some(name) => part.file_name(name.to_string_lossy().into_owned()),
+                    None => part,
                 }
-                Err(e) => println!("had error: {}", e),
             }
+        };
+        part.headers(self.headers.clone())
+    }
+}
+
+/// Sends `MultipartForm` payloads as `multipart/form-data`. The body is streamed rather than
+/// built up front, so it isn't compressed or signed, and forms aren't batched.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Multipart;
+
+/// How a batch of payloads is encoded into a single request body.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum BatchFormat {
+    /// An array of the payloads, encoded with the client's body format.
+    #[default]
+    JsonArray,
+    /// One JSON payload per line, sent as `application/x-ndjson` whatever the body format.
+    Ndjson,
+    /// Like `Ndjson`, but each line is encoded as it's sent rather than building the whole body
+    /// first, for batches too large to hold twice in memory. Since the body isn't known up front,
+    /// it's neither compressed nor signed.
+    StreamingNdjson,
+}
+
+/// The Content-Encoding that request bodies are compressed with.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Encoding {
+    Gzip,
+    Zstd,
+}
+
+impl Encoding {
+    fn header_value(self) -> HeaderValue {
+        match self {
+            Encoding::Gzip => HeaderValue::from_static("gzip"),
+            Encoding::Zstd => HeaderValue::from_static("zstd"),
         }
     }
+}
 
-    async fn post(&self, body: serde_json::Value) -> Result<(), DispatchError> {
-        self.tx
-            .send(body)
-            .await
-            .map_err(|_| DispatchError::SendFailed)?;
+/// Compresses request bodies of at least `min_bytes`. Smaller bodies aren't worth it, and are sent
+/// as they are.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Compression {
+    pub encoding: Encoding,
+    pub min_bytes: usize,
+}
 
-        Ok(())
+impl Compression {
+    pub fn new(encoding: Encoding) -> Self {
+        Compression {
+            encoding,
+            min_bytes: 1024,
+        }
     }
 
-    async fn flush(self) -> Result<(), DispatchError> {
-        drop(self.tx);
-        self.consumer
-            .await
-            .map_err(|_| DispatchError::FlushFailed)?;
+    fn compress(&self, body: &[u8]) -> io::Result<Vec<u8>> {
+        match self.encoding {
+            Encoding::Gzip => {
+                let mut encoder =
+                    flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+                encoder.write_all(body)?;
+                encoder.finish()
+            }
+            Encoding::Zstd => zstd::encode_all(body, zstd::DEFAULT_COMPRESSION_LEVEL),
+        }
+    }
+}
 
-        Ok(())
+/// Signs request bodies with HMAC-SHA256 using a shared secret, so that receivers can check that
+/// requests came from us. The signature covers the body as it's sent, after any compression.
+#[derive(Clone)]
+pub struct Signing {
+    secret: Vec<u8>,
+    header: HeaderName,
+    format: SignatureFormat,
+}
+
+/// How the signature is written in its header.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum SignatureFormat {
+    /// `t=<unix seconds>,v1=<hex signature>`, signing `<unix seconds>.<body>`, so that receivers
+    /// can reject old requests that are replayed.
+    #[default]
+    Timestamped,
+    /// `sha256=<hex signature>`, signing just the body.
+    Body,
+}
+
+impl Signing {
+    /// Signs with `secret` in an `x-signature` header with a timestamp.
+    pub fn new(secret: impl Into<Vec<u8>>) -> Self {
+        Signing {
+            secret: secret.into(),
+            header: HeaderName::from_static("x-signature"),
+            format: SignatureFormat::default(),
+        }
+    }
+
+    pub fn with_header(mut self, header: HeaderName) -> Self {
+        self.header = header;
+        self
+    }
+
+    pub fn with_format(mut self, format: SignatureFormat) -> Self {
+        self.format = format;
+        self
+    }
+
+    fn sign(&self, body: &[u8]) -> HeaderValue {
+        use hmac::Mac;
+
+        let mut mac = hmac::Hmac::<Sha256>::new_from_slice(&self.secret)
+            .expect("HMAC takes keys of any length");
+        let value = match self.format {
+            SignatureFormat::Timestamped => {
+                let timestamp = std::time::SystemTime::now()
+                    .duration_since(std::time::UNIX_EPOCH)
+                    .unwrap_or_default()
+                    .as_secs();
+                mac.update(format!("{}.", timestamp).as_bytes());
+                mac.update(body);
+                format!("t={},v1={}", timestamp, hex(&mac.finalize().into_bytes()))
+            }
+            SignatureFormat::Body => {
+                mac.update(body);
+                format!("sha256={}", hex(&mac.finalize().into_bytes()))
+            }
+        };
+
+        HeaderValue::try_from(value).expect("signatures are valid header values")
     }
 }
 
-#[cfg(test)]
-mod tests {
-    use std::{
-        cell::RefCell,
-        sync::{Arc, Mutex},
-    };
+impl fmt::Debug for Signing {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Signing")
+            .field("header", &self.header)
+            .field("format", &self.format)
+            .finish_non_exhaustive()
+    }
+}
 
-    use super::*;
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
 
-    struct MockClient {
-        calls: Arc<Mutex<RefCell<Vec<serde_json::Value>>>>,
+/// How a client with several endpoints picks which one to send to.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Balance {
+    /// Takes turns between the endpoints.
+    #[default]
+    RoundRobin,
+    /// Sends to the first endpoint, and only to the others while it's unhealthy.
+    Failover,
+}
+
+/// How long an endpoint is avoided after a request to it fails.
+const UNHEALTHY_FOR: Duration = Duration::from_secs(10);
+
+/// An endpoint is unhealthy for a while after failing to connect or returning a server error.
+struct Endpoint {
+    url: url::Url,
+    unhealthy_until: Mutex<Option<tokio::time::Instant>>,
+}
+
+impl Endpoint {
+    fn new(url: url::Url) -> Self {
+        Endpoint {
+            url,
+            unhealthy_until: Mutex::new(None),
+        }
     }
 
-    #[async_trait]
-    impl Client for MockClient {
-        async fn post(&self, body: serde_json::Value) -> Result<(), DispatchError> {
-            self.calls.lock().unwrap().borrow_mut().push(body);
-            Ok(())
+    fn healthy(&self) -> bool {
+        let until = *self
+            .unhealthy_until
+            .lock()
+            .unwrap_or_else(|e| e.into_inner());
+        until.is_none_or(|until| until <= tokio::time::Instant::now())
+    }
+
+    fn record(&self, succeeded: bool) {
+        let until = (!succeeded).then(|| tokio::time::Instant::now() + UNHEALTHY_FOR);
+        *self
+            .unhealthy_until
+            .lock()
+            .unwrap_or_else(|e| e.into_inner()) = until;
+    }
+}
+
+/// Sends requests to its endpoints with `headers`, unless a request has its own URL. A request that
+/// fails to reach an endpoint, or gets a server error, is sent to the next one, healthy endpoints
+/// first. Bodies are encoded with `F`, which is JSON by default.
+pub struct ReqwestClient<F = Json> {
+    client: reqwest::Client,
+    endpoints: Vec<Endpoint>,
+    balance: Balance,
+    next: AtomicUsize,
+    headers: HeaderMap,
+    batch_format: BatchFormat,
+    compression: Option<Compression>,
+    signing: Option<Signing>,
+    token_provider: Option<Arc<dyn TokenProvider>>,
+    format: F,
+}
+
+impl ReqwestClient {
+    pub fn new(headers: HeaderMap, url: url::Url) -> Self {
+        Self::with_endpoints(headers, vec![url], Balance::default())
+    }
+
+    pub fn with_endpoints(headers: HeaderMap, urls: Vec<url::Url>, balance: Balance) -> Self {
+        assert!(!urls.is_empty(), "a client needs at least one endpoint");
+        let client = reqwest::Client::builder().build().unwrap();
+
+        ReqwestClient {
+            client,
+            endpoints: urls.into_iter().map(Endpoint::new).collect(),
+            balance,
+            next: AtomicUsize::new(0),
+            headers,
+            batch_format: BatchFormat::default(),
+            compression: None,
+            signing: None,
+            token_provider: None,
+            format: Json,
         }
     }
+}
 
-    #[tokio::test]
-    async fn test_dispatcher() {
-        let calls = Arc::new(Mutex::new(RefCell::new(Vec::new())));
+impl<F> ReqwestClient<F> {
+    pub fn with_format<G>(self, format: G) -> ReqwestClient<G> {
+        ReqwestClient {
+            client: self.client,
+            endpoints: self.endpoints,
+            balance: self.balance,
+            next: self.next,
+            headers: self.headers,
+            batch_format: self.batch_format,
+            compression: self.compression,
+            signing: self.signing,
+            token_provider: self.token_provider,
+            format,
+        }
+    }
 
-        let client = MockClient {
-            calls: calls.clone(),
+    pub fn with_batch_format(mut self, format: BatchFormat) -> Self {
+        self.batch_format = format;
+        self
+    }
+
+    pub fn with_compression(mut self, compression: Compression) -> Self {
+        self.compression = Some(compression);
+        self
+    }
+
+    pub fn with_signing(mut self, signing: Signing) -> Self {
+        self.signing = Some(signing);
+        self
+    }
+
+    /// Sends each request with a bearer token from `provider`.
+    pub fn with_token_provider(mut self, provider: impl TokenProvider + 'static) -> Self {
+        self.token_provider = Some(Arc::new(provider));
+        self
+    }
+
+    /// Compresses an encoded body if it's large enough and signs it, returning it with the headers
+    /// describing it.
+    fn body(
+        &self,
+        content_type: &'static str,
+        body: Vec<u8>,
+    ) -> Result<(HeaderMap, Bytes), DispatchError> {
+        let mut headers = HeaderMap::new();
+        headers.insert(CONTENT_TYPE, HeaderValue::from_static(content_type));
+
+        let body = match self.compression {
+            Some(compression) if body.len() >= compression.min_bytes => {
+                headers.insert(CONTENT_ENCODING, compression.encoding.header_value());
+                compression
+                    .compress(&body)
+                    .map_err(|e| DispatchError::CompressFailed(Arc::new(e)))?
+            }
+            _ => body,
         };
-        let dispatch = Dispatcher::new(3, client, |_| {});
 
-        let mut want_calls = vec![];
+        if let Some(signing) = &self.signing {
+            headers.insert(signing.header.clone(), signing.sign(&body));
+        }
 
-        for idx in 0..20 {
-            let body = serde_json::json!({ "count": idx });
-            dispatch.post(body.clone()).await.unwrap();
-            want_calls.push(body);
+        Ok((headers, body.into()))
+    }
+
+    /// The endpoints in the order they should be tried.
+    fn endpoints(&self) -> Vec<&Endpoint> {
+        let start = match self.balance {
+            Balance::RoundRobin => self.next.fetch_add(1, Ordering::Relaxed),
+            Balance::Failover => 0,
+        };
+
+        let count = self.endpoints.len();
+        let mut endpoints: Vec<_> = (0..count)
+            .map(|idx| &self.endpoints[(start + idx) % count])
+            .collect();
+        // Unhealthy endpoints are still tried once the healthy ones have failed.
+        endpoints.sort_by_key(|endpoint| !endpoint.healthy());
+        endpoints
+    }
+
+    /// Sends a request to `url`, or to the endpoints in turn until one of them takes it. `build`
+    /// adds the body and any other headers, and is called again for every request that's sent, so
+    /// that bodies that can only be read once, such as streams, are never reused.
+    async fn send<B>(
+        &self,
+        method: Method,
+        url: Option<url::Url>,
+        build: B,
+    ) -> Result<Reply, DispatchError>
+    where
+        B: Fn(reqwest::RequestBuilder) -> reqwest::RequestBuilder + Sync,
+    {
+        if let Some(url) = url {
+            let response = self.execute(&method, url, &build).await??;
+            return reply(response).await;
         }
 
-        dispatch.flush().await.unwrap();
+        let mut last = None;
+        for endpoint in self.endpoints() {
+            let res = self.execute(&method, endpoint.url.clone(), &build).await?;
 
-        assert_eq!(want_calls, calls.lock().unwrap().clone().into_inner());
+            let failed = match &res {
+                Ok(response) => response.status().is_server_error(),
+                Err(_) => true,
+            };
+            endpoint.record(!failed);
+            if !failed {
+                return reply(res?).await;
+            }
+            last = Some(res);
+        }
+
+        reply(last.expect("a client has at least one endpoint")?).await
+    }
+
+    /// Sends a request to a single URL with the current auth token. A request that's rejected as
+    /// unauthorized is sent once more with a fresh token, since the token may have expired early
+    /// or been revoked. Only failing to get a token or to build the request is an error here, so
+    /// that the endpoint isn't blamed for it.
+    async fn execute<B>(
+        &self,
+        method: &Method,
+        url: url::Url,
+        build: &B,
+    ) -> Result<reqwest::Result<Response>, DispatchError>
+    where
+        B: Fn(reqwest::RequestBuilder) -> reqwest::RequestBuilder + Sync,
+    {
+        let request = |token: Option<&str>| {
+            let mut builder = build(self.client.request(method.clone(), url.clone()))
+                .headers(self.headers.clone());
+            if let Some(token) = token {
+                builder = builder.bearer_auth(token);
+            }
+            builder
+                .build()
+                .map_err(|e| DispatchError::BuildFailed(Arc::new(e)))
+        };
+
+        let Some(provider) = &self.token_provider else {
+            return Ok(self.client.execute(request(None)?).await);
+        };
+
+        let token = provider.token().await?;
+        let response = match self.client.execute(request(Some(&token))?).await {
+            Ok(response) if response.status() == StatusCode::UNAUTHORIZED => response,
+            res => return Ok(res),
+        };
+        drop(response);
+
+        provider.invalidate(&token).await;
+        let token = provider.token().await?;
+        Ok(self.client.execute(request(Some(&token))?).await)
+    }
+}
+
+/// Supplies the bearer tokens that requests are authorized with.
+#[async_trait]
+pub trait TokenProvider: Send + Sync {
+    /// The token to send requests with, fetching a new one if needed.
+    async fn token(&self) -> Result<String, DispatchError>;
+
+    /// Called when the server rejects `token`, so that the next call to `token` gets a new one.
+    async fn invalidate(&self, token: &str);
+}
+
+/// Fetches tokens with `fetch`, which returns the token and how long it's valid for. A token is
+/// reused until nine tenths of that time have passed, then refreshed by the next request that
+/// needs it. Concurrent requests wait for the same refresh.
+pub struct RefreshingToken<F> {
+    fetch: F,
+    current: tokio::sync::Mutex<Option<(String, tokio::time::Instant)>>,
+}
+
+impl<F> RefreshingToken<F> {
+    pub fn new(fetch: F) -> Self {
+        RefreshingToken {
+            fetch,
+            current: tokio::sync::Mutex::new(None),
+        }
+    }
+}
+
+#[async_trait]
+impl<F, Fut> TokenProvider for RefreshingToken<F>
+where
+    F: Fn() -> Fut + Send + Sync,
+    Fut: Future<Output = Result<(String, Duration), DispatchError>> + Send,
+{
+    async fn token(&self) -> Result<String, DispatchError> {
+        let mut current = self.current.lock().await;
+
+        if let Some((token, refresh_at)) = &*current {
+            if tokio::time::Instant::now() < *refresh_at {
+                return Ok(token.clone());
+            }
+        }
+
+        let (token, valid_for) = (self.fetch)().await?;
+        let refresh_at = tokio::time::Instant::now() + valid_for.mul_f64(0.9);
+        *current = Some((token.clone(), refresh_at));
+
+        Ok(token)
+    }
+
+    async fn invalidate(&self, token: &str) {
+        let mut current = self.current.lock().await;
+
+        // Another request may have already replaced it.
+        if matches!(&*current, Some((current, _)) if current == token) {
+            *current = None;
+        }
+    }
+}
+
+#[async_trait]
+impl<P, F> Client<P> for ReqwestClient<F>
+where
+    P: Payload,
+    F: BodyFormat<P> + Send + Sync,
+{
+    async fn post(&self, request: Request<P>) -> Result<Reply, DispatchError> {
+        let body = self.format.encode(&request.body)?;
+        let (headers, body) = self.body(self.format.content_type(), body)?;
+        let build = |builder: reqwest::RequestBuilder| {
+            builder
+                .headers(request.headers.clone())
+                .headers(headers.clone())
+                .body(body.clone())
+        };
+        self.send(request.method.clone(), request.url.clone(), build)
+            .await
+    }
+
+    /// Batches are always POSTed to the client's endpoints with its headers, so the method, URL
+    /// and headers of the requests in them are ignored.
+    async fn post_batch(&self, batch: Vec<Request<P>>) -> Result<Vec<Reply>, DispatchError> {
+        if self.batch_format == BatchFormat::StreamingNdjson {
+            let bodies: Arc<[P]> = batch.into_iter().map(|request| request.body).collect();
+            let build = |builder: reqwest::RequestBuilder| {
+                let bodies = bodies.clone();
+                let lines = futures::stream::iter(0..bodies.len()).map(move |idx| {
+                    let mut line = serde_json::to_vec(&bodies[idx])?;
+                    line.push(b'\n');
+                    Ok::<_, serde_json::Error>(line)
+                });
+                builder
+                    .header(CONTENT_TYPE, "application/x-ndjson")
+                    .body(reqwest::Body::wrap_stream(lines))
+            };
+            return Ok(vec![self.send(Method::POST, None, build).await?]);
+        }
+
+        let bodies: Vec<_> = batch.iter().map(|request| &request.body).collect();
+
+        let (headers, body) = match self.batch_format {
+            BatchFormat::JsonArray => {
+                let body = self.format.encode_batch(&bodies)?;
+                self.body(self.format.content_type(), body)?
+            }
+            BatchFormat::Ndjson | BatchFormat::StreamingNdjson => {
+                let mut body = Vec::new();
+                for payload in bodies {
+                    serde_json::to_writer(&mut body, payload)?;
+                    body.push(b'\n');
+                }
+                self.body("application/x-ndjson", body)?
+            }
+        };
+
+        let build =
+            |builder: reqwest::RequestBuilder| builder.headers(headers.clone()).body(body.clone());
+        Ok(vec![self.send(Method::POST, None, build).await?])
+    }
+}
+
+#[async_trait]
+impl Client<MultipartForm> for ReqwestClient<Multipart> {
+    async fn post(&self, request: Request<MultipartForm>) -> Result<Reply, DispatchError> {
+        let parts = request.body.prepare()?;
+        let build = |builder: reqwest::RequestBuilder| {
+            let form = parts
+                .iter()
+                .fold(reqwest::multipart::Form::new(), |form, part| {
+                    form.part(part.part.name.clone(), part.to_part())
+                });
+            builder.headers(request.headers.clone()).multipart(form)
+        };
+        self.send(request.method.clone(), request.url.clone(), build)
+            .await
+    }
+}
+
+/// Reads the server's reply, failing with `DispatchError::Rejected` unless its status is a success.
+async fn reply(response: Response) -> Result<Reply, DispatchError> {
+    check_status(&response)?;
+
+    let status = response.status();
+    let headers = response.headers().clone();
+    let body = response.bytes().await?;
+    if !status.is_success() {
+        return Err(DispatchError::Rejected { status, body });
+    }
+
+    Ok(Reply {
+        status,
+        headers,
+        body,
+    })
+}
+
+/// Fails with `DispatchError::Throttled` for a 429, or a 503 that says when to retry, and with
+/// `DispatchError::Unauthorized` for a 401. Only Retry-After headers giving a number of seconds are
+/// understood.
+fn check_status(response: &Response) -> Result<(), DispatchError> {
+    let retry_after = response
+        .headers()
+        .get(RETRY_AFTER)
+        .and_then(|value| value.to_str().ok()?.trim().parse().ok())
+        .map(Duration::from_secs);
+
+    match response.status() {
+        StatusCode::UNAUTHORIZED => Err(DispatchError::Unauthorized),
+        StatusCode::TOO_MANY_REQUESTS => Err(DispatchError::Throttled { retry_after }),
+        StatusCode::SERVICE_UNAVAILABLE if retry_after.is_some() => {
+            Err(DispatchError::Throttled { retry_after })
+        }
+        _ => Ok(()),
+    }
+}
+
+/// Publishes payloads as messages on a Kafka topic, so that the Dispatcher can deliver to Kafka
+/// the same way it does over HTTP.
+#[cfg(feature = "kafka")]
+pub struct KafkaClient<F = Json> {
+    producer: rdkafka::producer::FutureProducer,
+    topic: String,
+    format: F,
+    queue_timeout: Duration,
+}
+
+#[cfg(feature = "kafka")]
+impl KafkaClient {
+    /// Publishes to `topic` through the brokers in a comma-separated list.
+    pub fn new(
+        brokers: &str,
+        topic: impl Into<String>,
+    ) -> Result<Self, rdkafka::error::KafkaError> {
+        let producer = rdkafka::ClientConfig::new()
+            .set("bootstrap.servers", brokers)
+            .create()?;
+        Ok(Self::with_producer(producer, topic))
+    }
+
+    /// Publishes to `topic` with a producer that has already been configured.
+    pub fn with_producer(
+        producer: rdkafka::producer::FutureProducer,
+        topic: impl Into<String>,
+    ) -> Self {
+        KafkaClient {
+            producer,
+            topic: topic.into(),
+            format: Json,
+            queue_timeout: Duration::from_secs(5),
+        }
+    }
+}
+
+#[cfg(feature = "kafka")]
+impl<F> KafkaClient<F> {
+    /// Encodes message payloads with a different format than JSON.
+    pub fn with_format<G>(self, format: G) -> KafkaClient<G> {
+        KafkaClient {
+            producer: self.producer,
+            topic: self.topic,
+            format,
+            queue_timeout: self.queue_timeout,
+        }
+    }
+
+    /// How long publishing waits for room in the producer's local queue before failing.
+    pub fn with_queue_timeout(mut self, timeout: Duration) -> Self {
+        self.queue_timeout = timeout;
+        self
+    }
+}
+
+#[cfg(feature = "kafka")]
+#[async_trait]
+impl<P, F> Client<P> for KafkaClient<F>
+where
+    P: Payload,
+    F: BodyFormat<P> + Send + Sync,
+{
+    /// Messages are keyed by the request's partition key, and carry its headers along with the
+    /// Content-Type. The request's method and URL are ignored. The reply says where the message
+    /// was written in its `kafka-partition` and `kafka-offset` headers.
+    async fn post(&self, request: Request<P>) -> Result<Reply, DispatchError> {
+        use rdkafka::message::{Header, OwnedHeaders};
+        use rdkafka::producer::FutureRecord;
+
+        let payload = self.format.encode(&request.body)?;
+
+        let mut headers = OwnedHeaders::new().insert(Header {
+            key: CONTENT_TYPE.as_str(),
+            value: Some(self.format.content_type()),
+        });
+        for (name, value) in &request.headers {
+            headers = headers.insert(Header {
+                key: name.as_str(),
+                value: Some(value.as_bytes()),
+            });
+        }
+
+        let mut record = FutureRecord::<str, _>::to(&self.topic)
+            .payload(&payload)
+            .headers(headers);
+        if let Some(key) = &request.partition {
+            record = record.key(key.as_str());
+        }
+
+        let (partition, offset) = self
+            .producer
+            .send(record, self.queue_timeout)
+            .await
+            .map_err(|(e, _)| DispatchError::KafkaFailed(Arc::new(e)))?;
+
+        let mut reply = Reply::default();
+        let headers = &mut reply.headers;
+        headers.insert(HeaderName::from_static("kafka-partition"), partition.into());
+        headers.insert(HeaderName::from_static("kafka-offset"), offset.into());
+        Ok(reply)
+    }
+}
+
+/// The most messages SQS accepts in a single SendMessageBatch call.
+#[cfg(feature = "sqs")]
+const SQS_BATCH_LIMIT: usize = 10;
+
+/// How many times the messages SQS failed or didn't report on in a SendMessageBatch call are sent
+/// before the batch fails.
+#[cfg(feature = "sqs")]
+const SQS_BATCH_ATTEMPTS: usize = 3;
+
+/// Sends payloads as messages to an SQS queue, calling the SQS API with requests signed the same
+/// way the AWS SDKs sign them.
+///
+/// Message bodies are the JSON payloads, and request headers become string message attributes.
+/// On FIFO queues, the partition key is used as the message group ID and the idempotency key as
+/// the deduplication ID.
+#[cfg(feature = "sqs")]
+pub struct SqsClient {
+    client: reqwest::Client,
+    queue_url: url::Url,
+    endpoint: url::Url,
+    region: String,
+    provider: aws_credential_types::provider::SharedCredentialsProvider,
+    credentials: tokio::sync::Mutex<Option<aws_credential_types::Credentials>>,
+}
+
+#[cfg(feature = "sqs")]
+impl SqsClient {
+    /// Sends to the queue at `queue_url`, finding credentials and the region the same way the AWS
+    /// SDKs do, such as from the environment or a profile. Without a configured region, the one
+    /// in the queue's URL is used.
+    pub async fn new(queue_url: url::Url) -> Result<Self, DispatchError> {
+        let config = aws_config::load_defaults(aws_config::BehaviorVersion::latest()).await;
+
+        let region = config
+            .region()
+            .map(ToString::to_string)
+            .or_else(|| {
+                let host = queue_url.host_str()?;
+                Some(host.strip_prefix("sqs.")?.split('.').next()?.to_string())
+            })
+            .ok_or_else(|| sqs_failed("no region is configured", false))?;
+        let provider = config
+            .credentials_provider()
+            .ok_or_else(|| sqs_failed("no credentials are configured", false))?;
+
+        Ok(Self::with_credentials(queue_url, region, provider))
+    }
+
+    pub fn with_credentials(
+        queue_url: url::Url,
+        region: impl Into<String>,
+        provider: impl aws_credential_types::provider::ProvideCredentials + 'static,
+    ) -> Self {
+        let mut endpoint = queue_url.clone();
+        endpoint.set_path("/");
+
+        SqsClient {
+            client: reqwest::Client::new(),
+            queue_url,
+            endpoint,
+            region: region.into(),
+            provider: aws_credential_types::provider::SharedCredentialsProvider::new(provider),
+            credentials: tokio::sync::Mutex::new(None),
+        }
+    }
+
+    /// The current credentials, which are fetched again when they are about to expire.
+    async fn credentials(&self) -> Result<aws_credential_types::Credentials, DispatchError> {
+        use aws_credential_types::provider::ProvideCredentials;
+
+        let mut credentials = self.credentials.lock().await;
+        let refresh_at = std::time::SystemTime::now() + Duration::from_secs(300);
+        match &*credentials {
+            Some(current) if current.expiry().is_none_or(|expiry| expiry > refresh_at) => {
+                Ok(current.clone())
+            }
+            _ => {
+                let fresh = self
+                    .provider
+                    .provide_credentials()
+                    .await
+                    .map_err(|e| sqs_failed(e, true))?;
+                *credentials = Some(fresh.clone());
+                Ok(fresh)
+            }
+        }
+    }
+
+    fn message<P: Serialize>(
+        &self,
+        request: &Request<P>,
+    ) -> Result<serde_json::Map<String, serde_json::Value>, DispatchError> {
+        let mut message = serde_json::Map::new();
+        message.insert(
+            "MessageBody".into(),
+            serde_json::to_string(&request.body)?.into(),
+        );
+
+        let attributes: serde_json::Map<_, _> = request
+            .headers
+            .iter()
+            .filter_map(|(name, value)| {
+                let value = json!({ "DataType": "String", "StringValue": value.to_str().ok()? });
+                Some((name.to_string(), value))
+            })
+            .collect();
+        if !attributes.is_empty() {
+            message.insert("MessageAttributes".into(), attributes.into());
+        }
+
+        if self.queue_url.path().ends_with(".fifo") {
+            if let Some(group) = &request.partition {
+                message.insert("MessageGroupId".into(), group.clone().into());
+            }
+            if let Some(key) = &request.idempotency_key {
+                message.insert("MessageDeduplicationId".into(), key.clone().into());
+            }
+        }
+
+        Ok(message)
+    }
+
+    /// Calls an SQS action, failing with `DispatchError::Throttled` if SQS is throttling requests.
+    async fn call(&self, action: &str, body: serde_json::Value) -> Result<Reply, DispatchError> {
+        use aws_sigv4::http_request::{sign, SignableBody, SignableRequest, SigningSettings};
+
+        let body = serde_json::to_vec(&body)?;
+        let target = format!("AmazonSQS.{}", action);
+        let headers = [
+            ("content-type", "application/x-amz-json-1.0"),
+            ("x-amz-target", target.as_str()),
+        ];
+
+        let identity = self.credentials().await?.into();
+        let params = aws_sigv4::sign::v4::SigningParams::builder()
+            .identity(&identity)
+            .region(&self.region)
+            .name("sqs")
+            .time(std::time::SystemTime::now())
+            .settings(SigningSettings::default())
+            .build()
+            .map_err(|e| sqs_failed(e, false))?
+            .into();
+        let signable = SignableRequest::new(
+            "POST",
+            self.endpoint.as_str(),
+            headers.iter().copied(),
+            SignableBody::Bytes(&body),
+        )
+        .map_err(|e| sqs_failed(e, false))?;
+        let (signature, _) = sign(signable, &params)
+            .map_err(|e| sqs_failed(e, false))?
+            .into_parts();
+
+        let mut builder = self.client.post(self.endpoint.clone());
+        for (name, value) in headers.into_iter().chain(signature.headers()) {
+            builder = builder.header(name, value);
+        }
+        let response = builder.body(body).send().await?;
+
+        let status = response.status();
+        if status.is_success() {
+            return reply(response).await;
+        }
+
+        #[derive(Default, Deserialize)]
+        struct ErrorBody {
+            #[serde(rename = "__type", default)]
+            kind: String,
+            #[serde(default)]
+            message: String,
+        }
+
+        let error: ErrorBody = response.json().await.unwrap_or_default();
+        // Throttling is reported as ThrottlingException or RequestThrottled.
+        if error.kind.contains("Throttl") {
+            return Err(DispatchError::Throttled { retry_after: None });
+        }
+        // Client errors, such as AccessDenied or a malformed request, fail the same way again.
+        Err(sqs_failed(
+            format!("{} {} {}", status, error.kind, error.message).trim(),
+            status.is_server_error(),
+        ))
+    }
+}
+
+#[cfg(feature = "sqs")]
+fn sqs_failed(e: impl std::fmt::Display, transient: bool) -> DispatchError {
+    DispatchError::SqsFailed {
+        message: e.to_string(),
+        transient,
+    }
+}
+
+#[cfg(feature = "sqs")]
+#[async_trait]
+impl<P: Payload> Client<P> for SqsClient {
+    async fn post(&self, request: Request<P>) -> Result<Reply, DispatchError> {
+        let mut message = self.message(&request)?;
+        message.insert("QueueUrl".into(), self.queue_url.as_str().into());
+        self.call("SendMessage", message.into()).await
+    }
+
+    /// Sends the batch with SendMessageBatch, ten messages at a time. Each reply's body is the
+    /// result SQS gave for that message. Messages that SQS fails or leaves out of its result are
+    /// sent again on their own, up to three times in all, unless SQS says the failure was the
+    /// sender's fault. If one still fails, the whole batch fails, so messages that were already
+    /// sent may be sent again when it's retried.
+    async fn post_batch(&self, batch: Vec<Request<P>>) -> Result<Vec<Reply>, DispatchError> {
+        #[derive(Deserialize)]
+        #[serde(rename_all = "PascalCase")]
+        struct BatchResult {
+            #[serde(default)]
+            successful: Vec<serde_json::Value>,
+            #[serde(default)]
+            failed: Vec<BatchFailure>,
+        }
+
+        #[derive(Deserialize)]
+        #[serde(rename_all = "PascalCase")]
+        struct BatchFailure {
+            code: String,
+            #[serde(default)]
+            message: String,
+            #[serde(default)]
+            sender_fault: bool,
+        }
+
+        let mut replies = Vec::with_capacity(batch.len());
+        for chunk in batch.chunks(SQS_BATCH_LIMIT) {
+            let mut sent: Vec<Option<Reply>> = chunk.iter().map(|_| None).collect();
+            let mut pending: Vec<usize> = (0..chunk.len()).collect();
+
+            let mut attempt = 1;
+            while !pending.is_empty() {
+                let mut entries = Vec::with_capacity(pending.len());
+                for &idx in &pending {
+                    let mut entry = self.message(&chunk[idx])?;
+                    entry.insert("Id".into(), idx.to_string().into());
+                    entries.push(serde_json::Value::from(entry));
+                }
+
+                let body = json!({ "QueueUrl": self.queue_url.as_str(), "Entries": entries });
+                let reply = self.call("SendMessageBatch", body).await?;
+                let result: BatchResult = reply.json()?;
+                for entry in result.successful {
+                    let idx = entry["Id"].as_str().and_then(|id| id.parse::<usize>().ok());
+                    if let Some(slot) = idx.and_then(|idx| sent.get_mut(idx)) {
+                        *slot = Some(Reply {
+                            status: reply.status,
+                            headers: HeaderMap::new(),
+                            body: serde_json::to_vec(&entry)?.into(),
+                        });
+                    }
+                }
+
+                if let Some(failure) = result
+                    .failed
+                    .iter()
+                    .find(|failure| failure.sender_fault || attempt >= SQS_BATCH_ATTEMPTS)
+                {
+                    return Err(sqs_failed(
+                        format!("{} {}", failure.code, failure.message),
+                        !failure.sender_fault,
+                    ));
+                }
+                pending.retain(|&idx| sent[idx].is_none());
+                if pending.is_empty() {
+                    break;
+                }
+                // SQS should list every message as successful or failed, but one it leaves out of
+                // both can't be resent forever either.
+                if attempt >= SQS_BATCH_ATTEMPTS {
+                    let message = format!("no result for {} messages", pending.len());
+                    return Err(sqs_failed(message, true));
+                }
+                tracing::debug!(attempt, failed = pending.len(), "resending failed messages");
+                attempt += 1;
+            }
+
+            replies.extend(sent.into_iter().flatten());
+        }
+
+        Ok(replies)
+    }
+}
+
+/// Delivers payloads as unary gRPC calls made by `call`, which is usually a generated tonic client
+/// that's cloned for each call:
+///
+/// ```ignore
+/// GrpcClient::new(move |request| {
+///     let mut client = client.clone();
+///     async move { client.record(request).await }
+/// })
+/// ```
+///
+/// Request headers are sent as metadata, and the request's method and URL are ignored. The reply
+/// has the response metadata as its headers, and the encoded response message as its body.
+pub struct GrpcClient<F> {
+    call: F,
+}
+
+impl<F> GrpcClient<F> {
+    pub fn new(call: F) -> Self {
+        GrpcClient { call }
+    }
+}
+
+#[async_trait]
+impl<P, R, F, Fut> Client<P> for GrpcClient<F>
+where
+    P: Payload,
+    R: prost::Message,
+    F: Fn(tonic::Request<P>) -> Fut + Send + Sync,
+    Fut: Future<Output = Result<tonic::Response<R>, tonic::Status>> + Send,
+{
+    async fn post(&self, request: Request<P>) -> Result<Reply, DispatchError> {
+        use tonic::metadata::{KeyAndValueRef, MetadataKey, MetadataValue};
+
+        let mut call = tonic::Request::new(request.body);
+        for (name, value) in &request.headers {
+            let key = MetadataKey::from_bytes(name.as_str().as_bytes());
+            let value = MetadataValue::try_from(value.as_bytes());
+            if let (Ok(key), Ok(value)) = (key, value) {
+                call.metadata_mut().append(key, value);
+            }
+        }
+
+        let (metadata, message, _) = (self.call)(call).await?.into_parts();
+
+        let mut headers = HeaderMap::new();
+        for entry in metadata.iter() {
+            if let KeyAndValueRef::Ascii(key, value) = entry {
+                let name = HeaderName::from_bytes(key.as_str().as_bytes());
+                let value = HeaderValue::from_bytes(value.as_bytes());
+                if let (Ok(name), Ok(value)) = (name, value) {
+                    headers.append(name, value);
+                }
+            }
+        }
+
+        Ok(Reply {
+            status: StatusCode::OK,
+            headers,
+            body: message.encode_to_vec().into(),
+        })
+    }
+}
+
+/// How failed posts are retried. Delays grow exponentially from `base_delay` up to `max_delay`, and
+/// with jitter each delay is picked at random between zero and that bound so that retries from
+/// many payloads don't all land at once.
+#[derive(Debug, Clone)]
+pub struct RetryPolicy {
+    pub max_attempts: usize,
+    /// How many attempts in a row can be throttled before the payload fails with
+    /// `DispatchError::Throttled`. Throttled attempts don't count towards `max_attempts`.
+    pub max_throttled_attempts: usize,
+    pub base_delay: Duration,
+    pub max_delay: Duration,
+    pub jitter: bool,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy {
+            max_attempts: 3,
+            max_throttled_attempts: 10,
+            base_delay: Duration::from_millis(100),
+            max_delay: Duration::from_secs(10),
+            jitter: true,
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// Makes a single attempt per payload.
+    pub fn never() -> Self {
+        RetryPolicy {
+            max_attempts: 1,
+            ..Default::default()
+        }
+    }
+
+    /// The delay before the given retry, where the first retry is 1.
+    fn delay(&self, retry: usize) -> Duration {
+        let exp = u32::try_from(retry - 1).unwrap_or(u32::MAX).min(31);
+        let bound = self.base_delay.saturating_mul(1 << exp).min(self.max_delay);
+
+        if self.jitter {
+            bound.mul_f64(rand::thread_rng().gen_range(0.0..=1.0))
+        } else {
+            bound
+        }
+    }
+}
+
+/// Receives payloads that could not be delivered after every retry, so they can be recovered and
+/// replayed later instead of being lost. Requests that failed together as a batch are handed over
+/// together, otherwise there is one request at a time.
+pub trait DeadLetterSink<P = serde_json::Value> {
+    fn dead_letter(&self, requests: Vec<Request<P>>, error: DispatchError);
+}
+
+impl<P, F> DeadLetterSink<P> for F
+where
+    F: Fn(Vec<Request<P>>, DispatchError),
+{
+    fn dead_letter(&self, requests: Vec<Request<P>>, error: DispatchError) {
+        self(requests, error)
+    }
+}
+
+impl<P> DeadLetterSink<P> for mpsc::UnboundedSender<(Vec<Request<P>>, DispatchError)> {
+    fn dead_letter(&self, requests: Vec<Request<P>>, error: DispatchError) {
+        // Nothing is listening if the receiver is gone, so the requests can only be dropped.
+        let _ = self.send((requests, error));
+    }
+}
+
+/// Appends dead letters to a file as JSON lines of `{"request": ..., "error": "..."}`, one line per
+/// request.
+pub struct FileSink {
+    file: Mutex<File>,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(bound(
+    serialize = "P: Serialize + Clone",
+    deserialize = "P: DeserializeOwned"
+))]
+struct DeadLetter<P> {
+    request: Request<P>,
+    #[serde(default)]
+    error: String,
+}
+
+impl FileSink {
+    pub fn open(path: impl AsRef<Path>) -> io::Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+
+        Ok(FileSink {
+            file: Mutex::new(file),
+        })
+    }
+
+    /// Reads back the requests written to a dead letter file, so they can be posted again.
+    pub fn replay<P: DeserializeOwned>(path: impl AsRef<Path>) -> io::Result<Vec<Request<P>>> {
+        let reader = BufReader::new(File::open(path)?);
+
+        reader
+            .lines()
+            .map(|line| {
+                let letter: DeadLetter<P> = serde_json::from_str(&line?)?;
+                Ok(letter.request)
+            })
+            .collect()
+    }
+}
+
+impl<P: Serialize + Clone> DeadLetterSink<P> for FileSink {
+    fn dead_letter(&self, requests: Vec<Request<P>>, error: DispatchError) {
+        let error = error.to_string();
+
+        let mut file = self.file.lock().unwrap_or_else(|e| e.into_inner());
+        for request in requests {
+            let letter = DeadLetter {
+                request,
+                error: error.clone(),
+            };
+
+            let written = serde_json::to_writer(&mut *file, &letter)
+                .map_err(io::Error::from)
+                .and_then(|()| writeln!(file));
+            if let Err(e) = written {
+                tracing::error!(error = %e, "failed to write dead letter");
+            }
+        }
+    }
+}
+
+/// A client for tests that injects failures and latency. Scripted outcomes are used first, in
+/// order, and after that requests fail at random at `error_rate`. Randomness is seeded, so runs
+/// are repeatable. Clones share what they've been sent, so a clone can be kept to check on a
+/// client that was given to a Dispatcher.
+#[derive(Clone)]
+pub struct ChaosClient<P = serde_json::Value> {
+    error_rate: f64,
+    error: DispatchError,
+    latency: Latency,
+    state: Arc<ChaosState<P>>,
+}
+
+struct ChaosState<P> {
+    rng: Mutex<rand::rngs::StdRng>,
+    script: Mutex<VecDeque<Outcome>>,
+    received: Mutex<Vec<P>>,
+    calls: AtomicUsize,
+    in_flight: AtomicUsize,
+    max_in_flight: AtomicUsize,
+}
+
+/// What a `ChaosClient` does with a request.
+#[derive(Debug, Clone)]
+pub enum Outcome {
+    Deliver,
+    Fail(DispatchError),
+    /// Never finishes, like a hung connection.
+    Hang,
+}
+
+/// How long a `ChaosClient` takes with each request.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Latency {
+    Fixed(Duration),
+    /// Picked at random between the two.
+    Uniform(Duration, Duration),
+}
+
+impl Default for Latency {
+    fn default() -> Self {
+        Latency::Fixed(Duration::ZERO)
+    }
+}
+
+impl Latency {
+    fn sample(&self, rng: &mut impl Rng) -> Duration {
+        match *self {
+            Latency::Fixed(latency) => latency,
+            Latency::Uniform(min, max) => rng.gen_range(min..=max.max(min)),
+        }
+    }
+}
+
+impl<P> Default for ChaosClient<P> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<P> ChaosClient<P> {
+    /// A client that delivers everything right away.
+    pub fn new() -> Self {
+        use rand::SeedableRng;
+
+        ChaosClient {
+            error_rate: 0.0,
+            error: DispatchError::TimedOut,
+            latency: Latency::default(),
+            state: Arc::new(ChaosState {
+                rng: Mutex::new(rand::rngs::StdRng::seed_from_u64(0)),
+                script: Mutex::new(VecDeque::new()),
+                received: Mutex::new(Vec::new()),
+                calls: AtomicUsize::new(0),
+                in_flight: AtomicUsize::new(0),
+                max_in_flight: AtomicUsize::new(0),
+            }),
+        }
+    }
+
+    /// Fails this fraction of requests, with `DispatchError::TimedOut` unless `with_error` says
+    /// otherwise.
+    pub fn with_error_rate(mut self, rate: f64) -> Self {
+        self.error_rate = rate.clamp(0.0, 1.0);
+        self
+    }
+
+    pub fn with_error(mut self, error: DispatchError) -> Self {
+        self.error = error;
+        self
+    }
+
+    pub fn with_latency(mut self, latency: Latency) -> Self {
+        self.latency = latency;
+        self
+    }
+
+    pub fn with_seed(self, seed: u64) -> Self {
+        use rand::SeedableRng;
+
+        *lock(&self.state.rng) = rand::rngs::StdRng::seed_from_u64(seed);
+        self
+    }
+
+    /// Handles the next requests with these outcomes, in order.
+    pub fn with_script(self, outcomes: impl IntoIterator<Item = Outcome>) -> Self {
+        lock(&self.state.script).extend(outcomes);
+        self
+    }
+
+    /// The bodies of the requests that were delivered, in the order they were.
+    pub fn received(&self) -> Vec<P>
+    where
+        P: Clone,
+    {
+        lock(&self.state.received).clone()
+    }
+
+    /// How many times a request was sent, including ones that failed.
+    pub fn calls(&self) -> usize {
+        self.state.calls.load(Ordering::Relaxed)
+    }
+
+    /// The most requests there have been in flight at once.
+    pub fn max_concurrency(&self) -> usize {
+        self.state.max_in_flight.load(Ordering::Relaxed)
+    }
+}
+
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+#[async_trait]
+impl<P: Payload> Client<P> for ChaosClient<P> {
+    async fn post(&self, request: Request<P>) -> Result<Reply, DispatchError> {
+        let state = &self.state;
+        state.calls.fetch_add(1, Ordering::Relaxed);
+        let in_flight = state.in_flight.fetch_add(1, Ordering::Relaxed) + 1;
+        let _in_flight = Decrement(&state.in_flight);
+        state.max_in_flight.fetch_max(in_flight, Ordering::Relaxed);
+
+        let (outcome, latency) = {
+            let mut rng = lock(&state.rng);
+            let outcome = lock(&state.script).pop_front().unwrap_or_else(|| {
+                if rng.gen_bool(self.error_rate) {
+                    Outcome::Fail(self.error.clone())
+                } else {
+                    Outcome::Deliver
+                }
+            });
+            (outcome, self.latency.sample(&mut *rng))
+        };
+
+        tokio::time::sleep(latency).await;
+        match outcome {
+            Outcome::Deliver => {
+                lock(&state.received).push(request.body);
+                Ok(Reply::default())
+            }
+            Outcome::Fail(e) => Err(e),
+            Outcome::Hang => std::future::pending().await,
+        }
+    }
+}
+
+/// Appends requests to a local file as JSON lines rather than sending them anywhere, for local
+/// development or to capture requests while the real endpoint is down. Captured requests can be
+/// read back with `FileClient::replay` and posted again.
+pub struct FileClient {
+    path: PathBuf,
+    rotation: Option<Rotation>,
+    state: Mutex<FileClientState>,
+}
+
+struct FileClientState {
+    file: File,
+    written: u64,
+}
+
+/// When a `FileClient` starts a new file. Once writing a request would take the file over
+/// `max_bytes`, it's renamed to `<path>.1` and a new file is started, with older files moving up
+/// to `<path>.2` and so on. Only `max_files` old files are kept.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Rotation {
+    pub max_bytes: u64,
+    pub max_files: usize,
+}
+
+impl FileClient {
+    pub fn open(path: impl AsRef<Path>) -> io::Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        let file = OpenOptions::new().create(true).append(true).open(&path)?;
+        let written = file.metadata()?.len();
+
+        Ok(FileClient {
+            path,
+            rotation: None,
+            state: Mutex::new(FileClientState { file, written }),
+        })
+    }
+
+    pub fn with_rotation(mut self, rotation: Rotation) -> Self {
+        self.rotation = Some(rotation);
+        self
+    }
+
+    /// Reads back the requests written to a file, so they can be posted again.
+    pub fn replay<P: DeserializeOwned>(path: impl AsRef<Path>) -> io::Result<Vec<Request<P>>> {
+        let reader = BufReader::new(File::open(path)?);
+
+        reader
+            .lines()
+            .map(|line| Ok(serde_json::from_str(&line?)?))
+            .collect()
+    }
+
+    /// The path of the `n`th most recent file that was rotated out.
+    fn rotated(&self, n: usize) -> PathBuf {
+        let mut path = self.path.clone().into_os_string();
+        path.push(format!(".{}", n));
+        path.into()
+    }
+
+    fn rotate(&self, state: &mut FileClientState, max_files: usize) -> io::Result<()> {
+        for n in (1..max_files.max(1)).rev() {
+            match fs::rename(self.rotated(n), self.rotated(n + 1)) {
+                Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+                _ => {}
+            }
+        }
+        fs::rename(&self.path, self.rotated(1))?;
+
+        state.file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)?;
+        state.written = 0;
+        Ok(())
+    }
+
+    fn write<P: Serialize + Clone>(&self, request: Request<P>) -> io::Result<()> {
+        let mut line = serde_json::to_vec(&request)?;
+        line.push(b'\n');
+
+        let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        if let Some(rotation) = self.rotation {
+            let len = line.len() as u64;
+            if state.written > 0 && state.written + len > rotation.max_bytes {
+                self.rotate(&mut state, rotation.max_files)?;
+            }
+        }
+
+        state.file.write_all(&line)?;
+        state.written += line.len() as u64;
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl<P: Payload> Client<P> for FileClient {
+    async fn post(&self, request: Request<P>) -> Result<Reply, DispatchError> {
+        self.write(request)
+            .map_err(|e| DispatchError::WriteFailed(Arc::new(e)))?;
+        Ok(Reply::default())
+    }
+}
+
+/// Writes each posted request to a file before it is queued, and marks it as done once it has been
+/// delivered or given up on, so that nothing is lost if the process stops. Requests that were
+/// still pending when the journal was opened are posted again with `DispatcherHandle::recover`, so
+/// delivery is at least once.
+///
+/// The file is written by a thread of its own, so posting doesn't block the runtime on disk IO.
+/// Requests recorded while the previous write was being synced are written and synced together.
+///
+/// The file is compacted down to the pending requests when it is opened, and again whenever it
+/// holds at least `JOURNAL_COMPACT_ENTRIES` entries and most of them are for finished requests.
+pub struct Journal {
+    writes: mpsc::UnboundedSender<JournalWrite>,
+    next_id: AtomicU64,
+    recovered: Mutex<BTreeMap<u64, serde_json::Value>>,
+}
+
+/// How many entries the journal holds before it's worth compacting while it's open.
+const JOURNAL_COMPACT_ENTRIES: usize = 1000;
+
+enum JournalWrite {
+    /// A request's entry, and who to tell once it's durable.
+    Push {
+        line: Vec<u8>,
+        done: oneshot::Sender<Result<(), Arc<io::Error>>>,
+    },
+    Ack {
+        id: u64,
+        line: Vec<u8>,
+    },
+    /// Answered once everything sent before it has been written.
+    Flush(oneshot::Sender<()>),
+}
+
+/// The journal file, owned by the writer thread.
+struct JournalState {
+    file: File,
+    path: PathBuf,
+    /// How many entries are in the file, and how many of those are for pending requests.
+    entries: usize,
+    pending: usize,
+}
+
+impl JournalState {
+    /// Writes whatever has been sent until the journal is dropped.
+    fn run(mut self, mut writes: mpsc::UnboundedReceiver<JournalWrite>) {
+        while let Some(first) = writes.blocking_recv() {
+            let mut batch = vec![first];
+            while let Ok(write) = writes.try_recv() {
+                batch.push(write);
+            }
+
+            let res = self.write(&batch).map_err(Arc::new);
+            if let Err(e) = &res {
+                for write in &batch {
+                    if let JournalWrite::Ack { id, .. } = write {
+                        // This only means the request is delivered again after a restart.
+                        tracing::warn!(id, error = %e, "failed to acknowledge journal entry");
+                    }
+                }
+            }
+
+            for write in batch {
+                match write {
+                    JournalWrite::Push { done, .. } => _ = done.send(res.clone()),
+                    JournalWrite::Ack { .. } => {}
+                    JournalWrite::Flush(done) => _ = done.send(()),
+                }
+            }
+        }
+    }
+
+    /// Writes a batch of entries with a single sync, if any of them need one.
+    fn write(&mut self, batch: &[JournalWrite]) -> io::Result<()> {
+        let (mut buf, mut pushed, mut acked) = (Vec::new(), 0, 0);
+        for write in batch {
+            match write {
+                JournalWrite::Push { line, .. } => {
+                    buf.extend_from_slice(line);
+                    pushed += 1;
+                }
+                JournalWrite::Ack { line, .. } => {
+                    buf.extend_from_slice(line);
+                    acked += 1;
+                }
+                JournalWrite::Flush(_) => {}
+            }
+        }
+        if buf.is_empty() {
+            return Ok(());
+        }
+
+        self.file.write_all(&buf)?;
+        // Acknowledgements don't need to be durable, only the requests.
+        if pushed > 0 {
+            self.file.sync_data()?;
+        }
+
+        self.entries += pushed + acked;
+        self.pending = (self.pending + pushed).saturating_sub(acked);
+        if self.entries >= JOURNAL_COMPACT_ENTRIES && self.pending * 2 < self.entries {
+            // The file still has everything that's pending, so this can be tried again later.
+            if let Err(e) = self.compact() {
+                tracing::warn!(error = %e, "failed to compact journal");
+            }
+        }
+        Ok(())
+    }
+
+    /// Rewrites the file with just the pending requests.
+    fn compact(&mut self) -> io::Result<()> {
+        let (pending, _) = Journal::read(&self.path)?;
+        self.file = Journal::rewrite(&self.path, &pending)?;
+        self.entries = pending.len();
+        self.pending = pending.len();
+        Ok(())
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum JournalEntry<R> {
+    Pushed { id: u64, request: R },
+    Acked { id: u64 },
+}
+
+impl Journal {
+    pub fn open(path: impl AsRef<Path>) -> io::Result<Self> {
+        let path = path.as_ref();
+
+        let (pending, next_id) = if path.exists() {
+            Self::read(path)?
+        } else {
+            (BTreeMap::new(), 0)
+        };
+        let state = JournalState {
+            file: Self::rewrite(path, &pending)?,
+            path: path.to_path_buf(),
+            entries: pending.len(),
+            pending: pending.len(),
+        };
+
+        // The thread stops once the journal is dropped and it has written everything sent to it.
+        let (writes, rx) = mpsc::unbounded_channel();
+        std::thread::Builder::new()
+            .name("journal".into())
+            .spawn(move || state.run(rx))?;
+
+        Ok(Journal {
+            writes,
+            next_id: AtomicU64::new(next_id),
+            recovered: Mutex::new(pending),
+        })
+    }
+
+    /// Reads the requests that are pending, and the ID to give the next one.
+    fn read(path: &Path) -> io::Result<(BTreeMap<u64, serde_json::Value>, u64)> {
+        let mut pending = BTreeMap::new();
+        let mut next_id = 0;
+        for line in BufReader::new(File::open(path)?).lines() {
+            // A crash can leave the last line half written, and that request wasn't queued.
+            match serde_json::from_str(&line?) {
+                Ok(JournalEntry::Pushed { id, request }) => {
+                    pending.insert(id, request);
+                    next_id = next_id.max(id + 1);
+                }
+                Ok(JournalEntry::Acked { id }) => {
+                    pending.remove(&id);
+                }
+                Err(_) => {}
+            }
+        }
+        Ok((pending, next_id))
+    }
+
+    /// Replaces the file with one holding just `pending`, returning it opened for appending.
+    fn rewrite(path: &Path, pending: &BTreeMap<u64, serde_json::Value>) -> io::Result<File> {
+        // Rewrites the pending requests to a new file first, so a crash while compacting doesn't
+        // lose them.
+        let compacted = path.with_extension("compacting");
+        let mut file = File::create(&compacted)?;
+        for (&id, request) in pending {
+            serde_json::to_writer(&mut file, &JournalEntry::Pushed { id, request })?;
+            writeln!(file)?;
+        }
+        file.sync_all()?;
+        fs::rename(&compacted, path)?;
+
+        OpenOptions::new().append(true).open(path)
+    }
+
+    fn stopped() -> DispatchError {
+        DispatchError::JournalFailed(Arc::new(io::Error::other("journal writer stopped")))
+    }
+
+    /// Durably writes a request, returning the ID to acknowledge it with.
+    async fn record<P: Serialize + Clone>(
+        &self,
+        request: &Request<P>,
+    ) -> Result<u64, DispatchError> {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let mut line = serde_json::to_vec(&JournalEntry::Pushed { id, request })?;
+        line.push(b'\n');
+
+        let (done, written) = oneshot::channel();
+        self.writes
+            .send(JournalWrite::Push { line, done })
+            .map_err(|_| Self::stopped())?;
+        written
+            .await
+            .map_err(|_| Self::stopped())?
+            .map_err(DispatchError::JournalFailed)?;
+        Ok(id)
+    }
+
+    fn ack(&self, id: u64) {
+        let mut line = serde_json::to_vec(&JournalEntry::<()>::Acked { id })
+            .expect("an acknowledgement can always be encoded");
+        line.push(b'\n');
+        if self.writes.send(JournalWrite::Ack { id, line }).is_err() {
+            tracing::warn!(id, "failed to acknowledge journal entry");
+        }
+    }
+
+    /// Waits until everything recorded or acknowledged so far has been written.
+    async fn flush(&self) {
+        let (done, flushed) = oneshot::channel();
+        if self.writes.send(JournalWrite::Flush(done)).is_ok() {
+            let _ = flushed.await;
+        }
+    }
+
+    /// Takes the requests that were pending when the journal was opened. Requests that can't be
+    /// read as `P` are left in the journal.
+    fn recover<P: DeserializeOwned>(&self) -> Vec<(u64, Request<P>)> {
+        let recovered = std::mem::take(&mut *lock(&self.recovered));
+
+        recovered
+            .into_iter()
+            .filter_map(|(id, request)| match serde_json::from_value(request) {
+                Ok(request) => Some((id, request)),
+                Err(e) => {
+                    tracing::warn!(id, error = %e, "skipping unreadable journal entry");
+                    None
+                }
+            })
+            .collect()
+    }
+}
+
+/// How many bytes a payload takes up as JSON.
+fn encoded_len<P: Serialize>(body: &P) -> usize {
+    serde_json::to_vec(body).map_or(0, |encoded| encoded.len())
+}
+
+/// Groups payloads into batches that are sent as a single request. A batch is sent once it has
+/// `max_items` payloads, once adding the next payload would take its encoded size over `max_bytes`,
+/// or once `max_linger` has passed since its first payload was queued, whichever comes first.
+#[derive(Debug, Clone)]
+pub struct BatchPolicy {
+    pub max_items: usize,
+    pub max_bytes: usize,
+    pub max_linger: Duration,
+    /// Also sends whatever has been gathered at this interval, on a fixed schedule.
+    pub flush_interval: Option<Duration>,
+}
+
+impl Default for BatchPolicy {
+    fn default() -> Self {
+        BatchPolicy {
+            max_items: 100,
+            max_bytes: 1 << 20,
+            max_linger: Duration::from_millis(100),
+            flush_interval: None,
+        }
+    }
+}
+
+impl BatchPolicy {
+    fn batches<P: Payload>(
+        self,
+        queue: Arc<Queue<Queued<P>>>,
+    ) -> impl futures::Stream<Item = Vec<Queued<P>>> {
+        let encoded_len = |queued: &Queued<P>| encoded_len(&queued.request.body);
+
+        async_stream::stream! {
+            // A payload that didn't fit in the previous batch starts the next one.
+            let mut carry = None;
+            let interval = self
+                .flush_interval
+                .map(|interval| interval.max(Duration::from_millis(1)));
+            let mut next_flush = interval.map(|interval| tokio::time::Instant::now() + interval);
+
+            loop {
+                let first = match carry.take() {
+                    Some(payload) => payload,
+                    None => match queue.pop().await {
+                        Some(payload) => payload,
+                        None => break,
+                    },
+                };
+
+                let now = tokio::time::Instant::now();
+                let mut deadline = now + self.max_linger;
+                if let (Some(interval), Some(next_flush)) = (interval, &mut next_flush) {
+                    while *next_flush <= now {
+                        *next_flush += interval;
+                    }
+                    deadline = deadline.min(*next_flush);
+                }
+
+                let mut bytes = encoded_len(&first);
+                let mut batch = vec![first];
+
+                while batch.len() < self.max_items {
+                    let flush = queue.flush.notified();
+                    if queue.flushing.load(Ordering::Relaxed) > 0 {
+                        break;
+                    }
+
+                    let popped = tokio::select! {
+                        popped = tokio::time::timeout_at(deadline, queue.pop()) => popped,
+                        () = flush => break,
+                    };
+                    let Ok(Some(payload)) = popped else {
+                        break;
+                    };
+
+                    let len = encoded_len(&payload);
+                    if bytes + len > self.max_bytes {
+                        carry = Some(payload);
+                        break;
+                    }
+
+                    bytes += len;
+                    batch.push(payload);
+                }
+
+                yield batch;
+            }
+        }
+    }
+}
+
+/// Paces outgoing requests with one of the rate limiters, waiting for a permit before each attempt
+/// to send a request or batch.
+pub struct RateLimit {
+    admit: Mutex<Box<dyn FnMut() -> Option<Duration> + Send>>,
+}
+
+impl RateLimit {
+    pub(crate) fn new<L: RateLimiter + Send + 'static>(mut limiter: L) -> Self {
+        let admit = move || {
+            if limiter.allowed() {
+                None
+            } else {
+                Some(limiter.time_until_allowed())
+            }
+        };
+
+        RateLimit {
+            admit: Mutex::new(Box::new(admit)),
+        }
+    }
+
+    async fn acquire(&self) {
+        loop {
+            let wait = {
+                let mut admit = self.admit.lock().unwrap_or_else(|e| e.into_inner());
+                admit()
+            };
+
+            match wait {
+                None => return,
+                // Limiters can round the wait down to zero just before a permit is available.
+                Some(wait) => tokio::time::sleep(wait.max(Duration::from_millis(1))).await,
+            }
+        }
+    }
+}
+
+/// Stops sending for `cool_down` after `threshold` consecutive failed attempts, so that a downed
+/// endpoint isn't hammered with the whole queue. Once the cool-down is over a single probe is sent,
+/// and sending resumes if it succeeds or stops for another cool-down if it fails.
+///
+/// Only failures that could be transient count, other than throttling: a request refused because
+/// of the request itself, a throttled request or one past its deadline says nothing about whether
+/// the endpoint is down, so it neither counts as a failure nor resets the count.
+pub struct CircuitBreaker {
+    threshold: usize,
+    cool_down: Duration,
+    state: Mutex<BreakerState>,
+    changed: Notify,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Circuit {
+    Closed,
+    Open {
+        until: tokio::time::Instant,
+    },
+    /// A probe is in flight.
+    HalfOpen,
+}
+
+struct BreakerState {
+    circuit: Circuit,
+    failures: usize,
+}
+
+impl CircuitBreaker {
+    pub fn new(threshold: usize, cool_down: Duration) -> Self {
+        CircuitBreaker {
+            threshold: threshold.max(1),
+            cool_down,
+            state: Mutex::new(BreakerState {
+                circuit: Circuit::Closed,
+                failures: 0,
+            }),
+            changed: Notify::new(),
+        }
+    }
+
+    fn lock(&self) -> MutexGuard<'_, BreakerState> {
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Waits until an attempt may be sent.
+    async fn acquire(&self) -> BreakerPermit<'_> {
+        loop {
+            let changed = self.changed.notified();
+
+            let until = {
+                let mut state = self.lock();
+                match state.circuit {
+                    Circuit::Closed => return BreakerPermit(self, false),
+                    Circuit::Open { until } if tokio::time::Instant::now() >= until => {
+                        // This attempt is the probe.
+                        state.circuit = Circuit::HalfOpen;
+                        return BreakerPermit(self, true);
+                    }
+                    Circuit::Open { until } => Some(until),
+                    Circuit::HalfOpen => None,
+                }
+            };
+
+            match until {
+                Some(until) => tokio::time::sleep_until(until).await,
+                None => changed.await,
+            }
+        }
+    }
+
+    fn record(&self, succeeded: bool) {
+        let mut state = self.lock();
+
+        if succeeded {
+            state.circuit = Circuit::Closed;
+            state.failures = 0;
+        } else {
+            state.failures += 1;
+            if state.circuit == Circuit::HalfOpen || state.failures >= self.threshold {
+                state.circuit = Circuit::Open {
+                    until: tokio::time::Instant::now() + self.cool_down,
+                };
+                state.failures = 0;
+            }
+        }
+
+        drop(state);
+        self.changed.notify_waiters();
+    }
+}
+
+/// Lets an attempt through the circuit breaker, and whether it's the probe. A probe that's given up
+/// on without its outcome being recorded, such as when it's past its deadline by the time it's
+/// let through or its outcome was neutral, reopens the circuit so that the next attempt becomes the
+/// probe instead.
+struct BreakerPermit<'a>(&'a CircuitBreaker, bool);
+
+impl BreakerPermit<'_> {
+    fn record(mut self, succeeded: bool) {
+        self.1 = false;
+        self.0.record(succeeded);
+    }
+}
+
+impl Drop for BreakerPermit<'_> {
+    fn drop(&mut self) {
+        if !self.1 {
+            return;
+        }
+
+        let mut state = self.0.lock();
+        if state.circuit == Circuit::HalfOpen {
+            state.circuit = Circuit::Open {
+                until: tokio::time::Instant::now(),
+            };
+        }
+        drop(state);
+        self.0.changed.notify_waiters();
+    }
+}
+
+/// Adjusts how many attempts can be in flight between `min` and `max` as it sees how the server
+/// copes. The limit starts at `min` and grows by one for each limit's worth of attempts that
+/// succeed, and is multiplied by `backoff` whenever an attempt is throttled, fails in a way that
+/// can be retried, or takes longer than the latency target.
+///
+/// The Dispatcher takes up to `max` requests off the queue at once, whatever its concurrency, and
+/// they wait for the limit before each attempt.
+pub struct AdaptiveConcurrency {
+    min: usize,
+    max: usize,
+    latency_target: Option<Duration>,
+    backoff: f64,
+    state: Mutex<AdaptiveState>,
+    changed: Notify,
+}
+
+struct AdaptiveState {
+    limit: f64,
+    in_flight: usize,
+}
+
+impl AdaptiveConcurrency {
+    pub fn new(min: usize, max: usize) -> Self {
+        let min = min.max(1);
+        AdaptiveConcurrency {
+            min,
+            max: max.max(min),
+            latency_target: None,
+            backoff: 0.5,
+            state: Mutex::new(AdaptiveState {
+                limit: min as f64,
+                in_flight: 0,
+            }),
+            changed: Notify::new(),
+        }
+    }
+
+    /// Treats attempts that take longer than `target` as a sign that the server is overloaded.
+    pub fn with_latency_target(mut self, target: Duration) -> Self {
+        self.latency_target = Some(target);
+        self
+    }
+
+    /// How much to cut the limit by when the server is overloaded, between 0 and 1. Defaults to
+    /// halving it.
+    pub fn with_backoff(mut self, backoff: f64) -> Self {
+        self.backoff = backoff.clamp(0.0, 1.0);
+        self
+    }
+
+    /// How many attempts can currently be in flight.
+    pub fn limit(&self) -> usize {
+        lock(&self.state).limit as usize
+    }
+
+    /// Waits until there's room under the limit for another attempt.
+    async fn acquire(&self) -> AdaptivePermit<'_> {
+        loop {
+            let changed = self.changed.notified();
+
+            {
+                let mut state = lock(&self.state);
+                if state.in_flight < state.limit as usize {
+                    state.in_flight += 1;
+                    return AdaptivePermit(self);
+                }
+            }
+
+            changed.await;
+        }
+    }
+
+    fn record<T>(&self, res: &Result<T, DispatchError>, latency: Duration) {
+        let overloaded = match res {
+            Ok(_) => self.latency_target.is_some_and(|target| latency > target),
+            Err(DispatchError::Throttled { .. }) => true,
+            Err(e) => e.retryable(),
+        };
+
+        let mut state = lock(&self.state);
+        let limit = if overloaded {
+            state.limit * self.backoff
+        } else {
+            state.limit + 1.0 / state.limit
+        };
+        let limit = limit.clamp(self.min as f64, self.max as f64);
+        if limit as usize != state.limit as usize {
+            tracing::debug!(limit = limit as usize, "concurrency limit changed");
+        }
+        state.limit = limit;
+        drop(state);
+        self.changed.notify_waiters();
+    }
+}
+
+/// Makes room for another attempt once dropped.
+struct AdaptivePermit<'a>(&'a AdaptiveConcurrency);
+
+impl Drop for AdaptivePermit<'_> {
+    fn drop(&mut self) {
+        lock(&self.0.state).in_flight -= 1;
+        self.0.changed.notify_waiters();
+    }
+}
+
+/// Pauses all sending while the server is throttling requests.
+#[derive(Default)]
+struct Throttle {
+    until: Mutex<Option<tokio::time::Instant>>,
+}
+
+impl Throttle {
+    fn pause(&self, pause: Duration) {
+        let until = tokio::time::Instant::now() + pause;
+
+        let mut current = self.until.lock().unwrap_or_else(|e| e.into_inner());
+        *current = Some(current.map_or(until, |current| current.max(until)));
+    }
+
+    async fn wait(&self) {
+        // The pause can be extended while waiting.
+        loop {
+            let until = *self.until.lock().unwrap_or_else(|e| e.into_inner());
+            match until {
+                Some(until) if until > tokio::time::Instant::now() => {
+                    tokio::time::sleep_until(until).await
+                }
+                _ => return,
+            }
+        }
+    }
+}
+
+/// The order requests are delivered in.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DeliveryOrder {
+    /// Up to `concurrency` requests are in flight at once, so they can arrive in any order.
+    #[default]
+    Unordered,
+    /// Each request, including its retries, is finished before the next one is sent, so requests
+    /// arrive in the order they were posted. This ignores the concurrency.
+    Fifo,
+    /// Requests with the same [`Request::partition`] key are delivered one after another in the
+    /// order they were posted, while different keys are sent concurrently. Requests without a key
+    /// aren't ordered. Requests waiting behind another with the same key count towards the
+    /// concurrency. Batches can mix keys, so they are delivered as with `Fifo`.
+    Partitioned,
+}
+
+/// What the consumer does with posted requests when its cancellation token is cancelled. Either
+/// way, posting fails with `DispatchError::SendFailed` from then on.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum OnCancel {
+    /// Delivers what's already been posted, including retries, before stopping.
+    #[default]
+    Drain,
+    /// Stops straight away. Queued and in-flight requests fail with `DispatchError::Abandoned`,
+    /// and stay in the journal, if there is one, to be resumed later.
+    Abandon,
+}
+
+/// Changes requests before they are sent, e.g. to add auth headers or correlation IDs, or to
+/// rewrite bodies. Middleware runs before every attempt, on a fresh copy of the posted request.
+pub trait Middleware<P = serde_json::Value> {
+    fn process(&self, request: &mut Request<P>);
+}
+
+impl<P, F> Middleware<P> for F
+where
+    F: Fn(&mut Request<P>),
+{
+    fn process(&self, request: &mut Request<P>) {
+        self(request)
+    }
+}
+
+/// Called around every attempt to send a request, including retries, e.g. for audit logging or
+/// custom metrics. Both methods do nothing by default, so hooks only implement what they need.
+pub trait Hooks<P = serde_json::Value> {
+    /// Called with the request about to be sent, after any middleware has run. Changes made here,
+    /// such as scrubbing fields from the payload, are what gets sent.
+    fn before_send(&self, _request: &mut Request<P>) {}
+
+    /// Called with the request as it was sent, what came of it and how long the attempt took.
+    /// Every request in a batch is passed the batch's result, and its own reply if there is one.
+    fn after_send(
+        &self,
+        _request: &Request<P>,
+        _result: Result<&Reply, &DispatchError>,
+        _elapsed: Duration,
+    ) {
+    }
+}
+
+/// Sends each request's idempotency key in `header`, and optionally suppresses posts that repeat
+/// the key of one posted within `window`. Requests posted without a key get one when `generate` is
+/// set, which is a hash of their method, URL and body so that exact duplicates get the same key.
+///
+/// A key is forgotten once its request fails, so it can be posted again. Batches are sent with
+/// only the client's headers, so keys are only used to suppress duplicates when batching.
+pub struct Idempotency {
+    pub header: HeaderName,
+    pub generate: bool,
+    pub window: Option<Duration>,
+    recent: Mutex<RecentKeys>,
+}
+
+/// Keys posted within the window, along with the order they were posted in so that expired keys
+/// can be pruned from the front.
+#[derive(Default)]
+struct RecentKeys {
+    posted: HashMap<String, tokio::time::Instant>,
+    order: VecDeque<(String, tokio::time::Instant)>,
+}
+
+impl Default for Idempotency {
+    fn default() -> Self {
+        Idempotency {
+            header: HeaderName::from_static("idempotency-key"),
+            generate: true,
+            window: None,
+            recent: Mutex::default(),
+        }
+    }
+}
+
+impl Idempotency {
+    pub fn with_window(mut self, window: Duration) -> Self {
+        self.window = Some(window);
+        self
+    }
+
+    fn lock(&self) -> MutexGuard<'_, RecentKeys> {
+        self.recent.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Gives the request a key if it needs one, returning false if it's a duplicate that should be
+    /// suppressed.
+    fn admit<P: Serialize>(&self, request: &mut Request<P>) -> Result<bool, DispatchError> {
+        if request.idempotency_key.is_none() && self.generate {
+            let mut hash = Sha256::new();
+            hash.update(request.method.as_str());
+            hash.update(request.url.as_ref().map_or("", |url| url.as_str()));
+            hash.update(serde_json::to_vec(&request.body)?);
+            request.idempotency_key = Some(hex(&hash.finalize()));
+        }
+
+        let (Some(window), Some(key)) = (self.window, &request.idempotency_key) else {
+            return Ok(true);
+        };
+
+        let now = tokio::time::Instant::now();
+        let mut recent = self.lock();
+        while let Some((key, posted)) = recent.order.front().cloned() {
+            if now - posted < window {
+                break;
+            }
+            recent.order.pop_front();
+            // The key may have been forgotten and posted again since.
+            if recent.posted.get(&key) == Some(&posted) {
+                recent.posted.remove(&key);
+            }
+        }
+
+        if recent.posted.contains_key(key) {
+            return Ok(false);
+        }
+        recent.posted.insert(key.clone(), now);
+        recent.order.push_back((key.clone(), now));
+        Ok(true)
+    }
+
+    fn forget(&self, key: &str) {
+        self.lock().posted.remove(key);
+    }
+}
+
+/// Called with the request, the error and the attempt number (starting from 1) whenever an attempt
+/// to deliver a request fails, including attempts that will be retried.
+pub type ErrorCallback<P = serde_json::Value> =
+    Box<dyn Fn(&Request<P>, &DispatchError, usize) + Send + Sync>;
+
+/// Called with a summary each time `DispatcherHandle::flush` finishes, and once the consumer has
+/// stopped after being closed or cancelled.
+pub type FlushCallback = Box<dyn Fn(&FlushSummary) + Send + Sync>;
+
+/// Called with the queue's depth when it crosses a watermark.
+pub type WatermarkCallback = Box<dyn Fn(usize) + Send + Sync>;
+
+/// Tells the application when the Dispatcher is falling behind, so that it can shed or sample
+/// what it posts. `on_high` is called once the queue holds `high` requests, and then `on_low` once
+/// it has drained back down to `low`, after which `on_high` can be called again. Delayed requests
+/// don't count until they're due.
+///
+/// The callbacks are called by whoever changed the queue's depth, which may be the consumer, so
+/// they should be quick.
+pub struct Watermarks {
+    pub high: usize,
+    pub low: usize,
+    on_high: WatermarkCallback,
+    on_low: WatermarkCallback,
+}
+
+impl Watermarks {
+    pub fn new(
+        high: usize,
+        low: usize,
+        on_high: impl Fn(usize) + Send + Sync + 'static,
+        on_low: impl Fn(usize) + Send + Sync + 'static,
+    ) -> Self {
+        Watermarks {
+            high,
+            low,
+            on_high: Box::new(on_high),
+            on_low: Box::new(on_low),
+        }
+    }
+}
+
+/// What happened to requests between one flush and the next, or since the Dispatcher started for
+/// the first flush. Retries count each time a request, or a batch, was sent again.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct FlushSummary {
+    pub delivered: usize,
+    pub failed: usize,
+    pub dropped: usize,
+    pub retried: usize,
+    /// The JSON encoded size of the payloads that were delivered.
+    pub bytes: usize,
+    pub elapsed: Duration,
+}
+
+/// Configures how the Dispatcher delivers payloads beyond its concurrency.
+pub struct DispatchOptions<P = serde_json::Value> {
+    pub retry: RetryPolicy,
+    /// Where payloads go once retries are exhausted. They are dropped if this isn't set.
+    pub dead_letter: Option<Box<dyn DeadLetterSink<P> + Send + Sync>>,
+    /// Sends payloads in batches with `Client::post_batch` rather than one at a time.
+    pub batch: Option<BatchPolicy>,
+    /// When batching, this is called for each request in the failed batch.
+    pub on_error: Option<ErrorCallback<P>>,
+    pub on_flush: Option<FlushCallback>,
+    /// How many payloads can be queued waiting for the consumer. Defaults to 1, so posting waits
+    /// for the consumer to pick up the previous payload.
+    pub capacity: usize,
+    pub backpressure: Backpressure,
+    /// Limits how fast requests are sent, regardless of the concurrency.
+    pub rate_limit: Option<RateLimit>,
+    pub circuit_breaker: Option<CircuitBreaker>,
+    /// Adjusts how many attempts are in flight to suit the server, rather than keeping to the
+    /// Dispatcher's concurrency, which is replaced by its maximum.
+    pub adaptive_concurrency: Option<Arc<AdaptiveConcurrency>>,
+    /// Runs in order before each request is sent.
+    pub middleware: Vec<Box<dyn Middleware<P> + Send + Sync>>,
+    /// Run in order around each attempt to send a request.
+    pub hooks: Vec<Box<dyn Hooks<P> + Send + Sync>>,
+    pub order: DeliveryOrder,
+    /// How long each attempt to send a request or batch can take before it fails with
+    /// `DispatchError::TimedOut`, which can be retried.
+    pub timeout: Option<Duration>,
+    /// How long after being posted a payload can still be sent, including the time it spent
+    /// queued and on retries. Payloads still undelivered by then fail with
+    /// `DispatchError::DeadlineExceeded`. A batch has the deadline of its oldest payload.
+    pub deadline: Option<Duration>,
+    /// Keeps queued requests on disk until they are delivered.
+    pub journal: Option<Arc<Journal>>,
+    pub idempotency: Option<Arc<Idempotency>>,
+    /// Stops the consumer once cancelled, as if the Dispatcher had been closed, with what's still
+    /// queued handled according to `on_cancel`.
+    pub cancellation: Option<CancellationToken>,
+    pub on_cancel: OnCancel,
+    pub watermarks: Option<Watermarks>,
+    throttle: Throttle,
+    failures: Arc<Failures<P>>,
+}
+
+impl<P> Default for DispatchOptions<P> {
+    fn default() -> Self {
+        DispatchOptions {
+            retry: RetryPolicy::default(),
+            dead_letter: None,
+            batch: None,
+            on_error: None,
+            on_flush: None,
+            capacity: 1,
+            backpressure: Backpressure::default(),
+            rate_limit: None,
+            circuit_breaker: None,
+            adaptive_concurrency: None,
+            middleware: Vec::new(),
+            hooks: Vec::new(),
+            order: DeliveryOrder::default(),
+            timeout: None,
+            deadline: None,
+            journal: None,
+            idempotency: None,
+            cancellation: None,
+            on_cancel: OnCancel::default(),
+            watermarks: None,
+            throttle: Throttle::default(),
+            failures: Arc::default(),
+        }
+    }
+}
+
+impl<P> DispatchOptions<P> {
+    fn prepare(&self, mut request: Request<P>) -> Request<P> {
+        if let (Some(idempotency), Some(key)) = (&self.idempotency, &request.idempotency_key) {
+            // Keys are either hex or given by the caller, who can't expect an invalid header to
+            // be sent.
+            if let Ok(value) = HeaderValue::try_from(key) {
+                request.headers.insert(idempotency.header.clone(), value);
+            }
+        }
+
+        for middleware in &self.middleware {
+            middleware.process(&mut request);
+        }
+        for hook in &self.hooks {
+            hook.before_send(&mut request);
+        }
+        request
+    }
+
+    /// Runs the hooks after an attempt, with the requests as they were sent. A batch that got
+    /// fewer replies than it had requests gives the last one to the rest, as it's delivered.
+    fn after_send(
+        &self,
+        sent: &[Request<P>],
+        res: Result<&[Reply], &DispatchError>,
+        elapsed: Duration,
+    ) {
+        let missing = Reply::default();
+        for (idx, request) in sent.iter().enumerate() {
+            let res = res.map(|replies| replies.get(idx).or(replies.last()).unwrap_or(&missing));
+            for hook in &self.hooks {
+                hook.after_send(request, res, elapsed);
+            }
+        }
+    }
+
+    /// Sends an attempt once the server isn't throttling requests, and the circuit breaker and
+    /// rate limit allow it, as long as that's before the deadline.
+    async fn attempt<T, Fut>(
+        &self,
+        send: Fut,
+        deadline: Option<tokio::time::Instant>,
+    ) -> Result<T, DispatchError>
+    where
+        Fut: Future<Output = Result<T, DispatchError>>,
+    {
+        self.throttle.wait().await;
+        let breaker = match &self.circuit_breaker {
+            Some(breaker) => Some(breaker.acquire().await),
+            None => None,
+        };
+        if let Some(rate_limit) = &self.rate_limit {
+            rate_limit.acquire().await;
+        }
+        let permit = match &self.adaptive_concurrency {
+            Some(adaptive) => Some(adaptive.acquire().await),
+            None => None,
+        };
+
+        let now = tokio::time::Instant::now();
+        if deadline.is_some_and(|deadline| deadline <= now) {
+            return Err(DispatchError::DeadlineExceeded);
+        }
+
+        let timeout = self.timeout.map(|timeout| now + timeout);
+        let res = match timeout.into_iter().chain(deadline).min() {
+            Some(limit) => match tokio::time::timeout_at(limit, send).await {
+                Ok(res) => res,
+                Err(_) if Some(limit) == deadline => Err(DispatchError::DeadlineExceeded),
+                Err(_) => Err(DispatchError::TimedOut),
+            },
+            None => send.await,
+        };
+
+        if let Some(breaker) = breaker {
+            match &res {
+                Ok(_) => breaker.record(true),
+                Err(e) if e.retryable() && !matches!(e, DispatchError::Throttled { .. }) => {
+                    breaker.record(false)
+                }
+                Err(_) => drop(breaker),
+            }
+        }
+        if let Some(permit) = permit {
+            permit.0.record(&res, now.elapsed());
+        }
+        res
+    }
+
+    fn report(&self, request: &Request<P>, error: &DispatchError, attempt: usize) {
+        if let Some(on_error) = &self.on_error {
+            on_error(request, error, attempt);
+        }
+    }
+}
+
+/// Why `DispatcherBuilder` refused to build a Dispatcher.
+#[derive(Error, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConfigError {
+    #[error("concurrency must be greater than zero")]
+    ZeroConcurrency,
+    #[error("queue capacity must be greater than zero")]
+    ZeroCapacity,
+    #[error("retry policy must allow at least one attempt")]
+    ZeroAttempts,
+    #[error("base retry delay of {base:?} is longer than the maximum of {max:?}")]
+    RetryDelays { base: Duration, max: Duration },
+    #[error("timeout must be non-zero")]
+    ZeroTimeout,
+    #[error("deadline must be non-zero")]
+    ZeroDeadline,
+    #[error("batches must allow at least one item and one byte")]
+    EmptyBatch,
+    #[error("watermarks of {low} and {high} must be increasing and within the queue's capacity")]
+    Watermarks { high: usize, low: usize },
+}
+
+/// The settings of a Dispatcher that can be read from a config file, such as:
+///
+/// ```toml
+/// concurrency = 8
+/// capacity = 1000
+/// backpressure = "drop_oldest"
+/// order = "partitioned"
+/// timeout_ms = 5000
+///
+/// [retry]
+/// max_attempts = 5
+/// base_delay_ms = 200
+///
+/// [batch]
+/// max_items = 50
+/// max_linger_ms = 250
+/// ```
+///
+/// Anything left out has the same default as `DispatchOptions`, and a concurrency of 1.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct DispatcherConfig {
+    pub concurrency: usize,
+    pub capacity: usize,
+    pub backpressure: Backpressure,
+    pub order: DeliveryOrder,
+    pub timeout_ms: Option<u64>,
+    pub deadline_ms: Option<u64>,
+    pub retry: RetryConfig,
+    pub batch: Option<BatchConfig>,
+}
+
+impl Default for DispatcherConfig {
+    fn default() -> Self {
+        DispatcherConfig {
+            concurrency: 1,
+            capacity: 1,
+            backpressure: Backpressure::default(),
+            order: DeliveryOrder::default(),
+            timeout_ms: None,
+            deadline_ms: None,
+            retry: RetryConfig::default(),
+            batch: None,
+        }
+    }
+}
+
+/// A `RetryPolicy` in a config file.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RetryConfig {
+    pub max_attempts: usize,
+    pub max_throttled_attempts: usize,
+    pub base_delay_ms: u64,
+    pub max_delay_ms: u64,
+    pub jitter: bool,
+}
+
+impl Default for RetryConfig {
+    fn default() -> Self {
+        let policy = RetryPolicy::default();
+        RetryConfig {
+            max_attempts: policy.max_attempts,
+            max_throttled_attempts: policy.max_throttled_attempts,
+            base_delay_ms: policy.base_delay.as_millis() as u64,
+            max_delay_ms: policy.max_delay.as_millis() as u64,
+            jitter: policy.jitter,
+        }
+    }
+}
+
+impl From<&RetryConfig> for RetryPolicy {
+    fn from(config: &RetryConfig) -> Self {
+        RetryPolicy {
+            max_attempts: config.max_attempts,
+            max_throttled_attempts: config.max_throttled_attempts,
+            base_delay: Duration::from_millis(config.base_delay_ms),
+            max_delay: Duration::from_millis(config.max_delay_ms),
+            jitter: config.jitter,
+        }
+    }
+}
+
+/// A `BatchPolicy` in a config file.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct BatchConfig {
+    pub max_items: usize,
+    pub max_bytes: usize,
+    pub max_linger_ms: u64,
+    pub flush_interval_ms: Option<u64>,
+}
+
+impl Default for BatchConfig {
+    fn default() -> Self {
+        let policy = BatchPolicy::default();
+        BatchConfig {
+            max_items: policy.max_items,
+            max_bytes: policy.max_bytes,
+            max_linger_ms: policy.max_linger.as_millis() as u64,
+            flush_interval_ms: None,
+        }
+    }
+}
+
+impl From<&BatchConfig> for BatchPolicy {
+    fn from(config: &BatchConfig) -> Self {
+        BatchPolicy {
+            max_items: config.max_items,
+            max_bytes: config.max_bytes,
+            max_linger: Duration::from_millis(config.max_linger_ms),
+            flush_interval: config.flush_interval_ms.map(Duration::from_millis),
+        }
+    }
+}
+
+/// Builds a Dispatcher whose settings are checked first, rather than one that quietly treats a
+/// zero as one or never sends anything:
+///
+/// `DispatcherBuilder::new().concurrency(8).capacity(100).timeout(timeout).build(client, success)`
+///
+/// Settings that can't go in a config file, such as callbacks, are set on the builder.
+pub struct DispatcherBuilder<P = serde_json::Value> {
+    concurrency: usize,
+    options: DispatchOptions<P>,
+}
+
+impl<P: Payload> Default for DispatcherBuilder<P> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<P: Payload> DispatcherBuilder<P> {
+    pub fn new() -> Self {
+        Self::from_config(&DispatcherConfig::default())
+    }
+
+    pub fn from_config(config: &DispatcherConfig) -> Self {
+        let options = DispatchOptions {
+            retry: RetryPolicy::from(&config.retry),
+            batch: config.batch.as_ref().map(BatchPolicy::from),
+            capacity: config.capacity,
+            backpressure: config.backpressure,
+            order: config.order,
+            timeout: config.timeout_ms.map(Duration::from_millis),
+            deadline: config.deadline_ms.map(Duration::from_millis),
+            ..Default::default()
+        };
+
+        DispatcherBuilder {
+            concurrency: config.concurrency,
+            options,
+        }
+    }
+
+    pub fn concurrency(mut self, concurrency: usize) -> Self {
+        self.concurrency = concurrency;
+        self
+    }
+
+    pub fn capacity(mut self, capacity: usize) -> Self {
+        self.options.capacity = capacity;
+        self
+    }
+
+    pub fn backpressure(mut self, backpressure: Backpressure) -> Self {
+        self.options.backpressure = backpressure;
+        self
+    }
+
+    pub fn order(mut self, order: DeliveryOrder) -> Self {
+        self.options.order = order;
+        self
+    }
+
+    pub fn retry(mut self, retry: RetryPolicy) -> Self {
+        self.options.retry = retry;
+        self
+    }
+
+    pub fn batch(mut self, batch: BatchPolicy) -> Self {
+        self.options.batch = Some(batch);
+        self
+    }
+
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.options.timeout = Some(timeout);
+        self
+    }
+
+    pub fn deadline(mut self, deadline: Duration) -> Self {
+        self.options.deadline = Some(deadline);
+        self
+    }
+
+    pub fn dead_letter(mut self, sink: impl DeadLetterSink<P> + Send + Sync + 'static) -> Self {
+        self.options.dead_letter = Some(Box::new(sink));
+        self
+    }
+
+    pub fn on_error(
+        mut self,
+        on_error: impl Fn(&Request<P>, &DispatchError, usize) + Send + Sync + 'static,
+    ) -> Self {
+        self.options.on_error = Some(Box::new(on_error));
+        self
+    }
+
+    pub fn on_flush(mut self, on_flush: impl Fn(&FlushSummary) + Send + Sync + 'static) -> Self {
+        self.options.on_flush = Some(Box::new(on_flush));
+        self
+    }
+
+    pub fn rate_limit(mut self, rate_limit: RateLimit) -> Self {
+        self.options.rate_limit = Some(rate_limit);
+        self
+    }
+
+    pub fn circuit_breaker(mut self, breaker: CircuitBreaker) -> Self {
+        self.options.circuit_breaker = Some(breaker);
+        self
+    }
+
+    pub fn adaptive_concurrency(mut self, adaptive: Arc<AdaptiveConcurrency>) -> Self {
+        self.options.adaptive_concurrency = Some(adaptive);
+        self
+    }
+
+    /// Adds middleware to run after any that was added before.
+    pub fn middleware(mut self, middleware: impl Middleware<P> + Send + Sync + 'static) -> Self {
+        self.options.middleware.push(Box::new(middleware));
+        self
+    }
+
+    /// Adds hooks to run after any that were added before.
+    pub fn hooks(mut self, hooks: impl Hooks<P> + Send + Sync + 'static) -> Self {
+        self.options.hooks.push(Box::new(hooks));
+        self
+    }
+
+    pub fn journal(mut self, journal: Arc<Journal>) -> Self {
+        self.options.journal = Some(journal);
+        self
+    }
+
+    pub fn idempotency(mut self, idempotency: Arc<Idempotency>) -> Self {
+        self.options.idempotency = Some(idempotency);
+        self
+    }
+
+    pub fn cancellation(mut self, token: CancellationToken, on_cancel: OnCancel) -> Self {
+        self.options.cancellation = Some(token);
+        self.options.on_cancel = on_cancel;
+        self
+    }
+
+    pub fn watermarks(mut self, watermarks: Watermarks) -> Self {
+        self.options.watermarks = Some(watermarks);
+        self
+    }
+
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        let options = &self.options;
+
+        if self.concurrency == 0 {
+            return Err(ConfigError::ZeroConcurrency);
+        }
+        if options.capacity == 0 {
+            return Err(ConfigError::ZeroCapacity);
+        }
+        if options.retry.max_attempts == 0 {
+            return Err(ConfigError::ZeroAttempts);
+        }
+        let RetryPolicy {
+            base_delay: base,
+            max_delay: max,
+            ..
+        } = options.retry;
+        if base > max {
+            return Err(ConfigError::RetryDelays { base, max });
+        }
+        if options.timeout.is_some_and(|timeout| timeout.is_zero()) {
+            return Err(ConfigError::ZeroTimeout);
+        }
+        if options.deadline.is_some_and(|deadline| deadline.is_zero()) {
+            return Err(ConfigError::ZeroDeadline);
+        }
+        if let Some(batch) = &options.batch {
+            if batch.max_items == 0 || batch.max_bytes == 0 {
+                return Err(ConfigError::EmptyBatch);
+            }
+        }
+        if let Some(&Watermarks { high, low, .. }) = options.watermarks.as_ref() {
+            if low >= high || high > options.capacity {
+                return Err(ConfigError::Watermarks { high, low });
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Builds a Dispatcher as with `Dispatcher::with_options`.
+    pub fn build<T, F>(self, client: T, success: F) -> Result<Dispatcher<P>, ConfigError>
+    where
+        T: Client<P> + Send + Sync + 'static,
+        F: Fn(usize, &Reply) + Send + Sync + 'static,
+    {
+        self.validate()?;
+        Ok(Dispatcher::with_options(
+            self.concurrency,
+            client,
+            success,
+            self.options,
+        ))
+    }
+
+    /// Builds a handle and the consumer to run as with `Dispatcher::run`.
+    pub fn run<T, F>(
+        self,
+        client: T,
+        success: F,
+    ) -> Result<(DispatcherHandle<P>, impl Future<Output = ()> + Send), ConfigError>
+    where
+        T: Client<P> + Send + Sync + 'static,
+        F: Fn(usize, &Reply) + Send + Sync + 'static,
+    {
+        self.validate()?;
+        Ok(Dispatcher::run(
+            self.concurrency,
+            client,
+            success,
+            self.options,
+        ))
+    }
+}
+
+/// What posting does when the queue is full. Requests that are dropped resolve with
+/// `DispatchError::Dropped` if they are being tracked.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Backpressure {
+    /// Wait until there is room.
+    #[default]
+    Block,
+    /// Drop the request that has been queued the longest to make room, from the lowest priority
+    /// that's queued. If that's higher than the request being posted, that request is dropped
+    /// instead.
+    DropOldest,
+    /// Drop the request being posted.
+    DropNewest,
+    /// Fail with `DispatchError::QueueFull`.
+    Error,
+}
+
+/// A bounded queue between whoever is posting and the consumer. Unlike a channel, it can drop its
+/// oldest item to make room for a new one, and higher priority items skip ahead of lower ones.
+/// Delayed items are held aside until they're due, without counting towards the capacity.
+struct Queue<T> {
+    state: Mutex<QueueState<T>>,
+    capacity: usize,
+    pushed: Notify,
+    popped: Notify,
+    /// How many flushes are waiting. Batches are sent as soon as they're gathered while there are
+    /// any.
+    flushing: AtomicUsize,
+    /// Notified when a flush starts.
+    flush: Notify,
+    watermarks: Option<Watermarks>,
+}
+
+struct QueueState<T> {
+    /// The items waiting at each priority, lowest first.
+    items: [VecDeque<T>; Priority::LEVELS],
+    /// Items that can't be popped until they're due, soonest first. The sequence number keeps
+    /// items due at the same instant in the order they were pushed.
+    delayed: BTreeMap<(tokio::time::Instant, u64), (T, Priority)>,
+    sequence: u64,
+    closed: bool,
+    /// Nothing is popped while paused, though items can still be pushed.
+    paused: bool,
+    /// Whether the high watermark has been reached and the low one not since.
+    behind: bool,
+}
+
+impl<T> QueueState<T> {
+    fn len(&self) -> usize {
+        self.items.iter().map(VecDeque::len).sum()
+    }
+
+    /// Moves delayed items that are due onto the end of their priority's queue, even if that
+    /// takes it over capacity, since they were accepted when they were pushed.
+    fn promote(&mut self, now: tokio::time::Instant) {
+        while let Some(entry) = self.delayed.first_entry() {
+            if entry.key().0 > now {
+                break;
+            }
+            let (item, priority) = entry.remove();
+            self.items[priority as usize].push_back(item);
+        }
+    }
+}
+
+impl<T> Queue<T> {
+    fn new(capacity: usize) -> Self {
+        Queue {
+            state: Mutex::new(QueueState {
+                items: Default::default(),
+                delayed: BTreeMap::new(),
+                sequence: 0,
+                closed: false,
+                paused: false,
+                behind: false,
+            }),
+            capacity: capacity.max(1),
+            pushed: Notify::new(),
+            popped: Notify::new(),
+            flushing: AtomicUsize::new(0),
+            flush: Notify::new(),
+            watermarks: None,
+        }
+    }
+
+    fn with_watermarks(mut self, watermarks: Option<Watermarks>) -> Self {
+        self.watermarks = watermarks;
+        self
+    }
+
+    /// Releases the lock after the queue's depth has changed, then calls the watermark callback if
+    /// it crossed one.
+    fn changed(&self, mut state: MutexGuard<'_, QueueState<T>>) {
+        let Some(watermarks) = &self.watermarks else {
+            return;
+        };
+
+        let depth = state.len();
+        let callback = if !state.behind && depth >= watermarks.high {
+            state.behind = true;
+            &watermarks.on_high
+        } else if state.behind && depth <= watermarks.low {
+            state.behind = false;
+            &watermarks.on_low
+        } else {
+            return;
+        };
+        drop(state);
+        callback(depth);
+    }
+
+    /// Asks for batches to be sent without waiting for them to fill, until the returned guard is
+    /// dropped.
+    fn start_flush(&self) -> Decrement<'_> {
+        self.flushing.fetch_add(1, Ordering::Relaxed);
+        self.flush.notify_waiters();
+        Decrement(&self.flushing)
+    }
+
+    fn lock(&self) -> MutexGuard<'_, QueueState<T>> {
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Pushes an item, returning the item that was dropped instead if the queue was full and the
+    /// policy is to drop one.
+    async fn push(
+        &self,
+        item: T,
+        priority: Priority,
+        when_full: Backpressure,
+    ) -> Result<Option<T>, DispatchError> {
+        loop {
+            // Waiters must be registered before checking the queue so that a pop in between isn't
+            // missed.
+            let popped = self.popped.notified();
+
+            {
+                let mut state = self.lock();
+                if state.closed {
+                    return Err(DispatchError::SendFailed);
+                }
+
+                if state.len() < self.capacity {
+                    state.items[priority as usize].push_back(item);
+                    self.changed(state);
+                    self.pushed.notify_waiters();
+                    return Ok(None);
+                }
+
+                match when_full {
+                    Backpressure::Block => {}
+                    Backpressure::DropOldest => {
+                        // The queue is full, so some priority has items.
+                        let lowest = state.items.iter().position(|items| !items.is_empty());
+                        let lowest = lowest.unwrap_or_default();
+                        if lowest > priority as usize {
+                            return Ok(Some(item));
+                        }
+                        let oldest = state.items[lowest].pop_front();
+                        state.items[priority as usize].push_back(item);
+                        self.changed(state);
+                        self.pushed.notify_waiters();
+                        return Ok(oldest);
+                    }
+                    Backpressure::DropNewest => return Ok(Some(item)),
+                    Backpressure::Error => return Err(DispatchError::QueueFull),
+                }
+            }
+
+            popped.await;
+        }
+    }
+
+    /// Holds an item until `at`, after which it can be popped like any other.
+    fn push_delayed(
+        &self,
+        item: T,
+        priority: Priority,
+        at: tokio::time::Instant,
+    ) -> Result<(), DispatchError> {
+        let mut state = self.lock();
+        if state.closed {
+            return Err(DispatchError::SendFailed);
+        }
+
+        state.sequence += 1;
+        let key = (at, state.sequence);
+        state.delayed.insert(key, (item, priority));
+        drop(state);
+        // Wakes the consumer in case this is due sooner than what it's waiting for.
+        self.pushed.notify_waiters();
+        Ok(())
+    }
+
+    /// Pops the oldest item of the highest priority, waiting for one if the queue is empty or
+    /// paused. Returns `None` once the queue is closed and empty, including of delayed items.
+    async fn pop(&self) -> Option<T> {
+        loop {
+            let pushed = self.pushed.notified();
+
+            let due = {
+                let mut state = self.lock();
+                if !state.paused {
+                    state.promote(tokio::time::Instant::now());
+                    let item = state.items.iter_mut().rev().find_map(VecDeque::pop_front);
+                    if let Some(item) = item {
+                        self.changed(state);
+                        self.popped.notify_waiters();
+                        return Some(item);
+                    }
+                }
+
+                if state.closed && state.len() == 0 && state.delayed.is_empty() {
+                    return None;
+                }
+                match state.paused {
+                    // Resuming wakes whoever is waiting.
+                    true => None,
+                    false => state.delayed.keys().next().map(|&(at, _)| at),
+                }
+            };
+
+            match due {
+                Some(at) => {
+                    tokio::select! {
+                        _ = pushed => {}
+                        _ = tokio::time::sleep_until(at) => {}
+                    }
+                }
+                None => pushed.await,
+            }
+        }
+    }
+
+    fn set_paused(&self, paused: bool) {
+        self.lock().paused = paused;
+        self.pushed.notify_waiters();
+    }
+
+    /// Removes everything that's queued, including delayed items, returning how many there were.
+    fn clear(&self) -> usize {
+        let mut state = self.lock();
+        let items = std::mem::take(&mut state.items);
+        let delayed = std::mem::take(&mut state.delayed);
+        self.changed(state);
+        self.popped.notify_waiters();
+        items.iter().map(VecDeque::len).sum::<usize>() + delayed.len()
+    }
+
+    /// Pops items until the queue is closed and empty.
+    fn drain(self: Arc<Self>) -> impl futures::Stream<Item = T> {
+        futures::stream::unfold(self, |queue| async move {
+            let item = queue.pop().await?;
+            Some((item, queue))
+        })
+    }
+
+    /// Stops accepting new items. Items that are already queued can still be popped.
+    fn close(&self) {
+        self.lock().closed = true;
+        self.pushed.notify_waiters();
+        self.popped.notify_waiters();
+    }
+}
+
+/// Decrements a count when dropped, so that it's decremented even if whatever incremented it is
+/// cancelled.
+struct Decrement<'a>(&'a AtomicUsize);
+
+impl Drop for Decrement<'_> {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// A request waiting to be delivered, along with whoever is waiting to hear how it went.
+struct Queued<P> {
+    request: Request<P>,
+    done: Option<oneshot::Sender<Result<Reply, DispatchError>>>,
+    /// The request's journal entry, if there is a journal.
+    id: Option<u64>,
+    posted: tokio::time::Instant,
+    /// Covers the request from being posted until it's delivered or given up on.
+    span: tracing::Span,
+}
+
+impl<P> Queued<P> {
+    fn new(
+        request: Request<P>,
+        done: Option<oneshot::Sender<Result<Reply, DispatchError>>>,
+        id: Option<u64>,
+    ) -> Self {
+        let span = tracing::info_span!(
+            "payload",
+            method = %request.method,
+            url = request.url.as_ref().map(|url| url.as_str()),
+            partition = request.partition,
+            priority = ?request.priority,
+        );
+        tracing::trace!(parent: &span, "queued");
+
+        Queued {
+            request,
+            done,
+            id,
+            posted: tokio::time::Instant::now(),
+            span,
+        }
+    }
+}
+
+/// Resolves with the server's reply once a request posted with `DispatcherHandle::post_tracked`
+/// has been delivered, or with the error if it has failed to be after every retry.
+pub struct Delivery {
+    rx: oneshot::Receiver<Result<Reply, DispatchError>>,
+}
+
+impl Future for Delivery {
+    type Output = Result<Reply, DispatchError>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        Pin::new(&mut self.rx)
+            .poll(cx)
+            .map(|res| res.unwrap_or(Err(DispatchError::Abandoned)))
+    }
+}
+
+/// The upper bounds of the latency histogram's buckets.
+const LATENCY_BUCKETS: [Duration; 12] = [
+    Duration::from_millis(1),
+    Duration::from_millis(5),
+    Duration::from_millis(10),
+    Duration::from_millis(25),
+    Duration::from_millis(50),
+    Duration::from_millis(100),
+    Duration::from_millis(250),
+    Duration::from_millis(500),
+    Duration::from_secs(1),
+    Duration::from_secs(2),
+    Duration::from_secs(5),
+    Duration::from_secs(10),
+];
+
+/// Running totals of what happened to the requests that were posted.
+struct Counters {
+    accepted: AtomicUsize,
+    delivered: AtomicUsize,
+    failed: AtomicUsize,
+    dropped: AtomicUsize,
+    suppressed: AtomicUsize,
+    in_flight: AtomicUsize,
+    retried: AtomicUsize,
+    bytes: AtomicUsize,
+    /// One count per latency bucket, plus one for anything slower.
+    latency: [AtomicUsize; LATENCY_BUCKETS.len() + 1],
+    /// Notified whenever requests are delivered, fail or are dropped.
+    settled: Notify,
+    /// Set once the consumer has stopped, after which nothing else will settle.
+    stopped: AtomicBool,
+    on_flush: Option<FlushCallback>,
+    /// The totals as of the last summary, and when it was.
+    summarized: Mutex<(FlushSummary, tokio::time::Instant)>,
+}
+
+impl Counters {
+    fn new(on_flush: Option<FlushCallback>) -> Self {
+        Counters {
+            accepted: AtomicUsize::new(0),
+            delivered: AtomicUsize::new(0),
+            failed: AtomicUsize::new(0),
+            dropped: AtomicUsize::new(0),
+            suppressed: AtomicUsize::new(0),
+            in_flight: AtomicUsize::new(0),
+            retried: AtomicUsize::new(0),
+            bytes: AtomicUsize::new(0),
+            latency: Default::default(),
+            settled: Notify::new(),
+            stopped: AtomicBool::new(false),
+            on_flush,
+            summarized: Mutex::new((FlushSummary::default(), tokio::time::Instant::now())),
+        }
+    }
+
+    /// Returns what has happened since the last summary, calling the flush callback with it.
+    fn summarize(&self) -> FlushSummary {
+        let load = |counter: &AtomicUsize| counter.load(Ordering::Relaxed);
+        let totals = FlushSummary {
+            delivered: load(&self.delivered),
+            failed: load(&self.failed),
+            dropped: load(&self.dropped),
+            retried: load(&self.retried),
+            bytes: load(&self.bytes),
+            elapsed: Duration::ZERO,
+        };
+
+        let summary = {
+            let mut summarized = lock(&self.summarized);
+            let (last, at) = &*summarized;
+            let summary = FlushSummary {
+                delivered: totals.delivered - last.delivered,
+                failed: totals.failed - last.failed,
+                dropped: totals.dropped - last.dropped,
+                retried: totals.retried - last.retried,
+                bytes: totals.bytes - last.bytes,
+                elapsed: at.elapsed(),
+            };
+            *summarized = (totals, tokio::time::Instant::now());
+            summary
+        };
+        if let Some(on_flush) = &self.on_flush {
+            on_flush(&summary);
+        }
+        summary
+    }
+
+    /// Counts a request as accepted. This is done before it's pushed, since the consumer can take
+    /// it and finish with it before the push returns, and a finished request that was never
+    /// accepted would make the counters look idle too soon.
+    fn accept(&self) {
+        self.accepted.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Takes back `accept` for a request that couldn't be pushed after all.
+    fn unaccept(&self) {
+        self.accepted.fetch_sub(1, Ordering::Relaxed);
+        self.settled.notify_waiters();
+    }
+
+    /// Whether every request that was accepted has been delivered, has failed or was dropped.
+    fn idle(&self) -> bool {
+        let load = |counter: &AtomicUsize| counter.load(Ordering::Relaxed);
+        let finished = load(&self.delivered) + load(&self.failed) + load(&self.dropped);
+        self.stopped.load(Ordering::Relaxed) || finished >= load(&self.accepted)
+    }
+
+    /// Waits until `idle`, including for anything accepted while waiting.
+    async fn wait_idle(&self) {
+        loop {
+            let settled = self.settled.notified();
+            if self.idle() {
+                break;
+            }
+            settled.await;
+        }
+    }
+
+    fn observe(&self, latency: Duration) {
+        let bucket = LATENCY_BUCKETS
+            .iter()
+            .position(|&bound| latency <= bound)
+            .unwrap_or(LATENCY_BUCKETS.len());
+        self.latency[bucket].fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+/// Marks the consumer as stopped when it's dropped, whether it finished or was aborted.
+struct Stopped<'a>(&'a Counters);
+
+impl Drop for Stopped<'_> {
+    fn drop(&mut self) {
+        self.0.stopped.store(true, Ordering::Relaxed);
+        self.0.settled.notify_waiters();
+    }
+}
+
+/// The spawned task taking items off a queue, for both the Dispatcher and the Executor. Dropping
+/// it closes the queue, which lets the task finish what's queued and exit, as a dropped channel
+/// sender would.
+struct Consumer<T> {
+    queue: Arc<Queue<T>>,
+    task: tokio::task::JoinHandle<()>,
+}
+
+impl<T> Consumer<T> {
+    fn spawn(queue: Arc<Queue<T>>, task: impl Future<Output = ()> + Send + 'static) -> Self {
+        Consumer {
+            queue,
+            task: tokio::spawn(task),
+        }
+    }
+
+    /// Closes the queue and waits for the task to finish what was already queued.
+    async fn close(&mut self) -> Result<(), DispatchError> {
+        self.queue.close();
+        (&mut self.task)
+            .await
+            .map_err(|_| DispatchError::FlushFailed)
+    }
+}
+
+impl<T> Drop for Consumer<T> {
+    fn drop(&mut self) {
+        self.queue.close();
+    }
+}
+
+/// A snapshot of what the Dispatcher has done so far, and what it's doing now.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Metrics {
+    pub accepted: usize,
+    pub delivered: usize,
+    pub failed: usize,
+    /// Requests dropped from a full queue.
+    pub dropped: usize,
+    /// Duplicate posts that weren't queued.
+    pub suppressed: usize,
+    /// Requests waiting in the queue.
+    pub queued: usize,
+    /// Requests posted with a delay that aren't due yet.
+    pub delayed: usize,
+    /// Requests taken off the queue that haven't been delivered or failed yet.
+    pub in_flight: usize,
+    pub latency: LatencyHistogram,
+}
+
+/// How long requests took from being posted until they were delivered or failed.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LatencyHistogram {
+    /// How many requests took at most each duration, but longer than the one before.
+    pub buckets: Vec<(Duration, usize)>,
+    /// How many requests took longer than the last bucket.
+    pub slower: usize,
+}
+
+impl LatencyHistogram {
+    pub fn count(&self) -> usize {
+        self.buckets.iter().map(|(_, count)| count).sum::<usize>() + self.slower
+    }
+}
+
+/// What happened to the requests that were posted by the time the Dispatcher was shut down.
+/// Abandoned requests were still queued or in flight when the deadline passed.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ShutdownReport {
+    pub delivered: usize,
+    pub failed: usize,
+    pub abandoned: usize,
+}
+
+/// How many failed requests are kept to be returned by `flush` or `shutdown`. Any more are only
+/// counted, so that a Dispatcher that's never flushed doesn't hold on to every failure.
+const KEPT_FAILURES: usize = 1000;
+
+/// Requests that failed and weren't given to a dead-letter sink, waiting to be returned by the
+/// next flush or shutdown.
+type Failures<P> = Mutex<Vec<(Request<P>, DispatchError)>>;
+
+/// Returned by `DispatcherHandle::flush` and `Dispatcher::shutdown` when requests weren't
+/// delivered. For a flush, the counts are since the previous flush, and nothing is abandoned. For
+/// a shutdown, they're since the Dispatcher started.
+#[derive(Error, Debug, Clone)]
+#[error("{failed} requests failed and {abandoned} were abandoned")]
+pub struct DeliveryFailures<P = serde_json::Value> {
+    pub delivered: usize,
+    pub failed: usize,
+    pub abandoned: usize,
+    /// The requests that failed and why, unless they went to the dead-letter sink, that haven't
+    /// been returned by an earlier flush. At most the first thousand are kept.
+    pub requests: Vec<(Request<P>, DispatchError)>,
+}
+
+/// Delivers payloads of type `P` with a `Client`, with up to `concurrency` deliveries in flight.
+///
+/// Posting is done through a [`DispatcherHandle`], which the Dispatcher derefs to. Handles can be
+/// cloned and given to other tasks, while the Dispatcher itself stays with whoever closes or
+/// shuts it down.
+pub struct Dispatcher<P = serde_json::Value> {
+    handle: DispatcherHandle<P>,
+    executor: Executor<Vec<Queued<P>>, Queued<P>>,
+}
+
+impl<P> Deref for Dispatcher<P> {
+    type Target = DispatcherHandle<P>;
+
+    fn deref(&self) -> &Self::Target {
+        &self.handle
+    }
+}
+
+/// Posts payloads to a Dispatcher. Handles are cheap to clone, and posting through one fails with
+/// `DispatchError::SendFailed` once the Dispatcher has been closed, shut down or dropped, or
+/// once every handle has been dropped when it was created with `Dispatcher::run`.
+pub struct DispatcherHandle<P = serde_json::Value> {
+    queue: Arc<Queue<Queued<P>>>,
+    backpressure: Backpressure,
+    counters: Arc<Counters>,
+    journal: Option<Arc<Journal>>,
+    idempotency: Option<Arc<Idempotency>>,
+    failures: Arc<Failures<P>>,
+    open: Arc<CloseOnDrop<Queued<P>>>,
+}
+
+/// Closes the queue once the last handle sharing it is dropped.
+struct CloseOnDrop<T>(Arc<Queue<T>>);
+
+impl<T> Drop for CloseOnDrop<T> {
+    fn drop(&mut self) {
+        self.0.close();
+    }
+}
+
+impl<P> Clone for DispatcherHandle<P> {
+    fn clone(&self) -> Self {
+        DispatcherHandle {
+            queue: self.queue.clone(),
+            backpressure: self.backpressure,
+            counters: self.counters.clone(),
+            journal: self.journal.clone(),
+            idempotency: self.idempotency.clone(),
+            failures: self.failures.clone(),
+            open: self.open.clone(),
+        }
+    }
+}
+
+impl<P: Payload> DispatcherHandle<P> {
+    pub async fn post(&self, body: P) -> Result<(), DispatchError> {
+        self.post_request(Request::new(body)).await
+    }
+
+    /// Posts a body with its own method, URL or headers.
+    pub async fn post_request(&self, request: Request<P>) -> Result<(), DispatchError> {
+        self.enqueue(request, None, self.backpressure, None, None)
+            .await
+    }
+
+    /// Posts a body that's held back until `delay` has passed, then delivered like any other.
+    pub async fn post_after(&self, body: P, delay: Duration) -> Result<(), DispatchError> {
+        self.post_at(body, tokio::time::Instant::now() + delay)
+            .await
+    }
+
+    /// Posts a body that's held back until `at`, then delivered like any other. Requests that
+    /// aren't due yet don't take up room in the queue, so this never waits or drops anything,
+    /// but closing the Dispatcher or flushing waits for them to be delivered. Deadlines are from
+    /// when the request is due rather than when it was posted. A journal doesn't keep the delay,
+    /// so requests resumed from one are sent straight away.
+    pub async fn post_at(&self, body: P, at: tokio::time::Instant) -> Result<(), DispatchError> {
+        let request = Request::new(body);
+        self.enqueue(request, None, self.backpressure, None, Some(at))
+            .await
+    }
+
+    /// Posts a body, waiting at most `timeout` for room in the queue before failing with
+    /// `DispatchError::EnqueueTimedOut`.
+    pub async fn post_timeout(&self, body: P, timeout: Duration) -> Result<(), DispatchError> {
+        let request = Request::new(body);
+        self.enqueue(request, None, self.backpressure, Some(timeout), None)
+            .await
+    }
+
+    /// Posts a body without waiting for room in the queue. If the queue is full and the
+    /// backpressure policy would block, this fails with `DispatchError::QueueFull` instead.
+    pub async fn try_post(&self, body: P) -> Result<(), DispatchError> {
+        let when_full = match self.backpressure {
+            Backpressure::Block => Backpressure::Error,
+            other => other,
+        };
+        self.enqueue(Request::new(body), None, when_full, None, None)
+            .await
+    }
+
+    /// Posts a body and waits until it has been delivered, returning the server's reply, or the
+    /// error if it couldn't be.
+    pub async fn post_and_wait(&self, body: P) -> Result<Reply, DispatchError> {
+        self.post_tracked(Request::new(body)).await?.await
+    }
+
+    /// Posts a request, returning a future that resolves once that request has been delivered or
+    /// has failed. This allows posting many requests before waiting on any of them.
+    pub async fn post_tracked(&self, request: Request<P>) -> Result<Delivery, DispatchError> {
+        let (tx, rx) = oneshot::channel();
+        self.enqueue(request, Some(tx), self.backpressure, None, None)
+            .await?;
+
+        Ok(Delivery { rx })
+    }
+
+    /// Queues a request, or holds it until `at` if that's given. Duplicates that are suppressed
+    /// aren't queued, and are reported as delivered to anyone waiting on them.
+    async fn enqueue(
+        &self,
+        mut request: Request<P>,
+        done: Option<oneshot::Sender<Result<Reply, DispatchError>>>,
+        when_full: Backpressure,
+        timeout: Option<Duration>,
+        at: Option<tokio::time::Instant>,
+    ) -> Result<(), DispatchError> {
+        if let Some(idempotency) = &self.idempotency {
+            if !idempotency.admit(&mut request)? {
+                self.counters.suppressed.fetch_add(1, Ordering::Relaxed);
+                tracing::debug!(key = request.idempotency_key, "suppressed duplicate");
+                if let Some(done) = done {
+                    let _ = done.send(Ok(Reply::default()));
+                }
+                return Ok(());
+            }
+        }
+
+        let id = match &self.journal {
+            Some(journal) => Some(journal.record(&request).await?),
+            None => None,
+        };
+
+        let mut queued = Queued::new(request, done, id);
+        let Some(at) = at else {
+            return self.push(queued, when_full, timeout).await;
+        };
+
+        // Latency and deadlines count from when the request is due.
+        queued.posted = at;
+        let priority = queued.request.priority;
+        self.counters.accept();
+        if let Err(e) = self.queue.push_delayed(queued, priority, at) {
+            self.counters.unaccept();
+            self.ack(id);
+            return Err(e);
+        }
+
+        Ok(())
+    }
+
+    async fn push(
+        &self,
+        queued: Queued<P>,
+        when_full: Backpressure,
+        timeout: Option<Duration>,
+    ) -> Result<(), DispatchError> {
+        let id = queued.id;
+        let priority = queued.request.priority;
+        self.counters.accept();
+        let pushed = self.queue.push(queued, priority, when_full);
+        let pushed = match timeout {
+            Some(timeout) => tokio::time::timeout(timeout, pushed)
+                .await
+                .unwrap_or(Err(DispatchError::EnqueueTimedOut)),
+            None => pushed.await,
+        };
+        let dropped = match pushed {
+            Ok(dropped) => dropped,
+            Err(e) => {
+                self.counters.unaccept();
+                self.ack(id);
+                return Err(e);
+            }
+        };
+
+        if let Some(dropped) = dropped {
+            self.counters.dropped.fetch_add(1, Ordering::Relaxed);
+            self.counters.settled.notify_waiters();
+            self.ack(dropped.id);
+            if let Some(done) = dropped.done {
+                let _ = done.send(Err(DispatchError::Dropped));
+            }
+        }
+
+        Ok(())
+    }
+
+    fn ack(&self, id: Option<u64>) {
+        if let (Some(journal), Some(id)) = (&self.journal, id) {
+            journal.ack(id);
+        }
+    }
+
+    /// Waits for the journal to write the acknowledgements of everything that has finished.
+    async fn flush_journal(&self) {
+        if let Some(journal) = &self.journal {
+            journal.flush().await;
+        }
+    }
+
+    /// Posts the requests that were still pending in the journal when it was opened, returning
+    /// how many there were. This should be called before posting anything else, so that they go
+    /// out first.
+    pub async fn recover(&self) -> Result<usize, DispatchError>
+    where
+        P: DeserializeOwned,
+    {
+        let Some(journal) = &self.journal else {
+            return Ok(0);
+        };
+
+        let recovered = journal.recover();
+        let count = recovered.len();
+        for (id, request) in recovered {
+            let queued = Queued::new(request, None, Some(id));
+            self.push(queued, self.backpressure, None).await?;
+        }
+
+        Ok(count)
+    }
+
+    /// Waits until everything posted so far has been delivered or has failed, sending batches
+    /// that are being gathered right away rather than waiting for them to fill. Anything posted
+    /// while waiting is waited for too, so this returns once the Dispatcher is momentarily idle.
+    /// Unlike `Dispatcher::close`, posting can carry on afterwards.
+    ///
+    /// Fails if any requests failed since the previous flush, with those requests.
+    pub async fn flush(&self) -> Result<(), DeliveryFailures<P>> {
+        let _flushing = self.queue.start_flush();
+        self.counters.wait_idle().await;
+
+        let summary = self.counters.summarize();
+        if summary.failed == 0 {
+            return Ok(());
+        }
+        Err(DeliveryFailures {
+            delivered: summary.delivered,
+            failed: summary.failed,
+            abandoned: 0,
+            requests: std::mem::take(&mut *lock(&self.failures)),
+        })
+    }
+
+    /// Stops taking requests off the queue, such as while the server is down for maintenance.
+    /// Requests that are already in flight carry on, and requests can still be posted, but they
+    /// wait in the queue until `resume` is called. Flushing or closing a paused Dispatcher waits
+    /// until it's resumed.
+    pub fn pause(&self) {
+        tracing::info!("paused");
+        self.queue.set_paused(true);
+    }
+
+    /// Starts taking requests off the queue again after `pause`.
+    pub fn resume(&self) {
+        tracing::info!("resumed");
+        self.queue.set_paused(false);
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.queue.lock().paused
+    }
+
+    pub fn metrics(&self) -> Metrics {
+        let load = |counter: &AtomicUsize| counter.load(Ordering::Relaxed);
+
+        let buckets = LATENCY_BUCKETS
+            .iter()
+            .zip(&self.counters.latency)
+            .map(|(&bound, count)| (bound, load(count)))
+            .collect();
+
+        let (queued, delayed) = {
+            let state = self.queue.lock();
+            (state.len(), state.delayed.len())
+        };
+
+        Metrics {
+            accepted: load(&self.counters.accepted),
+            delivered: load(&self.counters.delivered),
+            failed: load(&self.counters.failed),
+            dropped: load(&self.counters.dropped),
+            suppressed: load(&self.counters.suppressed),
+            queued,
+            delayed,
+            in_flight: load(&self.counters.in_flight),
+            latency: LatencyHistogram {
+                buckets,
+                slower: load(&self.counters.latency[LATENCY_BUCKETS.len()]),
+            },
+        }
+    }
+}
+
+impl<P: Payload> Dispatcher<P> {
+    pub fn new<T, F>(concurrency: usize, client: T, success: F) -> Self
+    where
+        T: Client<P> + Send + Sync + 'static,
+        F: Fn(usize, &Reply) + Send + Sync + 'static,
+    {
+        Self::with_options(concurrency, client, success, DispatchOptions::default())
+    }
+
+    pub fn with_options<T, F>(
+        concurrency: usize,
+        client: T,
+        success: F,
+        options: DispatchOptions<P>,
+    ) -> Self
+    where
+        T: Client<P> + Send + Sync + 'static,
+        F: Fn(usize, &Reply) + Send + Sync + 'static,
+    {
+        let (handle, consumer) = Self::run(concurrency, client, success, options);
+
+        Dispatcher {
+            executor: Executor::spawn(
+                handle.queue.clone(),
+                handle.counters.clone(),
+                handle.backpressure,
+                consumer,
+            ),
+            handle,
+        }
+    }
+
+    /// Like `with_options`, but rather than spawning the consumer onto the tokio runtime, this
+    /// returns it as a future for the caller to run however they like, such as on a `LocalSet` or
+    /// in a `select!` alongside other work. The future completes once every handle has been
+    /// dropped and what was posted has been delivered or failed.
+    ///
+    /// Timeouts, retry delays and batching still use tokio's timers, so the future must be polled
+    /// from within a tokio runtime with time enabled.
+    pub fn run<T, F>(
+        concurrency: usize,
+        client: T,
+        success: F,
+        mut options: DispatchOptions<P>,
+    ) -> (DispatcherHandle<P>, impl Future<Output = ()> + Send)
+    where
+        T: Client<P> + Send + Sync + 'static,
+        F: Fn(usize, &Reply) + Send + Sync + 'static,
+    {
+        let queue = Queue::new(options.capacity).with_watermarks(options.watermarks.take());
+        let queue = Arc::new(queue);
+        let counters = Arc::new(Counters::new(options.on_flush.take()));
+
+        let handle = DispatcherHandle {
+            queue: queue.clone(),
+            backpressure: options.backpressure,
+            counters: counters.clone(),
+            journal: options.journal.clone(),
+            idempotency: options.idempotency.clone(),
+            failures: options.failures.clone(),
+            open: Arc::new(CloseOnDrop(queue.clone())),
+        };
+        let consumer = Self::consume(concurrency, queue, counters, client, success, options);
+
+        (handle, consumer)
+    }
+
+    /// A handle for posting from elsewhere, such as another task.
+    pub fn handle(&self) -> DispatcherHandle<P> {
+        self.handle.clone()
+    }
+
+    /// The Dispatcher's consumer: an Executor whose jobs are batches, or single requests when not
+    /// batching, and which runs each one by delivering it.
+    async fn consume<T, F>(
+        concurrency: usize,
+        queue: Arc<Queue<Queued<P>>>,
+        counters: Arc<Counters>,
+        client: T,
+        success: F,
+        options: DispatchOptions<P>,
+    ) where
+        T: Client<P> + Send + Sync + 'static,
+        F: Fn(usize, &Reply),
+    {
+        let mut count = 0;
+        let finish = |res: Result<Vec<Reply>, (Vec<Request<P>>, DispatchError)>| match res {
+            Ok(replies) => {
+                counters
+                    .delivered
+                    .fetch_add(replies.len(), Ordering::Relaxed);
+                for reply in &replies {
+                    success(count, reply);
+                    count += 1;
+                }
+            }
+            Err((requests, e)) => {
+                counters.failed.fetch_add(requests.len(), Ordering::Relaxed);
+
+                match &options.dead_letter {
+                    Some(sink) => sink.dead_letter(requests, e),
+                    None => {
+                        // Errors have already been reported if there's an error callback.
+                        if options.on_error.is_none() {
+                            let count = requests.len();
+                            tracing::error!(error = %e, count, "delivery failed");
+                        }
+                        let mut failures = lock(&options.failures);
+                        let room = KEPT_FAILURES.saturating_sub(failures.len());
+                        let kept = requests.into_iter().take(room);
+                        failures.extend(kept.map(|request| (request, e.clone())));
+                    }
+                }
+            }
+        };
+
+        let concurrency = match &options.adaptive_concurrency {
+            Some(adaptive) => adaptive.max,
+            None => concurrency,
+        };
+        let (concurrency, partition) = match options.order {
+            DeliveryOrder::Unordered => (concurrency, None),
+            DeliveryOrder::Partitioned if options.batch.is_none() => {
+                let key: fn(&Vec<Queued<P>>) -> Option<String> =
+                    |batch| batch[0].request.partition.clone();
+                (concurrency, Some(key))
+            }
+            DeliveryOrder::Fifo | DeliveryOrder::Partitioned => (1, None),
+        };
+
+        let jobs = match options.batch.clone() {
+            Some(policy) => policy.batches(queue.clone()).boxed(),
+            None => queue.clone().drain().map(|queued| vec![queued]).boxed(),
+        };
+        let schedule = Schedule {
+            queue,
+            jobs,
+            concurrency,
+            partition,
+            cancellation: options
+                .cancellation
+                .clone()
+                .map(|token| (token, options.on_cancel)),
+        };
+
+        let deliver = |batch| Self::deliver(&client, &counters, &options, batch);
+        schedule.run(&counters, deliver, finish).await;
+    }
+
+    /// Delivers a batch, or a single request when not batching, returning the reply to each
+    /// request. Anyone waiting on the requests is told how it went.
+    async fn deliver<T: Client<P> + Sync>(
+        client: &T,
+        counters: &Counters,
+        options: &DispatchOptions<P>,
+        batch: Vec<Queued<P>>,
+    ) -> Result<Vec<Reply>, (Vec<Request<P>>, DispatchError)> {
+        counters.in_flight.fetch_add(batch.len(), Ordering::Relaxed);
+
+        let mut waiters = Vec::new();
+        let mut ids = Vec::new();
+        let mut posted = Vec::new();
+        let mut spans = Vec::new();
+        let requests: Vec<_> = batch
+            .into_iter()
+            .map(|q| {
+                waiters.push(q.done);
+                ids.extend(q.id);
+                posted.push(q.posted);
+                spans.push(q.span);
+                q.request
+            })
+            .collect();
+
+        // A request is sent in its own span, and a batch in one that follows from the spans of
+        // the requests in it.
+        let span = match &spans[..] {
+            [span] => span.clone(),
+            spans => {
+                let batch = tracing::info_span!("batch", size = spans.len());
+                for span in spans {
+                    batch.follows_from(span);
+                }
+                batch
+            }
+        };
+
+        let deadline = options
+            .deadline
+            .zip(posted.iter().min())
+            .map(|(deadline, &oldest)| oldest + deadline);
+        // Only worth encoding the payloads again if someone will see the total.
+        let bytes = match counters.on_flush {
+            Some(_) => requests.iter().map(|r| encoded_len(&r.body)).sum(),
+            None => 0,
+        };
+        let res = Self::send(client, counters, options, requests, deadline)
+            .instrument(span)
+            .await;
+        if res.is_ok() {
+            counters.bytes.fetch_add(bytes, Ordering::Relaxed);
+        }
+
+        counters
+            .in_flight
+            .fetch_sub(posted.len(), Ordering::Relaxed);
+        for (idx, (posted, span)) in posted.into_iter().zip(&spans).enumerate() {
+            let latency = posted.elapsed();
+            counters.observe(latency);
+
+            let latency_ms = latency.as_millis() as u64;
+            match &res {
+                Ok(replies) => {
+                    let status = replies.get(idx).map(|reply| reply.status.as_u16());
+                    tracing::debug!(parent: span, status, latency_ms, "delivered");
+                }
+                Err((_, e)) => tracing::warn!(parent: span, error = %e, latency_ms, "failed"),
+            }
+        }
+
+        if let (Err((requests, _)), Some(idempotency)) = (&res, &options.idempotency) {
+            for key in requests.iter().filter_map(|r| r.idempotency_key.as_ref()) {
+                idempotency.forget(key);
+            }
+        }
+        // The callers may have stopped waiting, which is fine.
+        match &res {
+            Ok(replies) => {
+                for (waiter, reply) in waiters.into_iter().zip(replies) {
+                    if let Some(waiter) = waiter {
+                        let _ = waiter.send(Ok(reply.clone()));
+                    }
+                }
+            }
+            Err((_, e)) => {
+                for waiter in waiters.into_iter().flatten() {
+                    let _ = waiter.send(Err(e.clone()));
+                }
+            }
+        }
+        if let Some(journal) = &options.journal {
+            for id in ids {
+                journal.ack(id);
+            }
+        }
+
+        res
+    }
+
+    async fn send<T: Client<P> + Sync>(
+        client: &T,
+        counters: &Counters,
+        options: &DispatchOptions<P>,
+        mut batch: Vec<Request<P>>,
+        deadline: Option<tokio::time::Instant>,
+    ) -> Result<Vec<Reply>, (Vec<Request<P>>, DispatchError)> {
+        let count = batch.len();
+
+        if options.batch.is_some() {
+            let report = |batch: &Vec<_>, e: &_, attempt| {
+                for request in batch {
+                    options.report(request, e, attempt);
+                }
+            };
+            let send = |b: Vec<_>| async move {
+                let batch: Vec<_> = b.into_iter().map(|r| options.prepare(r)).collect();
+                // Requests are only kept for the hooks if there are any.
+                let sent = (!options.hooks.is_empty()).then(|| batch.clone());
+                let start = tokio::time::Instant::now();
+                let res = options.attempt(client.post_batch(batch), deadline).await;
+                if let Some(sent) = sent {
+                    let replies = res.as_ref().map(Vec::as_slice);
+                    options.after_send(&sent, replies, start.elapsed());
+                }
+                res
+            };
+            let mut replies =
+                Self::with_retry(options, counters, deadline, batch, send, report).await?;
+
+            // Every request gets the batch's reply if there's only one.
+            let last = replies.last().cloned().unwrap_or_default();
+            replies.resize(count, last);
+            Ok(replies)
+        } else {
+            let request = batch
+                .pop()
+                .expect("unbatched requests are sent one at a time");
+            let report = |request: &_, e: &_, attempt| options.report(request, e, attempt);
+            let send = |r| async move {
+                let request = options.prepare(r);
+                let sent = (!options.hooks.is_empty()).then(|| request.clone());
+                let start = tokio::time::Instant::now();
+                let res = options.attempt(client.post(request), deadline).await;
+                if let Some(sent) = sent {
+                    let reply = res.as_ref().map(std::slice::from_ref);
+                    options.after_send(std::slice::from_ref(&sent), reply, start.elapsed());
+                }
+                res
+            };
+            let reply = Self::with_retry(options, counters, deadline, request, send, report)
+                .await
+                .map_err(|(request, e)| (vec![request], e))?;
+            Ok(vec![reply])
+        }
+    }
+
+    /// Sends until the body is delivered or the retry policy gives up. When the server is
+    /// throttling requests, sending is paused for as long as it asks, or the retry delay if it
+    /// doesn't say, and the attempt counts towards the retry policy's limit on throttled attempts
+    /// rather than its limit on failed ones. Nothing is retried once it would be past the
+    /// deadline.
+    async fn with_retry<B, T, S, Fut, R>(
+        options: &DispatchOptions<P>,
+        counters: &Counters,
+        deadline: Option<tokio::time::Instant>,
+        body: B,
+        send: S,
+        report: R,
+    ) -> Result<T, (B, DispatchError)>
+    where
+        B: Clone,
+        S: Fn(B) -> Fut,
+        Fut: Future<Output = Result<T, DispatchError>>,
+        R: Fn(&B, &DispatchError, usize),
+    {
+        let retry = &options.retry;
+
+        let mut attempt = 1;
+        let mut throttled = 0;
+        loop {
+            match send(body.clone()).await {
+                Ok(reply) => return Ok(reply),
+                Err(e @ DispatchError::Throttled { retry_after }) => {
+                    report(&body, &e, attempt);
+                    throttled += 1;
+                    if throttled >= retry.max_throttled_attempts {
+                        return Err((body, e));
+                    }
+
+                    let pause = retry_after.unwrap_or_else(|| retry.delay(throttled));
+                    let resume_at = tokio::time::Instant::now() + pause;
+                    if deadline.is_some_and(|deadline| resume_at >= deadline) {
+                        tracing::debug!(attempt, "deadline passes before throttling ends");
+                        return Err((body, DispatchError::DeadlineExceeded));
+                    }
+                    tracing::info!(throttled, pause_ms = pause.as_millis() as u64, "throttled");
+                    options.throttle.pause(pause);
+                    counters.retried.fetch_add(1, Ordering::Relaxed);
+                    continue;
+                }
+                Err(e) => {
+                    report(&body, &e, attempt);
+                    // Only throttled attempts in a row count towards the limit on them.
+                    throttled = 0;
+                    if attempt >= retry.max_attempts || !e.retryable() {
+                        return Err((body, e));
+                    }
+                    tracing::info!(attempt, error = %e, "attempt failed, retrying");
+                }
+            }
+
+            let delay = retry.delay(attempt);
+            if deadline.is_some_and(|deadline| tokio::time::Instant::now() + delay >= deadline) {
+                tracing::debug!(attempt, "deadline passes before the next retry");
+                return Err((body, DispatchError::DeadlineExceeded));
+            }
+            tokio::time::sleep(delay).await;
+            counters.retried.fetch_add(1, Ordering::Relaxed);
+            attempt += 1;
+        }
+    }
+
+    /// Stops accepting new posts and waits up to `timeout` for what has already been posted to be
+    /// delivered. Anything still queued or in flight after that is abandoned. Fails if any
+    /// requests failed or were abandoned.
+    pub async fn shutdown(
+        mut self,
+        timeout: Duration,
+    ) -> Result<ShutdownReport, DeliveryFailures<P>> {
+        let consumer = &mut self.executor.consumer;
+        if tokio::time::timeout(timeout, consumer.close())
+            .await
+            .is_err()
+        {
+            consumer.task.abort();
+            // Waiting for the abort means the counters can't change any more.
+            let _ = (&mut consumer.task).await;
+        }
+        self.flush_journal().await;
+
+        let load = |counter: &AtomicUsize| counter.load(Ordering::Relaxed);
+        let delivered = load(&self.counters.delivered);
+        let failed = load(&self.counters.failed);
+        let finished = delivered + failed + load(&self.counters.dropped);
+
+        let abandoned = load(&self.counters.accepted).saturating_sub(finished);
+
+        if failed == 0 && abandoned == 0 {
+            return Ok(ShutdownReport {
+                delivered,
+                failed,
+                abandoned,
+            });
+        }
+        Err(DeliveryFailures {
+            delivered,
+            failed,
+            abandoned,
+            requests: std::mem::take(&mut *lock(&self.failures)),
+        })
+    }
+
+    /// Stops accepting new posts and waits for what has already been posted to be delivered or to
+    /// fail.
+    pub async fn close(mut self) -> Result<(), DispatchError> {
+        let res = self.executor.consumer.close().await;
+        self.flush_journal().await;
+        res
+    }
+}
+
+/// Runs `run` on each job from `jobs` with up to `concurrency` running at once, handing each
+/// result to `finish` as soon as it's done.
+async fn run_jobs<J, R, Fut>(
+    jobs: impl futures::Stream<Item = J>,
+    concurrency: usize,
+    run: R,
+    mut finish: impl FnMut(Fut::Output),
+) where
+    R: FnMut(J) -> Fut,
+    Fut: Future,
+{
+    let results = jobs.map(run).buffer_unordered(concurrency.max(1));
+    futures::pin_mut!(results);
+
+    while let Some(res) = results.next().await {
+        finish(res);
+    }
+}
+
+/// Like `run_jobs`, but keeping at most one job per key in flight. Jobs for a key that's busy
+/// wait in that key's lane until the one ahead of them is done, and jobs without a key run
+/// whenever there's room.
+async fn run_partitioned<J, R, Fut>(
+    jobs: impl futures::Stream<Item = J>,
+    concurrency: usize,
+    key: fn(&J) -> Option<String>,
+    mut run: R,
+    mut finish: impl FnMut(Fut::Output),
+) where
+    R: FnMut(J) -> Fut,
+    Fut: Future,
+{
+    let mut start = |job: J| {
+        let key = key(&job);
+        let done = run(job);
+        async move { (key, done.await) }
+    };
+    futures::pin_mut!(jobs);
+
+    let mut in_flight = FuturesUnordered::new();
+    let mut lanes: HashMap<String, VecDeque<J>> = HashMap::new();
+    let mut waiting = 0;
+    let mut closed = false;
+
+    loop {
+        tokio::select! {
+            job = jobs.next(), if !closed && in_flight.len() + waiting < concurrency.max(1) => {
+                let Some(job) = job else {
+                    closed = true;
+                    continue;
+                };
+
+                match key(&job) {
+                    Some(key) if lanes.contains_key(&key) => {
+                        lanes.get_mut(&key).unwrap().push_back(job);
+                        waiting += 1;
+                    }
+                    Some(key) => {
+                        lanes.insert(key, VecDeque::new());
+                        in_flight.push(start(job));
+                    }
+                    None => in_flight.push(start(job)),
+                }
+            }
+            Some((key, res)) = in_flight.next(), if !in_flight.is_empty() => {
+                finish(res);
+
+                let Some(key) = key else { continue };
+                match lanes.get_mut(&key).and_then(|lane| lane.pop_front()) {
+                    Some(next) => {
+                        waiting -= 1;
+                        in_flight.push(start(next));
+                    }
+                    None => {
+                        lanes.remove(&key);
+                    }
+                }
+            }
+            else => break,
+        }
+    }
+}
+
+/// Where an Executor's jobs come from and how they're run: `jobs` is made from what's pushed to
+/// `queue`, with up to `concurrency` running at once, or at most one per key with `partition`.
+/// Once `cancellation` is cancelled, the queue is closed and what's in it is either still run or
+/// abandoned.
+struct Schedule<Item, Job> {
+    queue: Arc<Queue<Item>>,
+    jobs: BoxStream<'static, Job>,
+    concurrency: usize,
+    partition: Option<fn(&Job) -> Option<String>>,
+    cancellation: Option<(CancellationToken, OnCancel)>,
+}
+
+impl<Item, Job> Schedule<Item, Job> {
+    /// Runs every job with `run` until there are none left or it's cancelled, handing each result
+    /// to `finish`.
+    async fn run<R, Fut>(self, counters: &Counters, run: R, mut finish: impl FnMut(Fut::Output))
+    where
+        R: FnMut(Job) -> Fut,
+        Fut: Future,
+    {
+        let _stopped = Stopped(counters);
+        let finish = |res| {
+            finish(res);
+            counters.settled.notify_waiters();
+        };
+
+        let Schedule {
+            queue,
+            jobs,
+            concurrency,
+            partition,
+            cancellation,
+        } = self;
+        let consume = async move {
+            match partition {
+                Some(key) => run_partitioned(jobs, concurrency, key, run, finish).await,
+                None => run_jobs(jobs, concurrency, run, finish).await,
+            }
+        };
+
+        let Some((token, on_cancel)) = cancellation else {
+            consume.await;
+            counters.summarize();
+            return;
+        };
+        futures::pin_mut!(consume);
+
+        tokio::select! {
+            _ = &mut consume => {
+                counters.summarize();
+                return;
+            }
+            _ = token.cancelled() => {}
+        }
+
+        queue.close();
+        match on_cancel {
+            OnCancel::Drain => {
+                tracing::info!("cancelled, running what's queued");
+                consume.await;
+            }
+            OnCancel::Abandon => {
+                // Dropping the queued items and then the running jobs drops whatever they hold,
+                // such as the senders that callers are waiting on, so they see them abandoned.
+                let abandoned = queue.clear();
+                tracing::info!(abandoned, "cancelled, abandoning what's queued");
+            }
+        }
+        counters.summarize();
+    }
+}
+
+/// How an Executor queues jobs.
+#[derive(Debug, Clone, Copy)]
+pub struct ExecutorOptions {
+    pub capacity: usize,
+    pub backpressure: Backpressure,
+}
+
+impl Default for ExecutorOptions {
+    fn default() -> Self {
+        ExecutorOptions {
+            capacity: 1,
+            backpressure: Backpressure::default(),
+        }
+    }
+}
+
+/// Runs jobs of any kind with an async function, with up to `concurrency` running at once. Jobs
+/// are queued, flushed and closed the same way as the Dispatcher's requests, since the Dispatcher
+/// is an Executor too, one whose jobs are delivered. This one has none of the retrying, batching
+/// or anything else that's specific to delivering requests. `done` is called with the result of
+/// each job as it finishes, so the function can fail with whatever error suits it.
+///
+/// `Item` is what's queued, which is the job itself unless the consumer makes jobs out of several
+/// items, as the Dispatcher does with batches.
+pub struct Executor<Job, Item = Job> {
+    backpressure: Backpressure,
+    counters: Arc<Counters>,
+    consumer: Consumer<Item>,
+    job: PhantomData<fn(Job)>,
+}
+
+impl<Job, Item> Executor<Job, Item> {
+    /// Spawns `consumer`, which takes items off `queue` and counts what happens to them.
+    fn spawn(
+        queue: Arc<Queue<Item>>,
+        counters: Arc<Counters>,
+        backpressure: Backpressure,
+        consumer: impl Future<Output = ()> + Send + 'static,
+    ) -> Self {
+        Executor {
+            backpressure,
+            counters,
+            consumer: Consumer::spawn(queue, consumer),
+            job: PhantomData,
+        }
+    }
+
+    /// Waits until every job submitted so far has finished. Anything submitted while waiting is
+    /// waited for too.
+    pub async fn flush(&self) {
+        self.counters.wait_idle().await;
+    }
+
+    /// Stops accepting new jobs and waits for those already submitted to finish.
+    pub async fn close(mut self) -> Result<(), DispatchError> {
+        self.consumer.close().await
+    }
+}
+
+impl<Job: Send + 'static> Executor<Job> {
+    pub fn new<F, Fut, T, E, D>(concurrency: usize, run: F, done: D) -> Self
+    where
+        F: Fn(Job) -> Fut + Send + 'static,
+        Fut: Future<Output = Result<T, E>> + Send,
+        T: Send,
+        E: Send,
+        D: FnMut(Result<T, E>) + Send + 'static,
+    {
+        Self::with_options(concurrency, run, done, ExecutorOptions::default())
+    }
+
+    pub fn with_options<F, Fut, T, E, D>(
+        concurrency: usize,
+        run: F,
+        mut done: D,
+        options: ExecutorOptions,
+    ) -> Self
+    where
+        F: Fn(Job) -> Fut + Send + 'static,
+        Fut: Future<Output = Result<T, E>> + Send,
+        T: Send,
+        E: Send,
+        D: FnMut(Result<T, E>) + Send + 'static,
+    {
+        let queue = Arc::new(Queue::new(options.capacity));
+        let counters = Arc::new(Counters::new(None));
+
+        let schedule = Schedule {
+            queue: queue.clone(),
+            jobs: queue.clone().drain().boxed(),
+            concurrency,
+            partition: None,
+            cancellation: None,
+        };
+        let consumer = {
+            let counters = counters.clone();
+            async move {
+                let finish = |res: Result<T, E>| {
+                    let counter = match &res {
+                        Ok(_) => &counters.delivered,
+                        Err(_) => &counters.failed,
+                    };
+                    counter.fetch_add(1, Ordering::Relaxed);
+                    done(res);
+                };
+                schedule.run(&counters, run, finish).await;
+            }
+        };
+
+        Self::spawn(queue, counters, options.backpressure, consumer)
+    }
+
+    /// Queues a job to be run. Fails with `DispatchError::SendFailed` once the Executor has been
+    /// closed, or with `DispatchError::QueueFull` if the queue is full and the backpressure policy
+    /// is to fail. Jobs that are dropped to make room are never run, and aren't passed to `done`.
+    pub async fn submit(&self, job: Job) -> Result<(), DispatchError> {
+        self.counters.accept();
+        let pushed = self
+            .consumer
+            .queue
+            .push(job, Priority::default(), self.backpressure)
+            .await;
+        let dropped = match pushed {
+            Ok(dropped) => dropped,
+            Err(e) => {
+                self.counters.unaccept();
+                return Err(e);
+            }
+        };
+
+        if dropped.is_some() {
+            self.counters.dropped.fetch_add(1, Ordering::Relaxed);
+            self.counters.settled.notify_waiters();
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        cell::RefCell,
+        sync::{Arc, Mutex},
+    };
+
+    use super::*;
+
+    struct MockClient {
+        calls: Arc<Mutex<RefCell<Vec<serde_json::Value>>>>,
+    }
+
+    #[async_trait]
+    impl Client for MockClient {
+        async fn post(&self, request: Request) -> Result<Reply, DispatchError> {
+            self.calls.lock().unwrap().borrow_mut().push(request.body);
+            Ok(Reply::default())
+        }
+    }
+
+    #[tokio::test]
+    async fn test_dispatcher() {
+        let calls = Arc::new(Mutex::new(RefCell::new(Vec::new())));
+
+        let client = MockClient {
+            calls: calls.clone(),
+        };
+        let dispatch = Dispatcher::new(3, client, |_, _| {});
+
+        let mut want_calls = vec![];
+
+        for idx in 0..20 {
+            let body = serde_json::json!({ "count": idx });
+            dispatch.post(body.clone()).await.unwrap();
+            want_calls.push(body);
+        }
+
+        dispatch.close().await.unwrap();
+
+        assert_eq!(want_calls, calls.lock().unwrap().clone().into_inner());
+    }
+
+    struct FlakyClient {
+        failures: Mutex<usize>,
+        calls: Arc<Mutex<usize>>,
+    }
+
+    #[async_trait]
+    impl Client for FlakyClient {
+        async fn post(&self, _request: Request) -> Result<Reply, DispatchError> {
+            *self.calls.lock().unwrap() += 1;
+
+            let mut failures = self.failures.lock().unwrap();
+            if *failures > 0 {
+                *failures -= 1;
+                return Err(DispatchError::TimedOut);
+            }
+            Ok(Reply::default())
+        }
+    }
+
+    #[tokio::test]
+    async fn test_dispatcher_retries() {
+        let policy = RetryPolicy {
+            max_attempts: 3,
+            jitter: false,
+            ..Default::default()
+        };
+        assert_eq!(Duration::from_millis(100), policy.delay(1));
+        assert_eq!(Duration::from_millis(200), policy.delay(2));
+        assert_eq!(Duration::from_secs(10), policy.delay(40));
+
+        let policy = RetryPolicy {
+            base_delay: Duration::from_millis(1),
+            ..policy
+        };
+
+        let succeeded = Arc::new(Mutex::new(0));
+        let calls = Arc::new(Mutex::new(0));
+
+        let client = FlakyClient {
+            failures: Mutex::new(2),
+            calls: calls.clone(),
+        };
+        let s = succeeded.clone();
+        let options = DispatchOptions {
+            retry: policy.clone(),
+            ..Default::default()
+        };
+        let dispatch =
+            Dispatcher::with_options(1, client, move |_, _| *s.lock().unwrap() += 1, options);
+        dispatch.post(json!({})).await.unwrap();
+        dispatch.close().await.unwrap();
+        assert_eq!((1, 3), (*succeeded.lock().unwrap(), *calls.lock().unwrap()));
+
+        // Payloads that still fail after the last attempt go to the dead letter sink.
+        let calls = Arc::new(Mutex::new(0));
+
+        let client = FlakyClient {
+            failures: Mutex::new(3),
+            calls: calls.clone(),
+        };
+        let (tx, mut rx) = mpsc::unbounded_channel();
+        let attempts = Arc::new(Mutex::new(Vec::new()));
+        let a = attempts.clone();
+        let options = DispatchOptions {
+            retry: policy,
+            dead_letter: Some(Box::new(tx)),
+            on_error: Some(Box::new(move |request: &Request, _, attempt| {
+                a.lock().unwrap().push((request.body.clone(), attempt))
+            })),
+            ..Default::default()
+        };
+        let dispatch = Dispatcher::with_options(1, client, |_, _| panic!("should fail"), options);
+        dispatch.post(json!({ "id": 1 })).await.unwrap();
+        dispatch.close().await.unwrap();
+
+        assert_eq!(3, *calls.lock().unwrap());
+        assert_eq!(
+            vec![
+                (json!({ "id": 1 }), 1),
+                (json!({ "id": 1 }), 2),
+                (json!({ "id": 1 }), 3)
+            ],
+            *attempts.lock().unwrap()
+        );
+        let (requests, err) = rx.recv().await.unwrap();
+        assert_eq!(
+            vec![json!({ "id": 1 })],
+            requests.into_iter().map(|r| r.body).collect::<Vec<_>>()
+        );
+        assert!(matches!(err, DispatchError::TimedOut));
+    }
+
+    #[tokio::test]
+    async fn test_dispatcher_dead_letter_file() {
+        let path = std::env::temp_dir().join(format!("dead-letters-{}.jsonl", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+
+        let client = FlakyClient {
+            failures: Mutex::new(usize::MAX),
+            calls: Arc::new(Mutex::new(0)),
+        };
+        let options = DispatchOptions {
+            retry: RetryPolicy::never(),
+            dead_letter: Some(Box::new(FileSink::open(&path).unwrap())),
+            ..Default::default()
+        };
+        let dispatch = Dispatcher::with_options(2, client, |_, _| {}, options);
+        for idx in 0..3 {
+            dispatch.post(json!({ "id": idx })).await.unwrap();
+        }
+        let put = Request::new(json!({ "id": 3 }))
+            .with_method(Method::PUT)
+            .with_url("http://example.com/put".parse().unwrap())
+            .with_header(
+                HeaderName::from_static("x-id"),
+                HeaderValue::from_static("3"),
+            );
+        dispatch.post_request(put).await.unwrap();
+        dispatch.close().await.unwrap();
+
+        let mut replayed: Vec<Request> = FileSink::replay(&path).unwrap();
+        replayed.sort_by_key(|r| r.body["id"].as_i64());
+        assert_eq!(
+            vec![
+                json!({ "id": 0 }),
+                json!({ "id": 1 }),
+                json!({ "id": 2 }),
+                json!({ "id": 3 })
+            ],
+            replayed.iter().map(|r| r.body.clone()).collect::<Vec<_>>()
+        );
+        assert_eq!(Method::POST, replayed[0].method);
+        assert_eq!(None, replayed[0].url);
+
+        // The method, URL and headers of requests survive being dead lettered.
+        let put = &replayed[3];
+        assert_eq!(Method::PUT, put.method);
+        assert_eq!("http://example.com/put", put.url.as_ref().unwrap().as_str());
+        assert_eq!("3", put.headers["x-id"]);
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    struct BatchClient {
+        batches: Arc<Mutex<Vec<Vec<serde_json::Value>>>>,
+    }
+
+    #[async_trait]
+    impl Client for BatchClient {
+        async fn post(&self, _request: Request) -> Result<Reply, DispatchError> {
+            panic!("batches should be posted with post_batch")
+        }
+
+        async fn post_batch(&self, batch: Vec<Request>) -> Result<Vec<Reply>, DispatchError> {
+            let bodies = batch.into_iter().map(|r| r.body).collect();
+            self.batches.lock().unwrap().push(bodies);
+            Ok(vec![Reply::default()])
+        }
+    }
+
+    async fn batch_sizes(policy: BatchPolicy, payloads: usize) -> (Vec<usize>, usize) {
+        let batches = Arc::new(Mutex::new(Vec::new()));
+        let succeeded = Arc::new(Mutex::new(0));
+
+        let client = BatchClient {
+            batches: batches.clone(),
+        };
+        let options = DispatchOptions {
+            batch: Some(policy),
+            ..Default::default()
+        };
+        let s = succeeded.clone();
+        let dispatch =
+            Dispatcher::with_options(1, client, move |_, _| *s.lock().unwrap() += 1, options);
+        for idx in 0..payloads {
+            dispatch.post(json!({ "count": idx })).await.unwrap();
+        }
+        dispatch.close().await.unwrap();
+
+        let sizes = batches.lock().unwrap().iter().map(Vec::len).collect();
+        let succeeded = *succeeded.lock().unwrap();
+        (sizes, succeeded)
+    }
+
+    #[tokio::test]
+    async fn test_dispatcher_batches() {
+        let linger = Duration::from_secs(60);
+
+        let policy = BatchPolicy {
+            max_items: 2,
+            max_linger: linger,
+            ..Default::default()
+        };
+        assert_eq!((vec![2, 2, 1], 5), batch_sizes(policy, 5).await);
+
+        // Each payload is encoded as `{"count":N}`, which is 11 bytes.
+        let policy = BatchPolicy {
+            max_bytes: 35,
+            max_linger: linger,
+            ..Default::default()
+        };
+        assert_eq!((vec![3, 3, 1], 7), batch_sizes(policy, 7).await);
+
+        // Batches are sent after lingering even if they aren't full.
+        let batches = Arc::new(Mutex::new(Vec::new()));
+        let client = BatchClient {
+            batches: batches.clone(),
+        };
+        let options = DispatchOptions {
+            batch: Some(BatchPolicy {
+                max_linger: Duration::from_millis(10),
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+        let dispatch = Dispatcher::with_options(1, client, |_, _| {}, options);
+        dispatch.post(json!({})).await.unwrap();
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert_eq!(1, batches.lock().unwrap().len());
+        dispatch.close().await.unwrap();
+    }
+
+    #[derive(Debug, Clone, PartialEq, Serialize)]
+    struct Event {
+        id: usize,
+        kind: &'static str,
+    }
+
+    struct EventClient {
+        events: Arc<Mutex<Vec<Event>>>,
+    }
+
+    #[async_trait]
+    impl Client<Event> for EventClient {
+        async fn post(&self, request: Request<Event>) -> Result<Reply, DispatchError> {
+            self.events.lock().unwrap().push(request.body);
+            Ok(Reply::default())
+        }
+    }
+
+    #[tokio::test]
+    async fn test_dispatcher_typed_payloads() {
+        let events = Arc::new(Mutex::new(Vec::new()));
+
+        let client = EventClient {
+            events: events.clone(),
+        };
+        let dispatch = Dispatcher::new(1, client, |_, _| {});
+
+        let want: Vec<_> = (0..3).map(|id| Event { id, kind: "click" }).collect();
+        for event in &want {
+            dispatch.post(event.clone()).await.unwrap();
+        }
+        dispatch.close().await.unwrap();
+
+        assert_eq!(want, *events.lock().unwrap());
+    }
+
+    #[test]
+    fn test_retryable_errors() {
+        assert!(DispatchError::TimedOut.retryable());
+        assert!(DispatchError::Throttled { retry_after: None }.retryable());
+        assert!(!DispatchError::Unauthorized.retryable());
+        assert!(!DispatchError::DeadlineExceeded.retryable());
+
+        #[cfg(feature = "sqs")]
+        {
+            assert!(sqs_failed("InternalError", true).retryable());
+            assert!(!sqs_failed("AccessDenied", false).retryable());
+        }
+    }
+
+    #[tokio::test]
+    async fn test_dispatcher_permanent_errors_are_not_retried() {
+        let client = ChaosClient::new().with_script([Outcome::Fail(DispatchError::EncodeFailed(
+            Arc::new(io::Error::other("bad payload")),
+        ))]);
+        let options = DispatchOptions {
+            on_error: Some(Box::new(|_, _, _| {})),
+            ..Default::default()
+        };
+        let dispatch = Dispatcher::with_options(1, client.clone(), |_, _| {}, options);
+
+        let res = dispatch.post_and_wait(json!(0)).await;
+        assert!(
+            matches!(res, Err(DispatchError::EncodeFailed(_))),
+            "{:?}",
+            res
+        );
+        assert_eq!(1, client.calls());
+    }
+
+    #[tokio::test]
+    async fn test_dispatcher_post_and_wait() {
+        let client = FlakyClient {
+            failures: Mutex::new(1),
+            calls: Arc::new(Mutex::new(0)),
+        };
+        let options = DispatchOptions {
+            retry: RetryPolicy::never(),
+            ..Default::default()
+        };
+        let dispatch = Dispatcher::with_options(1, client, |_, _| {}, options);
+
+        let failed = dispatch
+            .post_tracked(Request::new(json!({ "id": 1 })))
+            .await
+            .unwrap();
+        assert!(matches!(failed.await, Err(DispatchError::TimedOut)));
+
+        dispatch.post_and_wait(json!({ "id": 2 })).await.unwrap();
+        dispatch.close().await.unwrap();
+    }
+
+    /// Holds each post until the test releases it.
+    struct GatedClient {
+        started: mpsc::UnboundedSender<serde_json::Value>,
+        gate: Arc<tokio::sync::Semaphore>,
+        delivered: Arc<Mutex<Vec<serde_json::Value>>>,
+    }
+
+    #[async_trait]
+    impl Client for GatedClient {
+        async fn post(&self, request: Request) -> Result<Reply, DispatchError> {
+            self.started.send(request.body.clone()).unwrap();
+            self.gate.acquire().await.unwrap().forget();
+            self.delivered.lock().unwrap().push(request.body);
+            Ok(Reply::default())
+        }
+    }
+
+    #[tokio::test]
+    async fn test_dispatcher_backpressure() {
+        for (backpressure, want) in [
+            (Backpressure::DropOldest, vec![0, 2, 3]),
+            (Backpressure::DropNewest, vec![0, 1, 2]),
+            (Backpressure::Error, vec![0, 1, 2]),
+        ] {
+            let (started, mut started_rx) = mpsc::unbounded_channel();
+            let gate = Arc::new(tokio::sync::Semaphore::new(0));
+            let delivered = Arc::new(Mutex::new(Vec::new()));
+
+            let client = GatedClient {
+                started,
+                gate: gate.clone(),
+                delivered: delivered.clone(),
+            };
+            let options = DispatchOptions {
+                capacity: 2,
+                backpressure,
+                ..Default::default()
+            };
+            let dispatch = Dispatcher::with_options(1, client, |_, _| {}, options);
+
+            // The first payload is taken off the queue and held by the client, and the next two
+            // fill the queue.
+            dispatch.post(json!(0)).await.unwrap();
+            started_rx.recv().await.unwrap();
+            let first = dispatch.post_tracked(Request::new(json!(1))).await.unwrap();
+            dispatch.post(json!(2)).await.unwrap();
+
+            let full = dispatch.post_tracked(Request::new(json!(3))).await;
+            match backpressure {
+                Backpressure::DropOldest => {
+                    assert!(matches!(first.await, Err(DispatchError::Dropped)));
+                }
+                Backpressure::DropNewest => {
+                    assert!(matches!(full.unwrap().await, Err(DispatchError::Dropped)));
+                }
+                _ => assert!(matches!(full, Err(DispatchError::QueueFull))),
+            }
+
+            gate.add_permits(10);
+            dispatch.close().await.unwrap();
+
+            let want: Vec<_> = want.into_iter().map(|n| json!(n)).collect();
+            assert_eq!(want, *delivered.lock().unwrap(), "{:?}", backpressure);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_dispatcher_shutdown() {
+        let (started, mut started_rx) = mpsc::unbounded_channel();
+        let gate = Arc::new(tokio::sync::Semaphore::new(1));
+
+        let client = GatedClient {
+            started,
+            gate: gate.clone(),
+            delivered: Arc::new(Mutex::new(Vec::new())),
+        };
+        let options = DispatchOptions {
+            capacity: 10,
+            ..Default::default()
+        };
+        let dispatch = Dispatcher::with_options(1, client, |_, _| {}, options);
+
+        // Only the first payload can get through the gate, leaving one in flight and two queued.
+        for idx in 0..4 {
+            dispatch.post(json!(idx)).await.unwrap();
+        }
+        started_rx.recv().await.unwrap();
+        let abandoned = dispatch.post_tracked(Request::new(json!(4))).await.unwrap();
+
+        let failures = dispatch
+            .shutdown(Duration::from_millis(50))
+            .await
+            .unwrap_err();
+        assert_eq!(
+            (1, 0, 4),
+            (failures.delivered, failures.failed, failures.abandoned)
+        );
+        assert!(matches!(abandoned.await, Err(DispatchError::Abandoned)));
+
+        // Shutting down with enough time delivers everything.
+        let client = MockClient {
+            calls: Arc::new(Mutex::new(RefCell::new(Vec::new()))),
+        };
+        let dispatch = Dispatcher::new(2, client, |_, _| {});
+        for idx in 0..5 {
+            dispatch.post(json!(idx)).await.unwrap();
+        }
+        let report = dispatch.shutdown(Duration::from_secs(5)).await.unwrap();
+        assert_eq!(5, report.delivered);
+        assert_eq!(0, report.abandoned);
+    }
+
+    #[tokio::test]
+    async fn test_dispatcher_rate_limit() {
+        let calls = Arc::new(Mutex::new(RefCell::new(Vec::new())));
+
+        let client = MockClient {
+            calls: calls.clone(),
+        };
+        let limiter: FixedWindow = FixedWindow::new(Duration::from_millis(100), 2);
+        let options = DispatchOptions {
+            rate_limit: Some(RateLimit::new(limiter)),
+            capacity: 10,
+            ..Default::default()
+        };
+        let dispatch = Dispatcher::with_options(10, client, |_, _| {}, options);
+
+        // Two requests are sent per window, so the last two wait for the third window.
+        let start = std::time::Instant::now();
+        for idx in 0..6 {
+            dispatch.post(json!(idx)).await.unwrap();
+        }
+        dispatch.close().await.unwrap();
+
+        assert!(start.elapsed() >= Duration::from_millis(200));
+        assert_eq!(6, calls.lock().unwrap().borrow().len());
+    }
+
+    #[tokio::test]
+    async fn test_dispatcher_circuit_breaker() {
+        let calls = Arc::new(Mutex::new(Vec::new()));
+
+        struct OutageClient {
+            failures: Mutex<usize>,
+            calls: Arc<Mutex<Vec<std::time::Instant>>>,
+        }
+
+        #[async_trait]
+        impl Client for OutageClient {
+            async fn post(&self, _request: Request) -> Result<Reply, DispatchError> {
+                self.calls.lock().unwrap().push(std::time::Instant::now());
+
+                let mut failures = self.failures.lock().unwrap();
+                if *failures > 0 {
+                    *failures -= 1;
+                    return Err(DispatchError::TimedOut);
+                }
+                Ok(Reply::default())
+            }
+        }
+
+        let client = OutageClient {
+            failures: Mutex::new(3),
+            calls: calls.clone(),
+        };
+        let cool_down = Duration::from_millis(100);
+        let options = DispatchOptions {
+            retry: RetryPolicy::never(),
+            capacity: 10,
+            circuit_breaker: Some(CircuitBreaker::new(2, cool_down)),
+            ..Default::default()
+        };
+        let dispatch = Dispatcher::with_options(1, client, |_, _| {}, options);
+        for idx in 0..5 {
+            dispatch.post(json!(idx)).await.unwrap();
+        }
+        let failures = dispatch.shutdown(Duration::from_secs(5)).await.unwrap_err();
+        assert_eq!((2, 3), (failures.delivered, failures.failed));
+        assert_eq!(3, failures.requests.len());
+
+        // The breaker opens after the second failure, the first probe fails and opens it again,
+        // and the second probe succeeds and closes it.
+        let calls = calls.lock().unwrap();
+        let gaps: Vec<_> = calls.windows(2).map(|w| w[1] - w[0]).collect();
+        assert!(gaps[0] < cool_down);
+        assert!(gaps[1] >= cool_down);
+        assert!(gaps[2] >= cool_down);
+        assert!(gaps[3] < cool_down);
+    }
+
+    #[tokio::test]
+    async fn test_dispatcher_circuit_breaker_deadline() {
+        let client = ChaosClient::new().with_script([Outcome::Fail(DispatchError::TimedOut)]);
+        let options = DispatchOptions {
+            retry: RetryPolicy::never(),
+            capacity: 10,
+            circuit_breaker: Some(CircuitBreaker::new(1, Duration::from_millis(100))),
+            deadline: Some(Duration::from_millis(50)),
+            ..Default::default()
+        };
+        let dispatch = Dispatcher::with_options(1, client.clone(), |_, _| {}, options);
+
+        // The first request opens the circuit, and the second is past its deadline by the time
+        // it would be the probe.
+        let first = dispatch.post_tracked(Request::new(json!(0))).await.unwrap();
+        let second = dispatch.post_tracked(Request::new(json!(1))).await.unwrap();
+        assert!(matches!(first.await, Err(DispatchError::TimedOut)));
+        assert!(matches!(second.await, Err(DispatchError::DeadlineExceeded)));
+
+        let third = dispatch.post_tracked(Request::new(json!(2))).await.unwrap();
+        let delivered = tokio::time::timeout(Duration::from_secs(1), third).await;
+        assert!(matches!(delivered, Ok(Ok(_))), "{:?}", delivered);
+        assert_eq!(vec![json!(2)], client.received());
+        dispatch.close().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_dispatcher_circuit_breaker_ignores_permanent_errors() {
+        let rejected = || {
+            Outcome::Fail(DispatchError::Rejected {
+                status: StatusCode::BAD_REQUEST,
+                body: Bytes::new(),
+            })
+        };
+        let client = ChaosClient::new().with_script([
+            rejected(),
+            Outcome::Fail(DispatchError::Throttled { retry_after: None }),
+            rejected(),
+        ]);
+        let options = DispatchOptions {
+            retry: RetryPolicy::never(),
+            capacity: 10,
+            circuit_breaker: Some(CircuitBreaker::new(1, Duration::from_secs(60))),
+            on_error: Some(Box::new(|_, _, _| {})),
+            ..Default::default()
+        };
+        let dispatch = Dispatcher::with_options(1, client.clone(), |_, _| {}, options);
+
+        // None of these open the circuit, so the last request goes straight through. The throttled
+        // request is tried again, and rejected the second time.
+        for idx in 0..3 {
+            _ = dispatch.post_and_wait(json!(idx)).await;
+        }
+        assert_eq!(4, client.calls());
+        let delivered =
+            tokio::time::timeout(Duration::from_secs(1), dispatch.post_and_wait(json!(3))).await;
+        assert!(matches!(delivered, Ok(Ok(_))), "{:?}", delivered);
+        dispatch.close().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_dispatcher_middleware() {
+        let (seen, mut seen_rx) = mpsc::unbounded_channel();
+
+        struct HeaderClient {
+            seen: mpsc::UnboundedSender<(Option<HeaderValue>, serde_json::Value)>,
+        }
+
+        #[async_trait]
+        impl Client for HeaderClient {
+            async fn post(&self, request: Request) -> Result<Reply, DispatchError> {
+                let auth = request.headers.get("authorization").cloned();
+                self.seen.send((auth, request.body)).unwrap();
+                Ok(Reply::default())
+            }
+        }
+
+        let auth = |request: &mut Request| {
+            let token = HeaderValue::from_static("Bearer token");
+            request.headers.insert("authorization", token);
+        };
+        let stamp = |request: &mut Request| request.body["stamped"] = json!(true);
+
+        let options = DispatchOptions {
+            middleware: vec![Box::new(auth), Box::new(stamp)],
+            ..Default::default()
+        };
+        let dispatch = Dispatcher::with_options(1, HeaderClient { seen }, |_, _| {}, options);
+        dispatch.post(json!({ "id": 1 })).await.unwrap();
+        dispatch.close().await.unwrap();
+
+        let (auth, body) = seen_rx.recv().await.unwrap();
+        assert_eq!(Some(HeaderValue::from_static("Bearer token")), auth);
+        assert_eq!(json!({ "id": 1, "stamped": true }), body);
+    }
+
+    /// Takes longer to post lower numbers, so that concurrent posts finish in reverse.
+    struct SlowClient {
+        delivered: Arc<Mutex<Vec<u64>>>,
+    }
+
+    #[async_trait]
+    impl Client for SlowClient {
+        async fn post(&self, request: Request) -> Result<Reply, DispatchError> {
+            let n = request.body.as_u64().unwrap();
+            tokio::time::sleep(Duration::from_millis(20 * (5 - n))).await;
+            self.delivered.lock().unwrap().push(n);
+            Ok(Reply::default())
+        }
+    }
+
+    #[tokio::test]
+    async fn test_dispatcher_fifo() {
+        for (order, want) in [
+            (DeliveryOrder::Unordered, vec![4, 3, 2, 1, 0]),
+            (DeliveryOrder::Fifo, vec![0, 1, 2, 3, 4]),
+        ] {
+            let delivered = Arc::new(Mutex::new(Vec::new()));
+
+            let client = SlowClient {
+                delivered: delivered.clone(),
+            };
+            let options = DispatchOptions {
+                capacity: 10,
+                order,
+                ..Default::default()
+            };
+            let dispatch = Dispatcher::with_options(5, client, |_, _| {}, options);
+            for idx in 0..5 {
+                dispatch.post(json!(idx)).await.unwrap();
+            }
+            dispatch.close().await.unwrap();
+
+            assert_eq!(want, *delivered.lock().unwrap(), "{:?}", order);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_dispatcher_partitioned() {
+        let delivered = Arc::new(Mutex::new(Vec::new()));
+
+        let client = SlowClient {
+            delivered: delivered.clone(),
+        };
+        let options = DispatchOptions {
+            capacity: 10,
+            order: DeliveryOrder::Partitioned,
+            ..Default::default()
+        };
+        let dispatch = Dispatcher::with_options(5, client, |_, _| {}, options);
+        for idx in 0..5 {
+            let key = if idx % 2 == 0 { "even" } else { "odd" };
+            let request = Request::new(json!(idx)).with_partition(key);
+            dispatch.post_request(request).await.unwrap();
+        }
+        dispatch.close().await.unwrap();
+
+        // The keys are each in order, but the odd key finished its first request before the even.
+        assert_eq!(vec![1, 0, 3, 2, 4], *delivered.lock().unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_dispatcher_journal() {
+        let path = std::env::temp_dir().join(format!("journal-{}.jsonl", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+
+        // The first dispatcher is stopped while its client is stuck on the first request.
+        let (started, mut started_rx) = mpsc::unbounded_channel();
+        let client = GatedClient {
+            started,
+            gate: Arc::new(tokio::sync::Semaphore::new(0)),
+            delivered: Arc::new(Mutex::new(Vec::new())),
+        };
+        let options = DispatchOptions {
+            capacity: 10,
+            journal: Some(Arc::new(Journal::open(&path).unwrap())),
+            ..Default::default()
+        };
+        let dispatch = Dispatcher::with_options(1, client, |_, _| {}, options);
+        assert_eq!(0, dispatch.recover().await.unwrap());
+        for idx in 0..3 {
+            dispatch.post(json!(idx)).await.unwrap();
+        }
+        started_rx.recv().await.unwrap();
+        let failures = dispatch
+            .shutdown(Duration::from_millis(10))
+            .await
+            .unwrap_err();
+        assert_eq!(3, failures.abandoned);
+
+        // Everything is posted again by the next one.
+        let calls = Arc::new(Mutex::new(RefCell::new(Vec::new())));
+        let client = MockClient {
+            calls: calls.clone(),
+        };
+        let options = DispatchOptions {
+            capacity: 10,
+            journal: Some(Arc::new(Journal::open(&path).unwrap())),
+            ..Default::default()
+        };
+        let dispatch = Dispatcher::with_options(1, client, |_, _| {}, options);
+        assert_eq!(3, dispatch.recover().await.unwrap());
+        dispatch.post(json!(3)).await.unwrap();
+        dispatch.close().await.unwrap();
+        assert_eq!(
+            vec![json!(0), json!(1), json!(2), json!(3)],
+            *calls.lock().unwrap().borrow()
+        );
+
+        // Which leaves nothing pending.
+        let journal = Journal::open(&path).unwrap();
+        assert!(journal.recover::<serde_json::Value>().is_empty());
+        assert_eq!("", std::fs::read_to_string(&path).unwrap());
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_journal_compacts_while_open() {
+        let path = std::env::temp_dir().join(format!("compacting-{}.jsonl", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+
+        let journal = Journal::open(&path).unwrap();
+        // Recorded all at once, so that they're written in groups.
+        let requests: Vec<_> = (0..JOURNAL_COMPACT_ENTRIES)
+            .map(|idx| Request::new(json!(idx)))
+            .collect();
+        let ids = futures::future::try_join_all(requests.iter().map(|r| journal.record(r)))
+            .await
+            .unwrap();
+        for &id in &ids[1..] {
+            journal.ack(id);
+        }
+        journal.flush().await;
+
+        // Acknowledging everything but the first request would have doubled the file's entries.
+        let entries = std::fs::read_to_string(&path).unwrap().lines().count();
+        assert!(entries < JOURNAL_COMPACT_ENTRIES, "{} entries", entries);
+        drop(journal);
+
+        let recovered = Journal::open(&path).unwrap().recover::<serde_json::Value>();
+        assert_eq!(1, recovered.len());
+        assert_eq!(
+            (ids[0], json!(0)),
+            (recovered[0].0, recovered[0].1.body.clone())
+        );
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_dispatcher_metrics() {
+        let (started, mut started_rx) = mpsc::unbounded_channel();
+        let gate = Arc::new(tokio::sync::Semaphore::new(0));
+        let client = GatedClient {
+            started,
+            gate: gate.clone(),
+            delivered: Arc::new(Mutex::new(Vec::new())),
+        };
+        let options = DispatchOptions {
+            capacity: 10,
+            ..Default::default()
+        };
+        let dispatch = Dispatcher::with_options(1, client, |_, _| {}, options);
+        for idx in 0..3 {
+            dispatch.post(json!(idx)).await.unwrap();
+        }
+        started_rx.recv().await.unwrap();
+
+        let metrics = dispatch.metrics();
+        assert_eq!(3, metrics.accepted);
+        assert_eq!(2, metrics.queued);
+        assert_eq!(1, metrics.in_flight);
+        assert_eq!(0, metrics.latency.count());
+
+        gate.add_permits(4);
+        dispatch.post_and_wait(json!(3)).await.unwrap();
+
+        // The last delivery is counted just after its waiter is told.
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        let metrics = dispatch.metrics();
+        assert_eq!(4, metrics.accepted);
+        assert_eq!(4, metrics.delivered);
+        assert_eq!(0, metrics.queued);
+        assert_eq!(0, metrics.in_flight);
+        assert_eq!(4, metrics.latency.count());
+    }
+
+    #[tokio::test]
+    async fn test_dispatcher_throttled() {
+        struct ThrottledClient {
+            calls: Arc<Mutex<Vec<(serde_json::Value, tokio::time::Instant)>>>,
+        }
+
+        #[async_trait]
+        impl Client for ThrottledClient {
+            async fn post(&self, request: Request) -> Result<Reply, DispatchError> {
+                let mut calls = self.calls.lock().unwrap();
+                calls.push((request.body, tokio::time::Instant::now()));
+                if calls.len() == 1 {
+                    let retry_after = Some(Duration::from_millis(100));
+                    return Err(DispatchError::Throttled { retry_after });
+                }
+                Ok(Reply::default())
+            }
+        }
+
+        let calls = Arc::new(Mutex::new(Vec::new()));
+        let client = ThrottledClient {
+            calls: calls.clone(),
+        };
+        let options = DispatchOptions {
+            retry: RetryPolicy::never(),
+            capacity: 10,
+            ..Default::default()
+        };
+        let dispatch = Dispatcher::with_options(1, client, |_, _| {}, options);
+        dispatch.post(json!(0)).await.unwrap();
+        dispatch.post(json!(1)).await.unwrap();
+        dispatch.close().await.unwrap();
+
+        // Being throttled isn't a failure, so the first is retried even though retries are off,
+        // and nothing is sent until the server is ready.
+        let calls = calls.lock().unwrap();
+        let bodies: Vec<_> = calls.iter().map(|(body, _)| body.clone()).collect();
+        assert_eq!(vec![json!(0), json!(0), json!(1)], bodies);
+        assert!(calls[1].1 - calls[0].1 >= Duration::from_millis(100));
+    }
+
+    #[tokio::test]
+    async fn test_dispatcher_throttled_gives_up() {
+        let client = ChaosClient::new()
+            .with_error_rate(1.0)
+            .with_error(DispatchError::Throttled { retry_after: None });
+        let options = DispatchOptions {
+            retry: RetryPolicy {
+                max_throttled_attempts: 4,
+                base_delay: Duration::from_millis(1),
+                jitter: false,
+                ..Default::default()
+            },
+            on_error: Some(Box::new(|_, _, _| {})),
+            ..Default::default()
+        };
+        let dispatch = Dispatcher::with_options(1, client.clone(), |_, _| {}, options);
+
+        let start = tokio::time::Instant::now();
+        let res = dispatch.post_and_wait(json!(0)).await;
+        assert!(
+            matches!(res, Err(DispatchError::Throttled { .. })),
+            "{:?}",
+            res
+        );
+        assert_eq!(4, client.calls());
+        // The pauses back off: 1ms, 2ms and then 4ms.
+        assert!(start.elapsed() >= Duration::from_millis(7));
+
+        // A deadline that passes while throttled ends it sooner.
+        let client = ChaosClient::new()
+            .with_error_rate(1.0)
+            .with_error(DispatchError::Throttled {
+                retry_after: Some(Duration::from_millis(100)),
+            });
+        let options = DispatchOptions {
+            deadline: Some(Duration::from_millis(50)),
+            on_error: Some(Box::new(|_, _, _| {})),
+            ..Default::default()
+        };
+        let dispatch = Dispatcher::with_options(1, client.clone(), |_, _| {}, options);
+        let res = dispatch.post_and_wait(json!(0)).await;
+        assert!(
+            matches!(res, Err(DispatchError::DeadlineExceeded)),
+            "{:?}",
+            res
+        );
+        assert_eq!(1, client.calls());
+
+        // Any other outcome in between starts the count of throttled attempts over.
+        struct ScriptedClient(Mutex<VecDeque<Result<Reply, DispatchError>>>);
+
+        #[async_trait]
+        impl Client for ScriptedClient {
+            async fn post(&self, _request: Request) -> Result<Reply, DispatchError> {
+                lock(&self.0).pop_front().unwrap()
+            }
+        }
+
+        let throttled = || Err(DispatchError::Throttled { retry_after: None });
+        let client = ScriptedClient(Mutex::new(VecDeque::from([
+            throttled(),
+            throttled(),
+            Err(DispatchError::TimedOut),
+            throttled(),
+            throttled(),
+            Ok(Reply::default()),
+        ])));
+        let options = DispatchOptions {
+            retry: RetryPolicy {
+                max_throttled_attempts: 3,
+                base_delay: Duration::from_millis(1),
+                jitter: false,
+                ..Default::default()
+            },
+            on_error: Some(Box::new(|_, _, _| {})),
+            ..Default::default()
+        };
+        let dispatch = Dispatcher::with_options(1, client, |_, _| {}, options);
+        dispatch.post_and_wait(json!(0)).await.unwrap();
+    }
+
+    type Received = Arc<Mutex<Vec<String>>>;
+
+    /// Serves HTTP requests with empty 200 responses, keeping the lowercased requests.
+    async fn serve() -> (url::Url, Received) {
+        serve_with(|_| 200).await
+    }
+
+    /// Serves HTTP requests with empty responses, with the status `respond` gives for the
+    /// lowercased request.
+    async fn serve_with(respond: fn(&str) -> u16) -> (url::Url, Received) {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/", listener.local_addr().unwrap());
+        let requests = Received::default();
+
+        let received = requests.clone();
+        tokio::spawn(async move {
+            loop {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                let mut buf = vec![0; 4096];
+                let read = stream.read(&mut buf).await.unwrap();
+
+                let request = String::from_utf8_lossy(&buf[..read]).to_lowercase();
+                let response = format!(
+                    "HTTP/1.1 {} X\r\ncontent-length: 0\r\nconnection: close\r\n\r\n",
+                    respond(&request)
+                );
+                received.lock().unwrap().push(request);
+                stream.write_all(response.as_bytes()).await.unwrap();
+            }
+        });
+
+        (url.parse().unwrap(), requests)
+    }
+
+    #[tokio::test]
+    async fn test_reqwest_client_endpoints() {
+        let (first, first_requests) = serve().await;
+        let (second, second_requests) = serve().await;
+
+        let client = ReqwestClient::with_endpoints(
+            HeaderMap::new(),
+            vec![first.clone(), second.clone()],
+            Balance::RoundRobin,
+        );
+        for idx in 0..4 {
+            client.post(Request::new(json!(idx))).await.unwrap();
+        }
+        assert_eq!(2, first_requests.lock().unwrap().len());
+        assert_eq!(2, second_requests.lock().unwrap().len());
+
+        // Nothing is listening on the primary, so the requests fail over to the secondary.
+        let closed = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let down: url::Url = format!("http://{}/", closed.local_addr().unwrap())
+            .parse()
+            .unwrap();
+        drop(closed);
+
+        let client = ReqwestClient::with_endpoints(
+            HeaderMap::new(),
+            vec![down, second.clone()],
+            Balance::Failover,
+        );
+        for idx in 0..2 {
+            client.post(Request::new(json!(idx))).await.unwrap();
+        }
+        assert_eq!(4, second_requests.lock().unwrap().len());
+        assert!(!client.endpoints[0].healthy());
+        assert!(client.endpoints[1].healthy());
+    }
+
+    #[tokio::test]
+    async fn test_reqwest_client_token_refresh() {
+        let (url, requests) = serve_with(|request| {
+            if request.contains("authorization: bearer fresh") {
+                200
+            } else {
+                401
+            }
+        })
+        .await;
+
+        let fetches = Arc::new(AtomicUsize::new(0));
+        let fetched = fetches.clone();
+        let provider = RefreshingToken::new(move || {
+            let fetch = fetched.fetch_add(1, Ordering::SeqCst);
+            async move {
+                // The first token has been revoked early.
+                let token = if fetch == 0 { "stale" } else { "fresh" };
+                Ok((token.to_string(), Duration::from_secs(3600)))
+            }
+        });
+
+        let client = ReqwestClient::new(HeaderMap::new(), url).with_token_provider(provider);
+        client.post(Request::new(json!(0))).await.unwrap();
+        assert_eq!(2, fetches.load(Ordering::SeqCst));
+        assert_eq!(2, requests.lock().unwrap().len());
+
+        client.post(Request::new(json!(1))).await.unwrap();
+        assert_eq!(2, fetches.load(Ordering::SeqCst));
+        assert_eq!(3, requests.lock().unwrap().len());
+    }
+
+    #[tokio::test]
+    async fn test_reqwest_client_compression() {
+        let (url, requests) = serve().await;
+
+        for encoding in [Encoding::Gzip, Encoding::Zstd] {
+            let compression = Compression {
+                encoding,
+                min_bytes: 100,
+            };
+
+            let big = json!({ "data": "x".repeat(1000) });
+            let compressed = compression
+                .compress(&serde_json::to_vec(&big).unwrap())
+                .unwrap();
+            let decompressed = match encoding {
+                Encoding::Gzip => {
+                    let mut decoded = Vec::new();
+                    let mut decoder = flate2::read::GzDecoder::new(&compressed[..]);
+                    io::Read::read_to_end(&mut decoder, &mut decoded).unwrap();
+                    decoded
+                }
+                Encoding::Zstd => zstd::decode_all(&compressed[..]).unwrap(),
+            };
+            assert_eq!(
+                big,
+                serde_json::from_slice::<serde_json::Value>(&decompressed).unwrap()
+            );
+
+            let client =
+                ReqwestClient::new(HeaderMap::new(), url.clone()).with_compression(compression);
+            client.post(Request::new(json!("small"))).await.unwrap();
+            client.post(Request::new(big)).await.unwrap();
+        }
+
+        let requests = requests.lock().unwrap();
+        let encodings: Vec<_> = requests
+            .iter()
+            .map(|request| {
+                request
+                    .lines()
+                    .find_map(|line| line.strip_prefix("content-encoding: "))
+                    .map(str::trim)
+            })
+            .collect();
+        assert_eq!(vec![None, Some("gzip"), None, Some("zstd")], encodings);
+    }
+
+    #[tokio::test]
+    async fn test_dispatcher_idempotency() {
+        let (seen, mut seen_rx) = mpsc::unbounded_channel();
+
+        struct KeyClient {
+            seen: mpsc::UnboundedSender<(serde_json::Value, String)>,
+        }
+
+        #[async_trait]
+        impl Client for KeyClient {
+            async fn post(&self, request: Request) -> Result<Reply, DispatchError> {
+                let key = request.headers["idempotency-key"].to_str().unwrap();
+                self.seen.send((request.body, key.to_string())).unwrap();
+                Ok(Reply::default())
+            }
+        }
+
+        let options = DispatchOptions {
+            capacity: 10,
+            idempotency: Some(Arc::new(
+                Idempotency::default().with_window(Duration::from_secs(60)),
+            )),
+            ..Default::default()
+        };
+        let dispatch = Dispatcher::with_options(1, KeyClient { seen }, |_, _| {}, options);
+        dispatch.post(json!(0)).await.unwrap();
+        dispatch.post(json!(0)).await.unwrap();
+        dispatch.post(json!(1)).await.unwrap();
+        let keyed = Request::new(json!(2)).with_idempotency_key("two");
+        dispatch.post_request(keyed.clone()).await.unwrap();
+        dispatch.post_request(keyed).await.unwrap();
+        assert_eq!(2, dispatch.metrics().suppressed);
+        dispatch.close().await.unwrap();
+
+        let mut seen = Vec::new();
+        while let Some(sent) = seen_rx.recv().await {
+            seen.push(sent);
+        }
+        assert_eq!(3, seen.len());
+        assert_eq!(json!(0), seen[0].0);
+        assert_eq!(json!(1), seen[1].0);
+        assert_ne!(seen[0].1, seen[1].1);
+        assert_eq!((json!(2), "two".to_string()), seen[2]);
+    }
+
+    #[tokio::test]
+    async fn test_reqwest_client_formats() {
+        #[derive(Clone, PartialEq, Serialize, Deserialize, prost::Message)]
+        struct Event {
+            #[prost(string, tag = "1")]
+            name: String,
+            #[prost(uint32, tag = "2")]
+            count: u32,
+        }
+
+        let event = Event {
+            name: "click".to_string(),
+            count: 2,
+        };
+
+        let encoded = BodyFormat::encode(&MessagePack, &event).unwrap();
+        assert_eq!(event, rmp_serde::from_slice(&encoded).unwrap());
+        let encoded = BodyFormat::encode(&Form, &event).unwrap();
+        assert_eq!(b"name=click&count=2".to_vec(), encoded);
+        let encoded = BodyFormat::encode(&Protobuf, &event).unwrap();
+        assert_eq!(event, prost::Message::decode(&encoded[..]).unwrap());
+        let encoded = Protobuf.encode_batch(&[&event, &event]).unwrap();
+        let mut buf = &encoded[..];
+        for _ in 0..2 {
+            let decoded: Event = prost::Message::decode_length_delimited(&mut buf).unwrap();
+            assert_eq!(event, decoded);
+        }
+
+        let (url, requests) = serve().await;
+        let client = ReqwestClient::new(HeaderMap::new(), url).with_format(Protobuf);
+        client.post(Request::new(event)).await.unwrap();
+        assert!(requests.lock().unwrap()[0].contains("content-type: application/x-protobuf"));
+    }
+
+    #[tokio::test]
+    async fn test_dispatcher_replies() {
+        /// Assigns each record the ID it was posted with.
+        struct IdClient;
+
+        #[async_trait]
+        impl Client for IdClient {
+            async fn post(&self, request: Request) -> Result<Reply, DispatchError> {
+                Ok(Reply {
+                    status: StatusCode::CREATED,
+                    headers: HeaderMap::new(),
+                    body: serde_json::to_vec(&json!({ "id": request.body }))
+                        .unwrap()
+                        .into(),
+                })
+            }
+        }
+
+        #[derive(Deserialize)]
+        struct Created {
+            id: u64,
+        }
+
+        let ids = Arc::new(Mutex::new(Vec::new()));
+        let seen = ids.clone();
+        let success = move |_, reply: &Reply| {
+            let created: Created = reply.json().unwrap();
+            seen.lock().unwrap().push(created.id);
+        };
+        let dispatch = Dispatcher::new(1, IdClient, success);
+        dispatch.post(json!(1)).await.unwrap();
+        let reply = dispatch.post_and_wait(json!(2)).await.unwrap();
+        assert_eq!(StatusCode::CREATED, reply.status);
+        assert_eq!(2, reply.json::<Created>().unwrap().id);
+        dispatch.close().await.unwrap();
+
+        assert_eq!(vec![1, 2], *ids.lock().unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_dispatcher_timeouts() {
+        struct SleepyClient {
+            sleep: Duration,
+        }
+
+        #[async_trait]
+        impl Client for SleepyClient {
+            async fn post(&self, _request: Request) -> Result<Reply, DispatchError> {
+                tokio::time::sleep(self.sleep).await;
+                Ok(Reply::default())
+            }
+        }
+
+        // A hung request times out, and is retried until the retries run out.
+        let client = SleepyClient {
+            sleep: Duration::from_secs(60),
+        };
+        let options = DispatchOptions {
+            retry: RetryPolicy {
+                max_attempts: 2,
+                base_delay: Duration::from_millis(1),
+                ..Default::default()
+            },
+            timeout: Some(Duration::from_millis(20)),
+            ..Default::default()
+        };
+        let dispatch = Dispatcher::with_options(1, client, |_, _| {}, options);
+        let res = dispatch.post_and_wait(json!(0)).await;
+        assert!(matches!(res, Err(DispatchError::TimedOut)), "{:?}", res);
+
+        // Each request takes 100ms to send, so the second is cut off at its deadline and the
+        // third is failed without being sent.
+        let client = SleepyClient {
+            sleep: Duration::from_millis(100),
+        };
+        let options = DispatchOptions {
+            capacity: 10,
+            deadline: Some(Duration::from_millis(150)),
+            ..Default::default()
+        };
+        let dispatch = Dispatcher::with_options(1, client, |_, _| {}, options);
+        let mut deliveries = Vec::new();
+        for idx in 0..3 {
+            deliveries.push(
+                dispatch
+                    .post_tracked(Request::new(json!(idx)))
+                    .await
+                    .unwrap(),
+            );
+        }
+        let start = tokio::time::Instant::now();
+        let results = futures::future::join_all(deliveries).await;
+        assert!(start.elapsed() < Duration::from_millis(200));
+
+        assert!(results[0].is_ok());
+        for res in &results[1..] {
+            assert!(
+                matches!(res, Err(DispatchError::DeadlineExceeded)),
+                "{:?}",
+                res
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn test_dispatcher_try_post() {
+        let (started, mut started_rx) = mpsc::unbounded_channel();
+        let gate = Arc::new(tokio::sync::Semaphore::new(0));
+        let delivered = Arc::new(Mutex::new(Vec::new()));
+
+        let client = GatedClient {
+            started,
+            gate: gate.clone(),
+            delivered: delivered.clone(),
+        };
+        let options = DispatchOptions {
+            capacity: 1,
+            ..Default::default()
+        };
+        let dispatch = Dispatcher::with_options(1, client, |_, _| {}, options);
+
+        dispatch.try_post(json!(0)).await.unwrap();
+        started_rx.recv().await.unwrap();
+        dispatch.try_post(json!(1)).await.unwrap();
+
+        // The queue is full, and posting would block.
+        let full = tokio::time::timeout(Duration::from_secs(1), dispatch.try_post(json!(2)))
+            .await
+            .unwrap();
+        assert!(matches!(full, Err(DispatchError::QueueFull)));
+
+        gate.add_permits(10);
+        dispatch.close().await.unwrap();
+        assert_eq!(vec![json!(0), json!(1)], *delivered.lock().unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_dispatcher_post_timeout() {
+        let (started, mut started_rx) = mpsc::unbounded_channel();
+        let gate = Arc::new(tokio::sync::Semaphore::new(0));
+        let delivered = Arc::new(Mutex::new(Vec::new()));
+
+        let client = GatedClient {
+            started,
+            gate: gate.clone(),
+            delivered: delivered.clone(),
+        };
+        let options = DispatchOptions {
+            capacity: 1,
+            ..Default::default()
+        };
+        let dispatch = Dispatcher::with_options(1, client, |_, _| {}, options);
+
+        dispatch.post(json!(0)).await.unwrap();
+        started_rx.recv().await.unwrap();
+        dispatch.post(json!(1)).await.unwrap();
+
+        // The queue is full, so this gives up after waiting a little while.
+        let timeout = Duration::from_millis(20);
+        let res = dispatch.post_timeout(json!(2), timeout).await;
+        assert!(
+            matches!(res, Err(DispatchError::EnqueueTimedOut)),
+            "{:?}",
+            res
+        );
+
+        // Room frees up before the timeout.
+        let gate_clone = gate.clone();
+        tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+            gate_clone.add_permits(10);
+        });
+        let timeout = Duration::from_secs(5);
+        dispatch.post_timeout(json!(3), timeout).await.unwrap();
+
+        dispatch.close().await.unwrap();
+        let want = vec![json!(0), json!(1), json!(3)];
+        assert_eq!(want, *delivered.lock().unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_dispatcher_handles() {
+        let calls = Arc::new(Mutex::new(RefCell::new(Vec::new())));
+
+        let client = MockClient {
+            calls: calls.clone(),
+        };
+        let dispatch = Dispatcher::new(3, client, |_, _| {});
+
+        let posters: Vec<_> = (0..4)
+            .map(|task| {
+                let handle = dispatch.handle();
+                tokio::spawn(async move {
+                    for idx in 0..5 {
+                        handle.post(json!(task * 5 + idx)).await.unwrap();
+                    }
+                })
+            })
+            .collect();
+        for poster in posters {
+            poster.await.unwrap();
+        }
+
+        let handle = dispatch.handle();
+        dispatch.close().await.unwrap();
+
+        let mut got = calls.lock().unwrap().clone().into_inner();
+        got.sort_by_key(|v| v.as_u64());
+        assert_eq!((0..20).map(|n| json!(n)).collect::<Vec<_>>(), got);
+
+        // The handle outlives the Dispatcher, but can't post to it any more.
+        let res = handle.post(json!(20)).await;
+        assert!(matches!(res, Err(DispatchError::SendFailed)));
+    }
+
+    #[tokio::test]
+    async fn test_dispatcher_run() {
+        let calls = Arc::new(Mutex::new(RefCell::new(Vec::new())));
+
+        let client = MockClient {
+            calls: calls.clone(),
+        };
+        let (handle, consumer) = Dispatcher::run(3, client, |_, _| {}, DispatchOptions::default());
+
+        // The consumer runs alongside the posting, and finishes once the handle is dropped.
+        let post = async move {
+            for idx in 0..10 {
+                handle.post(json!(idx)).await.unwrap();
+            }
+        };
+        tokio::time::timeout(Duration::from_secs(5), async {
+            tokio::join!(consumer, post)
+        })
+        .await
+        .unwrap();
+
+        let want: Vec<_> = (0..10).map(|n| json!(n)).collect();
+        assert_eq!(want, calls.lock().unwrap().clone().into_inner());
+    }
+
+    #[tokio::test]
+    async fn test_dispatcher_priorities() {
+        let (started, mut started_rx) = mpsc::unbounded_channel();
+        let gate = Arc::new(tokio::sync::Semaphore::new(0));
+        let delivered = Arc::new(Mutex::new(Vec::new()));
+
+        let client = GatedClient {
+            started,
+            gate: gate.clone(),
+            delivered: delivered.clone(),
+        };
+        let options = DispatchOptions {
+            capacity: 4,
+            backpressure: Backpressure::DropOldest,
+            ..Default::default()
+        };
+        let dispatch = Dispatcher::with_options(1, client, |_, _| {}, options);
+
+        // The first payload is held by the client while the rest are queued behind it.
+        dispatch.post(json!(0)).await.unwrap();
+        started_rx.recv().await.unwrap();
+
+        let post = |n: u64, priority| {
+            let request = Request::new(json!(n)).with_priority(priority);
+            dispatch.post_tracked(request)
+        };
+        let low = post(1, Priority::Low).await.unwrap();
+        post(2, Priority::Normal).await.unwrap();
+        post(3, Priority::High).await.unwrap();
+        post(4, Priority::Normal).await.unwrap();
+
+        // The queue is full, so the oldest low priority payload makes room for this one.
+        post(5, Priority::High).await.unwrap();
+        assert!(matches!(low.await, Err(DispatchError::Dropped)));
+
+        // Nothing lower than this is queued, so it's dropped itself.
+        let dropped = post(6, Priority::Low).await.unwrap();
+        assert!(matches!(dropped.await, Err(DispatchError::Dropped)));
+
+        gate.add_permits(10);
+        dispatch.close().await.unwrap();
+
+        let want: Vec<_> = [0, 3, 5, 2, 4].into_iter().map(|n| json!(n)).collect();
+        assert_eq!(want, *delivered.lock().unwrap());
+    }
+
+    #[cfg(feature = "kafka")]
+    #[tokio::test]
+    async fn test_kafka_client_unreachable() {
+        // Nothing is listening, so the message times out in the producer's queue.
+        let producer = rdkafka::ClientConfig::new()
+            .set("bootstrap.servers", "127.0.0.1:1")
+            .set("message.timeout.ms", "100")
+            .create()
+            .unwrap();
+        let client = KafkaClient::with_producer(producer, "events");
+
+        let request = Request::new(json!({ "hello": "kafka" })).with_partition("key");
+        let res = client.post(request).await;
+        assert!(
+            matches!(res, Err(DispatchError::KafkaFailed(_))),
+            "{:?}",
+            res
+        );
+    }
+
+    #[cfg(feature = "sqs")]
+    #[tokio::test]
+    async fn test_sqs_client() {
+        let credentials =
+            aws_credential_types::Credentials::new("AKID", "SECRET", None, None, "test");
+
+        let (url, requests) = serve().await;
+        let queue_url = url.join("123456789012/events.fifo").unwrap();
+        let client = SqsClient::with_credentials(queue_url, "us-east-1", credentials.clone());
+
+        let request = Request::new(json!({ "hello": "sqs" }))
+            .with_partition("group")
+            .with_idempotency_key("key");
+        client.post(request).await.unwrap();
+
+        let request = requests.lock().unwrap().remove(0);
+        assert!(request.starts_with("post / "), "{}", request);
+        assert!(request.contains("x-amz-target: amazonsqs.sendmessage\r\n"));
+        assert!(request.contains("authorization: aws4-hmac-sha256 credential=akid/"));
+        assert!(request.contains(r#""messagegroupid":"group""#));
+        assert!(request.contains(r#""messagededuplicationid":"key""#));
+        assert!(request.contains(r#""messagebody":"{\"hello\":\"sqs\"}""#));
+
+        let (url, _) = serve_with(|_| 400).await;
+        let queue_url = url.join("123456789012/events").unwrap();
+        let client = SqsClient::with_credentials(queue_url, "us-east-1", credentials);
+        let res = client.post(Request::new(json!(1))).await;
+        assert!(
+            matches!(
+                res,
+                Err(DispatchError::SqsFailed {
+                    transient: false,
+                    ..
+                })
+            ),
+            "{:?}",
+            res
+        );
+    }
+
+    #[cfg(feature = "sqs")]
+    #[tokio::test]
+    async fn test_sqs_client_resends_failed_messages() {
+        use wiremock::{
+            matchers::{body_partial_json, method},
+            Mock, MockServer, ResponseTemplate,
+        };
+
+        let credentials =
+            aws_credential_types::Credentials::new("AKID", "SECRET", None, None, "test");
+        let server = MockServer::start().await;
+        let queue_url: url::Url = format!("{}/123456789012/events", server.uri())
+            .parse()
+            .unwrap();
+
+        // The first call fails the second message, which is then sent again on its own.
+        Mock::given(method("POST"))
+            .and(body_partial_json(
+                json!({ "Entries": [{ "Id": "0" }, { "Id": "1" }] }),
+            ))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+                "Successful": [{ "Id": "0", "MessageId": "a" }],
+                "Failed": [{ "Id": "1", "Code": "InternalError", "SenderFault": false }],
+            })))
+            .expect(1)
+            .mount(&server)
+            .await;
+        Mock::given(method("POST"))
+            .and(body_partial_json(json!({ "Entries": [{ "Id": "1" }] })))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+                "Successful": [{ "Id": "1", "MessageId": "b" }],
+            })))
+            .expect(1)
+            .mount(&server)
+            .await;
+
+        let client = SqsClient::with_credentials(queue_url, "us-east-1", credentials);
+        let batch = vec![Request::new(json!(0)), Request::new(json!(1))];
+        let replies = client.post_batch(batch).await.unwrap();
+        let ids: Vec<_> = replies
+            .iter()
+            .map(|reply| reply.json::<serde_json::Value>().unwrap()["MessageId"].clone())
+            .collect();
+        assert_eq!(vec![json!("a"), json!("b")], ids);
+        server.verify().await;
+
+        // Failures that are the sender's fault aren't sent again.
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+                "Failed": [{ "Id": "0", "Code": "InvalidMessageContents", "SenderFault": true }],
+            })))
+            .expect(1)
+            .mount(&server)
+            .await;
+        let queue_url = format!("{}/123456789012/events", server.uri())
+            .parse()
+            .unwrap();
+        let credentials =
+            aws_credential_types::Credentials::new("AKID", "SECRET", None, None, "test");
+        let client = SqsClient::with_credentials(queue_url, "us-east-1", credentials);
+        let res = client.post_batch(vec![Request::new(json!(0))]).await;
+        assert!(
+            matches!(
+                res,
+                Err(DispatchError::SqsFailed {
+                    transient: false,
+                    ..
+                })
+            ),
+            "{:?}",
+            res
+        );
+        server.verify().await;
+
+        // Messages missing from both lists are resent, but only as many times as failed ones.
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({})))
+            .expect(SQS_BATCH_ATTEMPTS as u64)
+            .mount(&server)
+            .await;
+        let queue_url = format!("{}/123456789012/events", server.uri())
+            .parse()
+            .unwrap();
+        let credentials =
+            aws_credential_types::Credentials::new("AKID", "SECRET", None, None, "test");
+        let client = SqsClient::with_credentials(queue_url, "us-east-1", credentials);
+        let res = client.post_batch(vec![Request::new(json!(0))]).await;
+        assert!(
+            matches!(
+                res,
+                Err(DispatchError::SqsFailed {
+                    transient: true,
+                    ..
+                })
+            ),
+            "{:?}",
+            res
+        );
+        server.verify().await;
+    }
+
+    #[tokio::test]
+    async fn test_grpc_client() {
+        #[derive(Clone, PartialEq, Serialize, prost::Message)]
+        struct Event {
+            #[prost(string, tag = "1")]
+            name: String,
+        }
+
+        #[derive(Clone, PartialEq, prost::Message)]
+        struct Ack {
+            #[prost(uint64, tag = "1")]
+            id: u64,
+        }
+
+        let client = GrpcClient::new(|request: tonic::Request<Event>| async move {
+            let trace = request.metadata().get("x-trace").cloned();
+            let mut response = tonic::Response::new(Ack { id: 7 });
+            if let Some(trace) = trace {
+                response.metadata_mut().insert("x-trace", trace);
+            }
+            Ok(response)
+        });
+
+        let event = Event {
+            name: "click".into(),
+        };
+        let request = Request::new(event)
+            .with_header(HeaderName::from_static("x-trace"), "abc".parse().unwrap());
+        let reply = client.post(request).await.unwrap();
+        assert_eq!("abc", reply.headers["x-trace"]);
+        assert_eq!(Ack { id: 7 }, prost::Message::decode(reply.body).unwrap());
+
+        // Calls refused because of the request aren't retried, but ones that failed for other
+        // reasons are.
+        for (status, want_calls) in [
+            (tonic::Status::invalid_argument("bad event"), 1),
+            (tonic::Status::unavailable("try later"), 3),
+        ] {
+            let calls = Arc::new(Mutex::new(0));
+            let counted = calls.clone();
+            let client = GrpcClient::new(move |_: tonic::Request<serde_json::Value>| {
+                *counted.lock().unwrap() += 1;
+                let status = status.clone();
+                async move { Err::<tonic::Response<Ack>, _>(status) }
+            });
+            let options = DispatchOptions {
+                retry: RetryPolicy {
+                    max_attempts: 3,
+                    base_delay: Duration::from_millis(1),
+                    ..Default::default()
+                },
+                ..Default::default()
+            };
+            let dispatch = Dispatcher::with_options(1, client, |_, _| {}, options);
+            let res = dispatch.post_and_wait(json!(1)).await;
+            assert!(
+                matches!(res, Err(DispatchError::GrpcFailed(_))),
+                "{:?}",
+                res
+            );
+            assert_eq!(want_calls, *calls.lock().unwrap());
+        }
+    }
+
+    #[tokio::test]
+    async fn test_file_client() {
+        let dir = std::env::temp_dir().join(format!("capture-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("requests.jsonl");
+
+        // Each line is 27 bytes, so each file fits two.
+        let rotation = Rotation {
+            max_bytes: 60,
+            max_files: 2,
+        };
+        let client = FileClient::open(&path).unwrap().with_rotation(rotation);
+        let dispatch = Dispatcher::new(1, client, |_, _| {});
+        for idx in 0..7 {
+            dispatch.post(json!(idx)).await.unwrap();
+        }
+        dispatch.close().await.unwrap();
+
+        let mut files: Vec<_> = std::fs::read_dir(&dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+            .collect();
+        files.sort();
+        assert_eq!(
+            vec!["requests.jsonl", "requests.jsonl.1", "requests.jsonl.2"],
+            files
+        );
+
+        // The oldest two were rotated out of the last file kept.
+        let mut replayed = Vec::new();
+        for name in files.iter().rev() {
+            let requests: Vec<Request> = FileClient::replay(dir.join(name)).unwrap();
+            replayed.extend(requests.into_iter().map(|r| r.body));
+        }
+        assert_eq!((2..7).map(|n| json!(n)).collect::<Vec<_>>(), replayed);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_reqwest_client_signing() {
+        use hmac::Mac;
+
+        let verify = |message: &str, signature: &str| {
+            let mut mac = hmac::Hmac::<Sha256>::new_from_slice(b"secret").unwrap();
+            mac.update(message.as_bytes());
+            assert_eq!(hex(&mac.finalize().into_bytes()), signature);
+        };
+
+        let (url, requests) = serve().await;
+        let client =
+            ReqwestClient::new(HeaderMap::new(), url.clone()).with_signing(Signing::new("secret"));
+        client
+            .post(Request::new(json!({ "event": "paid" })))
+            .await
+            .unwrap();
+
+        let request = requests.lock().unwrap().remove(0);
+        let (head, body) = request.split_once("\r\n\r\n").unwrap();
+        let header = head
+            .lines()
+            .find_map(|line| line.strip_prefix("x-signature: "))
+            .unwrap();
+        let (timestamp, signature) = header.split_once(",v1=").unwrap();
+        let timestamp = timestamp.strip_prefix("t=").unwrap();
+        verify(&format!("{}.{}", timestamp, body), signature);
+
+        let signing = Signing::new("secret")
+            .with_header(HeaderName::from_static("x-hub-signature-256"))
+            .with_format(SignatureFormat::Body);
+        let client = ReqwestClient::new(HeaderMap::new(), url).with_signing(signing);
+        client
+            .post(Request::new(json!({ "event": "paid" })))
+            .await
+            .unwrap();
+
+        let request = requests.lock().unwrap().remove(0);
+        let (head, body) = request.split_once("\r\n\r\n").unwrap();
+        let signature = head
+            .lines()
+            .find_map(|line| line.strip_prefix("x-hub-signature-256: sha256="))
+            .unwrap();
+        verify(body, signature);
+    }
+
+    #[tokio::test]
+    async fn test_dispatcher_flush() {
+        let calls = Arc::new(Mutex::new(RefCell::new(Vec::new())));
+
+        let client = MockClient {
+            calls: calls.clone(),
+        };
+        let options = DispatchOptions {
+            batch: Some(BatchPolicy {
+                max_linger: Duration::from_secs(60),
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+        let dispatch = Dispatcher::with_options(1, client, |_, _| {}, options);
+
+        // Flushing doesn't wait for the batches to linger, and posting carries on afterwards.
+        for round in 0..2 {
+            for idx in 0..3 {
+                dispatch.post(json!(round * 3 + idx)).await.unwrap();
+            }
+            tokio::time::timeout(Duration::from_secs(5), dispatch.flush())
+                .await
+                .unwrap()
+                .unwrap();
+            assert_eq!(3 * (round + 1), calls.lock().unwrap().borrow().len());
+        }
+
+        // Nothing to wait for.
+        tokio::time::timeout(Duration::from_secs(5), dispatch.flush())
+            .await
+            .unwrap()
+            .unwrap();
+        dispatch.close().await.unwrap();
+
+        // Batches are sent at every interval, even while they are lingering.
+        let calls = Arc::new(Mutex::new(RefCell::new(Vec::new())));
+        let client = MockClient {
+            calls: calls.clone(),
+        };
+        let options = DispatchOptions {
+            batch: Some(BatchPolicy {
+                max_linger: Duration::from_secs(60),
+                flush_interval: Some(Duration::from_millis(20)),
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+        let dispatch = Dispatcher::with_options(1, client, |_, _| {}, options);
+        dispatch.post(json!(0)).await.unwrap();
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        assert_eq!(1, calls.lock().unwrap().borrow().len());
+    }
+
+    /// Exercises `ReqwestClient` and the Dispatcher against a real HTTP server.
+    #[tokio::test]
+    async fn test_executor() {
+        let running = Arc::new(AtomicUsize::new(0));
+        let most = Arc::new(AtomicUsize::new(0));
+        let results = Arc::new(Mutex::new(Vec::new()));
+
+        let run = {
+            let (running, most) = (running.clone(), most.clone());
+            move |job: usize| {
+                let (running, most) = (running.clone(), most.clone());
+                async move {
+                    let now = running.fetch_add(1, Ordering::Relaxed) + 1;
+                    most.fetch_max(now, Ordering::Relaxed);
+                    tokio::time::sleep(Duration::from_millis(20)).await;
+                    running.fetch_sub(1, Ordering::Relaxed);
+                    match job % 3 {
+                        0 => Err(format!("job {} failed", job)),
+                        _ => Ok(job * 10),
+                    }
+                }
+            }
+        };
+        let r = results.clone();
+        let options = ExecutorOptions {
+            capacity: 10,
+            ..Default::default()
+        };
+        let executor = Executor::with_options(2, run, move |res| lock(&r).push(res), options);
+
+        for job in 1..=6 {
+            executor.submit(job).await.unwrap();
+        }
+        executor.flush().await;
+        assert_eq!(6, lock(&results).len());
+        assert_eq!(2, most.load(Ordering::Relaxed));
+
+        executor.submit(7).await.unwrap();
+        executor.close().await.unwrap();
+
+        let mut results = lock(&results).clone();
+        results.sort();
+        let want = vec![
+            Ok(10),
+            Ok(20),
+            Ok(40),
+            Ok(50),
+            Ok(70),
+            Err("job 3 failed".to_string()),
+            Err("job 6 failed".to_string()),
+        ];
+        assert_eq!(want, results);
+    }
+
+    mod http {
+        use super::*;
+        use wiremock::{
+            matchers::{body_json, header, method, path},
+            Mock, MockServer, ResponseTemplate,
+        };
+
+        fn url(server: &MockServer, path: &str) -> url::Url {
+            format!("{}{}", server.uri(), path).parse().unwrap()
+        }
+
+        #[tokio::test]
+        async fn test_http_headers() {
+            let server = MockServer::start().await;
+            Mock::given(method("PUT"))
+                .and(path("/events"))
+                .and(header("key", "val"))
+                .and(header("x-request", "1"))
+                .and(header("content-type", "application/json"))
+                .and(body_json(json!({ "hello": 1 })))
+                .respond_with(ResponseTemplate::new(200))
+                .expect(1)
+                .mount(&server)
+                .await;
+
+            let mut headers = HeaderMap::new();
+            headers.insert("key", "val".parse().unwrap());
+            let client = ReqwestClient::new(headers, url(&server, "/events"));
+            let dispatch = Dispatcher::new(1, client, |_, _| {});
+
+            let request = Request::new(json!({ "hello": 1 }))
+                .with_method(Method::PUT)
+                .with_header(HeaderName::from_static("x-request"), "1".parse().unwrap());
+            dispatch.post_request(request).await.unwrap();
+            dispatch.close().await.unwrap();
+
+            server.verify().await;
+        }
+
+        #[tokio::test]
+        async fn test_http_failover_on_server_error() {
+            let primary = MockServer::start().await;
+            Mock::given(method("POST"))
+                .respond_with(ResponseTemplate::new(500))
+                .expect(1)
+                .mount(&primary)
+                .await;
+            let secondary = MockServer::start().await;
+            Mock::given(method("POST"))
+                .respond_with(ResponseTemplate::new(200).set_body_string("ok"))
+                .expect(1)
+                .mount(&secondary)
+                .await;
+
+            let urls = vec![url(&primary, "/"), url(&secondary, "/")];
+            let client = ReqwestClient::with_endpoints(HeaderMap::new(), urls, Balance::Failover);
+            let dispatch = Dispatcher::new(1, client, |_, _| {});
+
+            let reply = dispatch.post_and_wait(json!(1)).await.unwrap();
+            assert_eq!(StatusCode::OK, reply.status);
+            assert_eq!("ok", reply.body);
+
+            primary.verify().await;
+            secondary.verify().await;
+        }
+
+        #[tokio::test]
+        async fn test_http_server_errors_are_retried() {
+            let server = MockServer::start().await;
+            Mock::given(method("POST"))
+                .respond_with(ResponseTemplate::new(503))
+                .up_to_n_times(1)
+                .with_priority(1)
+                .mount(&server)
+                .await;
+            Mock::given(method("POST"))
+                .respond_with(ResponseTemplate::new(200))
+                .mount(&server)
+                .await;
+
+            let client = ReqwestClient::new(HeaderMap::new(), url(&server, "/"));
+            let options = DispatchOptions {
+                retry: RetryPolicy {
+                    base_delay: Duration::from_millis(1),
+                    ..Default::default()
+                },
+                on_error: Some(Box::new(|_, _, _| {})),
+                ..Default::default()
+            };
+            let dispatch = Dispatcher::with_options(1, client, |_, _| {}, options);
+
+            let reply = dispatch.post_and_wait(json!(1)).await.unwrap();
+            assert_eq!(StatusCode::OK, reply.status);
+            assert_eq!(2, server.received_requests().await.unwrap().len());
+        }
+
+        #[tokio::test]
+        async fn test_http_client_errors_are_not_retried() {
+            let server = MockServer::start().await;
+            Mock::given(method("POST"))
+                .respond_with(ResponseTemplate::new(422).set_body_string("bad field"))
+                .expect(1)
+                .mount(&server)
+                .await;
+
+            let client = ReqwestClient::new(HeaderMap::new(), url(&server, "/"));
+            let (dead, mut dead_rx) = mpsc::unbounded_channel();
+            let options = DispatchOptions {
+                retry: RetryPolicy {
+                    base_delay: Duration::from_millis(1),
+                    ..Default::default()
+                },
+                dead_letter: Some(Box::new(dead)),
+                ..Default::default()
+            };
+            let dispatch = Dispatcher::with_options(1, client, |_, _| {}, options);
+
+            let res = dispatch.post_and_wait(json!(1)).await;
+            let Err(DispatchError::Rejected { status, body }) = res else {
+                panic!("{:?}", res);
+            };
+            assert_eq!(StatusCode::UNPROCESSABLE_ENTITY, status);
+            assert_eq!("bad field", body);
+
+            let (requests, _) = dead_rx.recv().await.unwrap();
+            assert_eq!(json!(1), requests[0].body);
+            server.verify().await;
+        }
+
+        #[tokio::test]
+        async fn test_http_retry_after() {
+            let server = MockServer::start().await;
+            Mock::given(method("POST"))
+                .respond_with(ResponseTemplate::new(429).insert_header("retry-after", "1"))
+                .up_to_n_times(1)
+                .with_priority(1)
+                .mount(&server)
+                .await;
+            Mock::given(method("POST"))
+                .respond_with(ResponseTemplate::new(200))
+                .mount(&server)
+                .await;
+
+            let client = ReqwestClient::new(HeaderMap::new(), url(&server, "/"));
+            let dispatch = Dispatcher::new(1, client, |_, _| {});
+
+            let start = tokio::time::Instant::now();
+            dispatch.post_and_wait(json!(1)).await.unwrap();
+            assert!(start.elapsed() >= Duration::from_secs(1));
+            assert_eq!(2, server.received_requests().await.unwrap().len());
+        }
+
+        #[tokio::test]
+        async fn test_http_multipart() {
+            let path = std::env::temp_dir().join(format!("report-{}.csv", std::process::id()));
+            std::fs::write(&path, "id,total\n1,42\n").unwrap();
+
+            let server = MockServer::start().await;
+            Mock::given(method("POST"))
+                .respond_with(ResponseTemplate::new(200))
+                .expect(1)
+                .mount(&server)
+                .await;
+
+            let client =
+                ReqwestClient::new(HeaderMap::new(), url(&server, "/")).with_format(Multipart);
+            let dispatch = Dispatcher::new(1, client, |_, _| {});
+            let form = MultipartForm::new()
+                .text("title", "weekly")
+                .file_with_type("report", &path, "text/csv");
+            dispatch.post_and_wait(form).await.unwrap();
+
+            let received = server.received_requests().await.unwrap();
+            let content_type = received[0].headers.get("content-type").unwrap();
+            assert!(content_type
+                .to_str()
+                .unwrap()
+                .starts_with("multipart/form-data; boundary="));
+            let body = String::from_utf8_lossy(&received[0].body);
+            assert!(
+                body.contains("name=\"title\"\r\n\r\nweekly\r\n"),
+                "{}",
+                body
+            );
+            let file_name = path.file_name().unwrap().to_string_lossy();
+            assert!(
+                body.contains(&format!("filename=\"{}\"", file_name)),
+                "{}",
+                body
+            );
+            assert!(
+                body.contains("content-type: text/csv\r\n\r\nid,total\n1,42\n"),
+                "{}",
+                body
+            );
+
+            // Missing files fail before anything is sent.
+            let _ = std::fs::remove_file(&path);
+            let form = MultipartForm::new().file("report", &path);
+            let res = dispatch.post_and_wait(form).await;
+            assert!(
+                matches!(res, Err(DispatchError::ReadFailed(_))),
+                "{:?}",
+                res
+            );
+            server.verify().await;
+        }
+
+        #[tokio::test]
+        async fn test_http_streaming_ndjson() {
+            let server = MockServer::start().await;
+            Mock::given(method("POST"))
+                .and(header("content-type", "application/x-ndjson"))
+                .respond_with(ResponseTemplate::new(200))
+                .expect(1)
+                .mount(&server)
+                .await;
+
+            let client = ReqwestClient::new(HeaderMap::new(), url(&server, "/"))
+                .with_batch_format(BatchFormat::StreamingNdjson);
+            let options = DispatchOptions {
+                batch: Some(BatchPolicy {
+                    max_items: 3,
+                    ..Default::default()
+                }),
+                capacity: 3,
+                ..Default::default()
+            };
+            let dispatch = Dispatcher::with_options(1, client, |_, _| {}, options);
+            for idx in 0..3 {
+                dispatch.post(json!({ "n": idx })).await.unwrap();
+            }
+            dispatch.close().await.unwrap();
+
+            let received = server.received_requests().await.unwrap();
+            assert_eq!(
+                "{\"n\":0}\n{\"n\":1}\n{\"n\":2}\n",
+                String::from_utf8_lossy(&received[0].body)
+            );
+            server.verify().await;
+        }
+
+        #[tokio::test]
+        async fn test_http_streamed_bodies_are_rebuilt_for_retries() {
+            let server = MockServer::start().await;
+            Mock::given(method("POST"))
+                .respond_with(ResponseTemplate::new(503))
+                .up_to_n_times(1)
+                .with_priority(1)
+                .mount(&server)
+                .await;
+            Mock::given(method("POST"))
+                .respond_with(ResponseTemplate::new(200))
+                .mount(&server)
+                .await;
+
+            let client = ReqwestClient::new(HeaderMap::new(), url(&server, "/"))
+                .with_batch_format(BatchFormat::StreamingNdjson);
+            let options = DispatchOptions {
+                batch: Some(BatchPolicy {
+                    max_items: 2,
+                    ..Default::default()
+                }),
+                retry: RetryPolicy {
+                    base_delay: Duration::from_millis(1),
+                    ..Default::default()
+                },
+                on_error: Some(Box::new(|_, _, _| {})),
+                ..Default::default()
+            };
+            let dispatch = Dispatcher::with_options(1, client, |_, _| {}, options);
+            for idx in 0..2 {
+                dispatch.post(json!({ "n": idx })).await.unwrap();
+            }
+            dispatch.close().await.unwrap();
+
+            let received = server.received_requests().await.unwrap();
+            assert_eq!(2, received.len());
+            for request in received {
+                assert_eq!(
+                    "{\"n\":0}\n{\"n\":1}\n",
+                    String::from_utf8_lossy(&request.body)
+                );
+            }
+        }
+
+        #[tokio::test]
+        async fn test_http_build_failures_are_errors() {
+            let client = ReqwestClient::new(HeaderMap::new(), "http://localhost/".parse().unwrap());
+            let request = Request::new(json!(1)).with_url("data:text/plain,1".parse().unwrap());
+
+            let err = client.post(request).await.unwrap_err();
+            assert!(matches!(err, DispatchError::BuildFailed(_)), "{:?}", err);
+            assert!(!err.retryable());
+        }
+
+        #[tokio::test]
+        async fn test_http_concurrency() {
+            let server = MockServer::start().await;
+            Mock::given(method("POST"))
+                .respond_with(ResponseTemplate::new(200).set_delay(Duration::from_millis(100)))
+                .mount(&server)
+                .await;
+
+            let client = ReqwestClient::new(HeaderMap::new(), url(&server, "/"));
+            let dispatch = Dispatcher::new(2, client, |_, _| {});
+
+            // Six requests, two at a time, take three rounds.
+            let start = tokio::time::Instant::now();
+            for idx in 0..6 {
+                dispatch.post(json!(idx)).await.unwrap();
+            }
+            dispatch.close().await.unwrap();
+            let elapsed = start.elapsed();
+            assert!(elapsed >= Duration::from_millis(300), "{:?}", elapsed);
+            assert!(elapsed < Duration::from_millis(600), "{:?}", elapsed);
+        }
+
+        #[tokio::test]
+        async fn test_http_flush() {
+            let server = MockServer::start().await;
+            Mock::given(method("POST"))
+                .respond_with(ResponseTemplate::new(200).set_delay(Duration::from_millis(20)))
+                .mount(&server)
+                .await;
+
+            let client = ReqwestClient::new(HeaderMap::new(), url(&server, "/"));
+            let dispatch = Dispatcher::new(2, client, |_, _| {});
+
+            for round in 1..=2 {
+                for idx in 0..5 {
+                    dispatch.post(json!(idx)).await.unwrap();
+                }
+                dispatch.flush().await.unwrap();
+
+                let received = server.received_requests().await.unwrap();
+                assert_eq!(5 * round, received.len());
+                assert_eq!(5 * round, dispatch.metrics().delivered);
+            }
+
+            dispatch.close().await.unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn test_chaos_client() {
+        let quick_retries = RetryPolicy {
+            max_attempts: 3,
+            base_delay: Duration::from_millis(1),
+            ..Default::default()
+        };
+
+        // Scripted failures are retried until the request is delivered.
+        let client = ChaosClient::new().with_script([
+            Outcome::Fail(DispatchError::TimedOut),
+            Outcome::Fail(DispatchError::TimedOut),
+        ]);
+        let options = DispatchOptions {
+            retry: quick_retries.clone(),
+            ..Default::default()
+        };
+        let dispatch = Dispatcher::with_options(1, client.clone(), |_, _| {}, options);
+        dispatch.post_and_wait(json!(0)).await.unwrap();
+        assert_eq!(3, client.calls());
+        assert_eq!(vec![json!(0)], client.received());
+
+        // A hung request is cut off by the timeout.
+        let client = ChaosClient::new().with_script([Outcome::Hang]);
+        let options = DispatchOptions {
+            retry: RetryPolicy::never(),
+            timeout: Some(Duration::from_millis(20)),
+            ..Default::default()
+        };
+        let dispatch = Dispatcher::with_options(1, client, |_, _| {}, options);
+        let res = dispatch.post_and_wait(json!(0)).await;
+        assert!(matches!(res, Err(DispatchError::TimedOut)), "{:?}", res);
+
+        // Random failures are the same for the same seed.
+        let failures = |seed| async move {
+            let client = ChaosClient::new().with_error_rate(0.5).with_seed(seed);
+            let options = DispatchOptions {
+                retry: RetryPolicy::never(),
+                on_error: Some(Box::new(|_, _, _| {})),
+                ..Default::default()
+            };
+            let dispatch = Dispatcher::with_options(1, client.clone(), |_, _| {}, options);
+            for idx in 0..50 {
+                dispatch.post(json!(idx)).await.unwrap();
+            }
+            dispatch.close().await.unwrap();
+            client.received()
+        };
+        let delivered = failures(7).await;
+        assert!((10..40).contains(&delivered.len()), "{}", delivered.len());
+        assert_eq!(delivered, failures(7).await);
+
+        // Concurrency is limited, and delivery is in order when it's one at a time.
+        for (order, want) in [(DeliveryOrder::Unordered, 3), (DeliveryOrder::Fifo, 1)] {
+            let client = ChaosClient::new().with_latency(Latency::Uniform(
+                Duration::from_millis(5),
+                Duration::from_millis(20),
+            ));
+            let options = DispatchOptions {
+                order,
+                ..Default::default()
+            };
+            let dispatch = Dispatcher::with_options(3, client.clone(), |_, _| {}, options);
+            for idx in 0..12 {
+                dispatch.post(json!(idx)).await.unwrap();
+            }
+            dispatch.close().await.unwrap();
+
+            assert_eq!(want, client.max_concurrency(), "{:?}", order);
+            assert_eq!(12, client.received().len());
+            if order == DeliveryOrder::Fifo {
+                assert_eq!(
+                    (0..12).map(|n| json!(n)).collect::<Vec<_>>(),
+                    client.received()
+                );
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_dispatcher_tracing() {
+        use tracing_subscriber::layer::SubscriberExt;
+
+        /// Keeps the names of the spans that events were recorded in, and the event messages.
+        #[derive(Clone, Default)]
+        struct Recorder(Arc<Mutex<Vec<(String, String)>>>);
+
+        impl<S> tracing_subscriber::Layer<S> for Recorder
+        where
+            S: tracing::Subscriber + for<'a> tracing_subscriber::registry::LookupSpan<'a>,
+        {
+            fn on_event(
+                &self,
+                event: &tracing::Event<'_>,
+                ctx: tracing_subscriber::layer::Context<'_, S>,
+            ) {
+                struct Message(String);
+                impl tracing::field::Visit for Message {
+                    fn record_debug(
+                        &mut self,
+                        field: &tracing::field::Field,
+                        value: &dyn fmt::Debug,
+                    ) {
+                        if field.name() == "message" {
+                            self.0 = format!("{:?}", value);
+                        }
+                    }
+                }
+
+                let mut message = Message(String::new());
+                event.record(&mut message);
+                let span = ctx.event_span(event).map_or("", |span| span.name());
+                self.0.lock().unwrap().push((span.to_string(), message.0));
+            }
+        }
+
+        let recorder = Recorder::default();
+        let subscriber = tracing_subscriber::registry().with(recorder.clone());
+        let _guard = tracing::subscriber::set_default(subscriber);
+
+        let client = ChaosClient::new().with_script([Outcome::Fail(DispatchError::TimedOut)]);
+        let options = DispatchOptions {
+            retry: RetryPolicy {
+                base_delay: Duration::from_millis(1),
+                ..Default::default()
+            },
+            on_error: Some(Box::new(|_, _, _| {})),
+            ..Default::default()
+        };
+        // The consumer runs on this thread so that it uses the same subscriber.
+        let (handle, consumer) = Dispatcher::run(1, client, |_, _| {}, options);
+        let post = async move { handle.post_and_wait(json!(0)).await.unwrap() };
+        tokio::join!(consumer, post);
+
+        let events = recorder.0.lock().unwrap().clone();
+        let payload = |message: &str| ("payload".to_string(), message.to_string());
+        assert!(
+            events.contains(&payload("attempt failed, retrying")),
+            "{:?}",
+            events
+        );
+        assert!(events.contains(&payload("delivered")), "{:?}", events);
+    }
+
+    #[tokio::test]
+    async fn test_dispatcher_pause() {
+        let client = ChaosClient::new().with_latency(Latency::Fixed(Duration::from_millis(50)));
+        let options = DispatchOptions {
+            capacity: 10,
+            ..Default::default()
+        };
+        let dispatch = Dispatcher::with_options(1, client.clone(), |_, _| {}, options);
+
+        dispatch.post(json!(0)).await.unwrap();
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        dispatch.pause();
+        assert!(dispatch.is_paused());
+        for idx in 1..3 {
+            dispatch.post(json!(idx)).await.unwrap();
+        }
+
+        // The request that was in flight is still delivered, but nothing more is taken.
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert_eq!(vec![json!(0)], client.received());
+        assert_eq!(2, dispatch.metrics().queued);
+
+        dispatch.resume();
+        dispatch.flush().await.unwrap();
+        assert_eq!(vec![json!(0), json!(1), json!(2)], client.received());
+        dispatch.close().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_dispatcher_watermarks() {
+        let events = Arc::new(Mutex::new(Vec::new()));
+        let (high, low) = (events.clone(), events.clone());
+        let watermarks = Watermarks::new(
+            3,
+            1,
+            move |depth| lock(&high).push(("high", depth)),
+            move |depth| lock(&low).push(("low", depth)),
+        );
+        let dispatch = DispatcherBuilder::new()
+            .capacity(10)
+            .watermarks(watermarks)
+            .build(ChaosClient::new(), |_, _| {})
+            .unwrap();
+
+        dispatch.pause();
+        for idx in 0..4 {
+            dispatch.post(json!(idx)).await.unwrap();
+        }
+        assert_eq!(vec![("high", 3)], lock(&events).clone());
+
+        dispatch.resume();
+        dispatch.flush().await.unwrap();
+        assert_eq!(vec![("high", 3), ("low", 1)], lock(&events).clone());
+
+        // Nothing more is called until the queue is behind again.
+        dispatch.post(json!(4)).await.unwrap();
+        dispatch.close().await.unwrap();
+        assert_eq!(2, lock(&events).len());
+    }
+
+    #[tokio::test]
+    async fn test_dispatcher_cancellation() {
+        for on_cancel in [OnCancel::Drain, OnCancel::Abandon] {
+            let client = ChaosClient::new().with_latency(Latency::Fixed(Duration::from_millis(20)));
+            let token = CancellationToken::new();
+            let options = DispatchOptions {
+                capacity: 10,
+                cancellation: Some(token.clone()),
+                on_cancel,
+                ..Default::default()
+            };
+            let dispatch = Dispatcher::with_options(1, client.clone(), |_, _| {}, options);
+
+            let mut deliveries = Vec::new();
+            for idx in 0..5 {
+                let request = Request::new(json!(idx));
+                deliveries.push(dispatch.post_tracked(request).await.unwrap());
+            }
+            token.cancel();
+
+            for delivery in deliveries {
+                let res = delivery.await;
+                match on_cancel {
+                    OnCancel::Drain => assert!(res.is_ok(), "{:?}", res),
+                    OnCancel::Abandon => {
+                        assert!(matches!(res, Err(DispatchError::Abandoned)), "{:?}", res)
+                    }
+                }
+            }
+
+            // Nothing more is accepted once cancelled, and the consumer has stopped.
+            let res = dispatch.post(json!(5)).await;
+            assert!(matches!(res, Err(DispatchError::SendFailed)), "{:?}", res);
+            dispatch.close().await.unwrap();
+
+            let want = if on_cancel == OnCancel::Drain { 5 } else { 0 };
+            assert_eq!(want, client.received().len(), "{:?}", on_cancel);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_dispatcher_delayed() {
+        let client = ChaosClient::new();
+        let dispatch = Dispatcher::new(1, client.clone(), |_, _| {});
+        let start = tokio::time::Instant::now();
+
+        dispatch
+            .post_at(json!(2), start + Duration::from_millis(60))
+            .await
+            .unwrap();
+        dispatch
+            .post_after(json!(1), Duration::from_millis(30))
+            .await
+            .unwrap();
+        dispatch.post(json!(0)).await.unwrap();
+
+        // Delayed requests don't take up room, even though the capacity is 1.
+        assert_eq!(2, dispatch.metrics().delayed);
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        assert_eq!(vec![json!(0)], client.received());
+
+        // Closing waits for the delayed requests, which go out in the order they're due.
+        dispatch.close().await.unwrap();
+        assert!(start.elapsed() >= Duration::from_millis(60));
+        assert_eq!(vec![json!(0), json!(1), json!(2)], client.received());
+    }
+
+    #[tokio::test]
+    async fn test_dispatcher_hooks() {
+        /// Scrubs passwords and records what happened to each attempt.
+        #[derive(Clone, Default)]
+        struct Audit(Arc<Mutex<Vec<String>>>);
+
+        impl Hooks for Audit {
+            fn before_send(&self, request: &mut Request) {
+                if let Some(body) = request.body.as_object_mut() {
+                    body.remove("password");
+                }
+            }
+
+            fn after_send(
+                &self,
+                request: &Request,
+                result: Result<&Reply, &DispatchError>,
+                _elapsed: Duration,
+            ) {
+                let outcome = match result {
+                    Ok(reply) => reply.status.to_string(),
+                    Err(e) => e.to_string(),
+                };
+                let entry = format!("{} {}", request.body, outcome);
+                self.0.lock().unwrap().push(entry);
+            }
+        }
+
+        let audit = Audit::default();
+        let client = ChaosClient::new().with_script([Outcome::Fail(DispatchError::TimedOut)]);
+        let options = DispatchOptions {
+            retry: RetryPolicy {
+                base_delay: Duration::from_millis(1),
+                ..Default::default()
+            },
+            on_error: Some(Box::new(|_, _, _| {})),
+            hooks: vec![Box::new(audit.clone())],
+            ..Default::default()
+        };
+        let dispatch = Dispatcher::with_options(1, client.clone(), |_, _| {}, options);
+        dispatch
+            .post(json!({ "user": "a", "password": "hunter2" }))
+            .await
+            .unwrap();
+        dispatch.close().await.unwrap();
+
+        assert_eq!(vec![json!({ "user": "a" })], client.received());
+        assert_eq!(
+            vec![
+                format!(r#"{{"user":"a"}} {}"#, DispatchError::TimedOut),
+                r#"{"user":"a"} 200 OK"#.to_string(),
+            ],
+            *audit.0.lock().unwrap()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_dispatcher_flush_summary() {
+        let summaries = Arc::new(Mutex::new(Vec::new()));
+        let on_flush = {
+            let summaries = summaries.clone();
+            move |summary: &FlushSummary| summaries.lock().unwrap().push(*summary)
+        };
+
+        let client = ChaosClient::new().with_script([
+            Outcome::Fail(DispatchError::TimedOut),
+            Outcome::Deliver,
+            Outcome::Deliver,
+            Outcome::Deliver,
+            Outcome::Fail(DispatchError::DeadlineExceeded),
+        ]);
+        let options = DispatchOptions {
+            retry: RetryPolicy {
+                base_delay: Duration::from_millis(1),
+                ..Default::default()
+            },
+            on_error: Some(Box::new(|_, _, _| {})),
+            on_flush: Some(Box::new(on_flush)),
+            capacity: 10,
+            ..Default::default()
+        };
+        let dispatch = Dispatcher::with_options(1, client, |_, _| {}, options);
+
+        for idx in 1..=3 {
+            dispatch.post(json!(idx)).await.unwrap();
+        }
+        dispatch.flush().await.unwrap();
+        // Each summary only covers what happened since the last one.
+        dispatch.post(json!(4)).await.unwrap();
+        dispatch.close().await.unwrap();
+
+        let summaries = summaries.lock().unwrap().clone();
+        let counts: Vec<_> = summaries
+            .iter()
+            .map(|s| (s.delivered, s.failed, s.retried, s.bytes))
+            .collect();
+        assert_eq!(vec![(3, 0, 1, 3), (0, 1, 0, 0)], counts);
+    }
+
+    #[tokio::test]
+    async fn test_dispatcher_adaptive_concurrency() {
+        let run = |latency, target| async move {
+            let adaptive = Arc::new(AdaptiveConcurrency::new(2, 8).with_latency_target(target));
+            let client = ChaosClient::new().with_latency(Latency::Fixed(latency));
+            let options = DispatchOptions {
+                adaptive_concurrency: Some(adaptive.clone()),
+                capacity: 100,
+                ..Default::default()
+            };
+            let dispatch = Dispatcher::with_options(1, client.clone(), |_, _| {}, options);
+            for idx in 0..60 {
+                dispatch.post(json!(idx)).await.unwrap();
+            }
+            dispatch.close().await.unwrap();
+
+            assert_eq!(60, client.received().len());
+            (adaptive.limit(), client.max_concurrency())
+        };
+
+        // A fast server gets more and more concurrency, up to the maximum.
+        let (limit, max_concurrency) =
+            run(Duration::from_millis(5), Duration::from_millis(100)).await;
+        assert_eq!(8, limit);
+        assert!(max_concurrency > 2, "{}", max_concurrency);
+
+        // A slow server is kept to the minimum.
+        let (limit, max_concurrency) =
+            run(Duration::from_millis(5), Duration::from_millis(1)).await;
+        assert_eq!(2, limit);
+        assert_eq!(2, max_concurrency);
+    }
+
+    #[tokio::test]
+    async fn test_dispatcher_builder() {
+        let config: DispatcherConfig = toml::from_str(
+            r#"
+            concurrency = 4
+            capacity = 10
+            backpressure = "drop_oldest"
+            timeout_ms = 500
+
+            [retry]
+            max_attempts = 5
+
+            [batch]
+            max_items = 2
+            "#,
+        )
+        .unwrap();
+        assert_eq!(4, config.concurrency);
+        assert_eq!(Backpressure::DropOldest, config.backpressure);
+        assert_eq!(5, config.retry.max_attempts);
+        assert_eq!(
+            RetryConfig::default().base_delay_ms,
+            config.retry.base_delay_ms
+        );
+        assert_eq!(Some(2), config.batch.as_ref().map(|batch| batch.max_items));
+
+        let client = ChaosClient::new();
+        let dispatch = DispatcherBuilder::from_config(&config)
+            .build(client.clone(), |_, _| {})
+            .unwrap();
+        for idx in 0..4 {
+            dispatch.post(json!(idx)).await.unwrap();
+        }
+        dispatch.close().await.unwrap();
+        assert_eq!(4, client.received().len());
+
+        let invalid: [(DispatcherBuilder, ConfigError); 6] = [
+            (
+                DispatcherBuilder::new().concurrency(0),
+                ConfigError::ZeroConcurrency,
+            ),
+            (
+                DispatcherBuilder::new().capacity(0),
+                ConfigError::ZeroCapacity,
+            ),
+            (
+                DispatcherBuilder::new().retry(RetryPolicy {
+                    max_attempts: 0,
+                    ..Default::default()
+                }),
+                ConfigError::ZeroAttempts,
+            ),
+            (
+                DispatcherBuilder::new().timeout(Duration::ZERO),
+                ConfigError::ZeroTimeout,
+            ),
+            (
+                DispatcherBuilder::new().batch(BatchPolicy {
+                    max_items: 0,
+                    ..Default::default()
+                }),
+                ConfigError::EmptyBatch,
+            ),
+            (
+                DispatcherBuilder::new()
+                    .capacity(10)
+                    .watermarks(Watermarks::new(20, 5, |_| {}, |_| {})),
+                ConfigError::Watermarks { high: 20, low: 5 },
+            ),
+        ];
+        for (builder, want) in invalid {
+            assert_eq!(Err(want), builder.validate());
+        }
+
+        assert!(toml::from_str::<DispatcherConfig>("concurency = 4").is_err());
+    }
+
+    #[tokio::test]
+    async fn test_dispatcher_flush_failures() {
+        let client = ChaosClient::new().with_script([
+            Outcome::Deliver,
+            Outcome::Fail(DispatchError::DeadlineExceeded),
+        ]);
+        let options = DispatchOptions {
+            on_error: Some(Box::new(|_, _, _| {})),
+            capacity: 10,
+            ..Default::default()
+        };
+        let dispatch = Dispatcher::with_options(1, client, |_, _| {}, options);
+
+        for idx in 0..3 {
+            dispatch.post(json!(idx)).await.unwrap();
+        }
+        let failures = dispatch.flush().await.unwrap_err();
+        assert_eq!(
+            (2, 1, 0),
+            (failures.delivered, failures.failed, failures.abandoned)
+        );
+        let (request, e) = &failures.requests[0];
+        assert_eq!(json!(1), request.body);
+        assert!(matches!(e, DispatchError::DeadlineExceeded), "{:?}", e);
+
+        // Failures are only returned once.
+        dispatch.post(json!(3)).await.unwrap();
+        dispatch.flush().await.unwrap();
     }
 }
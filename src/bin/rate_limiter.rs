@@ -1,10 +1,22 @@
+#![allow(dead_code)]
+
+use serde::Deserialize;
 use std::{
+    collections::{HashMap, VecDeque},
+    hash::Hash,
+    sync::{Arc, Mutex},
     thread,
-    time::{self, Instant},
+    time::{self, SystemTime},
+};
+
+use rate_limit::{
+    CalendarWindow, Clock, ConfigError, Decision, FixedWindow, Gcra, Instant, LeakyBucket,
+    MovingWindow, Persist, RateLimiter, RateLimiterBuilder, Shared, SlidingWindow, SystemClock,
+    TokenBucket, WallClock, MIN_WAIT,
 };
 
 fn main() -> anyhow::Result<()> {
-    let mut limiter = TokenBucket::new(time::Duration::from_secs(3600), 60);
+    let mut limiter: TokenBucket = TokenBucket::new(time::Duration::from_secs(3600), 60);
     for _ in 0..=10 {
         thread::sleep(time::Duration::from_secs(1));
         println!("{}", limiter.allowed());
@@ -19,149 +31,3273 @@ fn main() -> anyhow::Result<()> {
     Ok(())
 }
 
-trait RateLimiter {
-    fn new(window: time::Duration, limit: usize) -> Self;
-    fn allowed(&mut self) -> bool;
+/// The values of the usual rate limit response headers for a decision, so that every HTTP
+/// integration reports them the same way. Durations are given in whole seconds, rounded up so that
+/// clients don't come back early.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct RateLimitHeaders {
+    limit: usize,
+    remaining: usize,
+    /// Seconds until the quota is fully restored.
+    reset: u64,
+    /// Seconds to wait before retrying, only set when the request was denied.
+    retry_after: Option<u64>,
 }
 
-struct FixedWindow {
-    window_start: Instant,
-    hits: usize,
-    window: time::Duration,
-    limit: usize,
+impl RateLimitHeaders {
+    /// `reset_in` is the time until the limiter's `reset_at`.
+    fn new(decision: Decision, limit: usize, reset_in: time::Duration) -> Self {
+        let seconds = |d: time::Duration| d.as_secs() + u64::from(d.subsec_nanos() > 0);
+
+        match decision {
+            Decision::Allowed { remaining } => RateLimitHeaders {
+                limit,
+                remaining,
+                reset: seconds(reset_in),
+                retry_after: None,
+            },
+            Decision::Denied { retry_after } => RateLimitHeaders {
+                limit,
+                remaining: 0,
+                reset: seconds(std::cmp::max(reset_in, retry_after)),
+                retry_after: Some(seconds(retry_after)),
+            },
+        }
+    }
+
+    /// The de facto `X-RateLimit-*` headers, plus `Retry-After` on denial.
+    fn x_rate_limit(&self) -> Vec<(&'static str, String)> {
+        self.headers([
+            "X-RateLimit-Limit",
+            "X-RateLimit-Remaining",
+            "X-RateLimit-Reset",
+        ])
+    }
+
+    /// The `RateLimit-*` headers from the IETF draft, plus `Retry-After` on denial.
+    fn draft(&self) -> Vec<(&'static str, String)> {
+        self.headers(["RateLimit-Limit", "RateLimit-Remaining", "RateLimit-Reset"])
+    }
+
+    fn headers(&self, names: [&'static str; 3]) -> Vec<(&'static str, String)> {
+        let mut headers = vec![
+            (names[0], self.limit.to_string()),
+            (names[1], self.remaining.to_string()),
+            (names[2], self.reset.to_string()),
+        ];
+        if let Some(retry_after) = self.retry_after {
+            headers.push(("Retry-After", retry_after.to_string()));
+        }
+
+        headers
+    }
 }
 
-impl RateLimiter for FixedWindow {
-    fn new(window: time::Duration, limit: usize) -> Self {
-        FixedWindow {
-            window_start: Instant::now(),
-            hits: 0,
-            window,
-            limit,
+/// A clock driven by time sources supplied by the caller, for targets where
+/// `std::time::Instant::now()` and `SystemTime::now()` panic or aren't available, such as wasm32 in
+/// browser extensions and Cloudflare Workers.
+#[derive(Clone)]
+struct TickClock {
+    ticks: Arc<dyn Fn() -> time::Duration + Send + Sync>,
+    since_unix_epoch: Arc<dyn Fn() -> time::Duration + Send + Sync>,
+}
+
+impl TickClock {
+    /// `ticks` is a monotonic time since any epoch, such as `performance.now()`, and
+    /// `since_unix_epoch` is the wall clock time, such as `Date.now()`.
+    fn new(
+        ticks: impl Fn() -> time::Duration + Send + Sync + 'static,
+        since_unix_epoch: impl Fn() -> time::Duration + Send + Sync + 'static,
+    ) -> Self {
+        TickClock {
+            ticks: Arc::new(ticks),
+            since_unix_epoch: Arc::new(since_unix_epoch),
         }
     }
 
-    fn allowed(&mut self) -> bool {
-        let now = Instant::now();
+    /// Uses a single source of milliseconds since the Unix epoch, such as `Date.now()`, for both.
+    /// It needn't be strictly monotonic: time going backwards is treated as no time passing.
+    fn from_unix_millis(millis: impl Fn() -> f64 + Send + Sync + 'static) -> Self {
+        let source: Arc<dyn Fn() -> time::Duration + Send + Sync> =
+            Arc::new(move || time::Duration::from_secs_f64(millis().max(0.0) / 1000.0));
+        TickClock {
+            ticks: source.clone(),
+            since_unix_epoch: source,
+        }
+    }
+}
 
-        if now.duration_since(self.window_start) > self.window {
-            self.window_start = now;
-            self.hits = 0;
-        };
+impl Clock for TickClock {
+    fn now(&self) -> Instant {
+        Instant::from_ticks((self.ticks)())
+    }
+}
 
-        if self.hits >= self.limit {
-            return false;
-        };
+impl WallClock for TickClock {
+    fn wall(&self) -> SystemTime {
+        SystemTime::UNIX_EPOCH + (self.since_unix_epoch)()
+    }
+}
 
-        self.hits = self.hits + 1;
-        true
+/// Paces any iterator with a limiter, e.g. `for id in ids.into_iter().rate_limited(limiter)`.
+trait RateLimitedExt: Iterator + Sized {
+    fn rate_limited<L: RateLimiter>(self, limiter: L) -> RateLimited<Self, L> {
+        RateLimited {
+            inner: self,
+            limiter,
+        }
     }
 }
 
-struct MovingWindow {
-    prev_start: Instant,
-    prev_count: usize,
-    this_start: Instant,
-    this_count: usize,
-    window: time::Duration,
-    limit: usize,
+impl<I: Iterator> RateLimitedExt for I {}
+
+/// An iterator that blocks the calling thread until the limiter admits each item, see
+/// `RateLimitedExt::rate_limited`.
+struct RateLimited<I, L> {
+    inner: I,
+    limiter: L,
 }
 
-impl RateLimiter for MovingWindow {
-    fn new(window: time::Duration, limit: usize) -> Self {
-        let now = Instant::now();
+impl<I: Iterator, L: RateLimiter> Iterator for RateLimited<I, L> {
+    type Item = I::Item;
 
-        MovingWindow {
-            prev_start: now,
-            prev_count: 0,
-            this_start: now,
-            this_count: 0,
-            window,
-            limit,
+    fn next(&mut self) -> Option<I::Item> {
+        // Take the item first so that reaching the end doesn't wait for (or use up) a token.
+        let item = self.inner.next()?;
+        self.limiter.wait();
+        Some(item)
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.inner.size_hint()
+    }
+}
+
+/// Paces any stream with a limiter, e.g. to space out messages from a `ReceiverStream`.
+trait ThrottledExt: futures::Stream + Sized {
+    fn throttled<L: RateLimiter>(self, limiter: L) -> Throttled<Self, L> {
+        Throttled {
+            inner: self,
+            limiter,
+            granted: false,
+            delay: None,
         }
     }
+}
+
+impl<S: futures::Stream> ThrottledExt for S {}
+
+/// A stream that only polls the inner stream once the limiter admits the next item, sleeping until
+/// then. The token is taken before the item is available, so the end of the stream uses one up.
+struct Throttled<S, L> {
+    inner: S,
+    limiter: L,
+    granted: bool,
+    delay: Option<std::pin::Pin<Box<tokio::time::Sleep>>>,
+}
+
+impl<S, L> futures::Stream for Throttled<S, L>
+where
+    S: futures::Stream + Unpin,
+    L: RateLimiter + Unpin,
+{
+    type Item = S::Item;
 
-    fn allowed(&mut self) -> bool {
-        let now = Instant::now();
+    fn poll_next(
+        self: std::pin::Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+    ) -> std::task::Poll<Option<S::Item>> {
+        use std::future::Future;
 
-        // Cycle the current window values into the previous window repeatedly until we "catch up"
-        // to the present time. In cases where more than two windows duration have passed since the
-        // start of this window period this will cycle through twice and essentially reset the
-        // counter.
-        while now.duration_since(self.this_start) > self.window {
-            self.prev_start = self.this_start;
-            self.prev_count = self.this_count;
-            self.this_start = self.prev_start + self.window;
-            self.this_count = 0;
+        let this = self.get_mut();
+
+        while !this.granted {
+            if let Some(delay) = &mut this.delay {
+                futures::ready!(delay.as_mut().poll(cx));
+                this.delay = None;
+            }
+
+            if this.limiter.allowed() {
+                this.granted = true;
+            } else {
+                let wait = this.limiter.time_until_allowed().max(MIN_WAIT);
+                this.delay = Some(Box::pin(tokio::time::sleep(wait)));
+            }
         }
 
-        let this_period = now.duration_since(self.this_start);
-        let last_period = self.window - this_period;
+        let item = futures::ready!(std::pin::Pin::new(&mut this.inner).poll_next(cx));
+        this.granted = false;
+        std::task::Poll::Ready(item)
+    }
+}
+
+/// Enforces several limits at once, such as 10 per second and 100 per minute and 1000 per hour,
+/// each tracked by its own limiter. A request is only admitted if every limit allows it, in which
+/// case it is counted against all of them; a denied request isn't counted against any.
+///
+/// The first limit is the primary one reported by `window`/`limit` and changed by `update`.
+struct MultiWindow<L: RateLimiter> {
+    limiters: Vec<L>,
+    clock: L::Clock,
+}
+
+impl<L: RateLimiter> MultiWindow<L> {
+    /// Adds another limit that must also be satisfied.
+    fn and(mut self, window: time::Duration, limit: usize) -> Self {
+        self.limiters
+            .push(L::with_clock(window, limit, self.clock.clone()));
+        self
+    }
+}
+
+impl<L: RateLimiter> RateLimiter for MultiWindow<L> {
+    type Clock = L::Clock;
 
-        let hits_from_last_period =
-            (self.prev_count * last_period.as_micros() as usize) / self.window.as_micros() as usize;
+    fn with_clock(window: time::Duration, limit: usize, clock: L::Clock) -> Self {
+        MultiWindow {
+            limiters: vec![L::with_clock(window, limit, clock.clone())],
+            clock,
+        }
+    }
 
-        if self.this_count + hits_from_last_period >= self.limit {
+    fn allowed_n(&mut self, cost: usize) -> bool {
+        // Check everything before consuming anything so that a request denied by one limit
+        // doesn't use up quota in the others. Available quota never shrinks as time passes, so
+        // the limiters will still admit the request when it is consumed below.
+        if self.limiters.iter().any(|l| l.remaining() < cost) {
             return false;
         }
 
-        self.this_count = self.this_count + 1;
+        self.limiters.iter_mut().all(|l| l.allowed_n(cost))
+    }
 
-        true
+    fn time_until_allowed(&self) -> time::Duration {
+        self.limiters
+            .iter()
+            .map(|l| l.time_until_allowed())
+            .max()
+            .unwrap_or_default()
+    }
+
+    fn remaining(&self) -> usize {
+        self.limiters
+            .iter()
+            .map(|l| l.remaining())
+            .min()
+            .unwrap_or_default()
+    }
+
+    fn reset_at(&self) -> Instant {
+        self.limiters
+            .iter()
+            .map(|l| l.reset_at())
+            .max()
+            .unwrap_or_else(|| self.clock.now())
+    }
+
+    fn window(&self) -> time::Duration {
+        self.limiters[0].window()
+    }
+
+    fn limit(&self) -> usize {
+        self.limiters[0].limit()
+    }
+
+    fn update(&mut self, window: time::Duration, limit: usize) {
+        self.limiters[0].update(window, limit)
+    }
+
+    fn give_back(&mut self, n: usize) {
+        for limiter in &mut self.limiters {
+            limiter.give_back(n);
+        }
     }
 }
 
-struct TokenBucket {
-    tokens: usize,
-    last_hit: Instant,
-    window: time::Duration,
-    limit: usize,
+/// Combines two limiters of any kind, such as a token bucket for bursts and a fixed window as an
+/// hourly cap, admitting a request only if both do. As with `MultiWindow`, a request denied by
+/// either isn't counted against the other. Nest them to combine more than two.
+///
+/// The first limiter is the primary one reported by `window`/`limit` and changed by `update`.
+struct AllOf<A, B> {
+    first: A,
+    second: B,
+}
+
+impl<A, B> AllOf<A, B> {
+    fn new(first: A, second: B) -> Self {
+        AllOf { first, second }
+    }
+}
+
+impl<A, B> RateLimiter for AllOf<A, B>
+where
+    A: RateLimiter,
+    B: RateLimiter<Clock = A::Clock>,
+{
+    type Clock = A::Clock;
+
+    /// Uses the same limit for both, which is only useful for combining different algorithms. Use
+    /// `new` to combine differently configured limiters.
+    fn with_clock(window: time::Duration, limit: usize, clock: A::Clock) -> Self {
+        AllOf::new(
+            A::with_clock(window, limit, clock.clone()),
+            B::with_clock(window, limit, clock),
+        )
+    }
+
+    fn allowed_n(&mut self, cost: usize) -> bool {
+        // Check both before consuming either, see `MultiWindow::allowed_n`.
+        if self.first.remaining() < cost || self.second.remaining() < cost {
+            return false;
+        }
+
+        self.first.allowed_n(cost) && self.second.allowed_n(cost)
+    }
+
+    fn time_until_allowed(&self) -> time::Duration {
+        std::cmp::max(
+            self.first.time_until_allowed(),
+            self.second.time_until_allowed(),
+        )
+    }
+
+    fn remaining(&self) -> usize {
+        std::cmp::min(self.first.remaining(), self.second.remaining())
+    }
+
+    fn reset_at(&self) -> Instant {
+        std::cmp::max(self.first.reset_at(), self.second.reset_at())
+    }
+
+    fn window(&self) -> time::Duration {
+        self.first.window()
+    }
+
+    fn limit(&self) -> usize {
+        self.first.limit()
+    }
+
+    fn update(&mut self, window: time::Duration, limit: usize) {
+        self.first.update(window, limit)
+    }
+
+    fn give_back(&mut self, n: usize) {
+        self.first.give_back(n);
+        self.second.give_back(n);
+    }
 }
 
-impl TokenBucket {
-    fn new_tokens(&self, now: Instant) -> usize {
-        // Calculate the number of new tokens that should be accumlated based on the provided time.
-        // This is the time elapsed since the last token calculation times the rate of token
-        // accumulation.
-        let elapsed = now.duration_since(self.last_hit);
+/// Combines two limiters of any kind, admitting a request if either does, such as a per-client
+/// quota with a shared overflow pool behind it. The request is only counted against the first
+/// limiter that admits it.
+///
+/// The first limiter is the primary one reported by `window`/`limit` and changed by `update`, and
+/// the one that `give_back` returns quota to, since which one admitted a request isn't tracked.
+struct AnyOf<A, B> {
+    first: A,
+    second: B,
+}
+
+impl<A, B> AnyOf<A, B> {
+    fn new(first: A, second: B) -> Self {
+        AnyOf { first, second }
+    }
+}
+
+impl<A, B> RateLimiter for AnyOf<A, B>
+where
+    A: RateLimiter,
+    B: RateLimiter<Clock = A::Clock>,
+{
+    type Clock = A::Clock;
+
+    /// Uses the same limit for both, which is only useful for combining different algorithms. Use
+    /// `new` to combine differently configured limiters.
+    fn with_clock(window: time::Duration, limit: usize, clock: A::Clock) -> Self {
+        AnyOf::new(
+            A::with_clock(window, limit, clock.clone()),
+            B::with_clock(window, limit, clock),
+        )
+    }
+
+    fn allowed_n(&mut self, cost: usize) -> bool {
+        self.first.allowed_n(cost) || self.second.allowed_n(cost)
+    }
+
+    fn time_until_allowed(&self) -> time::Duration {
+        std::cmp::min(
+            self.first.time_until_allowed(),
+            self.second.time_until_allowed(),
+        )
+    }
+
+    fn remaining(&self) -> usize {
+        std::cmp::max(self.first.remaining(), self.second.remaining())
+    }
+
+    fn reset_at(&self) -> Instant {
+        std::cmp::min(self.first.reset_at(), self.second.reset_at())
+    }
+
+    fn window(&self) -> time::Duration {
+        self.first.window()
+    }
+
+    fn limit(&self) -> usize {
+        self.first.limit()
+    }
 
-        // Rate as tokens per microsecond, which will probably be a very small number.
-        let rate = self.limit as f64 / self.window.as_micros() as f64;
+    fn update(&mut self, window: time::Duration, limit: usize) {
+        self.first.update(window, limit)
+    }
 
-        // Microseconds elapsed * tokens per microsecond yields tokens to output.
-        (elapsed.as_micros() as f64 * rate) as usize
+    fn give_back(&mut self, n: usize) {
+        self.first.give_back(n)
     }
 }
 
-impl RateLimiter for TokenBucket {
-    fn new(window: time::Duration, limit: usize) -> Self {
-        TokenBucket {
-            tokens: 0,
-            last_hit: Instant::now(),
-            window,
-            limit,
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+enum Priority {
+    Low,
+    Normal,
+    High,
+}
+
+/// Sheds lower priority requests first as quota runs out. Each priority can be made to leave a
+/// fraction of the limit untouched, which only higher priority requests can use. For example,
+/// reserving 20% for `Low` means background work stops being admitted once 80% of the quota is
+/// used, leaving the rest for interactive traffic. Nothing is reserved by default.
+///
+/// Requests made through the `RateLimiter` trait are `Normal` priority.
+struct Prioritized<L: RateLimiter> {
+    inner: L,
+    reserved: [f64; 3],
+    clock: L::Clock,
+}
+
+impl<L: RateLimiter> Prioritized<L> {
+    /// Requests of `priority` must leave `fraction` (between 0 and 1) of the limit available.
+    fn with_reserved(mut self, priority: Priority, fraction: f64) -> Self {
+        self.reserved[priority as usize] = fraction.clamp(0.0, 1.0);
+        self
+    }
+
+    /// The quota that requests of `priority` must leave available.
+    fn floor(&self, priority: Priority) -> usize {
+        (self.inner.limit() as f64 * self.reserved[priority as usize]).ceil() as usize
+    }
+
+    fn allowed_with(&mut self, priority: Priority, cost: usize) -> bool {
+        let floor = self.floor(priority);
+        if self.inner.remaining() < cost + floor {
+            return false;
         }
+
+        self.inner.allowed_n(cost)
     }
+}
+
+impl<L: RateLimiter> RateLimiter for Prioritized<L> {
+    type Clock = L::Clock;
 
-    fn allowed(&mut self) -> bool {
-        let now = Instant::now();
+    fn with_clock(window: time::Duration, limit: usize, clock: L::Clock) -> Self {
+        Prioritized {
+            inner: L::with_clock(window, limit, clock.clone()),
+            reserved: [0.0; 3],
+            clock,
+        }
+    }
 
-        // Accumulate tokens at the rate of limit / window (tokens per time)
-        let new_tokens = self.new_tokens(now);
+    fn allowed_n(&mut self, cost: usize) -> bool {
+        self.allowed_with(Priority::Normal, cost)
+    }
 
-        // Only adjust the last hit time if at least one token was accumulated.
-        if new_tokens > 0 {
-            // Limit tokens to self.limit
-            self.tokens = std::cmp::min(self.tokens + new_tokens as usize, self.limit);
-            self.last_hit = now; // Based on accumulation of tokens
+    fn time_until_allowed(&self) -> time::Duration {
+        let floor = self.floor(Priority::Normal);
+        if floor == 0 || self.inner.remaining() > floor {
+            return self.inner.time_until_allowed();
         }
 
-        if self.tokens == 0 {
-            return false;
+        // The inner limiter only says when the next unit is available, but the request also has to
+        // leave the floor untouched. All of the quota is back by `reset_at`, so wait for that.
+        let reset = self.inner.reset_at().duration_since(self.clock.now());
+        std::cmp::max(self.inner.time_until_allowed(), reset)
+    }
+
+    fn remaining(&self) -> usize {
+        self.inner.remaining()
+    }
+
+    fn reset_at(&self) -> Instant {
+        self.inner.reset_at()
+    }
+
+    fn window(&self) -> time::Duration {
+        self.inner.window()
+    }
+
+    fn limit(&self) -> usize {
+        self.inner.limit()
+    }
+
+    fn update(&mut self, window: time::Duration, limit: usize) {
+        self.inner.update(window, limit)
+    }
+
+    fn give_back(&mut self, n: usize) {
+        self.inner.give_back(n)
+    }
+}
+
+/// Adjusts its limit based on feedback from the caller about how requests went, for upstreams that
+/// don't publish their limits. The limit grows additively, by about one for each limit's worth of
+/// successes, and is cut multiplicatively whenever a request is throttled or fails (AIMD).
+///
+/// The limit is kept between 1 and the configured limit by default. Use `with_bounds` to let it
+/// probe above that.
+struct Adaptive<L> {
+    inner: L,
+    current: f64,
+    min_limit: usize,
+    max_limit: usize,
+    decrease: f64,
+}
+
+impl<L: RateLimiter> Adaptive<L> {
+    fn with_bounds(mut self, min_limit: usize, max_limit: usize) -> Self {
+        self.min_limit = min_limit.max(1);
+        self.max_limit = max_limit.max(self.min_limit);
+        self.set_current(self.current);
+        self
+    }
+
+    /// The factor the limit is multiplied by on throttling or errors. Defaults to 0.5.
+    fn with_decrease(mut self, decrease: f64) -> Self {
+        self.decrease = decrease.clamp(0.0, 1.0);
+        self
+    }
+
+    fn on_success(&mut self) {
+        self.set_current(self.current + 1.0 / self.current);
+    }
+
+    fn on_throttled(&mut self) {
+        self.set_current(self.current * self.decrease);
+    }
+
+    fn on_error(&mut self) {
+        self.set_current(self.current * self.decrease);
+    }
+
+    fn set_current(&mut self, limit: f64) {
+        self.current = limit.clamp(self.min_limit as f64, self.max_limit as f64);
+
+        let limit = self.current as usize;
+        if limit != self.inner.limit() {
+            self.inner.set_limit(limit);
         }
+    }
+}
 
-        self.tokens = self.tokens - 1;
+impl<L: RateLimiter> RateLimiter for Adaptive<L> {
+    type Clock = L::Clock;
 
-        true
+    fn with_clock(window: time::Duration, limit: usize, clock: L::Clock) -> Self {
+        Adaptive {
+            inner: L::with_clock(window, limit, clock),
+            current: limit as f64,
+            min_limit: 1,
+            max_limit: limit,
+            decrease: 0.5,
+        }
+    }
+
+    fn allowed_n(&mut self, cost: usize) -> bool {
+        self.inner.allowed_n(cost)
+    }
+
+    fn time_until_allowed(&self) -> time::Duration {
+        self.inner.time_until_allowed()
+    }
+
+    fn remaining(&self) -> usize {
+        self.inner.remaining()
+    }
+
+    fn reset_at(&self) -> Instant {
+        self.inner.reset_at()
+    }
+
+    fn window(&self) -> time::Duration {
+        self.inner.window()
+    }
+
+    fn limit(&self) -> usize {
+        self.inner.limit()
+    }
+
+    fn update(&mut self, window: time::Duration, limit: usize) {
+        // An explicit update resets the adaptation to the new limit as the ceiling.
+        self.max_limit = std::cmp::max(limit, self.min_limit);
+        self.current = self.max_limit as f64;
+        self.inner.update(window, self.max_limit)
+    }
+
+    fn give_back(&mut self, n: usize) {
+        self.inner.give_back(n)
+    }
+}
+
+/// What happened on a single admission check, as passed to an `Observer`.
+#[derive(Debug, Clone, Copy)]
+struct Event<'a, K: ?Sized = ()> {
+    /// The name given to the limiter with `named`, or empty if it wasn't named.
+    limiter: &'a str,
+    key: &'a K,
+    cost: usize,
+    allowed: bool,
+    /// The quota left after the check.
+    remaining: usize,
+}
+
+/// Notified of every allowed and denied request, to log them or feed a metrics system without
+/// wrapping each call site. Any `Fn(&Event<K>)` closure is an observer.
+trait Observer<K: ?Sized = ()> {
+    fn observe(&self, event: &Event<K>);
+}
+
+impl<K: ?Sized, F: Fn(&Event<K>)> Observer<K> for F {
+    fn observe(&self, event: &Event<K>) {
+        self(event)
+    }
+}
+
+/// A decision captured by a `Recorder`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Recorded<K = ()> {
+    at: Instant,
+    key: K,
+    cost: usize,
+    allowed: bool,
+}
+
+/// An observer that keeps a log of every decision for assertions in tests. Clones share the log.
+/// Attach it to a keyed limiter with `with_observer`, or wrap an unkeyed limiter in a
+/// `RecordingLimiter`.
+struct Recorder<K, C> {
+    log: Arc<Mutex<Vec<Recorded<K>>>>,
+    clock: C,
+}
+
+impl<K, C: Clock> Recorder<K, C> {
+    fn new(clock: C) -> Self {
+        Recorder {
+            log: Arc::new(Mutex::new(Vec::new())),
+            clock,
+        }
+    }
+
+    fn log(&self) -> Vec<Recorded<K>>
+    where
+        K: Clone,
+    {
+        self.log.lock().unwrap().clone()
+    }
+}
+
+impl<K, C: Clone> Clone for Recorder<K, C> {
+    fn clone(&self) -> Self {
+        Recorder {
+            log: self.log.clone(),
+            clock: self.clock.clone(),
+        }
+    }
+}
+
+impl<K: Clone, C: Clock> Observer<K> for Recorder<K, C> {
+    fn observe(&self, event: &Event<K>) {
+        self.log.lock().unwrap().push(Recorded {
+            at: self.clock.now(),
+            key: event.key.clone(),
+            cost: event.cost,
+            allowed: event.allowed,
+        });
+    }
+}
+
+/// Wraps a limiter to record each of its decisions, see `Recorder`.
+struct RecordingLimiter<L: RateLimiter> {
+    inner: L,
+    recorder: Recorder<(), L::Clock>,
+}
+
+impl<L: RateLimiter> RecordingLimiter<L> {
+    fn log(&self) -> Vec<Recorded> {
+        self.recorder.log()
+    }
+}
+
+impl<L: RateLimiter> RateLimiter for RecordingLimiter<L> {
+    type Clock = L::Clock;
+
+    fn with_clock(window: time::Duration, limit: usize, clock: L::Clock) -> Self {
+        RecordingLimiter {
+            inner: L::with_clock(window, limit, clock.clone()),
+            recorder: Recorder::new(clock),
+        }
+    }
+
+    fn allowed_n(&mut self, cost: usize) -> bool {
+        let allowed = self.inner.allowed_n(cost);
+        self.recorder.observe(&Event {
+            limiter: "",
+            key: &(),
+            cost,
+            allowed,
+            remaining: self.inner.remaining(),
+        });
+        allowed
+    }
+
+    fn time_until_allowed(&self) -> time::Duration {
+        self.inner.time_until_allowed()
+    }
+
+    fn remaining(&self) -> usize {
+        self.inner.remaining()
+    }
+
+    fn reset_at(&self) -> Instant {
+        self.inner.reset_at()
+    }
+
+    fn window(&self) -> time::Duration {
+        self.inner.window()
+    }
+
+    fn limit(&self) -> usize {
+        self.inner.limit()
+    }
+
+    fn update(&mut self, window: time::Duration, limit: usize) {
+        self.inner.update(window, limit)
+    }
+
+    fn give_back(&mut self, n: usize) {
+        self.inner.give_back(n)
+    }
+}
+
+/// A limiter that makes a predefined sequence of decisions regardless of time, for testing code
+/// that embeds a limiter without real sleeps. Once the script runs out, every request is allowed.
+///
+/// `remaining` and `time_until_allowed` report the latest decision, so scripting denials with a
+/// zero `retry_after` keeps `wait` down to its minimum sleep.
+struct ScriptedLimiter {
+    script: VecDeque<Decision>,
+    last: Decision,
+    window: time::Duration,
+    limit: usize,
+    clock: SystemClock,
+}
+
+impl ScriptedLimiter {
+    fn from_script(decisions: impl IntoIterator<Item = Decision>) -> Self {
+        let mut limiter = Self::with_clock(time::Duration::from_secs(1), usize::MAX, SystemClock);
+        limiter.script = decisions.into_iter().collect();
+        limiter
+    }
+}
+
+impl RateLimiter for ScriptedLimiter {
+    type Clock = SystemClock;
+
+    fn with_clock(window: time::Duration, limit: usize, clock: SystemClock) -> Self {
+        ScriptedLimiter {
+            script: VecDeque::new(),
+            last: Decision::Allowed { remaining: limit },
+            window,
+            limit,
+            clock,
+        }
+    }
+
+    fn allowed_n(&mut self, _cost: usize) -> bool {
+        self.last = self.script.pop_front().unwrap_or(Decision::Allowed {
+            remaining: self.limit,
+        });
+        self.last.is_allowed()
+    }
+
+    fn time_until_allowed(&self) -> time::Duration {
+        match self.last {
+            Decision::Allowed { .. } => time::Duration::ZERO,
+            Decision::Denied { retry_after } => retry_after,
+        }
+    }
+
+    fn remaining(&self) -> usize {
+        match self.last {
+            Decision::Allowed { remaining } => remaining,
+            Decision::Denied { .. } => 0,
+        }
+    }
+
+    fn reset_at(&self) -> Instant {
+        self.clock.now() + self.time_until_allowed()
+    }
+
+    fn window(&self) -> time::Duration {
+        self.window
+    }
+
+    fn limit(&self) -> usize {
+        self.limit
+    }
+
+    fn update(&mut self, window: time::Duration, limit: usize) {
+        self.window = window;
+        self.limit = limit;
+    }
+
+    fn give_back(&mut self, _n: usize) {}
+}
+
+/// Allowed and denied requests over the trailing window, as returned by `Observed::stats`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+struct Stats {
+    allowed: usize,
+    denied: usize,
+}
+
+impl Stats {
+    /// The fraction of requests that were allowed, or 1 if there weren't any.
+    fn acceptance_ratio(&self) -> f64 {
+        match self.allowed + self.denied {
+            0 => 1.0,
+            total => self.allowed as f64 / total as f64,
+        }
+    }
+}
+
+/// Counts decisions in slices of a tenth of the window, so that the trailing window's stats are
+/// accurate to within one slice.
+struct RollingStats<C> {
+    slices: VecDeque<(Instant, Stats)>,
+    clock: C,
+}
+
+impl<C: Clock> RollingStats<C> {
+    const SLICES: u32 = 10;
+
+    fn new(clock: C) -> Self {
+        RollingStats {
+            slices: VecDeque::new(),
+            clock,
+        }
+    }
+
+    fn record(&mut self, allowed: bool, window: time::Duration) {
+        let now = self.clock.now();
+        let slice = std::cmp::max(window / Self::SLICES, time::Duration::from_nanos(1));
+
+        while self
+            .slices
+            .front()
+            .is_some_and(|(start, _)| now.duration_since(*start) >= window)
+        {
+            self.slices.pop_front();
+        }
+
+        match self.slices.back_mut() {
+            Some((start, stats)) if now.duration_since(*start) < slice => {
+                if allowed {
+                    stats.allowed += 1;
+                } else {
+                    stats.denied += 1;
+                }
+            }
+            _ => self.slices.push_back((
+                now,
+                Stats {
+                    allowed: allowed as usize,
+                    denied: !allowed as usize,
+                },
+            )),
+        }
+    }
+
+    fn stats(&self, window: time::Duration) -> Stats {
+        let now = self.clock.now();
+
+        self.slices
+            .iter()
+            .filter(|(start, _)| now.duration_since(*start) < window)
+            .fold(Stats::default(), |total, (_, stats)| Stats {
+                allowed: total.allowed + stats.allowed,
+                denied: total.denied + stats.denied,
+            })
+    }
+}
+
+/// Wraps any limiter to notify observers of each of its decisions and keep rolling stats of them
+/// over the limiter's window. Keyed limiters take observers directly, see
+/// `KeyedRateLimiter::with_observer`.
+struct Observed<L: RateLimiter> {
+    inner: L,
+    name: String,
+    observers: Vec<Box<dyn Observer + Send + Sync>>,
+    stats: RollingStats<L::Clock>,
+}
+
+impl<L: RateLimiter> Observed<L> {
+    fn named(mut self, name: impl Into<String>) -> Self {
+        self.name = name.into();
+        self
+    }
+
+    fn with_observer(mut self, observer: impl Observer + Send + Sync + 'static) -> Self {
+        self.observers.push(Box::new(observer));
+        self
+    }
+
+    /// Allowed and denied requests over the trailing window, e.g. to show throttle pressure on an
+    /// admin endpoint.
+    fn stats(&self) -> Stats {
+        self.stats.stats(self.inner.window())
+    }
+}
+
+impl<L: RateLimiter> RateLimiter for Observed<L> {
+    type Clock = L::Clock;
+
+    fn with_clock(window: time::Duration, limit: usize, clock: L::Clock) -> Self {
+        Observed {
+            inner: L::with_clock(window, limit, clock.clone()),
+            name: String::new(),
+            observers: Vec::new(),
+            stats: RollingStats::new(clock),
+        }
+    }
+
+    fn allowed_n(&mut self, cost: usize) -> bool {
+        let allowed = self.inner.allowed_n(cost);
+        self.stats.record(allowed, self.inner.window());
+
+        if !self.observers.is_empty() {
+            let event = Event {
+                limiter: &self.name,
+                key: &(),
+                cost,
+                allowed,
+                remaining: self.inner.remaining(),
+            };
+            for observer in &self.observers {
+                observer.observe(&event);
+            }
+        }
+
+        allowed
+    }
+
+    fn time_until_allowed(&self) -> time::Duration {
+        self.inner.time_until_allowed()
+    }
+
+    fn remaining(&self) -> usize {
+        self.inner.remaining()
+    }
+
+    fn reset_at(&self) -> Instant {
+        self.inner.reset_at()
+    }
+
+    fn window(&self) -> time::Duration {
+        self.inner.window()
+    }
+
+    fn limit(&self) -> usize {
+        self.inner.limit()
+    }
+
+    fn update(&mut self, window: time::Duration, limit: usize) {
+        self.inner.update(window, limit)
+    }
+
+    fn give_back(&mut self, n: usize) {
+        self.inner.give_back(n)
+    }
+}
+
+/// Publishes the decisions of named limiters as Prometheus metrics labelled by limiter name:
+/// `allowed_total` and `denied_total` counters, a `tokens_available` gauge holding the quota left
+/// after the latest check, and a `keys_tracked` gauge for keyed limiters.
+#[cfg(feature = "prometheus")]
+#[derive(Clone)]
+struct PrometheusMetrics {
+    allowed: prometheus::IntCounterVec,
+    denied: prometheus::IntCounterVec,
+    tokens_available: prometheus::IntGaugeVec,
+    keys_tracked: prometheus::IntGaugeVec,
+}
+
+#[cfg(feature = "prometheus")]
+impl PrometheusMetrics {
+    fn register(registry: &prometheus::Registry) -> prometheus::Result<Self> {
+        use prometheus::{IntCounterVec, IntGaugeVec, Opts};
+
+        let metrics = PrometheusMetrics {
+            allowed: IntCounterVec::new(
+                Opts::new("allowed_total", "Requests allowed by the limiter."),
+                &["limiter"],
+            )?,
+            denied: IntCounterVec::new(
+                Opts::new("denied_total", "Requests denied by the limiter."),
+                &["limiter"],
+            )?,
+            tokens_available: IntGaugeVec::new(
+                Opts::new("tokens_available", "Quota left after the latest check."),
+                &["limiter"],
+            )?,
+            keys_tracked: IntGaugeVec::new(
+                Opts::new("keys_tracked", "Keys tracked by a keyed limiter."),
+                &["limiter"],
+            )?,
+        };
+
+        registry.register(Box::new(metrics.allowed.clone()))?;
+        registry.register(Box::new(metrics.denied.clone()))?;
+        registry.register(Box::new(metrics.tokens_available.clone()))?;
+        registry.register(Box::new(metrics.keys_tracked.clone()))?;
+
+        Ok(metrics)
+    }
+
+    /// An observer to attach to an unkeyed limiter with `Observed::with_observer`.
+    fn observer(&self) -> impl Observer + Send + Sync + 'static {
+        let metrics = self.clone();
+        move |event: &Event| {
+            metrics.count(event.limiter, event.allowed);
+            metrics
+                .tokens_available
+                .with_label_values(&[event.limiter])
+                .set(event.remaining as i64);
+        }
+    }
+
+    /// An observer to attach to a keyed limiter. Only the counters are updated, since there is no
+    /// single quota to report; call `track_keys` to update `keys_tracked`.
+    fn keyed_observer<K: ?Sized + 'static>(&self) -> impl Observer<K> + Send + Sync + 'static {
+        let metrics = self.clone();
+        move |event: &Event<K>| metrics.count(event.limiter, event.allowed)
+    }
+
+    fn track_keys<K, L>(&self, limiter: &KeyedRateLimiter<K, L>)
+    where
+        K: Hash + Eq + Clone,
+        L: RateLimiter,
+    {
+        self.keys_tracked
+            .with_label_values(&[&limiter.name])
+            .set(limiter.len() as i64);
+    }
+
+    fn count(&self, limiter: &str, allowed: bool) {
+        if allowed {
+            self.allowed.with_label_values(&[limiter]).inc();
+        } else {
+            self.denied.with_label_values(&[limiter]).inc();
+        }
+    }
+}
+
+/// Returned by `RateLimitService` for requests the limiter denies. HTTP stacks can map it to a 429
+/// response, using `retry_after` for the `Retry-After` header.
+#[derive(thiserror::Error, Debug, Clone, Copy, PartialEq, Eq)]
+#[error("rate limited, retry after {retry_after:?}")]
+struct Rejected {
+    retry_after: time::Duration,
+}
+
+/// A `tower::Layer` that gates services with a limiter shared by every service it wraps.
+struct RateLimitLayer<L> {
+    limiter: Arc<Shared<L>>,
+}
+
+impl<L> RateLimitLayer<L> {
+    fn new(limiter: Arc<Shared<L>>) -> Self {
+        RateLimitLayer { limiter }
+    }
+}
+
+impl<L> Clone for RateLimitLayer<L> {
+    fn clone(&self) -> Self {
+        RateLimitLayer {
+            limiter: self.limiter.clone(),
+        }
+    }
+}
+
+impl<S, L> tower::Layer<S> for RateLimitLayer<L> {
+    type Service = RateLimitService<S, L>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        RateLimitService {
+            inner,
+            limiter: self.limiter.clone(),
+        }
+    }
+}
+
+/// Calls the inner service only for requests the limiter allows, failing the rest with `Rejected`
+/// without waiting. Unlike `tower::limit::RateLimit`, readiness doesn't depend on the limiter, so
+/// denied requests are rejected rather than queued.
+struct RateLimitService<S, L> {
+    inner: S,
+    limiter: Arc<Shared<L>>,
+}
+
+impl<S: Clone, L> Clone for RateLimitService<S, L> {
+    fn clone(&self) -> Self {
+        RateLimitService {
+            inner: self.inner.clone(),
+            limiter: self.limiter.clone(),
+        }
+    }
+}
+
+impl<S, L, Request> tower::Service<Request> for RateLimitService<S, L>
+where
+    S: tower::Service<Request>,
+    S::Error: Into<tower::BoxError>,
+    L: RateLimiter,
+{
+    type Response = S::Response;
+    type Error = tower::BoxError;
+    type Future = futures::future::Either<
+        futures::future::ErrInto<S::Future, tower::BoxError>,
+        futures::future::Ready<Result<S::Response, tower::BoxError>>,
+    >;
+
+    fn poll_ready(
+        &mut self,
+        cx: &mut std::task::Context<'_>,
+    ) -> std::task::Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx).map_err(Into::into)
+    }
+
+    fn call(&mut self, request: Request) -> Self::Future {
+        use futures::TryFutureExt;
+
+        match self.limiter.decide() {
+            Decision::Allowed { .. } => {
+                futures::future::Either::Left(self.inner.call(request).err_into())
+            }
+            Decision::Denied { retry_after } => {
+                futures::future::Either::Right(futures::future::ready(Err(Rejected {
+                    retry_after,
+                }
+                .into())))
+            }
+        }
+    }
+}
+
+/// Keeps independent limiter state per key, such as a client IP or API key. Limiters are created
+/// lazily the first time a key is seen, so every key gets its own full quota.
+///
+/// Keys are never forgotten by default. For unbounded key spaces, configure an idle TTL (and call
+/// `purge` periodically, see `sweep`) and/or a maximum number of keys, past which the least
+/// recently hit key is evicted. An evicted key starts over with a full quota.
+///
+/// Optionally, a `Penalty` locks out keys that keep getting denied, and observers added with
+/// `with_observer` are notified of every decision along with the key.
+struct KeyedRateLimiter<K, L: RateLimiter> {
+    limiters: HashMap<K, KeyedEntry<L>>,
+    window: time::Duration,
+    limit: usize,
+    idle_ttl: Option<time::Duration>,
+    max_keys: Option<usize>,
+    penalty: Option<Penalty>,
+    name: String,
+    observers: Vec<Box<dyn Observer<K> + Send + Sync>>,
+    factory: Option<Factory<L>>,
+    clock: L::Clock,
+}
+
+/// Creates the limiter for a new key from the window, limit and clock.
+type Factory<L> = Arc<dyn Fn(time::Duration, usize, <L as RateLimiter>::Clock) -> L + Send + Sync>;
+
+/// Locks a key out for `lockout` once it has been denied `strikes` times within `within`. Requests
+/// made during the lockout are denied without touching the key's limiter.
+#[derive(Debug, Clone, Copy)]
+struct Penalty {
+    strikes: usize,
+    within: time::Duration,
+    lockout: time::Duration,
+}
+
+struct KeyedEntry<L> {
+    limiter: L,
+    last_hit: Instant,
+    strikes: usize,
+    strikes_start: Instant,
+    locked_until: Option<Instant>,
+}
+
+impl<L: RateLimiter> KeyedEntry<L> {
+    fn new(limiter: L, now: Instant) -> Self {
+        KeyedEntry {
+            limiter,
+            last_hit: now,
+            strikes: 0,
+            strikes_start: now,
+            locked_until: None,
+        }
+    }
+
+    fn is_locked(&self, now: Instant) -> bool {
+        self.locked_until.is_some_and(|until| now < until)
+    }
+
+    fn admit(&mut self, cost: usize, now: Instant, penalty: Option<Penalty>) -> bool {
+        self.last_hit = now;
+
+        if self.is_locked(now) {
+            return false;
+        }
+
+        if self.limiter.allowed_n(cost) {
+            return true;
+        }
+
+        if let Some(penalty) = penalty {
+            // Strikes are counted in fixed windows starting from the first strike.
+            if now.duration_since(self.strikes_start) > penalty.within {
+                self.strikes = 0;
+                self.strikes_start = now;
+            }
+
+            self.strikes += 1;
+            if self.strikes >= penalty.strikes {
+                self.locked_until = Some(now + penalty.lockout);
+                self.strikes = 0;
+            }
+        }
+
+        false
+    }
+}
+
+/// How much of its quota a tracked key has used, as reported by `KeyedRateLimiter::usage`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct KeyUsage<K> {
+    key: K,
+    used: usize,
+    remaining: usize,
+    reset_at: Instant,
+}
+
+impl<K, L> KeyedRateLimiter<K, L>
+where
+    K: Hash + Eq + Clone,
+    L: RateLimiter,
+{
+    fn new(window: time::Duration, limit: usize) -> Self
+    where
+        L::Clock: Default,
+    {
+        Self::with_clock(window, limit, L::Clock::default())
+    }
+
+    /// Creates a keyed limiter whose per-key limiters all share `clock`.
+    fn with_clock(window: time::Duration, limit: usize, clock: L::Clock) -> Self {
+        KeyedRateLimiter {
+            limiters: HashMap::new(),
+            window,
+            limit,
+            idle_ttl: None,
+            max_keys: None,
+            penalty: None,
+            name: String::new(),
+            observers: Vec::new(),
+            factory: None,
+            clock,
+        }
+    }
+
+    fn named(mut self, name: impl Into<String>) -> Self {
+        self.name = name.into();
+        self
+    }
+
+    /// Creates the limiters for new keys with `factory` rather than `L::with_clock`, e.g. to
+    /// configure them beyond the window and limit.
+    fn with_factory(
+        mut self,
+        factory: impl Fn(time::Duration, usize, L::Clock) -> L + Send + Sync + 'static,
+    ) -> Self {
+        self.factory = Some(Arc::new(factory));
+        self
+    }
+
+    fn create(&self) -> L {
+        let clock = self.clock.clone();
+        match &self.factory {
+            Some(factory) => factory(self.window, self.limit, clock),
+            None => L::with_clock(self.window, self.limit, clock),
+        }
+    }
+
+    fn with_observer(mut self, observer: impl Observer<K> + Send + Sync + 'static) -> Self {
+        self.observers.push(Box::new(observer));
+        self
+    }
+
+    /// Lock keys out for `lockout` after they are denied `strikes` times within `within`.
+    fn with_penalty(
+        mut self,
+        strikes: usize,
+        within: time::Duration,
+        lockout: time::Duration,
+    ) -> Self {
+        self.penalty = Some(Penalty {
+            strikes,
+            within,
+            lockout,
+        });
+        self
+    }
+
+    /// Forget keys that haven't been hit for at least `ttl` when `purge` is called.
+    fn with_idle_ttl(mut self, ttl: time::Duration) -> Self {
+        self.idle_ttl = Some(ttl);
+        self
+    }
+
+    /// Track at most `max_keys` keys, evicting the least recently hit key to make room for a new
+    /// one.
+    fn with_max_keys(mut self, max_keys: usize) -> Self {
+        self.max_keys = Some(max_keys);
+        self
+    }
+
+    fn allowed(&mut self, key: &K) -> bool {
+        self.allowed_n(key, 1)
+    }
+
+    fn allowed_n(&mut self, key: &K, cost: usize) -> bool {
+        let allowed = self.admit(key, cost);
+
+        if !self.observers.is_empty() {
+            let event = Event {
+                limiter: &self.name,
+                key,
+                cost,
+                allowed,
+                remaining: self.remaining(key),
+            };
+            for observer in &self.observers {
+                observer.observe(&event);
+            }
+        }
+
+        allowed
+    }
+
+    fn admit(&mut self, key: &K, cost: usize) -> bool {
+        let now = self.clock.now();
+
+        // Avoid cloning the key on the common path where a limiter already exists for it.
+        if let Some(entry) = self.limiters.get_mut(key) {
+            return entry.admit(cost, now, self.penalty);
+        }
+
+        if let Some(max_keys) = self.max_keys {
+            while self.limiters.len() >= max_keys.max(1) {
+                self.evict_lru(now);
+            }
+        }
+
+        let mut entry = KeyedEntry::new(self.create(), now);
+        let allowed = entry.admit(cost, now, self.penalty);
+        self.limiters.insert(key.clone(), entry);
+
+        allowed
+    }
+
+    fn time_until_allowed(&self, key: &K) -> time::Duration {
+        let now = self.clock.now();
+
+        // Keys that haven't been seen yet have a full quota available.
+        self.limiters.get(key).map_or(time::Duration::ZERO, |e| {
+            let lockout = e
+                .locked_until
+                .map_or(time::Duration::ZERO, |u| u.duration_since(now));
+            std::cmp::max(lockout, e.limiter.time_until_allowed())
+        })
+    }
+
+    fn remaining(&self, key: &K) -> usize {
+        let now = self.clock.now();
+
+        self.limiters.get(key).map_or(self.limit, |e| {
+            if e.is_locked(now) {
+                0
+            } else {
+                e.limiter.remaining()
+            }
+        })
+    }
+
+    fn reset_at(&self, key: &K) -> Instant {
+        self.limiters
+            .get(key)
+            .map_or_else(|| self.clock.now(), |e| e.limiter.reset_at())
+    }
+
+    fn check(&self, key: &K) -> bool {
+        let now = self.clock.now();
+
+        self.limiters
+            .get(key)
+            .is_none_or(|e| !e.is_locked(now) && e.limiter.check())
+    }
+
+    fn decide(&mut self, key: &K) -> Decision {
+        if self.allowed(key) {
+            Decision::Allowed {
+                remaining: self.remaining(key),
+            }
+        } else {
+            Decision::Denied {
+                retry_after: self.time_until_allowed(key),
+            }
+        }
+    }
+
+    /// Returns quota to a key. Does nothing for keys that aren't tracked, since they already have
+    /// their full quota.
+    fn give_back(&mut self, key: &K, n: usize) {
+        if let Some(entry) = self.limiters.get_mut(key) {
+            entry.limiter.give_back(n);
+        }
+    }
+
+    /// Saves the state of every tracked key. See `Persist`.
+    fn save(&self) -> HashMap<K, L::State>
+    where
+        L: Persist,
+    {
+        self.limiters
+            .iter()
+            .map(|(k, e)| (k.clone(), e.limiter.save()))
+            .collect()
+    }
+
+    /// Restores saved per-key state, replacing whatever is tracked for those keys.
+    fn restore(&mut self, states: HashMap<K, L::State>)
+    where
+        L: Persist,
+    {
+        let now = self.clock.now();
+        for (key, state) in states {
+            let mut limiter = self.create();
+            limiter.restore(state);
+            self.limiters.insert(key, KeyedEntry::new(limiter, now));
+        }
+    }
+
+    /// Reconfigures every tracked key, as well as the limiters created for new keys from now on.
+    fn update(&mut self, window: time::Duration, limit: usize) {
+        self.window = window;
+        self.limit = limit;
+        for entry in self.limiters.values_mut() {
+            entry.limiter.update(window, limit);
+        }
+    }
+
+    /// Removes every key that has been idle for longer than the configured TTL, returning how many
+    /// were removed. Does nothing if no TTL is configured.
+    fn purge(&mut self) -> usize {
+        let Some(ttl) = self.idle_ttl else {
+            return 0;
+        };
+
+        let now = self.clock.now();
+        let before = self.limiters.len();
+        // Locked out keys are kept until their lockout ends, otherwise purging would let them off
+        // early.
+        self.limiters
+            .retain(|_, e| now.duration_since(e.last_hit) < ttl || e.is_locked(now));
+
+        before - self.limiters.len()
+    }
+
+    /// Evicts the least recently hit key, preferring keys that aren't locked out so that a key
+    /// can't escape its lockout by waiting for others to push it out. Locked out keys are only
+    /// evicted once every key is locked out.
+    fn evict_lru(&mut self, now: Instant) {
+        // A linear scan keeps the common allowed() path free of any bookkeeping beyond updating
+        // the last hit time, at the cost of making inserts at capacity O(n).
+        let oldest = self
+            .limiters
+            .iter()
+            .min_by_key(|(_, e)| (e.is_locked(now), e.last_hit))
+            .map(|(k, _)| k.clone());
+
+        if let Some(key) = oldest {
+            self.limiters.remove(&key);
+        }
+    }
+
+    fn len(&self) -> usize {
+        self.limiters.len()
+    }
+
+    /// Reports the usage of every tracked key, in no particular order.
+    fn usage(&self) -> Vec<KeyUsage<K>> {
+        self.limiters
+            .keys()
+            .map(|key| {
+                let remaining = self.remaining(key);
+                KeyUsage {
+                    key: key.clone(),
+                    used: self.limit.saturating_sub(remaining),
+                    remaining,
+                    reset_at: self.reset_at(key),
+                }
+            })
+            .collect()
+    }
+
+    /// Reports the usage of the `n` keys that have used the most of their quota, heaviest first.
+    fn top_talkers(&self, n: usize) -> Vec<KeyUsage<K>> {
+        let mut usage = self.usage();
+        usage.sort_by_key(|u| std::cmp::Reverse(u.used));
+        usage.truncate(n);
+        usage
+    }
+}
+
+/// Per-key limits that also share a global budget, e.g. each tenant gets 100 rps but the whole
+/// service is capped at 1000 rps. A request must be admitted by both its key's limiter and the
+/// parent, and a request denied by either doesn't count against the other.
+///
+/// By default the global budget is first come, first served, so a hot key can use it all up before
+/// others get a chance. With fairness enabled, each key can use at most its weighted share of the
+/// parent's limit per parent window, where the share is split between the keys that have made a
+/// request within the last window.
+struct HierarchicalLimiter<K, L: RateLimiter> {
+    parent: L,
+    children: KeyedRateLimiter<K, L>,
+    fairness: Option<Fairness<K>>,
+}
+
+struct Fairness<K> {
+    weights: HashMap<K, usize>,
+    usage: HashMap<K, FairUsage>,
+    period_start: Instant,
+}
+
+struct FairUsage {
+    used: usize,
+    last_seen: Instant,
+}
+
+impl<K, L> HierarchicalLimiter<K, L>
+where
+    K: Hash + Eq + Clone,
+    L: RateLimiter,
+{
+    fn new(parent: L, children: KeyedRateLimiter<K, L>) -> Self {
+        HierarchicalLimiter {
+            parent,
+            children,
+            fairness: None,
+        }
+    }
+
+    /// Split the global budget between active keys instead of serving it first come, first
+    /// served. Every key has a weight of 1 unless set with `set_weight`.
+    fn with_fairness(mut self) -> Self {
+        self.fairness = Some(Fairness {
+            weights: HashMap::new(),
+            usage: HashMap::new(),
+            period_start: self.children.clock.now(),
+        });
+        self
+    }
+
+    /// Gives `key` a larger (or smaller) share of the global budget relative to other keys. Only
+    /// has an effect with fairness enabled.
+    fn set_weight(&mut self, key: K, weight: usize) {
+        if let Some(fairness) = &mut self.fairness {
+            fairness.weights.insert(key, weight);
+        }
+    }
+
+    fn allowed(&mut self, key: &K) -> bool {
+        self.allowed_n(key, 1)
+    }
+
+    fn allowed_n(&mut self, key: &K, cost: usize) -> bool {
+        if !self.within_fair_share(key, cost) {
+            return false;
+        }
+
+        // Check both levels before consuming from either, like MultiWindow does.
+        if self.parent.remaining() < cost || self.children.remaining(key) < cost {
+            return false;
+        }
+
+        if !self.children.allowed_n(key, cost) {
+            return false;
+        }
+        // The parent can still deny what its remaining quota suggested it would admit, in which
+        // case the key shouldn't be charged for a request that wasn't admitted.
+        if !self.parent.allowed_n(cost) {
+            self.children.give_back(key, cost);
+            return false;
+        }
+
+        // Only admitted requests count towards the key's fair share.
+        if let Some(usage) = self.fairness.as_mut().and_then(|f| f.usage.get_mut(key)) {
+            usage.used += cost;
+        }
+        true
+    }
+
+    fn within_fair_share(&mut self, key: &K, cost: usize) -> bool {
+        let Some(fairness) = &mut self.fairness else {
+            return true;
+        };
+
+        let now = self.children.clock.now();
+        let window = self.parent.window();
+
+        // Shares are accounted per parent window. Keys that haven't been seen for a whole window
+        // no longer count as active and are dropped.
+        if now.duration_since(fairness.period_start) > window {
+            fairness.period_start = now;
+            fairness
+                .usage
+                .retain(|_, u| now.duration_since(u.last_seen) <= window);
+            for usage in fairness.usage.values_mut() {
+                usage.used = 0;
+            }
+        }
+
+        // Being denied still counts as contending for the budget, so mark the key as active
+        // before working out the shares.
+        fairness
+            .usage
+            .entry(key.clone())
+            .or_insert(FairUsage {
+                used: 0,
+                last_seen: now,
+            })
+            .last_seen = now;
+
+        let weight_of = |k: &K| fairness.weights.get(k).copied().unwrap_or(1);
+        let active_weight: usize = fairness
+            .usage
+            .iter()
+            .filter(|(_, u)| now.duration_since(u.last_seen) <= window)
+            .map(|(k, _)| weight_of(k))
+            .sum();
+
+        let share = std::cmp::max(
+            1,
+            self.parent.limit() * weight_of(key) / active_weight.max(1),
+        );
+
+        fairness.usage[key].used + cost <= share
+    }
+
+    fn time_until_allowed(&self, key: &K) -> time::Duration {
+        std::cmp::max(
+            self.parent.time_until_allowed(),
+            self.children.time_until_allowed(key),
+        )
+    }
+
+    fn remaining(&self, key: &K) -> usize {
+        std::cmp::min(self.parent.remaining(), self.children.remaining(key))
+    }
+
+    fn give_back(&mut self, key: &K, n: usize) {
+        self.parent.give_back(n);
+        self.children.give_back(key, n);
+
+        if let Some(usage) = self.fairness.as_mut().and_then(|f| f.usage.get_mut(key)) {
+            usage.used = usage.used.saturating_sub(n);
+        }
+    }
+}
+
+/// Periodically purges idle keys from a keyed limiter shared with request handlers. Intended to be
+/// spawned as a background task for the lifetime of the process.
+async fn sweep<K, L>(limiter: Arc<Mutex<KeyedRateLimiter<K, L>>>, every: time::Duration)
+where
+    K: Hash + Eq + Clone,
+    L: RateLimiter,
+{
+    let mut interval = tokio::time::interval(every);
+    loop {
+        interval.tick().await;
+        limiter.lock().unwrap_or_else(|e| e.into_inner()).purge();
+    }
+}
+
+/// Picks the key that an HTTP request is rate limited by, for `KeyedRateLimit`. Any
+/// `Fn(&Request) -> Option<K>` closure is an extractor.
+trait KeyExtractor {
+    type Key: Hash + Eq + Clone;
+
+    /// Returns `None` for requests that shouldn't be limited.
+    fn extract(&self, request: &axum::extract::Request) -> Option<Self::Key>;
+}
+
+impl<K, F> KeyExtractor for F
+where
+    K: Hash + Eq + Clone,
+    F: Fn(&axum::extract::Request) -> Option<K>,
+{
+    type Key = K;
+
+    fn extract(&self, request: &axum::extract::Request) -> Option<K> {
+        self(request)
+    }
+}
+
+/// Keys requests by the client's IP address. The router has to be served with
+/// `into_make_service_with_connect_info::<SocketAddr>()` for the address to be available.
+#[derive(Debug, Clone, Copy)]
+struct PeerIp;
+
+impl KeyExtractor for PeerIp {
+    type Key = std::net::IpAddr;
+
+    fn extract(&self, request: &axum::extract::Request) -> Option<std::net::IpAddr> {
+        request
+            .extensions()
+            .get::<axum::extract::ConnectInfo<std::net::SocketAddr>>()
+            .map(|info| info.0.ip())
+    }
+}
+
+/// Keys requests by the value of a header, such as an API key.
+#[derive(Debug, Clone)]
+struct HeaderKey(axum::http::HeaderName);
+
+impl KeyExtractor for HeaderKey {
+    type Key = String;
+
+    fn extract(&self, request: &axum::extract::Request) -> Option<String> {
+        let value = request.headers().get(&self.0)?;
+        value.to_str().ok().map(str::to_owned)
+    }
+}
+
+/// Keys requests by their path, so that each route is limited separately.
+#[derive(Debug, Clone, Copy)]
+struct PathKey;
+
+impl KeyExtractor for PathKey {
+    type Key = String;
+
+    fn extract(&self, request: &axum::extract::Request) -> Option<String> {
+        Some(request.uri().path().to_owned())
+    }
+}
+
+/// axum middleware that applies a keyed limiter to requests. Denied requests get a 429 Too Many
+/// Requests response with `Retry-After`, and every limited response carries the `X-RateLimit-*`
+/// headers. Requests the extractor finds no key for are let through.
+struct KeyedRateLimit<E: KeyExtractor, L: RateLimiter> {
+    limiter: Arc<Mutex<KeyedRateLimiter<E::Key, L>>>,
+    extractor: E,
+}
+
+impl<E: KeyExtractor + Clone, L: RateLimiter> Clone for KeyedRateLimit<E, L> {
+    fn clone(&self) -> Self {
+        KeyedRateLimit {
+            limiter: self.limiter.clone(),
+            extractor: self.extractor.clone(),
+        }
+    }
+}
+
+impl<E, L> KeyedRateLimit<E, L>
+where
+    E: KeyExtractor + Clone + Send + Sync + 'static,
+    E::Key: Send + 'static,
+    L: RateLimiter + Send + 'static,
+    L::Clock: Send,
+{
+    /// The limiter is shared so that it can also be purged in the background, see `sweep`.
+    fn new(limiter: Arc<Mutex<KeyedRateLimiter<E::Key, L>>>, extractor: E) -> Self {
+        KeyedRateLimit { limiter, extractor }
+    }
+
+    /// Applies the middleware to every route of `router`.
+    fn apply<S>(self, router: axum::Router<S>) -> axum::Router<S>
+    where
+        S: Clone + Send + Sync + 'static,
+    {
+        router.layer(axum::middleware::from_fn_with_state(self, Self::handle))
+    }
+
+    async fn handle(
+        axum::extract::State(this): axum::extract::State<Self>,
+        request: axum::extract::Request,
+        next: axum::middleware::Next,
+    ) -> axum::response::Response {
+        use axum::{
+            http::{HeaderName, HeaderValue, StatusCode},
+            response::IntoResponse,
+        };
+
+        let Some(key) = this.extractor.extract(&request) else {
+            return next.run(request).await;
+        };
+
+        let headers = {
+            let mut limiter = this.limiter.lock().unwrap_or_else(|e| e.into_inner());
+            let decision = limiter.decide(&key);
+            let reset_in = limiter.reset_at(&key).duration_since(limiter.clock.now());
+            RateLimitHeaders::new(decision, limiter.limit, reset_in)
+        };
+
+        let mut response = match headers.retry_after {
+            Some(_) => StatusCode::TOO_MANY_REQUESTS.into_response(),
+            None => next.run(request).await,
+        };
+
+        for (name, value) in headers.x_rate_limit() {
+            if let (Ok(name), Ok(value)) =
+                (HeaderName::try_from(name), HeaderValue::try_from(value))
+            {
+                response.headers_mut().insert(name, value);
+            }
+        }
+
+        response
+    }
+}
+
+/// A tonic interceptor that rejects RPCs with `RESOURCE_EXHAUSTED` when a keyed limiter denies
+/// them, keyed by a metadata entry such as `authorization`. Rejections carry a
+/// `grpc-retry-pushback-ms` hint for clients. RPCs without the entry are let through.
+struct RateLimitInterceptor<L: RateLimiter> {
+    limiter: Arc<Mutex<KeyedRateLimiter<String, L>>>,
+    metadata_key: String,
+}
+
+impl<L: RateLimiter> Clone for RateLimitInterceptor<L> {
+    fn clone(&self) -> Self {
+        RateLimitInterceptor {
+            limiter: self.limiter.clone(),
+            metadata_key: self.metadata_key.clone(),
+        }
+    }
+}
+
+impl<L: RateLimiter> RateLimitInterceptor<L> {
+    fn new(
+        limiter: Arc<Mutex<KeyedRateLimiter<String, L>>>,
+        metadata_key: impl Into<String>,
+    ) -> Self {
+        RateLimitInterceptor {
+            limiter,
+            metadata_key: metadata_key.into(),
+        }
+    }
+}
+
+impl<L: RateLimiter> tonic::service::Interceptor for RateLimitInterceptor<L> {
+    fn call(&mut self, request: tonic::Request<()>) -> Result<tonic::Request<()>, tonic::Status> {
+        let Some(key) = request
+            .metadata()
+            .get(self.metadata_key.as_str())
+            .and_then(|value| value.to_str().ok())
+        else {
+            return Ok(request);
+        };
+
+        let decision = {
+            let mut limiter = self.limiter.lock().unwrap_or_else(|e| e.into_inner());
+            limiter.decide(&key.to_owned())
+        };
+
+        match decision {
+            Decision::Allowed { .. } => Ok(request),
+            Decision::Denied { retry_after } => {
+                let mut status =
+                    tonic::Status::resource_exhausted(Rejected { retry_after }.to_string());
+                status.metadata_mut().insert(
+                    "grpc-retry-pushback-ms",
+                    (retry_after.as_millis() as u64).into(),
+                );
+                Err(status)
+            }
+        }
+    }
+}
+
+/// The algorithms that can be chosen at runtime, e.g. in a config file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum Algorithm {
+    FixedWindow,
+    CalendarWindow,
+    MovingWindow,
+    SlidingWindow,
+    TokenBucket,
+    LeakyBucket,
+    Gcra,
+}
+
+/// Any of the algorithms, chosen at runtime. `with_clock` creates a token bucket; use
+/// `with_algorithm` to pick another.
+enum AnyLimiter<C = SystemClock> {
+    FixedWindow(FixedWindow<C>),
+    CalendarWindow(CalendarWindow<C>),
+    MovingWindow(MovingWindow<C>),
+    SlidingWindow(SlidingWindow<C>),
+    TokenBucket(TokenBucket<C>),
+    LeakyBucket(LeakyBucket<C>),
+    Gcra(Gcra<C>),
+}
+
+/// Runs `$body` with `$l` bound to whichever limiter `$limiter` holds.
+macro_rules! each_limiter {
+    ($limiter:expr, $l:ident => $body:expr) => {
+        match $limiter {
+            AnyLimiter::FixedWindow($l) => $body,
+            AnyLimiter::CalendarWindow($l) => $body,
+            AnyLimiter::MovingWindow($l) => $body,
+            AnyLimiter::SlidingWindow($l) => $body,
+            AnyLimiter::TokenBucket($l) => $body,
+            AnyLimiter::LeakyBucket($l) => $body,
+            AnyLimiter::Gcra($l) => $body,
+        }
+    };
+}
+
+impl<C: WallClock> AnyLimiter<C> {
+    /// Creates a limiter of the given algorithm. The burst is only used by token buckets.
+    fn with_algorithm(
+        algorithm: Algorithm,
+        window: time::Duration,
+        limit: usize,
+        burst: Option<usize>,
+        clock: C,
+    ) -> Self {
+        match algorithm {
+            Algorithm::FixedWindow => {
+                AnyLimiter::FixedWindow(FixedWindow::with_clock(window, limit, clock))
+            }
+            Algorithm::CalendarWindow => {
+                AnyLimiter::CalendarWindow(CalendarWindow::with_clock(window, limit, clock))
+            }
+            Algorithm::MovingWindow => {
+                AnyLimiter::MovingWindow(MovingWindow::with_clock(window, limit, clock))
+            }
+            Algorithm::SlidingWindow => {
+                AnyLimiter::SlidingWindow(SlidingWindow::with_clock(window, limit, clock))
+            }
+            Algorithm::TokenBucket => {
+                let bucket = TokenBucket::with_clock(window, limit, clock);
+                AnyLimiter::TokenBucket(match burst {
+                    Some(burst) => bucket.with_capacity(burst),
+                    None => bucket,
+                })
+            }
+            Algorithm::LeakyBucket => {
+                AnyLimiter::LeakyBucket(LeakyBucket::with_clock(window, limit, clock))
+            }
+            Algorithm::Gcra => AnyLimiter::Gcra(Gcra::with_clock(window, limit, clock)),
+        }
+    }
+}
+
+impl<C: WallClock> RateLimiter for AnyLimiter<C> {
+    type Clock = C;
+
+    fn with_clock(window: time::Duration, limit: usize, clock: C) -> Self {
+        AnyLimiter::TokenBucket(TokenBucket::with_clock(window, limit, clock))
+    }
+
+    fn allowed_n(&mut self, cost: usize) -> bool {
+        each_limiter!(self, l => l.allowed_n(cost))
+    }
+
+    fn time_until_allowed(&self) -> time::Duration {
+        each_limiter!(self, l => l.time_until_allowed())
+    }
+
+    fn remaining(&self) -> usize {
+        each_limiter!(self, l => l.remaining())
+    }
+
+    fn reset_at(&self) -> Instant {
+        each_limiter!(self, l => l.reset_at())
+    }
+
+    fn window(&self) -> time::Duration {
+        each_limiter!(self, l => l.window())
+    }
+
+    fn limit(&self) -> usize {
+        each_limiter!(self, l => l.limit())
+    }
+
+    fn update(&mut self, window: time::Duration, limit: usize) {
+        each_limiter!(self, l => l.update(window, limit))
+    }
+
+    fn give_back(&mut self, n: usize) {
+        each_limiter!(self, l => l.give_back(n))
+    }
+}
+
+/// What a configured limiter keys requests by. The registry only tells keys apart; working out the
+/// key for a request (e.g. with a `KeyExtractor`) is up to the service.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum KeyStrategy {
+    /// One limit shared by all requests.
+    #[default]
+    Global,
+    PeerIp,
+    Path,
+    /// The value of the named header.
+    Header(String),
+}
+
+/// The definition of a named limiter in a config file, such as:
+///
+/// ```toml
+/// [login]
+/// algorithm = "token_bucket"
+/// window_ms = 60000
+/// limit = 5
+/// burst = 10
+/// key = { header = "x-api-key" }
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+struct LimiterConfig {
+    algorithm: Algorithm,
+    window_ms: u64,
+    limit: usize,
+    burst: Option<usize>,
+    #[serde(default)]
+    key: KeyStrategy,
+}
+
+impl LimiterConfig {
+    fn validate(&self) -> Result<(), ConfigError> {
+        let builder = RateLimiterBuilder::new()
+            .window(time::Duration::from_millis(self.window_ms))
+            .limit(self.limit);
+        let builder = match self.burst {
+            Some(burst) => builder.burst(burst),
+            None => builder,
+        };
+
+        builder.validate()?;
+        if self.burst.is_some() && self.algorithm != Algorithm::TokenBucket {
+            return Err(ConfigError::BurstUnsupported);
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(thiserror::Error, Debug)]
+enum RegistryError {
+    #[error("failed to parse limiter config")]
+    Parse(#[from] toml::de::Error),
+    #[error("invalid config for limiter {name:?}")]
+    Invalid {
+        name: String,
+        #[source]
+        source: ConfigError,
+    },
+}
+
+/// Named limiters loaded from a TOML config file with a `LimiterConfig` table per name, so that
+/// services can look up the limiter for a route with `registry.get("route_name")`.
+struct Registry<C: WallClock = SystemClock> {
+    limiters: HashMap<String, RegisteredLimiter<C>>,
+}
+
+/// A limiter in a `Registry`, tracking each key separately unless its key strategy is global.
+struct RegisteredLimiter<C: WallClock = SystemClock> {
+    config: LimiterConfig,
+    limiter: Mutex<KeyedRateLimiter<String, AnyLimiter<C>>>,
+}
+
+impl Registry {
+    fn from_toml(config: &str) -> Result<Self, RegistryError> {
+        Self::from_toml_with_clock(config, SystemClock)
+    }
+}
+
+impl<C: WallClock + Send + Sync + 'static> Registry<C> {
+    fn from_toml_with_clock(config: &str, clock: C) -> Result<Self, RegistryError> {
+        let configs: HashMap<String, LimiterConfig> = toml::from_str(config)?;
+
+        let mut limiters = HashMap::new();
+        for (name, config) in configs {
+            if let Err(source) = config.validate() {
+                return Err(RegistryError::Invalid { name, source });
+            }
+
+            let (algorithm, burst) = (config.algorithm, config.burst);
+            let limiter = KeyedRateLimiter::with_clock(
+                time::Duration::from_millis(config.window_ms),
+                config.limit,
+                clock.clone(),
+            )
+            .named(name.clone())
+            .with_factory(move |window, limit, clock| {
+                AnyLimiter::with_algorithm(algorithm, window, limit, burst, clock)
+            });
+
+            limiters.insert(
+                name,
+                RegisteredLimiter {
+                    config,
+                    limiter: Mutex::new(limiter),
+                },
+            );
+        }
+
+        Ok(Registry { limiters })
+    }
+
+    fn get(&self, name: &str) -> Option<&RegisteredLimiter<C>> {
+        self.limiters.get(name)
+    }
+}
+
+impl<C: WallClock> RegisteredLimiter<C> {
+    fn key_strategy(&self) -> &KeyStrategy {
+        &self.config.key
+    }
+
+    fn allowed(&self, key: &str) -> bool {
+        self.decide(key).is_allowed()
+    }
+
+    /// Checks a request with the given key, which is ignored for the global key strategy.
+    fn decide(&self, key: &str) -> Decision {
+        let key = match self.config.key {
+            KeyStrategy::Global => "",
+            _ => key,
+        };
+
+        let mut limiter = self.limiter.lock().unwrap_or_else(|e| e.into_inner());
+        limiter.decide(&key.to_owned())
+    }
+}
+
+#[derive(thiserror::Error, Debug)]
+enum StoreError {
+    #[error("redis request failed")]
+    Redis(#[from] redis::RedisError),
+    #[error("stored limiter state is invalid")]
+    State(#[from] serde_json::Error),
+    #[error("gave up after {0} conflicting updates")]
+    Contention(usize),
+}
+
+/// Storage for limiter state that several processes can share, so that they enforce one limit
+/// between them rather than one limit each. Values are opaque to the store.
+trait Store {
+    fn get(&mut self, key: &str) -> Result<Option<Vec<u8>>, StoreError>;
+
+    /// Sets `key` to `value` only if it currently holds `expected` (`None` meaning it is unset),
+    /// returning whether it did. The key expires if it isn't set again within `ttl`.
+    fn compare_and_set(
+        &mut self,
+        key: &str,
+        expected: Option<&[u8]>,
+        value: &[u8],
+        ttl: time::Duration,
+    ) -> Result<bool, StoreError>;
+}
+
+/// A store local to the process, for tests and for running without Redis. Clones share the same
+/// values. Keys never expire.
+#[derive(Debug, Clone, Default)]
+struct MemoryStore {
+    values: Arc<Mutex<HashMap<String, Vec<u8>>>>,
+}
+
+impl Store for MemoryStore {
+    fn get(&mut self, key: &str) -> Result<Option<Vec<u8>>, StoreError> {
+        let values = self.values.lock().unwrap_or_else(|e| e.into_inner());
+        Ok(values.get(key).cloned())
+    }
+
+    fn compare_and_set(
+        &mut self,
+        key: &str,
+        expected: Option<&[u8]>,
+        value: &[u8],
+        _ttl: time::Duration,
+    ) -> Result<bool, StoreError> {
+        let mut values = self.values.lock().unwrap_or_else(|e| e.into_inner());
+        if values.get(key).map(Vec::as_slice) != expected {
+            return Ok(false);
+        }
+
+        values.insert(key.to_owned(), value.to_vec());
+        Ok(true)
+    }
+}
+
+/// Keeps limiter state in Redis. The compare and set runs as a script so that it is atomic on the
+/// server.
+struct RedisStore {
+    conn: redis::Connection,
+    cas: redis::Script,
+}
+
+impl RedisStore {
+    fn open(url: &str) -> Result<Self, StoreError> {
+        let conn = redis::Client::open(url)?.get_connection()?;
+        let cas = redis::Script::new(
+            r"
+            local current = redis.call('GET', KEYS[1])
+            if (ARGV[1] == '1' and current ~= ARGV[2]) or (ARGV[1] == '0' and current) then
+                return 0
+            end
+            redis.call('SET', KEYS[1], ARGV[3], 'PX', ARGV[4])
+            return 1
+            ",
+        );
+
+        Ok(RedisStore { conn, cas })
+    }
+}
+
+impl Store for RedisStore {
+    fn get(&mut self, key: &str) -> Result<Option<Vec<u8>>, StoreError> {
+        Ok(redis::cmd("GET").arg(key).query(&mut self.conn)?)
+    }
+
+    fn compare_and_set(
+        &mut self,
+        key: &str,
+        expected: Option<&[u8]>,
+        value: &[u8],
+        ttl: time::Duration,
+    ) -> Result<bool, StoreError> {
+        let swapped = self
+            .cas
+            .key(key)
+            .arg(if expected.is_some() { "1" } else { "0" })
+            .arg(expected.unwrap_or_default())
+            .arg(value)
+            .arg(ttl.as_millis().max(1) as u64)
+            .invoke(&mut self.conn)?;
+
+        Ok(swapped)
+    }
+}
+
+/// Enforces a single limit across every process sharing a store, keeping the state of any
+/// `Persist` limiter under one key. Each check loads the state, applies it to a fresh limiter and
+/// writes the result back, starting over if another process updated the key in the meantime.
+///
+/// State is saved as wall clock times, so processes sharing a key need reasonably synchronized
+/// clocks. The key expires after being idle for twice the window; a limiter that has been idle
+/// that long has forgotten its hits anyway.
+struct Distributed<S, L: RateLimiter> {
+    store: S,
+    key: String,
+    window: time::Duration,
+    limit: usize,
+    clock: L::Clock,
+    max_retries: usize,
+}
+
+type RedisTokenBucket = Distributed<RedisStore, TokenBucket>;
+type RedisFixedWindow = Distributed<RedisStore, FixedWindow>;
+
+impl<S: Store, L: RateLimiter + Persist> Distributed<S, L> {
+    fn new(store: S, key: impl Into<String>, window: time::Duration, limit: usize) -> Self
+    where
+        L::Clock: Default,
+    {
+        Self::with_clock(store, key, window, limit, L::Clock::default())
+    }
+
+    fn with_clock(
+        store: S,
+        key: impl Into<String>,
+        window: time::Duration,
+        limit: usize,
+        clock: L::Clock,
+    ) -> Self {
+        Distributed {
+            store,
+            key: key.into(),
+            window,
+            limit,
+            clock,
+            max_retries: 10,
+        }
+    }
+
+    /// Sets how many times a check is retried when other processes keep updating the key first.
+    fn with_max_retries(mut self, max_retries: usize) -> Self {
+        self.max_retries = max_retries;
+        self
+    }
+
+    fn allowed(&mut self) -> Result<bool, StoreError> {
+        self.allowed_n(1)
+    }
+
+    fn allowed_n(&mut self, cost: usize) -> Result<bool, StoreError> {
+        self.modify(|limiter| limiter.allowed_n(cost))
+    }
+
+    fn give_back(&mut self, n: usize) -> Result<(), StoreError> {
+        self.modify(|limiter| limiter.give_back(n))
+    }
+
+    fn time_until_allowed(&mut self) -> Result<time::Duration, StoreError> {
+        Ok(self.load()?.0.time_until_allowed())
+    }
+
+    fn remaining(&mut self) -> Result<usize, StoreError> {
+        Ok(self.load()?.0.remaining())
+    }
+
+    fn load(&mut self) -> Result<(L, Option<Vec<u8>>), StoreError> {
+        let current = self.store.get(&self.key)?;
+        let mut limiter = L::with_clock(self.window, self.limit, self.clock.clone());
+        if let Some(state) = &current {
+            limiter.restore(serde_json::from_slice(state)?);
+        }
+
+        Ok((limiter, current))
+    }
+
+    fn modify<T>(&mut self, f: impl Fn(&mut L) -> T) -> Result<T, StoreError> {
+        for _ in 0..=self.max_retries {
+            let (mut limiter, current) = self.load()?;
+            let out = f(&mut limiter);
+            let state = serde_json::to_vec(&limiter.save())?;
+
+            if self
+                .store
+                .compare_and_set(&self.key, current.as_deref(), &state, self.window * 2)?
+            {
+                return Ok(out);
+            }
+        }
+
+        Err(StoreError::Contention(self.max_retries))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rate_limit::ManualClock;
+
+    #[test]
+    fn test_distributed_limit_is_shared() {
+        let clock = ManualClock::new();
+        let store = MemoryStore::default();
+        let mut a: Distributed<_, FixedWindow<ManualClock>> = Distributed::with_clock(
+            store.clone(),
+            "fixed",
+            time::Duration::from_secs(1),
+            10,
+            clock.clone(),
+        );
+        let mut b: Distributed<_, FixedWindow<ManualClock>> = Distributed::with_clock(
+            store.clone(),
+            "fixed",
+            time::Duration::from_secs(1),
+            10,
+            clock.clone(),
+        );
+
+        assert_eq!(6, (0..6).filter(|_| a.allowed().unwrap()).count());
+        assert_eq!(4, (0..10).filter(|_| b.allowed().unwrap()).count());
+        assert_eq!(0, a.remaining().unwrap());
+
+        clock.advance(time::Duration::from_millis(1001));
+        assert!(a.allowed().unwrap());
+        assert_eq!(9, b.remaining().unwrap());
+
+        let mut bucket: Distributed<_, TokenBucket<ManualClock>> = Distributed::with_clock(
+            store.clone(),
+            "bucket",
+            time::Duration::from_secs(1),
+            10,
+            clock.clone(),
+        );
+        let mut other: Distributed<_, TokenBucket<ManualClock>> = Distributed::with_clock(
+            store,
+            "bucket",
+            time::Duration::from_secs(1),
+            10,
+            clock.clone(),
+        );
+
+        assert!(!bucket.allowed().unwrap());
+        clock.advance(time::Duration::from_millis(500));
+        assert_eq!(2, (0..2).filter(|_| bucket.allowed().unwrap()).count());
+        assert_eq!(3, (0..10).filter(|_| other.allowed().unwrap()).count());
+    }
+
+    #[test]
+    fn test_distributed_gives_up_under_contention() {
+        struct Contended;
+
+        impl Store for Contended {
+            fn get(&mut self, _key: &str) -> Result<Option<Vec<u8>>, StoreError> {
+                Ok(None)
+            }
+
+            fn compare_and_set(
+                &mut self,
+                _key: &str,
+                _expected: Option<&[u8]>,
+                _value: &[u8],
+                _ttl: time::Duration,
+            ) -> Result<bool, StoreError> {
+                Ok(false)
+            }
+        }
+
+        let mut limiter: Distributed<_, FixedWindow<ManualClock>> = Distributed::with_clock(
+            Contended,
+            "key",
+            time::Duration::from_secs(1),
+            10,
+            ManualClock::new(),
+        )
+        .with_max_retries(3);
+
+        assert!(matches!(limiter.allowed(), Err(StoreError::Contention(3))));
+    }
+
+    #[test]
+    fn test_observers_see_every_decision() {
+        let clock = ManualClock::new();
+        let seen = Arc::new(Mutex::new(Vec::new()));
+
+        let log = seen.clone();
+        let mut limiter: Observed<FixedWindow<ManualClock>> =
+            Observed::with_clock(time::Duration::from_secs(1), 2, clock.clone())
+                .named("global")
+                .with_observer(move |e: &Event| {
+                    log.lock()
+                        .unwrap()
+                        .push((e.limiter.to_owned(), e.allowed, e.remaining))
+                });
+
+        assert_eq!(2, (0..3).filter(|_| limiter.allowed()).count());
+        assert_eq!(
+            vec![
+                ("global".to_owned(), true, 1),
+                ("global".to_owned(), true, 0),
+                ("global".to_owned(), false, 0),
+            ],
+            *seen.lock().unwrap()
+        );
+
+        let denied = Arc::new(Mutex::new(Vec::new()));
+        let log = denied.clone();
+        let mut keyed: KeyedRateLimiter<&str, FixedWindow<ManualClock>> =
+            KeyedRateLimiter::with_clock(time::Duration::from_secs(1), 1, clock)
+                .named("per-user")
+                .with_observer(move |e: &Event<&str>| {
+                    if !e.allowed {
+                        log.lock().unwrap().push(format!("{} {}", e.limiter, e.key));
+                    }
+                });
+
+        assert!(keyed.allowed(&"alice"));
+        assert!(keyed.allowed(&"bob"));
+        assert!(!keyed.allowed(&"bob"));
+        assert_eq!(vec!["per-user bob".to_owned()], *denied.lock().unwrap());
+    }
+
+    #[cfg(feature = "prometheus")]
+    #[test]
+    fn test_prometheus_metrics() {
+        let registry = prometheus::Registry::new();
+        let metrics = PrometheusMetrics::register(&registry).unwrap();
+        let clock = ManualClock::new();
+
+        let mut limiter: Observed<FixedWindow<ManualClock>> =
+            Observed::with_clock(time::Duration::from_secs(1), 3, clock.clone())
+                .named("global")
+                .with_observer(metrics.observer());
+        let mut keyed: KeyedRateLimiter<u32, FixedWindow<ManualClock>> =
+            KeyedRateLimiter::with_clock(time::Duration::from_secs(1), 1, clock)
+                .named("per-user")
+                .with_observer(metrics.keyed_observer());
+
+        (0..5).for_each(|_| {
+            limiter.allowed();
+        });
+        (0..4).for_each(|key| {
+            keyed.allowed(&(key % 2));
+        });
+        metrics.track_keys(&keyed);
+
+        let value = |name: &str, limiter: &str| {
+            registry
+                .gather()
+                .iter()
+                .find(|family| family.get_name() == name)
+                .and_then(|family| {
+                    family.get_metric().iter().find_map(|m| {
+                        m.get_label()
+                            .iter()
+                            .any(|l| l.get_value() == limiter)
+                            .then(|| m.get_counter().get_value() + m.get_gauge().get_value())
+                    })
+                })
+                .unwrap_or_default()
+        };
+
+        assert_eq!(3.0, value("allowed_total", "global"));
+        assert_eq!(2.0, value("denied_total", "global"));
+        assert_eq!(0.0, value("tokens_available", "global"));
+        assert_eq!(2.0, value("allowed_total", "per-user"));
+        assert_eq!(2.0, value("denied_total", "per-user"));
+        assert_eq!(2.0, value("keys_tracked", "per-user"));
+    }
+
+    #[test]
+    fn test_rolling_stats() {
+        let clock = ManualClock::new();
+        let mut limiter: Observed<FixedWindow<ManualClock>> =
+            Observed::with_clock(time::Duration::from_secs(1), 4, clock.clone());
+
+        assert_eq!(1.0, limiter.stats().acceptance_ratio());
+
+        (0..6).for_each(|_| {
+            limiter.allowed();
+        });
+        assert_eq!(
+            Stats {
+                allowed: 4,
+                denied: 2
+            },
+            limiter.stats()
+        );
+
+        // Only the requests from the last 600ms are left in the trailing window.
+        clock.advance(time::Duration::from_millis(600));
+        (0..2).for_each(|_| {
+            limiter.allowed();
+        });
+        clock.advance(time::Duration::from_millis(500));
+        limiter.allowed();
+        assert_eq!(
+            Stats {
+                allowed: 1,
+                denied: 2
+            },
+            limiter.stats()
+        );
+        assert!((limiter.stats().acceptance_ratio() - 1.0 / 3.0).abs() < 1e-9);
+
+        clock.advance(time::Duration::from_secs(2));
+        assert_eq!(Stats::default(), limiter.stats());
+    }
+
+    #[test]
+    fn test_rate_limit_headers() {
+        let allowed = RateLimitHeaders::new(
+            Decision::Allowed { remaining: 7 },
+            10,
+            time::Duration::from_millis(1500),
+        );
+        assert_eq!(
+            vec![
+                ("X-RateLimit-Limit", "10".to_owned()),
+                ("X-RateLimit-Remaining", "7".to_owned()),
+                ("X-RateLimit-Reset", "2".to_owned()),
+            ],
+            allowed.x_rate_limit()
+        );
+
+        let denied = RateLimitHeaders::new(
+            Decision::Denied {
+                retry_after: time::Duration::from_secs(3),
+            },
+            10,
+            time::Duration::from_secs(1),
+        );
+        assert_eq!(
+            vec![
+                ("RateLimit-Limit", "10".to_owned()),
+                ("RateLimit-Remaining", "0".to_owned()),
+                ("RateLimit-Reset", "3".to_owned()),
+                ("Retry-After", "3".to_owned()),
+            ],
+            denied.draft()
+        );
+    }
+
+    #[test]
+    fn test_tick_clock() {
+        use std::sync::atomic::{AtomicU64, Ordering};
+
+        let millis = Arc::new(AtomicU64::new(1_700_000_000_000));
+        let source = millis.clone();
+        let clock = TickClock::from_unix_millis(move || source.load(Ordering::SeqCst) as f64);
+        let mut limiter = FixedWindow::with_clock(time::Duration::from_secs(1), 2, clock.clone());
+
+        assert_eq!(
+            SystemTime::UNIX_EPOCH + time::Duration::from_secs(1_700_000_000),
+            clock.wall()
+        );
+        assert_eq!(2, (0..3).filter(|_| limiter.allowed()).count());
+
+        millis.fetch_add(1001, Ordering::SeqCst);
+        assert!(limiter.allowed());
+    }
+
+    #[test]
+    fn test_all_of_and_any_of() {
+        let clock = ManualClock::new();
+        let second = time::Duration::from_secs(1);
+
+        // Bursts of up to 5 a second, but no more than 8 in an hour.
+        let mut limiter = AllOf::new(
+            TokenBucket::with_clock(second, 5, clock.clone()),
+            FixedWindow::with_clock(time::Duration::from_secs(3600), 8, clock.clone()),
+        );
+
+        clock.advance(second);
+        assert_eq!(5, (0..10).filter(|_| limiter.allowed()).count());
+        clock.advance(second);
+        assert_eq!(3, (0..10).filter(|_| limiter.allowed()).count());
+
+        // The denials above didn't use up the bucket.
+        assert_eq!(2, limiter.first.remaining());
+        assert_eq!(0, limiter.remaining());
+
+        let mut limiter = AnyOf::new(
+            FixedWindow::with_clock(second, 2, clock.clone()),
+            FixedWindow::with_clock(time::Duration::from_secs(3600), 3, clock.clone()),
+        );
+
+        assert_eq!(5, (0..10).filter(|_| limiter.allowed()).count());
+        clock.advance(time::Duration::from_millis(1001));
+        assert_eq!(2, (0..10).filter(|_| limiter.allowed()).count());
+        assert_eq!(0, limiter.second.remaining());
+    }
+
+    #[test]
+    fn test_recording_and_scripted_limiters() {
+        let clock = ManualClock::new();
+        let start = clock.now();
+        let mut limiter: RecordingLimiter<FixedWindow<ManualClock>> =
+            RecordingLimiter::with_clock(time::Duration::from_secs(1), 1, clock.clone());
+
+        limiter.allowed();
+        clock.advance(time::Duration::from_millis(10));
+        limiter.allowed_n(2);
+
+        assert_eq!(
+            vec![
+                Recorded {
+                    at: start,
+                    key: (),
+                    cost: 1,
+                    allowed: true
+                },
+                Recorded {
+                    at: start + time::Duration::from_millis(10),
+                    key: (),
+                    cost: 2,
+                    allowed: false
+                },
+            ],
+            limiter.log()
+        );
+
+        let recorder = Recorder::new(clock.clone());
+        let mut keyed: KeyedRateLimiter<&str, FixedWindow<ManualClock>> =
+            KeyedRateLimiter::with_clock(time::Duration::from_secs(1), 1, clock)
+                .with_observer(recorder.clone());
+        keyed.allowed(&"a");
+        keyed.allowed(&"a");
+        assert_eq!(
+            vec![("a", true), ("a", false)],
+            recorder
+                .log()
+                .into_iter()
+                .map(|r| (r.key, r.allowed))
+                .collect::<Vec<_>>()
+        );
+
+        let mut scripted = ScriptedLimiter::from_script([
+            Decision::Allowed { remaining: 1 },
+            Decision::Denied {
+                retry_after: time::Duration::ZERO,
+            },
+            Decision::Denied {
+                retry_after: time::Duration::ZERO,
+            },
+        ]);
+        assert_eq!(Decision::Allowed { remaining: 1 }, scripted.decide());
+
+        // Waits through the zero-length denials with only the minimum sleep.
+        let start = time::Instant::now();
+        scripted.wait();
+        assert!(start.elapsed() < time::Duration::from_secs(1));
+        assert!(scripted.allowed());
+    }
+
+    #[test]
+    fn test_registry_from_toml() {
+        let config = r#"
+            [login]
+            algorithm = "token_bucket"
+            window_ms = 1000
+            limit = 1
+            burst = 3
+            key = "peer_ip"
+
+            [search]
+            algorithm = "fixed_window"
+            window_ms = 1000
+            limit = 2
+        "#;
+
+        let clock = ManualClock::new();
+        let registry = Registry::from_toml_with_clock(config, clock.clone()).unwrap();
+
+        let login = registry.get("login").unwrap();
+        assert_eq!(&KeyStrategy::PeerIp, login.key_strategy());
+        // The bucket for a new key starts out empty and fills up to the burst.
+        assert!(!login.allowed("10.0.0.1"));
+        clock.advance(time::Duration::from_secs(5));
+        assert_eq!(3, (0..5).filter(|_| login.allowed("10.0.0.1")).count());
+        assert!(!login.allowed("10.0.0.2"));
+
+        let search = registry.get("search").unwrap();
+        assert!(search.allowed("a"));
+        assert!(search.allowed("b"));
+        assert!(!search.allowed("c"));
+
+        assert!(registry.get("missing").is_none());
+
+        let invalid = "[bad]\nalgorithm = \"gcra\"\nwindow_ms = 1000\nlimit = 1\nburst = 2\n";
+        assert!(matches!(
+            Registry::from_toml(invalid),
+            Err(RegistryError::Invalid {
+                source: ConfigError::BurstUnsupported,
+                ..
+            })
+        ));
+        assert!(matches!(
+            Registry::from_toml("[bad]\nalgorithm = \"nope\""),
+            Err(RegistryError::Parse(_))
+        ));
+    }
+
+    #[test]
+    fn test_keyed_usage() {
+        let clock = ManualClock::new();
+        let mut limiter: KeyedRateLimiter<&str, FixedWindow<ManualClock>> =
+            KeyedRateLimiter::with_clock(time::Duration::from_secs(1), 5, clock.clone());
+
+        for (key, hits) in [("a", 1), ("b", 4), ("c", 7)] {
+            for _ in 0..hits {
+                limiter.allowed(&key);
+            }
+        }
+
+        let mut usage = limiter.usage();
+        usage.sort_by_key(|u| u.key);
+        assert_eq!(
+            vec![("a", 1, 4), ("b", 4, 1), ("c", 5, 0)],
+            usage
+                .iter()
+                .map(|u| (u.key, u.used, u.remaining))
+                .collect::<Vec<_>>()
+        );
+        assert_eq!(limiter.reset_at(&"a"), usage[0].reset_at);
+
+        let top = limiter.top_talkers(2);
+        assert_eq!(
+            vec!["c", "b"],
+            top.iter().map(|u| u.key).collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn test_calendar_window_aligns_to_wall_clock() {
+        // 30 seconds past the top of an hour.
+        let clock =
+            ManualClock::at(SystemTime::UNIX_EPOCH + time::Duration::from_secs(3600 * 24 + 30));
+        let hour = time::Duration::from_secs(3600);
+        let mut limiter = CalendarWindow::with_clock(hour, 2, clock.clone());
+
+        assert_eq!(2, (0..5).filter(|_| limiter.allowed()).count());
+        assert_eq!(
+            time::Duration::from_secs(3570),
+            limiter.time_until_allowed()
+        );
+
+        // Save the state and restore it into a fresh limiter, as a new process would.
+        let state = limiter.save();
+        let mut restored = CalendarWindow::with_clock(hour, 2, clock.clone());
+        restored.restore(state.clone());
+        assert!(!restored.allowed());
+
+        clock.advance(time::Duration::from_secs(3570));
+        assert_eq!(2, restored.remaining());
+        assert!(restored.allowed());
+
+        // State saved in an earlier window doesn't count against the current one.
+        let mut stale = CalendarWindow::with_clock(hour, 2, clock.clone());
+        stale.restore(state);
+        assert_eq!(2, stale.remaining());
+    }
+
+    #[test]
+    fn test_multi_window_consumes_all_or_nothing() {
+        let clock = ManualClock::new();
+        let mut limiter: MultiWindow<FixedWindow<ManualClock>> =
+            MultiWindow::with_clock(time::Duration::from_secs(1), 3, clock.clone())
+                .and(time::Duration::from_secs(60), 5);
+
+        assert_eq!(3, (0..10).filter(|_| limiter.allowed()).count());
+        assert_eq!(0, limiter.remaining());
+
+        // The per-second limit resets, but the per-minute limit only has two left.
+        clock.advance(time::Duration::from_millis(1001));
+        assert_eq!(2, limiter.remaining());
+        assert!(!limiter.allowed_n(3));
+        assert_eq!(2, (0..10).filter(|_| limiter.allowed()).count());
+
+        // A request denied by the per-minute limit didn't count against the per-second one.
+        clock.advance(time::Duration::from_millis(1001));
+        assert!(!limiter.allowed());
+        assert_eq!(3, limiter.limiters[0].remaining());
+    }
+
+    #[test]
+    fn test_priority_sheds_low_priority_first() {
+        let mut limiter: Prioritized<FixedWindow<ManualClock>> =
+            Prioritized::with_clock(time::Duration::from_secs(1), 10, ManualClock::new())
+                .with_reserved(Priority::Low, 0.2);
+
+        assert_eq!(
+            8,
+            (0..20)
+                .filter(|_| limiter.allowed_with(Priority::Low, 1))
+                .count()
+        );
+        assert!(limiter.allowed_with(Priority::High, 1));
+        assert!(limiter.allowed());
+        assert!(!limiter.allowed_with(Priority::High, 1));
+
+        // Denied while quota is left, because using it would eat into the reserve.
+        let clock = ManualClock::new();
+        let mut limiter: Prioritized<FixedWindow<ManualClock>> =
+            Prioritized::with_clock(time::Duration::from_secs(1), 10, clock.clone())
+                .with_reserved(Priority::Normal, 0.2);
+        assert_eq!(8, (0..20).filter(|_| limiter.allowed()).count());
+        clock.advance(time::Duration::from_millis(400));
+        assert_eq!(2, limiter.remaining());
+        assert_eq!(
+            time::Duration::from_millis(600),
+            limiter.time_until_allowed()
+        );
+    }
+
+    #[test]
+    fn test_adaptive_aimd() {
+        let mut limiter: Adaptive<Gcra<ManualClock>> =
+            Adaptive::with_clock(time::Duration::from_secs(1), 20, ManualClock::new())
+                .with_bounds(2, 40);
+
+        limiter.on_throttled();
+        assert_eq!(10, limiter.limit());
+        limiter.on_error();
+        assert_eq!(5, limiter.limit());
+
+        // About one more per limit's worth of successes.
+        (0..5).for_each(|_| limiter.on_success());
+        assert_eq!(5, limiter.limit());
+        (0..6).for_each(|_| limiter.on_success());
+        assert_eq!(6, limiter.limit());
+
+        (0..10).for_each(|_| limiter.on_throttled());
+        assert_eq!(2, limiter.limit());
+        (0..10_000).for_each(|_| limiter.on_success());
+        assert_eq!(40, limiter.limit());
+    }
+
+    #[test]
+    fn test_persisted_state_round_trips() {
+        let window = time::Duration::from_secs(1);
+        let clock = ManualClock::new();
+        let mut bucket = TokenBucket::with_clock(window, 10, clock.clone());
+        let mut gcra = Gcra::with_clock(window, 10, clock.clone());
+
+        clock.advance(time::Duration::from_millis(750));
+        assert!(bucket.allowed_n(3));
+        assert!(gcra.allowed_n(6));
+
+        let saved_bucket = serde_json::to_string(&bucket.save()).unwrap();
+        let saved_gcra = serde_json::to_string(&gcra.save()).unwrap();
+
+        // Another process with its own monotonic clock, starting at the same wall clock time.
+        let other = ManualClock::at(clock.wall());
+        let mut bucket = TokenBucket::with_clock(window, 10, other.clone());
+        bucket.restore(serde_json::from_str(&saved_bucket).unwrap());
+        let mut gcra = Gcra::with_clock(window, 10, other.clone());
+        gcra.restore(serde_json::from_str(&saved_gcra).unwrap());
+
+        assert_eq!(4, bucket.remaining());
+        assert_eq!(4, gcra.remaining());
+        other.advance(time::Duration::from_millis(250));
+        assert_eq!(7, bucket.remaining());
+        assert_eq!(6, gcra.remaining());
+    }
+
+    #[test]
+    fn test_keyed_state_round_trips() {
+        let clock = ManualClock::new();
+        let mut limiter: KeyedRateLimiter<String, FixedWindow<ManualClock>> =
+            KeyedRateLimiter::with_clock(time::Duration::from_secs(60), 5, clock.clone());
+        limiter.allowed_n(&"a".to_string(), 3);
+        limiter.allowed_n(&"b".to_string(), 5);
+
+        let saved = serde_json::to_string(&limiter.save()).unwrap();
+
+        let mut restored: KeyedRateLimiter<String, FixedWindow<ManualClock>> =
+            KeyedRateLimiter::with_clock(time::Duration::from_secs(60), 5, clock.clone());
+        restored.restore(serde_json::from_str(&saved).unwrap());
+        assert_eq!(2, restored.remaining(&"a".to_string()));
+        assert_eq!(0, restored.remaining(&"b".to_string()));
+    }
+
+    #[test]
+    fn test_keyed_limits_are_independent() {
+        let mut limiter: KeyedRateLimiter<&str, FixedWindow<ManualClock>> =
+            KeyedRateLimiter::with_clock(time::Duration::from_secs(1), 2, ManualClock::new());
+
+        assert!(limiter.allowed(&"a"));
+        assert!(limiter.allowed(&"a"));
+        assert!(!limiter.allowed(&"a"));
+
+        assert!(limiter.allowed(&"b"));
+        assert_eq!(2, limiter.len());
+    }
+
+    #[test]
+    fn test_keyed_purges_idle_keys() {
+        let clock = ManualClock::new();
+        let mut limiter: KeyedRateLimiter<&str, FixedWindow<ManualClock>> =
+            KeyedRateLimiter::with_clock(time::Duration::from_secs(1), 2, clock.clone())
+                .with_idle_ttl(time::Duration::from_secs(60));
+
+        limiter.allowed(&"a");
+        clock.advance(time::Duration::from_secs(60));
+        limiter.allowed(&"b");
+
+        assert_eq!(1, limiter.purge());
+        assert_eq!(1, limiter.len());
+    }
+
+    #[test]
+    fn test_keyed_penalty_locks_out_repeat_offenders() {
+        let clock = ManualClock::new();
+        let mut limiter: KeyedRateLimiter<&str, FixedWindow<ManualClock>> =
+            KeyedRateLimiter::with_clock(time::Duration::from_secs(1), 2, clock.clone())
+                .with_penalty(
+                    3,
+                    time::Duration::from_secs(10),
+                    time::Duration::from_secs(60),
+                );
+
+        assert_eq!(2, (0..5).filter(|_| limiter.allowed(&"a")).count());
+        assert_eq!(
+            time::Duration::from_secs(60),
+            limiter.time_until_allowed(&"a")
+        );
+
+        // The window has reset, but the key is still serving its lockout.
+        clock.advance(time::Duration::from_secs(30));
+        assert!(!limiter.allowed(&"a"));
+        assert_eq!(0, limiter.remaining(&"a"));
+        assert!(limiter.allowed(&"b"));
+
+        clock.advance(time::Duration::from_secs(30));
+        assert!(limiter.allowed(&"a"));
+    }
+
+    #[test]
+    fn test_hierarchical_shares_parent_budget() {
+        let clock = ManualClock::new();
+        let window = time::Duration::from_secs(1);
+        let mut limiter: HierarchicalLimiter<&str, FixedWindow<ManualClock>> =
+            HierarchicalLimiter::new(
+                FixedWindow::with_clock(window, 5, clock.clone()),
+                KeyedRateLimiter::with_clock(window, 3, clock.clone()),
+            );
+
+        assert_eq!(3, (0..10).filter(|_| limiter.allowed(&"a")).count());
+
+        // Denials from the per-key limit don't use up the global budget.
+        assert_eq!(2, limiter.remaining(&"b"));
+        assert_eq!(2, (0..10).filter(|_| limiter.allowed(&"b")).count());
+        assert!(!limiter.allowed(&"c"));
+
+        // And denials from the global limit don't use up the per-key budget.
+        assert_eq!(1, limiter.children.remaining(&"b"));
+        assert_eq!(3, limiter.children.remaining(&"c"));
+    }
+
+    #[test]
+    fn test_hierarchical_fairness_splits_budget() {
+        let clock = ManualClock::new();
+        let window = time::Duration::from_secs(1);
+        let mut limiter: HierarchicalLimiter<&str, FixedWindow<ManualClock>> =
+            HierarchicalLimiter::new(
+                FixedWindow::with_clock(window, 10, clock.clone()),
+                KeyedRateLimiter::with_clock(window, 10, clock.clone()),
+            )
+            .with_fairness();
+
+        // With "b" active, the hot key only gets half of the budget.
+        assert!(limiter.allowed(&"b"));
+        assert_eq!(5, (0..20).filter(|_| limiter.allowed(&"a")).count());
+        assert_eq!(4, (0..20).filter(|_| limiter.allowed(&"b")).count());
+
+        // Weights skew the split.
+        clock.advance(time::Duration::from_millis(1001));
+        limiter.set_weight("a", 4);
+        assert!(limiter.allowed(&"b"));
+        assert_eq!(8, (0..20).filter(|_| limiter.allowed(&"a")).count());
+        assert_eq!(1, (0..20).filter(|_| limiter.allowed(&"b")).count());
+    }
+
+    #[test]
+    fn test_hierarchical_parent_denials_charge_nothing() {
+        let clock = ManualClock::new();
+        let window = time::Duration::from_secs(1);
+        // Reserving half of the parent's quota makes it deny while it still reports quota left.
+        let parent = Prioritized::<FixedWindow<ManualClock>>::with_clock(window, 10, clock.clone())
+            .with_reserved(Priority::Normal, 0.5);
+        let mut limiter =
+            HierarchicalLimiter::new(parent, KeyedRateLimiter::with_clock(window, 10, clock))
+                .with_fairness();
+
+        assert_eq!(5, (0..10).filter(|_| limiter.allowed(&"a")).count());
+
+        // Neither the key's own limit nor its fair share were charged for the denials.
+        assert_eq!(5, limiter.children.remaining(&"a"));
+        assert_eq!(5, limiter.fairness.as_ref().unwrap().usage[&"a"].used);
+    }
+
+    #[test]
+    fn test_keyed_evicts_least_recently_hit() {
+        let clock = ManualClock::new();
+        let mut limiter: KeyedRateLimiter<&str, FixedWindow<ManualClock>> =
+            KeyedRateLimiter::with_clock(time::Duration::from_secs(1), 1, clock.clone())
+                .with_max_keys(2);
+
+        assert!(limiter.allowed(&"a"));
+        clock.advance(time::Duration::from_millis(1));
+        assert!(limiter.allowed(&"b"));
+        clock.advance(time::Duration::from_millis(1));
+        assert!(!limiter.allowed(&"a"));
+        clock.advance(time::Duration::from_millis(1));
+        assert!(limiter.allowed(&"c"));
+        assert_eq!(2, limiter.len());
+
+        // "b" was the least recently hit, so it was evicted and starts over.
+        assert!(limiter.allowed(&"b"));
+    }
+
+    #[test]
+    fn test_keyed_eviction_keeps_locked_out_keys() {
+        let clock = ManualClock::new();
+        let mut limiter: KeyedRateLimiter<&str, FixedWindow<ManualClock>> =
+            KeyedRateLimiter::with_clock(time::Duration::from_secs(1), 1, clock.clone())
+                .with_max_keys(2)
+                .with_penalty(
+                    2,
+                    time::Duration::from_secs(10),
+                    time::Duration::from_secs(60),
+                );
+
+        assert_eq!(1, (0..3).filter(|_| limiter.allowed(&"a")).count());
+        clock.advance(time::Duration::from_millis(1));
+        assert!(limiter.allowed(&"b"));
+        clock.advance(time::Duration::from_millis(1));
+        assert!(limiter.allowed(&"c"));
+
+        // "a" was the least recently hit, but it's locked out, so "b" was evicted instead.
+        assert_eq!(2, limiter.len());
+        assert!(!limiter.allowed(&"a"));
+        assert_eq!(
+            time::Duration::from_secs(60) - time::Duration::from_millis(2),
+            limiter.time_until_allowed(&"a")
+        );
+    }
+
+    #[test]
+    fn test_rate_limited_iterator() {
+        let limiter: TokenBucket = TokenBucket::new(time::Duration::from_millis(100), 10);
+
+        let start = time::Instant::now();
+        let items: Vec<_> = (0..5).rate_limited(limiter).collect();
+
+        // The bucket starts empty and accrues a token every 10ms.
+        assert_eq!(vec![0, 1, 2, 3, 4], items);
+        assert!(start.elapsed() >= time::Duration::from_millis(50));
+    }
+
+    #[tokio::test]
+    async fn test_throttled_stream() {
+        use futures::StreamExt;
+
+        let limiter: TokenBucket = TokenBucket::new(time::Duration::from_millis(100), 10);
+
+        let start = time::Instant::now();
+        let items: Vec<_> = futures::stream::iter(0..5)
+            .throttled(limiter)
+            .collect()
+            .await;
+
+        // The bucket starts empty and accrues a token every 10ms.
+        assert_eq!(vec![0, 1, 2, 3, 4], items);
+        assert!(start.elapsed() >= time::Duration::from_millis(50));
+
+        // A denial with no wait sleeps briefly and checks again rather than spinning.
+        let limiter = ScriptedLimiter::from_script([Decision::Denied {
+            retry_after: time::Duration::ZERO,
+        }]);
+        let items: Vec<_> = tokio::time::timeout(
+            time::Duration::from_secs(1),
+            futures::stream::iter(0..2).throttled(limiter).collect(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(vec![0, 1], items);
+    }
+
+    #[tokio::test]
+    async fn test_rate_limit_layer() {
+        use tower::{Layer, ServiceExt};
+
+        let clock = ManualClock::new();
+        let limiter: Arc<Shared<FixedWindow<ManualClock>>> = Arc::new(Shared::with_clock(
+            time::Duration::from_secs(1),
+            2,
+            clock.clone(),
+        ));
+        let service = RateLimitLayer::new(limiter).layer(tower::service_fn(|n: u32| async move {
+            Ok::<_, std::convert::Infallible>(n * 2)
+        }));
+
+        assert_eq!(2, service.clone().oneshot(1).await.unwrap());
+        assert_eq!(4, service.clone().oneshot(2).await.unwrap());
+
+        let err = service.clone().oneshot(3).await.unwrap_err();
+        assert!(err.downcast_ref::<Rejected>().is_some());
+
+        clock.advance(time::Duration::from_millis(1001));
+        assert_eq!(8, service.oneshot(4).await.unwrap());
+    }
+
+    #[test]
+    fn test_rate_limit_interceptor() {
+        use tonic::service::Interceptor;
+
+        let limiter: Arc<Mutex<KeyedRateLimiter<String, FixedWindow<ManualClock>>>> =
+            Arc::new(Mutex::new(KeyedRateLimiter::with_clock(
+                time::Duration::from_secs(1),
+                1,
+                ManualClock::new(),
+            )));
+        let mut interceptor = RateLimitInterceptor::new(limiter, "authorization");
+
+        let request = |key: Option<&str>| {
+            let mut request = tonic::Request::new(());
+            if let Some(key) = key {
+                request
+                    .metadata_mut()
+                    .insert("authorization", key.parse().unwrap());
+            }
+            request
+        };
+
+        assert!(interceptor.call(request(Some("a"))).is_ok());
+        assert!(interceptor.call(request(Some("b"))).is_ok());
+        assert!(interceptor.call(request(None)).is_ok());
+
+        let status = interceptor.call(request(Some("a"))).unwrap_err();
+        assert_eq!(tonic::Code::ResourceExhausted, status.code());
+        assert!(status.metadata().contains_key("grpc-retry-pushback-ms"));
+    }
+
+    #[tokio::test]
+    async fn test_keyed_rate_limit_middleware() {
+        use axum::{body::Body, http::StatusCode};
+        use tower::ServiceExt;
+
+        let limiter: Arc<Mutex<KeyedRateLimiter<String, FixedWindow<ManualClock>>>> =
+            Arc::new(Mutex::new(KeyedRateLimiter::with_clock(
+                time::Duration::from_secs(1),
+                1,
+                ManualClock::new(),
+            )));
+        let app = KeyedRateLimit::new(
+            limiter,
+            HeaderKey(axum::http::HeaderName::from_static("x-api-key")),
+        )
+        .apply(axum::Router::new().route("/", axum::routing::get(|| async { "ok" })));
+
+        let request = |key: Option<&str>| {
+            let mut builder = axum::http::Request::builder().uri("/");
+            if let Some(key) = key {
+                builder = builder.header("x-api-key", key);
+            }
+            builder.body(Body::empty()).unwrap()
+        };
+
+        let response = app.clone().oneshot(request(Some("a"))).await.unwrap();
+        assert_eq!(StatusCode::OK, response.status());
+        assert_eq!("0", response.headers()["x-ratelimit-remaining"]);
+
+        let response = app.clone().oneshot(request(Some("a"))).await.unwrap();
+        assert_eq!(StatusCode::TOO_MANY_REQUESTS, response.status());
+        assert!(response.headers().contains_key("retry-after"));
+
+        let response = app.clone().oneshot(request(Some("b"))).await.unwrap();
+        assert_eq!(StatusCode::OK, response.status());
+
+        let response = app.oneshot(request(None)).await.unwrap();
+        assert_eq!(StatusCode::OK, response.status());
+        assert!(!response.headers().contains_key("x-ratelimit-limit"));
     }
 }
@@ -0,0 +1,165 @@
+use rate_limit::{RateLimiterBuilder, Shared, TokenBucket};
+use std::{sync::Arc, time};
+
+/// `rate_limiter_load <url> <rps> <seconds> <concurrency>` generates paced load against a URL, see
+/// `load`.
+fn main() -> anyhow::Result<()> {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let [url, rps, seconds, concurrency] = args.as_slice() else {
+        anyhow::bail!("usage: rate_limiter_load <url> <rps> <seconds> <concurrency>");
+    };
+
+    let duration = time::Duration::from_secs(seconds.parse()?);
+    let report = tokio::runtime::Runtime::new()?.block_on(load(
+        url,
+        rps.parse()?,
+        duration,
+        concurrency.parse()?,
+    ))?;
+    print!("{report}");
+    Ok(())
+}
+
+/// Latencies of the successful requests made by `load`, and how many failed.
+#[derive(Debug, Clone, PartialEq)]
+struct LoadReport {
+    latencies: Vec<time::Duration>,
+    errors: usize,
+    elapsed: time::Duration,
+}
+
+impl LoadReport {
+    /// The latency that `p` percent of requests were at or under.
+    fn percentile(&self, p: f64) -> time::Duration {
+        if self.latencies.is_empty() {
+            return time::Duration::ZERO;
+        }
+
+        // Nearest rank, on latencies kept sorted by `load`.
+        let rank = (p / 100.0 * self.latencies.len() as f64).ceil() as usize;
+        self.latencies[rank.clamp(1, self.latencies.len()) - 1]
+    }
+}
+
+impl std::fmt::Display for LoadReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let total = self.latencies.len() + self.errors;
+        writeln!(
+            f,
+            "{total} requests in {:.1?} ({:.1} rps), {} failed",
+            self.elapsed,
+            total as f64 / self.elapsed.as_secs_f64().max(f64::EPSILON),
+            self.errors
+        )?;
+        for p in [50.0, 90.0, 99.0, 100.0] {
+            writeln!(f, "  p{p:<4} {:.1?}", self.percentile(p))?;
+        }
+        Ok(())
+    }
+}
+
+/// Sends GET requests to `url` from `concurrency` tasks for `duration`, paced by a token bucket to
+/// `rps` requests per second overall. Requests failing or getting an error status count as errors.
+/// Fails without sending anything if `rps` isn't a rate the token bucket supports or there are no
+/// tasks to send from, and fails if any of the tasks panicked.
+async fn load(
+    url: &str,
+    rps: usize,
+    duration: time::Duration,
+    concurrency: usize,
+) -> anyhow::Result<LoadReport> {
+    let window = time::Duration::from_secs(1);
+    RateLimiterBuilder::new()
+        .window(window)
+        .limit(rps)
+        .validate()?;
+    anyhow::ensure!(concurrency > 0, "concurrency must be at least 1");
+
+    let limiter: Arc<Shared<TokenBucket>> = Arc::new(Shared::new(window, rps));
+    let client = reqwest::Client::new();
+    let start = time::Instant::now();
+    let deadline = start + duration;
+
+    let workers: Vec<_> = (0..concurrency)
+        .map(|_| {
+            let (limiter, client, url) = (limiter.clone(), client.clone(), url.to_owned());
+            tokio::spawn(async move {
+                let mut latencies = Vec::new();
+                let mut errors = 0;
+
+                loop {
+                    limiter.acquire().await;
+                    if time::Instant::now() >= deadline {
+                        break;
+                    }
+
+                    let sent = time::Instant::now();
+                    match client
+                        .get(&url)
+                        .send()
+                        .await
+                        .and_then(|r| r.error_for_status())
+                    {
+                        Ok(_) => latencies.push(sent.elapsed()),
+                        Err(_) => errors += 1,
+                    }
+                }
+
+                (latencies, errors)
+            })
+        })
+        .collect();
+
+    let mut report = LoadReport {
+        latencies: Vec::new(),
+        errors: 0,
+        elapsed: time::Duration::ZERO,
+    };
+    for worker in futures::future::join_all(workers).await {
+        let (latencies, errors) = worker?;
+        report.latencies.extend(latencies);
+        report.errors += errors;
+    }
+    report.latencies.sort();
+    report.elapsed = start.elapsed();
+
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_load_report_percentiles() {
+        let report = LoadReport {
+            latencies: (1..=200).map(time::Duration::from_millis).collect(),
+            errors: 50,
+            elapsed: time::Duration::from_secs(5),
+        };
+
+        assert_eq!(time::Duration::from_millis(100), report.percentile(50.0));
+        assert_eq!(time::Duration::from_millis(198), report.percentile(99.0));
+        assert_eq!(time::Duration::from_millis(200), report.percentile(100.0));
+        assert!(report
+            .to_string()
+            .starts_with("250 requests in 5.0s (50.0 rps), 50 failed"));
+
+        let empty = LoadReport {
+            latencies: Vec::new(),
+            errors: 0,
+            elapsed: time::Duration::from_secs(1),
+        };
+        assert_eq!(time::Duration::ZERO, empty.percentile(50.0));
+    }
+
+    #[tokio::test]
+    async fn test_load_rejects_bad_arguments() {
+        let url = "http://127.0.0.1:9";
+        let duration = time::Duration::from_secs(1);
+
+        let err = load(url, 0, duration, 4).await.unwrap_err();
+        assert!(err.is::<rate_limit::ConfigError>());
+        assert!(load(url, 10, duration, 0).await.is_err());
+    }
+}
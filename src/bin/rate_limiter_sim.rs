@@ -0,0 +1,207 @@
+use rate_limit::{
+    CalendarWindow, ConfigError, FixedWindow, Gcra, LeakyBucket, ManualClock, MovingWindow,
+    RateLimiter, RateLimiterBuilder, SlidingWindow, TokenBucket,
+};
+use std::{collections::VecDeque, time};
+
+/// `rate_limiter_sim <trace> <window ms> <limit>` compares the algorithms on a recorded trace of
+/// requests, see `simulate`.
+fn main() -> anyhow::Result<()> {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let [trace, window_ms, limit] = args.as_slice() else {
+        anyhow::bail!("usage: rate_limiter_sim <trace> <window ms> <limit>");
+    };
+
+    let trace = parse_trace(&std::fs::read_to_string(trace)?)?;
+    let window = time::Duration::from_millis(window_ms.parse()?);
+    print!("{}", simulate(&trace, window, limit.parse()?)?);
+    Ok(())
+}
+
+/// Reads request timestamps in seconds, either as the first column of a CSV (a header row is
+/// skipped) or as the `timestamp` field of JSON lines. Returns them relative to the earliest one.
+fn parse_trace(contents: &str) -> anyhow::Result<Vec<time::Duration>> {
+    let mut seconds = Vec::new();
+
+    for (n, line) in contents.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+
+        let timestamp = if line.starts_with('{') {
+            let value: serde_json::Value = serde_json::from_str(line)?;
+            value["timestamp"].as_f64()
+        } else {
+            line.split(',').next().and_then(|s| s.trim().parse().ok())
+        };
+
+        match timestamp {
+            Some(timestamp) => seconds.push(timestamp),
+            None if n == 0 => continue,
+            None => anyhow::bail!("line {} has no timestamp: {line}", n + 1),
+        }
+    }
+
+    seconds.sort_by(f64::total_cmp);
+    let start = seconds.first().copied().unwrap_or_default();
+
+    Ok(seconds
+        .into_iter()
+        .map(|s| time::Duration::from_secs_f64(s - start))
+        .collect())
+}
+
+/// Replays a trace against a limiter under virtual time, returning which requests were admitted.
+/// Fails if the limiter can't be built with `window` and `limit`.
+fn replay<L: RateLimiter<Clock = ManualClock>>(
+    trace: &[time::Duration],
+    window: time::Duration,
+    limit: usize,
+) -> Result<Vec<bool>, ConfigError> {
+    let clock = ManualClock::new();
+    let mut limiter: L = RateLimiterBuilder::new()
+        .window(window)
+        .limit(limit)
+        .clock(clock.clone())
+        .build()?;
+    let mut elapsed = time::Duration::ZERO;
+
+    Ok(trace
+        .iter()
+        .map(|&at| {
+            clock.advance(at - elapsed);
+            elapsed = at;
+            limiter.allowed()
+        })
+        .collect())
+}
+
+/// The decisions of an exact sliding window: a request is admitted if fewer than `limit` requests
+/// were admitted within the window before it.
+fn ideal(trace: &[time::Duration], window: time::Duration, limit: usize) -> Vec<bool> {
+    let mut admitted = VecDeque::new();
+
+    trace
+        .iter()
+        .map(|&at| {
+            while admitted.front().is_some_and(|&t| at - t >= window) {
+                admitted.pop_front();
+            }
+            let allowed = admitted.len() < limit;
+            if allowed {
+                admitted.push_back(at);
+            }
+            allowed
+        })
+        .collect()
+}
+
+/// Replays a trace against every algorithm and reports, for each, how many requests it admitted
+/// that an exact sliding window would have denied (over) and the reverse (under). Fails if `window`
+/// and `limit` aren't a valid configuration, such as a zero window or limit.
+fn simulate(
+    trace: &[time::Duration],
+    window: time::Duration,
+    limit: usize,
+) -> Result<String, ConfigError> {
+    let expected = ideal(trace, window, limit);
+    let results = [
+        (
+            "fixed window",
+            replay::<FixedWindow<ManualClock>>(trace, window, limit)?,
+        ),
+        (
+            "calendar window",
+            replay::<CalendarWindow<ManualClock>>(trace, window, limit)?,
+        ),
+        (
+            "moving window",
+            replay::<MovingWindow<ManualClock>>(trace, window, limit)?,
+        ),
+        (
+            "sliding window",
+            replay::<SlidingWindow<ManualClock>>(trace, window, limit)?,
+        ),
+        (
+            "token bucket",
+            replay::<TokenBucket<ManualClock>>(trace, window, limit)?,
+        ),
+        (
+            "leaky bucket",
+            replay::<LeakyBucket<ManualClock>>(trace, window, limit)?,
+        ),
+        ("gcra", replay::<Gcra<ManualClock>>(trace, window, limit)?),
+    ];
+
+    let mut report = format!(
+        "{:<16} {:>9} {:>9} {:>9}\n",
+        "algorithm", "admitted", "over", "under"
+    );
+    report += &format!(
+        "{:<16} {:>9} {:>9} {:>9}\n",
+        "ideal",
+        expected.iter().filter(|&&a| a).count(),
+        0,
+        0
+    );
+
+    for (name, admitted) in results {
+        let over = admitted.iter().zip(&expected).filter(|&(&a, &e)| a && !e);
+        let under = admitted.iter().zip(&expected).filter(|&(&a, &e)| !a && e);
+        report += &format!(
+            "{:<16} {:>9} {:>9} {:>9}\n",
+            name,
+            admitted.iter().filter(|&&a| a).count(),
+            over.count(),
+            under.count()
+        );
+    }
+
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_trace_simulation() {
+        let csv = "timestamp,path\n100.5,/a\n100.0,/b\n101.25,/c\n";
+        let expected = vec![
+            time::Duration::ZERO,
+            time::Duration::from_millis(500),
+            time::Duration::from_millis(1250),
+        ];
+        assert_eq!(expected, parse_trace(csv).unwrap());
+
+        let jsonl = "{\"timestamp\": 100.0}\n{\"timestamp\": 100.5}\n\n{\"timestamp\": 101.25}\n";
+        assert_eq!(expected, parse_trace(jsonl).unwrap());
+        assert!(parse_trace("1.0\nnot a number\n").is_err());
+
+        // A burst of 10 at the end of one window and 10 more right after the boundary.
+        let trace: Vec<_> = (0..10)
+            .map(|_| time::Duration::from_millis(900))
+            .chain((0..10).map(|_| time::Duration::from_millis(1100)))
+            .collect();
+        let window = time::Duration::from_secs(1);
+
+        assert_eq!(10, ideal(&trace, window, 10).iter().filter(|&&a| a).count());
+
+        let report = simulate(&trace, window, 10).unwrap();
+        let fixed = report
+            .lines()
+            .find(|l| l.starts_with("fixed window"))
+            .unwrap();
+        assert_eq!(
+            vec!["fixed", "window", "20", "10", "0"],
+            fixed.split_whitespace().collect::<Vec<_>>()
+        );
+
+        assert_eq!(Err(ConfigError::ZeroLimit), simulate(&trace, window, 0));
+        assert_eq!(
+            Err(ConfigError::ZeroWindow),
+            simulate(&trace, time::Duration::ZERO, 10)
+        );
+    }
+}